@@ -0,0 +1,101 @@
+use std::{collections::HashMap, net::SocketAddr, sync::Mutex, time::Duration};
+
+use tokio::time::Instant;
+
+/// Consecutive failed connection attempts after which a peer is banned
+pub const FAILURES_BEFORE_BAN: u32 = 2;
+/// Pieces failing verification after which the peer that sent them is banned
+pub const CORRUPT_PIECES_BEFORE_BAN: u32 = 2;
+/// Requests for blocks we can't serve after which the peer is banned
+pub const INVALID_REQUESTS_BEFORE_BAN: u32 = 10;
+/// How long a peer is banned for, growing with every failure after the ban
+pub const BAN_DURATIONS: [Duration; 3] = [
+    Duration::from_secs(5 * 60),
+    Duration::from_secs(15 * 60),
+    Duration::from_secs(60 * 60),
+];
+
+/// Peers that repeatedly failed to connect or handshake, shared by all
+/// torrents of a session so dead addresses aren't dialed over and over
+#[derive(Debug, Default)]
+pub struct BanList {
+    peers: Mutex<HashMap<SocketAddr, FailureRecord>>,
+}
+
+#[derive(Debug, Default)]
+struct FailureRecord {
+    failures: u32,
+    banned_until: Option<Instant>,
+}
+
+impl BanList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a failed connection attempt, banning the peer once it failed
+    /// `FAILURES_BEFORE_BAN` times in a row
+    pub fn record_failure(&self, addr: SocketAddr) {
+        let mut peers = self.peers.lock().unwrap();
+        let record = peers.entry(addr).or_default();
+        record.failures += 1;
+
+        if record.failures >= FAILURES_BEFORE_BAN {
+            let ban =
+                ((record.failures - FAILURES_BEFORE_BAN) as usize).min(BAN_DURATIONS.len() - 1);
+            record.banned_until = Some(Instant::now() + BAN_DURATIONS[ban]);
+        }
+    }
+
+    /// Bans a peer for the longest ban duration, for misbehaving rather
+    /// than merely being unreachable
+    pub fn ban(&self, addr: SocketAddr) {
+        let mut peers = self.peers.lock().unwrap();
+        let record = peers.entry(addr).or_default();
+        record.banned_until = Some(Instant::now() + BAN_DURATIONS[BAN_DURATIONS.len() - 1]);
+    }
+
+    /// Forgets the failures of a peer we connected to successfully
+    pub fn record_success(&self, addr: SocketAddr) {
+        self.peers.lock().unwrap().remove(&addr);
+    }
+
+    /// Whether the peer should not be dialed right now
+    pub fn is_banned(&self, addr: &SocketAddr) -> bool {
+        self.peers
+            .lock()
+            .unwrap()
+            .get(addr)
+            .and_then(|record| record.banned_until)
+            .is_some_and(|until| Instant::now() < until)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn ban_grows_with_failures() {
+        let bans = BanList::new();
+        let addr = SocketAddr::from(([10, 0, 0, 1], 6881));
+
+        bans.record_failure(addr);
+        assert!(!bans.is_banned(&addr));
+
+        bans.record_failure(addr);
+        assert!(bans.is_banned(&addr));
+        tokio::time::advance(BAN_DURATIONS[0]).await;
+        assert!(!bans.is_banned(&addr));
+
+        bans.record_failure(addr);
+        tokio::time::advance(BAN_DURATIONS[0]).await;
+        assert!(bans.is_banned(&addr));
+        tokio::time::advance(BAN_DURATIONS[1] - BAN_DURATIONS[0]).await;
+        assert!(!bans.is_banned(&addr));
+
+        bans.record_failure(addr);
+        bans.record_success(addr);
+        assert!(!bans.is_banned(&addr));
+    }
+}
@@ -1,5 +1,12 @@
-use std::{collections::BTreeMap, fmt::Display, iter::Peekable, path::PathBuf};
-
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    iter::Peekable,
+    num::{IntErrorKind, ParseIntError},
+    path::PathBuf,
+};
+
+use bytes::BufMut;
 use thiserror::Error;
 
 const INT_PREFIX: u8 = b'i';
@@ -51,7 +58,7 @@ impl TryFrom<&BencodeType> for i64 {
     type Error = BencodeGetErr;
     fn try_from(value: &BencodeType) -> Result<Self, Self::Error> {
         match value {
-            BencodeType::Integer(x) => Ok(x.clone()),
+            BencodeType::Integer(x) => Ok(*x),
             _ => Err(BencodeGetErr::InvalidConversion),
         }
     }
@@ -103,15 +110,19 @@ impl<'a> TryFrom<&'a BencodeType> for PathBuf {
 // TODO: update T to accept TryInfo instead;
 // https://doc.rust-lang.org/std/convert/trait.TryFrom.html
 pub trait BencodeMapDecoder {
-    fn get_decode<'a, T>(self: &'a Self, key: &str) -> Option<T>
+    fn get_decode<'a, T>(&'a self, key: &str) -> Option<T>
     where
         T: TryFrom<&'a BencodeType>;
-    fn try_decode(bytes: &Vec<u8>) -> Result<BencodeMap, BencodeParseErr>;
-    fn print_keys(self: &Self);
+    /// Follows `keys` through nested dictionaries, e.g. `["info", "name"]`,
+    /// returning `None` if a key is missing or its value isn't a dictionary
+    /// where another key follows
+    fn get_path(&self, keys: &[&str]) -> Option<&BencodeType>;
+    fn try_decode(bytes: &[u8]) -> Result<BencodeMap, BencodeParseErr>;
+    fn print_keys(&self);
 }
 
 impl BencodeMapDecoder for BencodeMap {
-    fn get_decode<'a, T>(self: &'a Self, key: &str) -> Option<T>
+    fn get_decode<'a, T>(&'a self, key: &str) -> Option<T>
     where
         T: TryFrom<&'a BencodeType>,
     {
@@ -122,9 +133,25 @@ impl BencodeMapDecoder for BencodeMap {
         }
     }
 
-    fn try_decode(bytes: &Vec<u8>) -> Result<BencodeMap, BencodeParseErr> {
+    fn get_path(&self, keys: &[&str]) -> Option<&BencodeType> {
+        let (last, parents) = keys.split_last()?;
+
+        let mut map = self;
+        for key in parents {
+            match map.get(key.as_bytes())? {
+                BencodeType::Dictionary(nested) => map = nested,
+                _ => return None,
+            }
+        }
+
+        map.get(last.as_bytes())
+    }
+
+    fn try_decode(bytes: &[u8]) -> Result<BencodeMap, BencodeParseErr> {
         match bytes.first() {
-            Some(_) => match read_dictionary(&mut bytes.iter().cloned().peekable().clone())? {
+            Some(_) => match read_dictionary(&mut bytes.iter().cloned().peekable())
+                .map_err(|err| err.located(bytes.len()))?
+            {
                 BencodeType::Dictionary(x) => Ok(x),
                 _ => Err(BencodeParseErr::InvalidDictionaryBencode(String::from(
                     ERROR_INVALID_DICT,
@@ -136,9 +163,8 @@ impl BencodeMapDecoder for BencodeMap {
         }
     }
 
-    fn print_keys(self: &Self) {
-        let mut iter = self.keys().into_iter();
-        while let Some(x) = iter.next() {
+    fn print_keys(&self) {
+        for x in self.keys() {
             if let Ok(y) = String::from_utf8(x.clone()) {
                 println!("{y}");
             } else {
@@ -149,13 +175,35 @@ impl BencodeMapDecoder for BencodeMap {
 }
 
 pub trait BencodeMapEncoder {
-    fn get_encode(self: &Self) -> Vec<u8>;
+    fn get_encode(&self) -> Vec<u8>;
+    /// Appends the encoded dictionary to `buf`
+    fn encode_into(&self, buf: &mut impl BufMut);
+    /// Exact number of bytes `encode_into` appends
+    fn encoded_len(&self) -> usize;
 }
 
 impl BencodeMapEncoder for BencodeMap {
-    fn get_encode(self: &Self) -> Vec<u8> {
-        let wrapper = BencodeType::Dictionary(self.clone());
-        encode(&wrapper)
+    fn get_encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(self.encoded_len());
+        self.encode_into(&mut buffer);
+        buffer
+    }
+
+    fn encoded_len(&self) -> usize {
+        let entries: usize = self
+            .iter()
+            .map(|(key, value)| encoded_string_len(key) + value.encoded_len())
+            .sum();
+        entries + 2
+    }
+
+    fn encode_into(&self, buf: &mut impl BufMut) {
+        buf.put_u8(DICTIONARY_PREFIX);
+        for (key, value) in self {
+            encode_string_into(key, buf);
+            value.encode_into(buf);
+        }
+        buf.put_u8(DICTIONARY_SUFFIX);
     }
 }
 
@@ -167,21 +215,77 @@ impl Display for BencodeType {
                 Ok(utf8) => write!(f, "String({utf8})"),
                 Err(b) => write!(f, "String({b})"),
             },
-            BencodeType::Dictionary(x) => write!(f, "Dictionary({x:?})"),
-            BencodeType::List(x) => write!(f, "List({x:?}"),
+            BencodeType::Dictionary(x) => {
+                write!(f, "Dictionary({{")?;
+                for (index, (key, value)) in x.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: {value}", String::from_utf8_lossy(key))?;
+                }
+                write!(f, "}})")
+            }
+            BencodeType::List(x) => {
+                write!(f, "List([")?;
+                for (index, value) in x.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{value}")?;
+                }
+                write!(f, "])")
+            }
         }
     }
 }
 
 impl BencodeType {
-    pub fn get_string(self: &Self) -> Result<Vec<u8>, BencodeGetErr> {
+    /// Renders the value in bencode wire form, which `decode_to_vec` parses
+    /// back into an equal value as long as every string is valid UTF-8
+    pub fn to_bencode_string(&self) -> String {
+        String::from_utf8_lossy(&encode(self)).into_owned()
+    }
+
+    /// Appends the encoded value to `buf`, e.g. a `BytesMut` or `Vec<u8>`,
+    /// without building intermediate buffers
+    pub fn encode_into(&self, buf: &mut impl BufMut) {
+        match self {
+            BencodeType::Integer(x) => {
+                buf.put_u8(INT_PREFIX);
+                buf.put_slice(x.to_string().as_bytes());
+                buf.put_u8(INT_SUFFIX);
+            }
+            BencodeType::String(x) => encode_string_into(x, buf),
+            BencodeType::List(x) => {
+                buf.put_u8(LIST_PREFIX);
+                for item in x {
+                    item.encode_into(buf);
+                }
+                buf.put_u8(LIST_SUFFIX);
+            }
+            BencodeType::Dictionary(x) => x.encode_into(buf),
+        }
+    }
+
+    /// Exact number of bytes `encode_into` appends, so buffers can be
+    /// allocated once up front
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            BencodeType::Integer(x) => decimal_len(x.unsigned_abs()) + usize::from(*x < 0) + 2,
+            BencodeType::String(x) => encoded_string_len(x),
+            BencodeType::List(x) => x.iter().map(BencodeType::encoded_len).sum::<usize>() + 2,
+            BencodeType::Dictionary(x) => x.encoded_len(),
+        }
+    }
+
+    pub fn get_string(&self) -> Result<Vec<u8>, BencodeGetErr> {
         match self {
             Self::String(x) => Ok(x.clone()),
             _ => Err(BencodeGetErr::InvalidType),
         }
     }
 
-    pub fn get_utf8_string(self: &Self) -> Result<String, BencodeGetErr> {
+    pub fn get_utf8_string(&self) -> Result<String, BencodeGetErr> {
         match self {
             Self::String(x) => String::from_utf8(x.clone()).map_err(|_| BencodeGetErr::InvalidUtf8),
             _ => Err(BencodeGetErr::InvalidUtf8),
@@ -197,28 +301,48 @@ pub enum BencodeParseErr {
     InvalidBencode(String),
     #[error("Invalid integer bencode type found")]
     InvalidIntegerBencode(String),
+    #[error("Integer {0} does not fit in 64 bits")]
+    IntegerOverflow(String),
     #[error("Invalid list bencode type found")]
     InvalidListBencode(String),
     #[error("Invalid dictionary bencode type found")]
     InvalidDictionaryBencode(String),
+    #[error("Dictionary keys must be strings, found '{}' at offset {offset}", .byte.escape_ascii())]
+    InvalidDictionaryKey { byte: u8, offset: usize },
     #[error("Invalid string bencode type found")]
     InvalidStringBencode(String),
 }
 
-pub fn decode_to_vec(encoded_value: &Vec<u8>) -> Result<Vec<BencodeType>, BencodeParseErr> {
+impl BencodeParseErr {
+    /// Turns the position of an invalid key, counted from the end of the
+    /// input while parsing, into an offset from the start of the input
+    fn located(self, input_len: usize) -> Self {
+        match self {
+            BencodeParseErr::InvalidDictionaryKey { byte, offset } => {
+                BencodeParseErr::InvalidDictionaryKey {
+                    byte,
+                    offset: input_len - offset,
+                }
+            }
+            err => err,
+        }
+    }
+}
+
+pub fn decode_to_vec(encoded_value: &[u8]) -> Result<Vec<BencodeType>, BencodeParseErr> {
     let mut vec: Vec<BencodeType> = Vec::new();
 
     let mut iter = encoded_value.iter().copied().peekable();
 
-    while let Some(_) = iter.peek() {
-        vec.push(read_value(&mut iter)?);
+    while iter.peek().is_some() {
+        vec.push(read_value(&mut iter).map_err(|err| err.located(encoded_value.len()))?);
     }
 
     Ok(vec)
 }
 
 fn read_value(
-    iter: &mut Peekable<impl Iterator<Item = u8>>,
+    iter: &mut Peekable<impl ExactSizeIterator<Item = u8>>,
 ) -> Result<BencodeType, BencodeParseErr> {
     if let Some(c) = iter.peek() {
         match c {
@@ -236,7 +360,7 @@ fn read_value(
 fn read_integer(iter: &mut impl Iterator<Item = u8>) -> Result<BencodeType, BencodeParseErr> {
     let mut temp = String::new();
 
-    while let Some(x) = iter.next() {
+    for x in iter.by_ref() {
         match x {
             b'-' => temp.push(char::from(x)),
             b'0'..=b'9' => temp.push(char::from(x)),
@@ -258,11 +382,16 @@ fn read_integer(iter: &mut impl Iterator<Item = u8>) -> Result<BencodeType, Benc
 
     temp.parse()
         .map(BencodeType::Integer)
-        .map_err(|_| BencodeParseErr::InvalidIntegerBencode(String::from(ERROR_INVALID_INTEGER)))
+        .map_err(|err: ParseIntError| match err.kind() {
+            IntErrorKind::PosOverflow | IntErrorKind::NegOverflow => {
+                BencodeParseErr::IntegerOverflow(temp)
+            }
+            _ => BencodeParseErr::InvalidIntegerBencode(String::from(ERROR_INVALID_INTEGER)),
+        })
 }
 
 fn read_list(
-    iter: &mut Peekable<impl Iterator<Item = u8>>,
+    iter: &mut Peekable<impl ExactSizeIterator<Item = u8>>,
 ) -> Result<BencodeType, BencodeParseErr> {
     match iter.next() {
         Some(x) if x == LIST_PREFIX => {}
@@ -292,7 +421,7 @@ fn read_list(
 }
 
 fn read_dictionary(
-    iter: &mut Peekable<impl Iterator<Item = u8>>,
+    iter: &mut Peekable<impl ExactSizeIterator<Item = u8>>,
 ) -> Result<BencodeType, BencodeParseErr> {
     match iter.next() {
         Some(x) if x == DICTIONARY_PREFIX => {}
@@ -317,10 +446,12 @@ fn read_dictionary(
                 let value = read_value(iter)?;
                 result.insert(key, value);
             }
-            _ => {
-                return Err(BencodeParseErr::InvalidDictionaryBencode(String::from(
-                    ERROR_INVALID_KEY,
-                )));
+            &byte => {
+                // Counted from the end until `located` knows the input length
+                return Err(BencodeParseErr::InvalidDictionaryKey {
+                    byte,
+                    offset: iter.len(),
+                });
             }
         }
     }
@@ -355,52 +486,33 @@ fn read_string(iter: &mut impl Iterator<Item = u8>) -> Result<BencodeType, Benco
     Ok(BencodeType::String(result))
 }
 
-fn encode_string(bytes: &Vec<u8>) -> Vec<u8> {
-    let mut buffer = Vec::new();
-    buffer.extend_from_slice(bytes.len().to_string().as_bytes());
-    buffer.push(STRING_DELIMITER);
-    buffer.extend_from_slice(&bytes);
+fn encode_string_into(bytes: &[u8], buf: &mut impl BufMut) {
+    buf.put_slice(bytes.len().to_string().as_bytes());
+    buf.put_u8(STRING_DELIMITER);
+    buf.put_slice(bytes);
+}
 
-    buffer
+/// Number of digits of `n` written in decimal
+fn decimal_len(n: u64) -> usize {
+    n.checked_ilog10().map_or(1, |log| log as usize + 1)
 }
 
-pub fn encode(value: &BencodeType) -> Vec<u8> {
-    let mut buffer = Vec::new();
+fn encoded_string_len(bytes: &[u8]) -> usize {
+    decimal_len(bytes.len() as u64) + 1 + bytes.len()
+}
 
-    match value {
-        BencodeType::Integer(x) => {
-            buffer.push(INT_PREFIX);
-            buffer.extend_from_slice(x.to_string().as_bytes());
-            buffer.push(INT_SUFFIX);
-        }
-        BencodeType::String(x) => {
-            buffer.extend_from_slice(&encode_string(x));
-        }
-        BencodeType::List(x) => {
-            buffer.push(LIST_PREFIX);
-            for item in x {
-                buffer.extend_from_slice(&encode(item));
-            }
-            buffer.push(LIST_SUFFIX);
-        }
-        BencodeType::Dictionary(x) => {
-            buffer.push(DICTIONARY_PREFIX);
-            for (key, value) in x {
-                buffer.extend_from_slice(&encode_string(key));
-                buffer.extend_from_slice(&encode(value));
-            }
-            buffer.push(DICTIONARY_SUFFIX);
-        }
-    }
+pub fn encode(value: &BencodeType) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(value.encoded_len());
+    value.encode_into(&mut buffer);
 
     buffer
 }
 
 pub fn encode_vec(values: &Vec<BencodeType>) -> Vec<u8> {
-    let mut buffer = Vec::new();
+    let mut buffer = Vec::with_capacity(values.iter().map(BencodeType::encoded_len).sum());
 
     for x in values {
-        buffer.extend_from_slice(&encode(&x));
+        x.encode_into(&mut buffer);
     }
 
     buffer
@@ -413,7 +525,7 @@ mod tests {
     // INTEGER READ TESTS
     #[test]
     fn read_integer_success() {
-        let mut data = "i3e".bytes().into_iter();
+        let mut data = "i3e".bytes();
         let expected = Ok(BencodeType::Integer(3));
 
         let result = read_integer(&mut data);
@@ -423,7 +535,7 @@ mod tests {
 
     #[test]
     fn read_integer_invalid_format() {
-        let mut data = "ie3".bytes().into_iter();
+        let mut data = "ie3".bytes();
         let expected = Err(BencodeParseErr::InvalidIntegerBencode(String::from(
             ERROR_INVALID_INTEGER,
         )));
@@ -435,7 +547,7 @@ mod tests {
 
     #[test]
     fn read_integer_non_numeric() {
-        let mut data = "i0te".bytes().into_iter();
+        let mut data = "i0te".bytes();
         let expected = Err(BencodeParseErr::InvalidIntegerBencode(String::from(
             ERROR_NON_NUMERIC_CHARACTER,
         )));
@@ -445,9 +557,31 @@ mod tests {
         assert_eq!(result, expected)
     }
 
+    #[test]
+    fn read_integer_overflow() {
+        let mut data = "i9999999999999999999999e".bytes();
+        let expected = Err(BencodeParseErr::IntegerOverflow(String::from(
+            "9999999999999999999999",
+        )));
+
+        let result = read_integer(&mut data);
+
+        assert_eq!(result, expected)
+    }
+
+    #[test]
+    fn read_integer_large_in_range() {
+        let mut data = "i9223372036854775807e".bytes();
+        let expected = Ok(BencodeType::Integer(i64::MAX));
+
+        let result = read_integer(&mut data);
+
+        assert_eq!(result, expected)
+    }
+
     #[test]
     fn read_integer_neg_zero() {
-        let mut data = "i-0e".bytes().into_iter();
+        let mut data = "i-0e".bytes();
         let expected = Err(BencodeParseErr::InvalidIntegerBencode(String::from(
             ERROR_NEGATIVE_ZERO,
         )));
@@ -460,7 +594,7 @@ mod tests {
     // STRING READ TESTS
     #[test]
     fn read_string_success() {
-        let mut data = "6:pieces".bytes().into_iter();
+        let mut data = "6:pieces".bytes();
         let expected = Ok(BencodeType::String(String::from("pieces").into_bytes()));
         let result = read_string(&mut data);
 
@@ -469,7 +603,7 @@ mod tests {
 
     #[test]
     fn read_string_no_chars() {
-        let mut data = "0:".bytes().into_iter();
+        let mut data = "0:".bytes();
         let expected = Ok(BencodeType::String(String::from("").into_bytes()));
 
         let result = read_string(&mut data);
@@ -479,7 +613,7 @@ mod tests {
 
     #[test]
     fn read_string_invalid_len_char() {
-        let mut data = "4r:test".bytes().into_iter();
+        let mut data = "4r:test".bytes();
         let expected = Err(BencodeParseErr::InvalidStringBencode(String::from(
             ERROR_NON_NUMERIC_CHARACTER,
         )));
@@ -491,7 +625,7 @@ mod tests {
 
     #[test]
     fn read_string_not_enough_chars() {
-        let mut data = "4:hi".bytes().into_iter();
+        let mut data = "4:hi".bytes();
         let expected = Err(BencodeParseErr::InvalidStringBencode(String::from(
             ERROR_NOT_ENOUGH_CHARS,
         )));
@@ -503,7 +637,7 @@ mod tests {
 
     #[test]
     fn read_string_no_len() {
-        let mut data = ":hi".bytes().into_iter();
+        let mut data = ":hi".bytes();
         let expected = Err(BencodeParseErr::InvalidStringBencode(String::from(
             ERROR_MISSING_PREFIX,
         )));
@@ -516,7 +650,7 @@ mod tests {
     // LIST READ TESTS
     #[test]
     fn read_list_success() {
-        let mut data = "l4:spam4:eggse".bytes().into_iter().peekable();
+        let mut data = "l4:spam4:eggse".bytes().peekable();
         let expected = Ok(BencodeType::List(vec![
             BencodeType::String(String::from("spam").into_bytes()),
             BencodeType::String(String::from("eggs").into_bytes()),
@@ -529,7 +663,7 @@ mod tests {
 
     #[test]
     fn read_list_nested() {
-        let mut data = "l4:spaml4:eggsee".bytes().into_iter().peekable();
+        let mut data = "l4:spaml4:eggsee".bytes().peekable();
         let expected = Ok(BencodeType::List(vec![
             BencodeType::String(String::from("spam").into_bytes()),
             BencodeType::List(vec![BencodeType::String(String::from("eggs").into_bytes())]),
@@ -542,7 +676,7 @@ mod tests {
 
     #[test]
     fn read_list_invalid_string() {
-        let mut data = "l24:spam4:eggse".bytes().into_iter().peekable();
+        let mut data = "l24:spam4:eggse".bytes().peekable();
         let expected = Err(BencodeParseErr::InvalidStringBencode(String::from(
             ERROR_NOT_ENOUGH_CHARS,
         )));
@@ -554,7 +688,7 @@ mod tests {
 
     #[test]
     fn read_list_invalid_bencode() {
-        let mut data = "lx23e".bytes().into_iter().peekable();
+        let mut data = "lx23e".bytes().peekable();
         let expected = Err(BencodeParseErr::InvalidBencode(b'x'.to_string()));
 
         let result = read_list(&mut data);
@@ -564,7 +698,7 @@ mod tests {
 
     #[test]
     fn read_list_missing_prefix() {
-        let mut data = "i2ee".bytes().into_iter().peekable();
+        let mut data = "i2ee".bytes().peekable();
         let expected = Err(BencodeParseErr::InvalidListBencode(String::from(
             ERROR_MISSING_PREFIX,
         )));
@@ -576,7 +710,7 @@ mod tests {
 
     #[test]
     fn read_list_missing_suffix() {
-        let mut data = "li2e".bytes().into_iter().peekable();
+        let mut data = "li2e".bytes().peekable();
         let expected = Err(BencodeParseErr::InvalidListBencode(String::from(
             ERROR_MISSING_SUFFIX,
         )));
@@ -589,7 +723,7 @@ mod tests {
     // DICTIONARY READ TESTS
     #[test]
     fn read_dictionary_success() {
-        let mut data = "d3:cow3:moo4:spam4:eggse".bytes().into_iter().peekable();
+        let mut data = "d3:cow3:moo4:spam4:eggse".bytes().peekable();
         let mut map: BencodeMap = BencodeMap::new();
         map.insert(
             String::from("cow").into_bytes(),
@@ -610,7 +744,6 @@ mod tests {
     fn read_dictionary_nested_map() {
         let mut data = "d3:cow3:moo4:spam4:eggs4:dictd3:key5:valueee"
             .bytes()
-            .into_iter()
             .peekable();
 
         let mut map: BencodeMap = BencodeMap::new();
@@ -642,7 +775,7 @@ mod tests {
 
     #[test]
     fn read_dictionary_missing_prefix() {
-        let mut data = "3:cow3:moo4:spam4:eggse".bytes().into_iter().peekable();
+        let mut data = "3:cow3:moo4:spam4:eggse".bytes().peekable();
         let expected = Err(BencodeParseErr::InvalidDictionaryBencode(String::from(
             ERROR_MISSING_PREFIX,
         )));
@@ -654,19 +787,45 @@ mod tests {
 
     #[test]
     fn read_dictionary_invalid_key() {
-        let mut data = "die33:moo4:spam4:eggse".bytes().into_iter().peekable();
-        let expected = Err(BencodeParseErr::InvalidDictionaryBencode(String::from(
-            ERROR_INVALID_KEY,
-        )));
+        let result = BencodeMap::try_decode(b"d3:cow3:mooi3e3:mooe");
+        let expected = Err(BencodeParseErr::InvalidDictionaryKey {
+            byte: INT_PREFIX,
+            offset: 11,
+        });
+
+        assert_eq!(result, expected);
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Dictionary keys must be strings, found 'i' at offset 11"
+        );
+    }
 
-        let result = read_dictionary(&mut data);
+    #[test]
+    fn read_dictionary_nested_invalid_key() {
+        let result = decode_to_vec(b"li1ed4:infodle0:eee");
+        let expected = Err(BencodeParseErr::InvalidDictionaryKey {
+            byte: LIST_PREFIX,
+            offset: 12,
+        });
 
-        assert_eq!(result, expected)
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn read_dictionary_empty() {
+        assert_eq!(BencodeMap::try_decode(b"de"), Ok(BencodeMap::new()));
+        assert_eq!(
+            decode_to_vec(b"d4:infodee"),
+            Ok(vec![BencodeType::Dictionary(BencodeMap::from([(
+                b"info".to_vec(),
+                BencodeType::Dictionary(BencodeMap::new())
+            )]))])
+        );
     }
 
     #[test]
     fn read_dictionary_missing_suffix() {
-        let mut data = "d3:cow3:moo4:spam4:eggs".bytes().into_iter().peekable();
+        let mut data = "d3:cow3:moo4:spam4:eggs".bytes().peekable();
         let expected = Err(BencodeParseErr::InvalidDictionaryBencode(String::from(
             ERROR_MISSING_SUFFIX,
         )));
@@ -675,4 +834,115 @@ mod tests {
 
         assert_eq!(result, expected)
     }
+
+    // PATH TESTS
+    fn nested_map() -> BencodeMap {
+        BencodeMap::try_decode(b"d4:infod5:filesd4:sizei7eee4:name3:fooe").unwrap()
+    }
+
+    #[test]
+    fn get_path_finds_nested_value() {
+        let map = nested_map();
+
+        assert_eq!(
+            map.get_path(&["info", "files", "size"]),
+            Some(&BencodeType::Integer(7))
+        );
+        assert_eq!(
+            map.get_path(&["name"]),
+            Some(&BencodeType::String(b"foo".to_vec()))
+        );
+    }
+
+    #[test]
+    fn get_path_missing_key() {
+        let map = nested_map();
+
+        assert_eq!(map.get_path(&["info", "length"]), None);
+        assert_eq!(map.get_path(&["other", "size"]), None);
+        assert_eq!(map.get_path(&[]), None);
+    }
+
+    #[test]
+    fn get_path_through_non_dictionary() {
+        assert_eq!(nested_map().get_path(&["name", "size"]), None);
+    }
+
+    // DISPLAY TESTS
+    #[test]
+    fn display_closes_nested_values() {
+        let value = BencodeType::List(vec![
+            BencodeType::Integer(1),
+            BencodeType::String(b"spam".to_vec()),
+        ]);
+
+        assert_eq!(value.to_string(), "List([Integer(1), String(spam)])");
+    }
+
+    // ENCODE TESTS
+    #[test]
+    fn encoders_match_checked_in_torrent() {
+        let torrent =
+            std::fs::read("../test/torrent_files/debian-13.1.0-amd64-netinst.iso.torrent").unwrap();
+        let values = decode_to_vec(&torrent).unwrap();
+        let BencodeType::Dictionary(map) = &values[0] else {
+            panic!("Expected a dictionary");
+        };
+
+        let mut buf = bytes::BytesMut::new();
+        values[0].encode_into(&mut buf);
+        let mut appended = b"prefix".to_vec();
+        map.encode_into(&mut appended);
+
+        assert_eq!(encode_vec(&values), torrent);
+        assert_eq!(&buf[..], torrent);
+        assert_eq!(map.get_encode(), torrent);
+        assert_eq!(map.encoded_len(), torrent.len());
+        assert_eq!(appended, [b"prefix".as_slice(), &torrent].concat());
+    }
+
+    #[test]
+    fn encoded_len_matches_encoding() {
+        let mut map = nested_map();
+        map.insert(b"".to_vec(), BencodeType::String(vec![0xff; 10]));
+        let values = [
+            BencodeType::Integer(0),
+            BencodeType::Integer(-10),
+            BencodeType::Integer(i64::MIN),
+            BencodeType::Integer(i64::MAX),
+            BencodeType::String(Vec::new()),
+            BencodeType::List(vec![
+                BencodeType::List(Vec::new()),
+                BencodeType::Dictionary(map.clone()),
+                BencodeType::Integer(99),
+            ]),
+            BencodeType::Dictionary(map),
+        ];
+
+        for value in &values {
+            assert_eq!(value.encoded_len(), encode(value).len(), "{value}");
+        }
+    }
+
+    #[test]
+    fn bencode_string_round_trips() {
+        let mut map = BencodeMap::new();
+        map.insert(
+            b"name".to_vec(),
+            BencodeType::String(b"debian.iso".to_vec()),
+        );
+        map.insert(b"length".to_vec(), BencodeType::Integer(-42));
+        map.insert(
+            b"tiers".to_vec(),
+            BencodeType::List(vec![BencodeType::List(vec![BencodeType::String(
+                b"http://tracker/announce".to_vec(),
+            )])]),
+        );
+        let value = BencodeType::Dictionary(map);
+
+        let text = value.to_bencode_string();
+        let decoded = decode_to_vec(text.as_bytes()).unwrap();
+
+        assert_eq!(decoded, vec![value]);
+    }
 }
@@ -0,0 +1,254 @@
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+use crate::{
+    mse::EncryptionMode,
+    peer_id::CLIENT_PREFIX,
+    peer_manager::DEFAULT_MAX_CONNECTIONS,
+    piece_manager::{PieceStrategy, BLOCK_SIZE, DEFAULT_MAX_PIECES_IN_PROGRESS, MAX_BLOCK_SIZE},
+    tracker::DEFAULT_NUMWANT,
+    transport::TransportKind,
+};
+
+/// Port announced to trackers when none is configured
+pub const DEFAULT_PORT: u16 = 6881;
+/// Name of the directory of this client within the platform's data directory
+const STATE_DIR_NAME: &str = "rtorrent";
+
+/// Directory resume files are kept in when none is configured: the
+/// platform's per-user state directory, or `.rtorrent` in the working
+/// directory if it can't be determined
+pub fn default_state_dir() -> PathBuf {
+    let env = |key| {
+        std::env::var_os(key)
+            .filter(|value| !value.is_empty())
+            .map(PathBuf::from)
+    };
+    let base = if cfg!(windows) {
+        env("LOCALAPPDATA")
+    } else if cfg!(target_os = "macos") {
+        env("HOME").map(|home| home.join("Library/Application Support"))
+    } else {
+        env("XDG_STATE_HOME").or_else(|| env("HOME").map(|home| home.join(".local/state")))
+    };
+
+    base.map_or_else(
+        || PathBuf::from(format!(".{STATE_DIR_NAME}")),
+        |base| base.join(STATE_DIR_NAME),
+    )
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ConfigErr {
+    #[error("Block size {0} is not a power of two of at most {MAX_BLOCK_SIZE} bytes")]
+    InvalidBlockSize(usize),
+    #[error("At least one piece must be allowed in progress")]
+    NoPiecesInProgress,
+}
+
+/// Settings shared by every torrent in a session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionConfig {
+    /// Port announced to trackers for incoming connections
+    pub port: u16,
+    /// Directory downloaded files are written to
+    pub download_dir: PathBuf,
+    /// Directory resume files are kept in, named after the info hash of
+    /// their torrent, so they don't clutter the download directory
+    pub state_dir: PathBuf,
+    /// Maximum number of concurrent peer connections per torrent
+    pub max_connections: usize,
+    /// Session-wide download limit in bytes per second, 0 is unlimited
+    pub download_limit: u64,
+    /// Session-wide upload limit in bytes per second, 0 is unlimited
+    pub upload_limit: u64,
+    /// Azureus-style prefix of the generated peer ID
+    pub peer_id_prefix: [u8; 8],
+    /// Whether outgoing connections use Message Stream Encryption
+    pub encryption: EncryptionMode,
+    /// Protocol outgoing connections are made over
+    pub transport: TransportKind,
+    /// Number of peers asked for in each announce
+    pub numwant: u32,
+    /// Size of the blocks pieces are requested from peers in, only set
+    /// through the builder so it is always valid
+    block_size: usize,
+    /// Whether every tracker of a torrent is announced to at once, rather
+    /// than only the first one
+    pub announce_to_all_trackers: bool,
+    /// Maximum number of pieces of a torrent downloaded at once, only set
+    /// through the builder so it is always valid
+    max_pieces_in_progress: usize,
+    /// Order in which the pieces of new torrents are downloaded
+    pub piece_strategy: PieceStrategy,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            port: DEFAULT_PORT,
+            download_dir: PathBuf::from("."),
+            state_dir: default_state_dir(),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            download_limit: 0,
+            upload_limit: 0,
+            peer_id_prefix: *CLIENT_PREFIX,
+            encryption: EncryptionMode::default(),
+            transport: TransportKind::default(),
+            numwant: DEFAULT_NUMWANT,
+            block_size: BLOCK_SIZE,
+            announce_to_all_trackers: false,
+            max_pieces_in_progress: DEFAULT_MAX_PIECES_IN_PROGRESS,
+            piece_strategy: PieceStrategy::default(),
+        }
+    }
+}
+
+impl SessionConfig {
+    /// Starts building a config from the defaults
+    pub fn builder() -> SessionConfigBuilder {
+        SessionConfigBuilder::default()
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn max_pieces_in_progress(&self) -> usize {
+        self.max_pieces_in_progress
+    }
+
+    /// Checks the settings that can't be used as they are
+    fn validate(&self) -> Result<(), ConfigErr> {
+        if !self.block_size.is_power_of_two() || self.block_size > MAX_BLOCK_SIZE {
+            return Err(ConfigErr::InvalidBlockSize(self.block_size));
+        }
+        if self.max_pieces_in_progress == 0 {
+            return Err(ConfigErr::NoPiecesInProgress);
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SessionConfigBuilder {
+    config: SessionConfig,
+}
+
+impl SessionConfigBuilder {
+    pub fn port(mut self, port: u16) -> Self {
+        self.config.port = port;
+        self
+    }
+
+    pub fn download_dir(mut self, download_dir: impl Into<PathBuf>) -> Self {
+        self.config.download_dir = download_dir.into();
+        self
+    }
+
+    pub fn state_dir(mut self, state_dir: impl Into<PathBuf>) -> Self {
+        self.config.state_dir = state_dir.into();
+        self
+    }
+
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.config.max_connections = max_connections;
+        self
+    }
+
+    pub fn download_limit(mut self, bytes_per_second: u64) -> Self {
+        self.config.download_limit = bytes_per_second;
+        self
+    }
+
+    pub fn upload_limit(mut self, bytes_per_second: u64) -> Self {
+        self.config.upload_limit = bytes_per_second;
+        self
+    }
+
+    pub fn peer_id_prefix(mut self, prefix: [u8; 8]) -> Self {
+        self.config.peer_id_prefix = prefix;
+        self
+    }
+
+    pub fn encryption(mut self, encryption: EncryptionMode) -> Self {
+        self.config.encryption = encryption;
+        self
+    }
+
+    pub fn transport(mut self, transport: TransportKind) -> Self {
+        self.config.transport = transport;
+        self
+    }
+
+    pub fn numwant(mut self, numwant: u32) -> Self {
+        self.config.numwant = numwant;
+        self
+    }
+
+    pub fn block_size(mut self, block_size: usize) -> Self {
+        self.config.block_size = block_size;
+        self
+    }
+
+    pub fn announce_to_all_trackers(mut self, announce_to_all_trackers: bool) -> Self {
+        self.config.announce_to_all_trackers = announce_to_all_trackers;
+        self
+    }
+
+    pub fn max_pieces_in_progress(mut self, max_pieces_in_progress: usize) -> Self {
+        self.config.max_pieces_in_progress = max_pieces_in_progress;
+        self
+    }
+
+    pub fn piece_strategy(mut self, piece_strategy: PieceStrategy) -> Self {
+        self.config.piece_strategy = piece_strategy;
+        self
+    }
+
+    pub fn build(self) -> Result<SessionConfig, ConfigErr> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builder_overrides_defaults() {
+        let config = SessionConfig::builder()
+            .port(51413)
+            .download_dir("/tmp/downloads")
+            .encryption(EncryptionMode::Preferred)
+            .numwant(200)
+            .block_size(1 << 13)
+            .build()
+            .unwrap();
+
+        assert_eq!(config.port, 51413);
+        assert_eq!(config.download_dir, PathBuf::from("/tmp/downloads"));
+        assert_eq!(config.max_connections, DEFAULT_MAX_CONNECTIONS);
+        assert_eq!(&config.peer_id_prefix, CLIENT_PREFIX);
+        assert_eq!(config.encryption, EncryptionMode::Preferred);
+        assert_eq!(config.numwant, 200);
+        assert_eq!(config.block_size, 1 << 13);
+    }
+
+    #[test]
+    fn invalid_block_sizes_rejected() {
+        for block_size in [0, 3000, 1 << 16] {
+            assert_eq!(
+                SessionConfig::builder().block_size(block_size).build(),
+                Err(ConfigErr::InvalidBlockSize(block_size))
+            );
+        }
+        assert_eq!(
+            SessionConfig::builder().max_pieces_in_progress(0).build(),
+            Err(ConfigErr::NoPiecesInProgress)
+        );
+    }
+}
@@ -0,0 +1,23 @@
+use std::net::SocketAddr;
+
+/// Events buffered for each subscriber, older ones are dropped once a slow
+/// subscriber falls this far behind
+pub const EVENT_CAPACITY: usize = 256;
+
+/// Something that happened to a torrent, delivered to the receivers returned
+/// by `Torrent::subscribe` so they don't have to poll its status
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TorrentEvent {
+    /// The piece with the given index was verified and stored
+    PieceCompleted(usize),
+    PeerConnected(SocketAddr),
+    PeerDisconnected(SocketAddr),
+    /// Every piece that isn't skipped has been downloaded
+    DownloadFinished,
+    /// A tracker returned the given number of peers
+    Announced {
+        peers: usize,
+    },
+    /// A tracker could not be announced to, with the reason why
+    AnnounceFailed(String),
+}
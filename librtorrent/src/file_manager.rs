@@ -0,0 +1,445 @@
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind, SeekFrom},
+    ops::Range,
+    path::{Component, Path, PathBuf},
+    time::SystemTime,
+};
+
+use bytes::Bytes;
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::Mutex,
+};
+
+use crate::meta_info::TorrentInfo;
+
+/// A file of a torrent and where its data starts within the torrent
+#[derive(Debug)]
+struct TorrentFile {
+    path: PathBuf,
+    /// Offset of the file's first byte in the concatenated torrent data
+    offset: u64,
+    length: u64,
+    /// Padding files are all zeros and never stored on disk
+    padding: bool,
+    /// Opened on first use and kept open afterwards
+    handle: Mutex<Option<File>>,
+}
+
+/// Maps pieces onto the files of a torrent and does all reading and
+/// writing of them
+#[derive(Debug)]
+pub struct FileManager {
+    files: Vec<TorrentFile>,
+    piece_length: u64,
+    /// The file of a single file torrent, or the directory holding the
+    /// files of a multi-file torrent
+    root: PathBuf,
+}
+
+impl FileManager {
+    /// Lays out the files of `info` under `download_dir`. Single file
+    /// torrents are stored as `download_dir/name`, multi-file torrents in
+    /// the directory `download_dir/name`.
+    pub fn new(info: &TorrentInfo, download_dir: &Path) -> Self {
+        let root = sanitized_join(download_dir, &[PathBuf::from(&info.name)]);
+        Self::with_root(info, root)
+    }
+
+    /// Lays out the files of `info` at `root`, the file of a single file
+    /// torrent or the directory of a multi-file one, whatever its name
+    pub fn with_root(info: &TorrentInfo, root: PathBuf) -> Self {
+        let layout: Vec<(PathBuf, u64, bool)> = match &info.files {
+            Some(files) => files
+                .iter()
+                .map(|file| {
+                    let path = sanitized_join(&root, &file.path);
+                    (path, file.length as u64, file.is_padding())
+                })
+                .collect(),
+            None => vec![(root.clone(), info.total_length() as u64, false)],
+        };
+
+        let mut offset = 0;
+        let files = layout
+            .into_iter()
+            .map(|(path, length, padding)| {
+                let file = TorrentFile {
+                    path,
+                    offset,
+                    length,
+                    padding,
+                    handle: Mutex::new(None),
+                };
+                offset += length;
+                file
+            })
+            .collect();
+
+        FileManager {
+            files,
+            piece_length: info.piece_length as u64,
+            root,
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The pieces holding data of file `index`, counting files as listed
+    /// without padding files. With `exclusive` set, pieces that also hold
+    /// data of other files, besides padding, are left out.
+    pub fn file_pieces(&self, index: usize, exclusive: bool) -> Option<Range<usize>> {
+        let file = self.files.iter().filter(|file| !file.padding).nth(index)?;
+        let end = file.offset + file.length;
+        let mut first = (file.offset / self.piece_length) as usize;
+        let mut last = end.div_ceil(self.piece_length) as usize;
+
+        if exclusive {
+            let shared = |piece: usize| {
+                let start = piece as u64 * self.piece_length;
+                let length = self.piece_length.min(self.total_length() - start);
+                self.spans(start, length as usize)
+                    .unwrap_or_default()
+                    .iter()
+                    .any(|(other, _, _)| !other.padding && !std::ptr::eq(*other, file))
+            };
+            while first < last && shared(first) {
+                first += 1;
+            }
+            while first < last && shared(last - 1) {
+                last -= 1;
+            }
+        }
+
+        Some(first..last)
+    }
+
+    /// Reads `length` bytes starting `offset` bytes into piece `piece`
+    pub async fn read_block(&self, piece: usize, offset: u64, length: usize) -> io::Result<Bytes> {
+        let mut buf = vec![0u8; length];
+        let start = piece as u64 * self.piece_length + offset;
+
+        for (file, file_offset, range) in self.spans(start, length)? {
+            if file.padding {
+                // Already zeroed
+                continue;
+            }
+
+            let mut handle = file.handle.lock().await;
+            let handle = file.open(&mut handle, false).await?;
+            handle.seek(SeekFrom::Start(file_offset)).await?;
+            handle.read_exact(&mut buf[range]).await?;
+        }
+
+        Ok(Bytes::from(buf))
+    }
+
+    /// Writes the data of piece `piece`, creating files and directories as needed
+    pub async fn write_piece(&self, piece: usize, bytes: &[u8]) -> io::Result<()> {
+        let start = piece as u64 * self.piece_length;
+
+        for (file, file_offset, range) in self.spans(start, bytes.len())? {
+            if file.padding {
+                continue;
+            }
+
+            let mut handle = file.handle.lock().await;
+            let handle = file.open(&mut handle, true).await?;
+            handle.seek(SeekFrom::Start(file_offset)).await?;
+            handle.write_all(&bytes[range]).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Flushes every file that was written to disk
+    pub async fn sync_all(&self) -> io::Result<()> {
+        for file in &self.files {
+            if let Some(handle) = file.handle.lock().await.as_ref() {
+                handle.sync_all().await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Modification time of the most recently modified file, fails with
+    /// `NotFound` if none of the files exist
+    pub async fn last_modified(&self) -> io::Result<SystemTime> {
+        let mut last_modified = None;
+        for file in self.files.iter().filter(|file| !file.padding) {
+            match tokio::fs::metadata(&file.path).await {
+                Ok(metadata) => {
+                    let modified = metadata.modified()?;
+                    last_modified = last_modified.max(Some(modified));
+                }
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        last_modified.ok_or_else(|| io::Error::from(ErrorKind::NotFound))
+    }
+
+    /// Whether the files on disk are long enough to hold every byte of
+    /// `pieces`, as they should be after those pieces were written
+    pub async fn holds_pieces(&self, pieces: &[usize]) -> io::Result<bool> {
+        let mut needed: HashMap<&Path, u64> = HashMap::new();
+        for &piece in pieces {
+            let start = piece as u64 * self.piece_length;
+            let length = self.piece_length.min(self.total_length() - start);
+            for (file, file_offset, range) in self.spans(start, length as usize)? {
+                if !file.padding {
+                    let end = file_offset + range.len() as u64;
+                    let size = needed.entry(&file.path).or_default();
+                    *size = (*size).max(end);
+                }
+            }
+        }
+
+        for (path, size) in needed {
+            match tokio::fs::metadata(path).await {
+                Ok(metadata) if metadata.len() >= size => {}
+                Ok(_) => return Ok(false),
+                Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn total_length(&self) -> u64 {
+        self.files
+            .last()
+            .map_or(0, |file| file.offset + file.length)
+    }
+
+    /// The files overlapping the `length` bytes at `start` of the torrent
+    /// data, with the offset into each file and the matching range of the
+    /// data
+    fn spans(
+        &self,
+        start: u64,
+        length: usize,
+    ) -> io::Result<Vec<(&TorrentFile, u64, Range<usize>)>> {
+        let end = start + length as u64;
+        let total_length = self.total_length();
+        if end > total_length {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{start}..{end} is outside of the torrent's {total_length} bytes"),
+            ));
+        }
+
+        Ok(self
+            .files
+            .iter()
+            .filter_map(|file| {
+                let overlap_start = start.max(file.offset);
+                let overlap_end = end.min(file.offset + file.length);
+                (overlap_start < overlap_end).then(|| {
+                    let range = (overlap_start - start) as usize..(overlap_end - start) as usize;
+                    (file, overlap_start - file.offset, range)
+                })
+            })
+            .collect())
+    }
+}
+
+impl TorrentFile {
+    /// Opens the file if it isn't yet, creating it and its directories
+    /// when `create` is set
+    async fn open<'a>(
+        &self,
+        handle: &'a mut Option<File>,
+        create: bool,
+    ) -> io::Result<&'a mut File> {
+        if handle.is_none() {
+            if create {
+                if let Some(dir) = self.path.parent() {
+                    tokio::fs::create_dir_all(dir).await?;
+                }
+            }
+
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(create)
+                .truncate(false)
+                .open(&self.path)
+                .await?;
+            *handle = Some(file);
+        }
+
+        Ok(handle.as_mut().expect("opened above"))
+    }
+}
+
+/// Joins the path components of a torrent file onto `root`, dropping any
+/// that could escape it such as `..` or absolute paths
+fn sanitized_join(root: &Path, components: &[PathBuf]) -> PathBuf {
+    let mut path = root.to_path_buf();
+    for component in components.iter().flat_map(|part| part.components()) {
+        if let Component::Normal(part) = component {
+            path.push(part);
+        }
+    }
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{meta_info::FileInfo, test_util::temp_path};
+
+    use super::*;
+
+    /// Files of 3 and 5 bytes in pieces of 4, so piece 0 spans both files
+    fn multi_file_info() -> TorrentInfo {
+        TorrentInfo {
+            name: "multi".to_string(),
+            piece_length: 4,
+            pieces: vec![0; 2 * 20],
+            length: None,
+            files: Some(vec![
+                FileInfo {
+                    length: 3,
+                    path: vec![PathBuf::from("a.txt")],
+                    attr: None,
+                },
+                FileInfo {
+                    length: 5,
+                    path: vec![PathBuf::from("dir"), PathBuf::from("b.txt")],
+                    attr: None,
+                },
+            ]),
+            private: None,
+            meta_version: None,
+            file_tree: None,
+            source: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn piece_written_across_file_boundary() {
+        let download_dir = temp_path("files-write");
+        let files = FileManager::new(&multi_file_info(), &download_dir);
+
+        files.write_piece(0, b"aaab").await.unwrap();
+        files.write_piece(1, b"bbbb").await.unwrap();
+        files.sync_all().await.unwrap();
+
+        let a = tokio::fs::read(download_dir.join("multi/a.txt")).await;
+        let b = tokio::fs::read(download_dir.join("multi/dir/b.txt")).await;
+        tokio::fs::remove_dir_all(&download_dir).await.unwrap();
+
+        assert_eq!(a.unwrap(), b"aaa");
+        assert_eq!(b.unwrap(), b"bbbbb");
+    }
+
+    #[tokio::test]
+    async fn block_read_across_file_boundary() {
+        let download_dir = temp_path("files-read");
+        tokio::fs::create_dir_all(download_dir.join("multi/dir"))
+            .await
+            .unwrap();
+        tokio::fs::write(download_dir.join("multi/a.txt"), b"012")
+            .await
+            .unwrap();
+        tokio::fs::write(download_dir.join("multi/dir/b.txt"), b"34567")
+            .await
+            .unwrap();
+
+        let files = FileManager::new(&multi_file_info(), &download_dir);
+        let spanning = files.read_block(0, 2, 2).await;
+        let last = files.read_block(1, 1, 3).await;
+        let past_end = files.read_block(1, 2, 4).await;
+        tokio::fs::remove_dir_all(&download_dir).await.unwrap();
+
+        assert_eq!(spanning.unwrap().as_ref(), b"23");
+        assert_eq!(last.unwrap().as_ref(), b"567");
+        assert_eq!(past_end.unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn missing_files_not_found() {
+        let files = FileManager::new(&multi_file_info(), &temp_path("files-missing"));
+
+        assert_eq!(
+            files.read_block(0, 0, 1).await.unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+        assert_eq!(
+            files.last_modified().await.unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+    }
+
+    #[tokio::test]
+    async fn padding_files_not_stored() {
+        let download_dir = temp_path("files-padding");
+        let mut info = multi_file_info();
+        info.files.as_mut().unwrap().insert(
+            1,
+            FileInfo {
+                length: 1,
+                path: vec![PathBuf::from(".pad")],
+                attr: Some("p".to_string()),
+            },
+        );
+        info.pieces = vec![0; 3 * 20];
+        let files = FileManager::new(&info, &download_dir);
+
+        files.write_piece(0, b"aaaX").await.unwrap();
+        let block = files.read_block(0, 2, 2).await;
+        let padding_exists = download_dir.join("multi/.pad").exists();
+        tokio::fs::remove_dir_all(&download_dir).await.unwrap();
+
+        assert_eq!(block.unwrap().as_ref(), b"a\0");
+        assert!(!padding_exists);
+    }
+
+    #[test]
+    fn padding_files_not_counted_for_file_pieces() {
+        let mut info = multi_file_info();
+        info.files.as_mut().unwrap().insert(
+            1,
+            FileInfo {
+                length: 1,
+                path: vec![PathBuf::from(".pad")],
+                attr: Some("p".to_string()),
+            },
+        );
+        info.pieces = vec![0; 3 * 20];
+        let files = FileManager::new(&info, &temp_path("files-padding-pieces"));
+
+        assert_eq!(files.file_pieces(0, true), Some(0..1));
+        assert_eq!(files.file_pieces(1, true), Some(1..3));
+        assert_eq!(files.file_pieces(2, false), None);
+    }
+
+    #[test]
+    fn shared_pieces_left_out_when_exclusive() {
+        let files = FileManager::new(&multi_file_info(), &temp_path("files-pieces"));
+
+        assert_eq!(files.file_pieces(0, false), Some(0..1));
+        assert!(files.file_pieces(0, true).unwrap().is_empty());
+        assert_eq!(files.file_pieces(1, false), Some(0..2));
+        assert_eq!(files.file_pieces(1, true), Some(1..2));
+        assert_eq!(files.file_pieces(2, false), None);
+    }
+
+    #[test]
+    fn paths_cannot_escape_download_dir() {
+        let path = sanitized_join(
+            Path::new("/downloads/multi"),
+            &[PathBuf::from("../.."), PathBuf::from("/etc/passwd")],
+        );
+
+        assert_eq!(path, PathBuf::from("/downloads/multi/etc/passwd"));
+    }
+}
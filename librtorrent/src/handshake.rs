@@ -1,3 +1,8 @@
+use std::{io, time::Duration};
+
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
 const PROTOCOL: &[u8; PROTOCOL_SIZE] = b"BitTorrent protocol";
 
 const LEGNTH_SIZE: usize = 1;
@@ -7,6 +12,8 @@ const INFOHASH_SIZE: usize = 20;
 const PEER_ID_SIZE: usize = 20;
 pub const TOTAL_SIZE: usize =
     LEGNTH_SIZE + PROTOCOL_SIZE + RESERVED_SIZE + INFOHASH_SIZE + PEER_ID_SIZE;
+/// Time a peer has to send its handshake once we are connected
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
 const LEGNTH_OFFSET: usize = 0;
 const PROTOCOL_OFFSET: usize = LEGNTH_OFFSET + LEGNTH_SIZE;
@@ -14,6 +21,12 @@ const RESERVED_OFFSET: usize = PROTOCOL_OFFSET + PROTOCOL_SIZE;
 const INFOHASH_OFFSET: usize = RESERVED_OFFSET + RESERVED_SIZE;
 const PEER_ID_OFFSET: usize = INFOHASH_OFFSET + INFOHASH_SIZE;
 
+// Reserved bits announcing protocol extensions
+const FAST_EXTENSION_BYTE: usize = 7;
+const FAST_EXTENSION_BIT: u8 = 0x04;
+const EXTENSION_PROTOCOL_BYTE: usize = 5;
+const EXTENSION_PROTOCOL_BIT: u8 = 0x10;
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Handshake {
     pub length: u8,
@@ -23,9 +36,16 @@ pub struct Handshake {
     pub peer_id: [u8; PEER_ID_SIZE],
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Error)]
 pub enum HandshakeErr {
+    #[error("Handshake is not {TOTAL_SIZE} bytes long")]
     InvalidSize,
+    #[error("Handshake is not for the BitTorrent protocol")]
+    InvalidProtocol,
+    #[error("Timed out waiting for the peer's handshake")]
+    Timeout,
+    #[error("Failed to read handshake: {0}")]
+    ReadFailed(io::Error),
 }
 
 impl Handshake {
@@ -34,8 +54,8 @@ impl Handshake {
             length: PROTOCOL_SIZE.try_into().unwrap(),
             protocol: *PROTOCOL,
             reserved: [0; RESERVED_SIZE],
-            info_hash: info_hash,
-            peer_id: peer_id,
+            info_hash,
+            peer_id,
         }
     }
 
@@ -43,6 +63,11 @@ impl Handshake {
         if bytes.len() != TOTAL_SIZE {
             return Err(HandshakeErr::InvalidSize);
         }
+        if bytes[LEGNTH_OFFSET] as usize != PROTOCOL_SIZE
+            || bytes[PROTOCOL_OFFSET..RESERVED_OFFSET] != *PROTOCOL
+        {
+            return Err(HandshakeErr::InvalidProtocol);
+        }
 
         Ok(Handshake {
             length: bytes[LEGNTH_OFFSET],
@@ -53,7 +78,19 @@ impl Handshake {
         })
     }
 
-    pub fn to_bytes(self: &Self) -> [u8; TOTAL_SIZE] {
+    /// Reads a handshake, failing if the peer doesn't send a complete one
+    /// within `HANDSHAKE_TIMEOUT`
+    pub async fn from_stream<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Self, HandshakeErr> {
+        let mut buf = [0u8; TOTAL_SIZE];
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, stream.read_exact(&mut buf))
+            .await
+            .map_err(|_| HandshakeErr::Timeout)?
+            .map_err(HandshakeErr::ReadFailed)?;
+
+        Self::from_bytes(&buf)
+    }
+
+    pub fn to_bytes(&self) -> [u8; TOTAL_SIZE] {
         let mut result: [u8; TOTAL_SIZE] = [0; TOTAL_SIZE];
         result[LEGNTH_OFFSET] = self.length;
         result[PROTOCOL_OFFSET..RESERVED_OFFSET].copy_from_slice(&self.protocol);
@@ -63,7 +100,27 @@ impl Handshake {
         result
     }
 
-    pub fn is_valid(self: &Self, other: &Handshake) -> bool {
+    /// Sets the reserved bit announcing support for the fast extension (BEP-6)
+    pub fn with_fast_extension(mut self) -> Self {
+        self.reserved[FAST_EXTENSION_BYTE] |= FAST_EXTENSION_BIT;
+        self
+    }
+
+    pub fn supports_fast_extension(&self) -> bool {
+        self.reserved[FAST_EXTENSION_BYTE] & FAST_EXTENSION_BIT != 0
+    }
+
+    /// Sets the reserved bit announcing support for the extension protocol (BEP-10)
+    pub fn with_extension_protocol(mut self) -> Self {
+        self.reserved[EXTENSION_PROTOCOL_BYTE] |= EXTENSION_PROTOCOL_BIT;
+        self
+    }
+
+    pub fn supports_extension_protocol(&self) -> bool {
+        self.reserved[EXTENSION_PROTOCOL_BYTE] & EXTENSION_PROTOCOL_BIT != 0
+    }
+
+    pub fn is_valid(&self, other: &Handshake) -> bool {
         self.length == other.length
             && self.protocol == other.protocol
             && self.info_hash == other.info_hash
@@ -72,6 +129,8 @@ impl Handshake {
 
 #[cfg(test)]
 mod tests {
+    use tokio::io::AsyncWriteExt;
+
     use super::*;
 
     #[test]
@@ -85,8 +144,87 @@ mod tests {
         ];
         let hs = Handshake::new(info_hash, peer_id);
         let ab = hs.to_bytes();
-        let hs2 = Handshake::from_bytes(&ab.to_vec()).unwrap();
+        let hs2 = Handshake::from_bytes(ab.as_ref()).unwrap();
 
         assert_eq!(hs, hs2);
     }
+
+    #[test]
+    fn wrong_protocol_rejected() {
+        let bytes = Handshake::new([1; 20], [2; 20]).to_bytes();
+        assert!(Handshake::from_bytes(&bytes).is_ok());
+
+        let mut wrong_length = bytes;
+        wrong_length[LEGNTH_OFFSET] = 18;
+        assert!(matches!(
+            Handshake::from_bytes(&wrong_length),
+            Err(HandshakeErr::InvalidProtocol)
+        ));
+
+        let mut wrong_protocol = bytes;
+        wrong_protocol[PROTOCOL_OFFSET..RESERVED_OFFSET].copy_from_slice(b"BitTorrent Protocol");
+        assert!(matches!(
+            Handshake::from_bytes(&wrong_protocol),
+            Err(HandshakeErr::InvalidProtocol)
+        ));
+    }
+
+    #[tokio::test]
+    async fn handshake_read_from_stream() {
+        let hs = Handshake::new([1; 20], [2; 20]);
+        let (mut ours, mut theirs) = tokio::io::duplex(TOTAL_SIZE);
+        tokio::spawn(async move {
+            let bytes = hs.to_bytes();
+            // Sent in two writes to check partial reads are assembled
+            theirs.write_all(&bytes[..10]).await.unwrap();
+            tokio::task::yield_now().await;
+            theirs.write_all(&bytes[10..]).await.unwrap();
+        });
+
+        let received = Handshake::from_stream(&mut ours).await.unwrap();
+
+        assert_eq!(received, Handshake::new([1; 20], [2; 20]));
+    }
+
+    #[tokio::test]
+    async fn truncated_handshake_rejected() {
+        let bytes = Handshake::new([1; 20], [2; 20]).to_bytes();
+
+        let result = Handshake::from_stream(&mut &bytes[..TOTAL_SIZE - 1]).await;
+
+        assert!(matches!(result, Err(HandshakeErr::ReadFailed(_))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silent_peer_times_out() {
+        let (mut ours, mut theirs) = tokio::io::duplex(TOTAL_SIZE);
+        theirs.write_all(&[PROTOCOL_SIZE as u8]).await.unwrap();
+
+        let result = Handshake::from_stream(&mut ours).await;
+
+        assert!(matches!(result, Err(HandshakeErr::Timeout)));
+    }
+
+    #[test]
+    fn fast_extension_bit() {
+        let hs = Handshake::new([1; 20], [2; 20]);
+        assert!(!hs.supports_fast_extension());
+
+        let hs = hs.with_fast_extension();
+        let bytes = hs.to_bytes();
+        assert_eq!(bytes[RESERVED_OFFSET + 7], 0x04);
+        assert!(Handshake::from_bytes(&bytes)
+            .unwrap()
+            .supports_fast_extension());
+    }
+
+    #[test]
+    fn extension_protocol_bit() {
+        let hs = Handshake::new([1; 20], [2; 20]).with_extension_protocol();
+        let bytes = hs.to_bytes();
+
+        assert_eq!(bytes[RESERVED_OFFSET + 5], 0x10);
+        assert!(hs.supports_extension_protocol());
+        assert!(!hs.supports_fast_extension());
+    }
 }
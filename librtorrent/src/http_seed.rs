@@ -0,0 +1,193 @@
+//! Piece requests to BEP-17 HTTP seeds, listed under `httpseeds`
+use std::{ops::Range, str::FromStr, time::Duration};
+
+use bytes::Bytes;
+use reqwest::{Client, StatusCode, Url};
+use thiserror::Error;
+use url::ParseError;
+
+use crate::tracker::{percent_encode, USER_AGENT};
+
+/// Time asked to wait before retrying when a busy seed doesn't say how long
+pub const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Error)]
+pub enum HttpSeedErr {
+    #[error("URL parse error")]
+    UrlParseError(#[from] ParseError),
+    #[error("Reqwest error {0}")]
+    ReqwestError(#[from] reqwest::Error),
+    #[error("HTTP seed is busy, retry in {0:?}")]
+    Busy(Duration),
+    #[error("HTTP seed returned HTTP {0}")]
+    HttpStatus(u16),
+}
+
+/// A single BEP-17 seed script, e.g. `http://example.com/seed.php`
+#[derive(Debug, Clone)]
+pub struct HttpSeed {
+    url: String,
+    client: Client,
+}
+
+impl HttpSeed {
+    pub fn new(url: impl Into<String>, timeout: Duration) -> Result<Self, HttpSeedErr> {
+        let client = Client::builder()
+            .timeout(timeout)
+            .user_agent(USER_AGENT)
+            .build()?;
+        Ok(HttpSeed {
+            url: url.into(),
+            client,
+        })
+    }
+
+    /// Fetches `ranges` of piece `piece`, or the whole piece if there are
+    /// none. A busy seed fails with `HttpSeedErr::Busy` holding how long
+    /// it asked us to wait.
+    pub async fn fetch_piece(
+        &self,
+        info_hash: &[u8; 20],
+        piece: usize,
+        ranges: &[Range<usize>],
+    ) -> Result<Bytes, HttpSeedErr> {
+        let url = piece_url(&self.url, info_hash, piece, ranges)?;
+        let response = self.client.get(url).send().await?;
+
+        match response.status() {
+            status if status.is_success() => Ok(response.bytes().await?),
+            // The body of a busy seed is the number of seconds to wait
+            StatusCode::SERVICE_UNAVAILABLE => {
+                let body = response.text().await.unwrap_or_default();
+                let retry_after = body
+                    .trim()
+                    .parse()
+                    .map(Duration::from_secs)
+                    .unwrap_or(DEFAULT_RETRY_AFTER);
+                Err(HttpSeedErr::Busy(retry_after))
+            }
+            status => Err(HttpSeedErr::HttpStatus(status.as_u16())),
+        }
+    }
+}
+
+/// Builds the BEP-17 request for `ranges` of piece `piece`, i.e.
+/// `<seed>?info_hash=<hash>&piece=<index>&ranges=<start>-<end>,...`.
+/// The ranges in the URL are inclusive, `ranges` is left out when empty.
+pub fn piece_url(
+    seed: &str,
+    info_hash: &[u8; 20],
+    piece: usize,
+    ranges: &[Range<usize>],
+) -> Result<Url, HttpSeedErr> {
+    // Seeds may already carry a query string of their own
+    let separator = if seed.contains('?') { '&' } else { '?' };
+    let mut url = format!(
+        "{seed}{separator}info_hash={}&piece={piece}",
+        percent_encode(info_hash)
+    );
+    if !ranges.is_empty() {
+        let ranges: Vec<String> = ranges
+            .iter()
+            .map(|range| format!("{}-{}", range.start, range.end - 1))
+            .collect();
+        url.push_str(&format!("&ranges={}", ranges.join(",")));
+    }
+
+    Ok(Url::from_str(&url)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    #[test]
+    fn piece_url_format() {
+        let hash = [0x12u8; 20];
+        let encoded = "%12".repeat(20);
+
+        assert_eq!(
+            piece_url("http://seed.example/seed.php", &hash, 3, &[])
+                .unwrap()
+                .as_str(),
+            format!("http://seed.example/seed.php?info_hash={encoded}&piece=3")
+        );
+        assert_eq!(
+            piece_url(
+                "http://seed.example/seed.php",
+                &hash,
+                3,
+                &[0..1024, 2048..4096]
+            )
+            .unwrap()
+            .as_str(),
+            format!(
+                "http://seed.example/seed.php?info_hash={encoded}&piece=3&ranges=0-1023,2048-4095"
+            )
+        );
+        assert_eq!(
+            piece_url("http://seed.example/seed?t=1", &hash, 0, &[])
+                .unwrap()
+                .as_str(),
+            format!("http://seed.example/seed?t=1&info_hash={encoded}&piece=0")
+        );
+    }
+
+    /// Answers a single request with `response`, returning the seed URL and
+    /// the request line received
+    async fn serve_once(response: &'static str) -> (String, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let request = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let n = stream.read(&mut buf).await.unwrap();
+            stream.write_all(response.as_bytes()).await.unwrap();
+            let request = String::from_utf8_lossy(&buf[..n]).to_string();
+            request.lines().next().unwrap_or_default().to_string()
+        });
+        (format!("http://127.0.0.1:{port}/seed"), request)
+    }
+
+    #[tokio::test]
+    async fn piece_fetched() {
+        let (url, request) =
+            serve_once("HTTP/1.1 200 OK\r\nContent-Length: 4\r\nConnection: close\r\n\r\nabcd")
+                .await;
+        let seed = HttpSeed::new(url, Duration::from_secs(5)).unwrap();
+
+        let data = seed
+            .fetch_piece(&[1u8; 20], 2, &[0..2, 2..4])
+            .await
+            .unwrap();
+
+        assert_eq!(&data[..], b"abcd");
+        assert_eq!(
+            request.await.unwrap(),
+            format!(
+                "GET /seed?info_hash={}&piece=2&ranges=0-1,2-3 HTTP/1.1",
+                "%01".repeat(20)
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn busy_seed_asks_to_retry() {
+        let (url, _request) = serve_once(
+            "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 2\r\nConnection: close\r\n\r\n45",
+        )
+        .await;
+        let seed = HttpSeed::new(url, Duration::from_secs(5)).unwrap();
+
+        match seed.fetch_piece(&[1u8; 20], 0, &[]).await {
+            Err(HttpSeedErr::Busy(retry_after)) => {
+                assert_eq!(retry_after, Duration::from_secs(45))
+            }
+            other => panic!("Expected a busy seed, got {other:?}"),
+        }
+    }
+}
@@ -0,0 +1,476 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::warn;
+use thiserror::Error;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::Mutex,
+};
+
+use crate::{
+    bencode::{BencodeMap, BencodeMapDecoder, BencodeMapEncoder, BencodeParseErr, BencodeType},
+    meta_info::{FromBencodeTypeErr, FromBencodemap},
+    session::Session,
+    torrent::{TorrentState, TorrentStatus},
+};
+
+// Control messages are framed as a 4 byte big endian length followed by a bencoded dictionary
+pub const DEFAULT_CONTROL_ADDR: &str = "127.0.0.1:6890";
+const LENGTH_SIZE: usize = 4;
+const MAX_FRAME_SIZE: usize = 1 << 24;
+
+// Request keys
+const COMMAND_KEY: &str = "command";
+const PATH_KEY: &str = "path";
+const INFO_HASH_KEY: &str = "info hash";
+
+// Response keys
+const RESPONSE_KEY: &str = "response";
+const MESSAGE_KEY: &str = "message";
+const TORRENTS_KEY: &str = "torrents";
+
+// TorrentStatus keys
+const NAME_KEY: &str = "name";
+const STATE_KEY: &str = "state";
+const PIECES_COMPLETED_KEY: &str = "pieces completed";
+const PIECES_TOTAL_KEY: &str = "pieces total";
+const BYTES_DOWNLOADED_KEY: &str = "bytes downloaded";
+const BYTES_TOTAL_KEY: &str = "bytes total";
+const CONNECTED_PEERS_KEY: &str = "connected peers";
+const DOWNLOAD_RATE_KEY: &str = "download rate";
+const UPLOAD_RATE_KEY: &str = "upload rate";
+const LAST_ANNOUNCE_KEY: &str = "last announce";
+const NEXT_ANNOUNCE_KEY: &str = "next announce";
+const TRACKER_PEERS_KEY: &str = "tracker peers";
+
+// Command and response names
+const ADD: &str = "add";
+const REMOVE: &str = "remove";
+const LIST: &str = "list";
+const INFO: &str = "info";
+const ADDED: &str = "added";
+const REMOVED: &str = "removed";
+const ERROR: &str = "error";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum IpcRequest {
+    Add { path: String },
+    Remove { info_hash: [u8; 20] },
+    List,
+    Info { info_hash: [u8; 20] },
+}
+
+#[derive(Debug, Clone)]
+pub enum IpcResponse {
+    Added { info_hash: [u8; 20] },
+    Removed,
+    List(Vec<TorrentStatus>),
+    Info(TorrentStatus),
+    Error(String),
+}
+
+#[derive(Debug, Error)]
+pub enum IpcErr {
+    #[error("IO error {0}")]
+    IoErr(#[from] std::io::Error),
+    #[error("Bencode parse error {0}")]
+    BencodeParseErr(#[from] BencodeParseErr),
+    #[error("FromBencodeTypeErr {0}")]
+    FromBencodeTypeErr(#[from] FromBencodeTypeErr),
+    #[error("Frame of {0} bytes exceeds the maximum frame size")]
+    FrameTooLarge(usize),
+    #[error("Daemon error: {0}")]
+    DaemonErr(String),
+}
+
+impl IpcRequest {
+    pub fn to_bencodemap(&self) -> BencodeMap {
+        let mut map = BencodeMap::new();
+
+        let command = match self {
+            IpcRequest::Add { path } => {
+                insert_string(&mut map, PATH_KEY, path.as_bytes());
+                ADD
+            }
+            IpcRequest::Remove { info_hash } => {
+                insert_string(&mut map, INFO_HASH_KEY, info_hash);
+                REMOVE
+            }
+            IpcRequest::List => LIST,
+            IpcRequest::Info { info_hash } => {
+                insert_string(&mut map, INFO_HASH_KEY, info_hash);
+                INFO
+            }
+        };
+        insert_string(&mut map, COMMAND_KEY, command.as_bytes());
+
+        map
+    }
+}
+
+impl FromBencodemap for IpcRequest {
+    fn from_bencodemap(bencode_map: &BencodeMap) -> Result<Self, FromBencodeTypeErr> {
+        if !Self::is_valid_bencodemap(bencode_map) {
+            return Err(FromBencodeTypeErr::MissingValue(String::from(COMMAND_KEY)));
+        }
+
+        let command: String = bencode_map.get_decode(COMMAND_KEY).unwrap();
+        match command.as_str() {
+            ADD => Ok(IpcRequest::Add {
+                path: bencode_map
+                    .get_decode(PATH_KEY)
+                    .ok_or(FromBencodeTypeErr::MissingValue(String::from(PATH_KEY)))?,
+            }),
+            REMOVE => Ok(IpcRequest::Remove {
+                info_hash: get_info_hash(bencode_map)?,
+            }),
+            LIST => Ok(IpcRequest::List),
+            INFO => Ok(IpcRequest::Info {
+                info_hash: get_info_hash(bencode_map)?,
+            }),
+            _ => Err(FromBencodeTypeErr::MissingValue(format!(
+                "Unknown command {command}"
+            ))),
+        }
+    }
+
+    fn is_valid_bencodemap(bencode_map: &BencodeMap) -> bool {
+        bencode_map.contains_key(COMMAND_KEY.as_bytes())
+    }
+}
+
+impl IpcResponse {
+    pub fn to_bencodemap(&self) -> BencodeMap {
+        let mut map = BencodeMap::new();
+
+        let response = match self {
+            IpcResponse::Added { info_hash } => {
+                insert_string(&mut map, INFO_HASH_KEY, info_hash);
+                ADDED
+            }
+            IpcResponse::Removed => REMOVED,
+            IpcResponse::List(statuses) => {
+                map.insert(
+                    TORRENTS_KEY.as_bytes().to_vec(),
+                    BencodeType::List(
+                        statuses
+                            .iter()
+                            .map(|status| BencodeType::Dictionary(status_to_bencodemap(status)))
+                            .collect(),
+                    ),
+                );
+                LIST
+            }
+            IpcResponse::Info(status) => {
+                map.extend(status_to_bencodemap(status));
+                INFO
+            }
+            IpcResponse::Error(message) => {
+                insert_string(&mut map, MESSAGE_KEY, message.as_bytes());
+                ERROR
+            }
+        };
+        insert_string(&mut map, RESPONSE_KEY, response.as_bytes());
+
+        map
+    }
+}
+
+impl FromBencodemap for IpcResponse {
+    fn from_bencodemap(bencode_map: &BencodeMap) -> Result<Self, FromBencodeTypeErr> {
+        if !Self::is_valid_bencodemap(bencode_map) {
+            return Err(FromBencodeTypeErr::MissingValue(String::from(RESPONSE_KEY)));
+        }
+
+        let response: String = bencode_map.get_decode(RESPONSE_KEY).unwrap();
+        match response.as_str() {
+            ADDED => Ok(IpcResponse::Added {
+                info_hash: get_info_hash(bencode_map)?,
+            }),
+            REMOVED => Ok(IpcResponse::Removed),
+            LIST => {
+                let torrents: Vec<BencodeMap> = bencode_map
+                    .get_decode(TORRENTS_KEY)
+                    .ok_or(FromBencodeTypeErr::MissingValue(String::from(TORRENTS_KEY)))?;
+                Ok(IpcResponse::List(
+                    torrents
+                        .iter()
+                        .map(status_from_bencodemap)
+                        .collect::<Result<_, _>>()?,
+                ))
+            }
+            INFO => Ok(IpcResponse::Info(status_from_bencodemap(bencode_map)?)),
+            ERROR => Ok(IpcResponse::Error(
+                bencode_map.get_decode(MESSAGE_KEY).unwrap_or_default(),
+            )),
+            _ => Err(FromBencodeTypeErr::MissingValue(format!(
+                "Unknown response {response}"
+            ))),
+        }
+    }
+
+    fn is_valid_bencodemap(bencode_map: &BencodeMap) -> bool {
+        bencode_map.contains_key(RESPONSE_KEY.as_bytes())
+    }
+}
+
+fn insert_string(map: &mut BencodeMap, key: &str, value: &[u8]) {
+    map.insert(key.as_bytes().to_vec(), BencodeType::String(value.to_vec()));
+}
+
+fn insert_integer(map: &mut BencodeMap, key: &str, value: i64) {
+    map.insert(key.as_bytes().to_vec(), BencodeType::Integer(value));
+}
+
+fn get_info_hash(bencode_map: &BencodeMap) -> Result<[u8; 20], FromBencodeTypeErr> {
+    let info_hash: Vec<u8> =
+        bencode_map
+            .get_decode(INFO_HASH_KEY)
+            .ok_or(FromBencodeTypeErr::MissingValue(String::from(
+                INFO_HASH_KEY,
+            )))?;
+
+    info_hash
+        .try_into()
+        .map_err(|_| FromBencodeTypeErr::MissingValue(String::from(INFO_HASH_KEY)))
+}
+
+fn get_integer(bencode_map: &BencodeMap, key: &str) -> Result<i64, FromBencodeTypeErr> {
+    bencode_map
+        .get_decode(key)
+        .ok_or(FromBencodeTypeErr::MissingValue(String::from(key)))
+}
+
+fn status_to_bencodemap(status: &TorrentStatus) -> BencodeMap {
+    let mut map = BencodeMap::new();
+    insert_string(&mut map, INFO_HASH_KEY, &status.info_hash);
+    insert_string(&mut map, NAME_KEY, status.name.as_bytes());
+    insert_string(&mut map, STATE_KEY, status.state.to_string().as_bytes());
+    insert_integer(
+        &mut map,
+        PIECES_COMPLETED_KEY,
+        status.pieces_completed as i64,
+    );
+    insert_integer(&mut map, PIECES_TOTAL_KEY, status.pieces_total as i64);
+    insert_integer(
+        &mut map,
+        BYTES_DOWNLOADED_KEY,
+        status.bytes_downloaded as i64,
+    );
+    insert_integer(&mut map, BYTES_TOTAL_KEY, status.bytes_total as i64);
+    insert_integer(&mut map, CONNECTED_PEERS_KEY, status.connected_peers as i64);
+    insert_integer(&mut map, DOWNLOAD_RATE_KEY, status.download_rate as i64);
+    insert_integer(&mut map, UPLOAD_RATE_KEY, status.upload_rate as i64);
+    // Announce times are sent as Unix timestamps and left out until known
+    for (key, time) in [
+        (LAST_ANNOUNCE_KEY, status.last_announce),
+        (NEXT_ANNOUNCE_KEY, status.next_announce),
+    ] {
+        if let Some(secs) = time.and_then(|time| time.duration_since(UNIX_EPOCH).ok()) {
+            insert_integer(&mut map, key, secs.as_secs() as i64);
+        }
+    }
+    insert_integer(
+        &mut map,
+        TRACKER_PEERS_KEY,
+        status.tracker_peer_count as i64,
+    );
+    map
+}
+
+fn status_from_bencodemap(bencode_map: &BencodeMap) -> Result<TorrentStatus, FromBencodeTypeErr> {
+    let state: String = bencode_map
+        .get_decode(STATE_KEY)
+        .ok_or(FromBencodeTypeErr::MissingValue(String::from(STATE_KEY)))?;
+
+    let state = match state.as_str() {
+        "paused" => TorrentState::Paused,
+        "searching" => TorrentState::Searching,
+        "downloading" => TorrentState::Downloading,
+        "seeding" => TorrentState::Seeding,
+        _ => TorrentState::Stopped,
+    };
+
+    Ok(TorrentStatus {
+        info_hash: get_info_hash(bencode_map)?,
+        name: bencode_map
+            .get_decode(NAME_KEY)
+            .ok_or(FromBencodeTypeErr::MissingValue(String::from(NAME_KEY)))?,
+        state,
+        pieces_completed: get_integer(bencode_map, PIECES_COMPLETED_KEY)? as usize,
+        pieces_total: get_integer(bencode_map, PIECES_TOTAL_KEY)? as usize,
+        bytes_downloaded: get_integer(bencode_map, BYTES_DOWNLOADED_KEY)? as u64,
+        bytes_total: get_integer(bencode_map, BYTES_TOTAL_KEY)? as u64,
+        connected_peers: get_integer(bencode_map, CONNECTED_PEERS_KEY)? as usize,
+        download_rate: get_integer(bencode_map, DOWNLOAD_RATE_KEY)? as u64,
+        upload_rate: get_integer(bencode_map, UPLOAD_RATE_KEY)? as u64,
+        last_announce: get_time(bencode_map, LAST_ANNOUNCE_KEY),
+        next_announce: get_time(bencode_map, NEXT_ANNOUNCE_KEY),
+        tracker_peer_count: get_integer(bencode_map, TRACKER_PEERS_KEY)? as usize,
+    })
+}
+
+fn get_time(bencode_map: &BencodeMap, key: &str) -> Option<SystemTime> {
+    let secs: i64 = bencode_map.get_decode(key)?;
+    Some(UNIX_EPOCH + Duration::from_secs(secs.try_into().ok()?))
+}
+
+pub async fn write_frame<W>(stream: &mut W, map: &BencodeMap) -> Result<(), IpcErr>
+where
+    W: AsyncWrite + Unpin,
+{
+    let payload = map.get_encode();
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(IpcErr::FrameTooLarge(payload.len()));
+    }
+
+    stream.write_u32(payload.len() as u32).await?;
+    stream.write_all(&payload).await?;
+    stream.flush().await?;
+
+    Ok(())
+}
+
+pub async fn read_frame<R>(stream: &mut R) -> Result<BencodeMap, IpcErr>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut len_buf = [0u8; LENGTH_SIZE];
+    stream.read_exact(&mut len_buf).await?;
+
+    let length = u32::from_be_bytes(len_buf) as usize;
+    if length > MAX_FRAME_SIZE {
+        return Err(IpcErr::FrameTooLarge(length));
+    }
+
+    let mut payload = vec![0u8; length];
+    stream.read_exact(&mut payload).await?;
+
+    Ok(BencodeMap::try_decode(&payload)?)
+}
+
+/// Sends a single request to the daemon listening on `addr` and waits for its response
+pub async fn send_request(addr: &str, request: &IpcRequest) -> Result<IpcResponse, IpcErr> {
+    let mut stream = TcpStream::connect(addr).await?;
+
+    write_frame(&mut stream, &request.to_bencodemap()).await?;
+    let map = read_frame(&mut stream).await?;
+
+    Ok(IpcResponse::from_bencodemap(&map)?)
+}
+
+/// Accepts control connections on `listener` and applies their requests to `session`.
+/// Only returns if accepting a connection fails.
+pub async fn serve(listener: TcpListener, session: Arc<Mutex<Session>>) -> Result<(), IpcErr> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let session = session.clone();
+
+        tokio::spawn(async move {
+            if let Err(err) = handle_connection(stream, session).await {
+                warn!("Control connection from {addr} failed: {err}");
+            }
+        });
+    }
+}
+
+async fn handle_connection(
+    mut stream: TcpStream,
+    session: Arc<Mutex<Session>>,
+) -> Result<(), IpcErr> {
+    loop {
+        let map = match read_frame(&mut stream).await {
+            Ok(map) => map,
+            Err(IpcErr::IoErr(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        };
+
+        let response = match IpcRequest::from_bencodemap(&map) {
+            Ok(request) => handle_request(request, &session).await,
+            Err(err) => IpcResponse::Error(err.to_string()),
+        };
+
+        write_frame(&mut stream, &response.to_bencodemap()).await?;
+    }
+}
+
+async fn handle_request(request: IpcRequest, session: &Arc<Mutex<Session>>) -> IpcResponse {
+    match request {
+        IpcRequest::Add { path } => {
+            let mut session = session.lock().await;
+            let info_hash = match session.add_torrent(&path, None).await {
+                Ok(info_hash) => info_hash,
+                Err(err) => return IpcResponse::Error(err.to_string()),
+            };
+
+            // Starting only spawns the announce, so the session isn't held
+            // while waiting for the tracker
+            if let Err(err) = session.start_torrent(&info_hash).await {
+                warn!("Failed to start torrent: {err}");
+            }
+
+            IpcResponse::Added { info_hash }
+        }
+        IpcRequest::Remove { info_hash } => {
+            match session.lock().await.remove_torrent(&info_hash).await {
+                Ok(_) => IpcResponse::Removed,
+                Err(err) => IpcResponse::Error(err.to_string()),
+            }
+        }
+        IpcRequest::List => IpcResponse::List(session.lock().await.list()),
+        IpcRequest::Info { info_hash } => match session.lock().await.status(&info_hash) {
+            Ok(status) => IpcResponse::Info(status),
+            Err(err) => IpcResponse::Error(err.to_string()),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_round_trip() {
+        let requests = [
+            IpcRequest::Add {
+                path: "test.torrent".to_string(),
+            },
+            IpcRequest::Remove {
+                info_hash: [1u8; 20],
+            },
+            IpcRequest::List,
+            IpcRequest::Info {
+                info_hash: [2u8; 20],
+            },
+        ];
+
+        for request in requests {
+            let decoded = IpcRequest::from_bencodemap(&request.to_bencodemap()).unwrap();
+            assert_eq!(decoded, request);
+        }
+    }
+
+    #[tokio::test]
+    async fn frame_round_trip() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let response = IpcResponse::Added {
+            info_hash: [3u8; 20],
+        };
+
+        write_frame(&mut client, &response.to_bencodemap())
+            .await
+            .unwrap();
+        let map = read_frame(&mut server).await.unwrap();
+
+        assert!(matches!(
+            IpcResponse::from_bencodemap(&map).unwrap(),
+            IpcResponse::Added { info_hash } if info_hash == [3u8; 20]
+        ));
+    }
+}
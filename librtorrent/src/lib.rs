@@ -1,10 +1,29 @@
+pub mod ban_list;
 pub mod bencode;
+pub mod config;
+pub mod event;
+pub mod file_manager;
 pub mod handshake;
+pub mod http_seed;
+pub mod ipc;
+pub mod lsd;
 pub mod message;
 pub mod meta_info;
+pub mod mse;
 pub mod peer;
+pub mod peer_id;
 pub mod peer_manager;
+pub mod pex;
+pub mod piece_hash;
 pub mod piece_manager;
+pub mod piece_picker;
+pub mod rate_limiter;
+pub mod rate_tracker;
 pub mod session;
 pub mod torrent;
 pub mod tracker;
+pub mod transport;
+pub mod utp;
+
+#[cfg(test)]
+mod test_util;
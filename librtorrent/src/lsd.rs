@@ -0,0 +1,308 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::{debug, trace, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+use thiserror::Error;
+use tokio::{net::UdpSocket, sync::watch};
+
+use crate::peer_manager::{DiscoveredPeers, PeerSource};
+
+/// Multicast group local service discovery announces are sent to (BEP-14)
+pub const LSD_MULTICAST_ADDR: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+pub const LSD_PORT: u16 = 6771;
+/// How often every torrent is announced to the local network
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// Info hashes sent in a single announce so it fits in one datagram
+const MAX_HASHES_PER_ANNOUNCE: usize = 20;
+/// Large enough for an announce of `MAX_HASHES_PER_ANNOUNCE` hashes
+const MAX_ANNOUNCE_SIZE: usize = 1500;
+
+const REQUEST_LINE: &str = "BT-SEARCH * HTTP/1.1";
+const HOST_HEADER: &str = "Host";
+const PORT_HEADER: &str = "Port";
+const INFOHASH_HEADER: &str = "Infohash";
+const COOKIE_HEADER: &str = "cookie";
+
+/// Torrents looking for local peers by info hash, shared with the discovery task
+pub type LocalTorrents = Arc<Mutex<HashMap<[u8; 20], DiscoveredPeers>>>;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum LsdErr {
+    #[error("Invalid local discovery announce: {0}")]
+    InvalidAnnounce(String),
+}
+
+/// A `BT-SEARCH` announce of the torrents a client on the local network has
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LsdAnnounce {
+    /// Port the announcing client accepts peer connections on
+    pub port: u16,
+    pub info_hashes: Vec<[u8; 20]>,
+    /// Lets a client recognise and ignore its own announces
+    pub cookie: Option<String>,
+}
+
+impl LsdAnnounce {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut message = format!(
+            "{REQUEST_LINE}\r\n{HOST_HEADER}: {LSD_MULTICAST_ADDR}:{LSD_PORT}\r\n{PORT_HEADER}: {}\r\n",
+            self.port
+        );
+        for info_hash in &self.info_hashes {
+            let hex: String = info_hash.iter().map(|byte| format!("{byte:02x}")).collect();
+            message.push_str(&format!("{INFOHASH_HEADER}: {hex}\r\n"));
+        }
+        if let Some(cookie) = &self.cookie {
+            message.push_str(&format!("{COOKIE_HEADER}: {cookie}\r\n"));
+        }
+        message.push_str("\r\n\r\n");
+
+        message.into_bytes()
+    }
+
+    /// Parses an announce. Header names are matched case-insensitively and
+    /// unknown headers are ignored.
+    pub fn parse(bytes: &[u8]) -> Result<Self, LsdErr> {
+        let text = std::str::from_utf8(bytes)
+            .map_err(|_| LsdErr::InvalidAnnounce(String::from("not UTF-8")))?;
+        let mut lines = text.lines();
+
+        if lines.next() != Some(REQUEST_LINE) {
+            return Err(LsdErr::InvalidAnnounce(String::from(
+                "missing BT-SEARCH request line",
+            )));
+        }
+
+        let mut port = None;
+        let mut info_hashes = Vec::new();
+        let mut cookie = None;
+        for line in lines.take_while(|line| !line.is_empty()) {
+            let Some((name, value)) = line.split_once(':') else {
+                return Err(LsdErr::InvalidAnnounce(format!(
+                    "malformed header {line:?}"
+                )));
+            };
+            let value = value.trim();
+
+            if name.eq_ignore_ascii_case(PORT_HEADER) {
+                port = Some(
+                    value
+                        .parse::<u16>()
+                        .map_err(|_| LsdErr::InvalidAnnounce(format!("invalid port {value:?}")))?,
+                );
+            } else if name.eq_ignore_ascii_case(INFOHASH_HEADER) {
+                info_hashes.push(parse_info_hash(value)?);
+            } else if name.eq_ignore_ascii_case(COOKIE_HEADER) {
+                cookie = Some(value.to_string());
+            }
+        }
+
+        let port = port.ok_or(LsdErr::InvalidAnnounce(String::from("missing port")))?;
+        if info_hashes.is_empty() {
+            return Err(LsdErr::InvalidAnnounce(String::from("no info hashes")));
+        }
+
+        Ok(LsdAnnounce {
+            port,
+            info_hashes,
+            cookie,
+        })
+    }
+}
+
+/// Announces torrents to the local network and listens for the announces
+/// of other clients on it
+#[derive(Debug)]
+pub struct LocalDiscovery {
+    socket: UdpSocket,
+    /// Sent with our announces so we can skip them when they loop back
+    cookie: String,
+}
+
+impl LocalDiscovery {
+    /// Joins the discovery multicast group on every interface. The port is
+    /// bound with `SO_REUSEADDR`, so other clients on this host can share it.
+    pub async fn bind() -> io::Result<Self> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, LSD_PORT)).into())?;
+        let socket = UdpSocket::from_std(socket.into())?;
+        socket.join_multicast_v4(LSD_MULTICAST_ADDR, Ipv4Addr::UNSPECIFIED)?;
+
+        let cookie = rand::random::<[u8; 4]>()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+
+        Ok(LocalDiscovery { socket, cookie })
+    }
+
+    /// Multicasts that we accept connections on `port` for `info_hashes`
+    pub async fn announce(&self, port: u16, info_hashes: &[[u8; 20]]) -> io::Result<()> {
+        let group = SocketAddrV4::new(LSD_MULTICAST_ADDR, LSD_PORT);
+        for chunk in info_hashes.chunks(MAX_HASHES_PER_ANNOUNCE) {
+            let announce = LsdAnnounce {
+                port,
+                info_hashes: chunk.to_vec(),
+                cookie: Some(self.cookie.clone()),
+            };
+            self.socket.send_to(&announce.to_bytes(), group).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Announces every torrent in `torrents`, logging failures
+    async fn announce_all(&self, port: u16, torrents: &LocalTorrents) {
+        let info_hashes: Vec<[u8; 20]> = torrents.lock().unwrap().keys().copied().collect();
+        if info_hashes.is_empty() {
+            return;
+        }
+        if let Err(err) = self.announce(port, &info_hashes).await {
+            warn!("Failed to announce to the local network: {err}");
+        }
+    }
+
+    /// Waits for an announce from another client, returning it with the
+    /// address it was sent from. Invalid announces and our own are skipped.
+    pub async fn recv(&self) -> io::Result<(SocketAddr, LsdAnnounce)> {
+        let mut buf = [0u8; MAX_ANNOUNCE_SIZE];
+        loop {
+            let (len, from) = self.socket.recv_from(&mut buf).await?;
+            match LsdAnnounce::parse(&buf[..len]) {
+                Ok(announce) if announce.cookie.as_ref() == Some(&self.cookie) => {}
+                Ok(announce) => return Ok((from, announce)),
+                Err(err) => trace!("Ignoring announce from {from}: {err}"),
+            }
+        }
+    }
+
+    /// Announces the torrents in `torrents` every `ANNOUNCE_INTERVAL`, and
+    /// right away whenever `port` changes, and hands peers announcing one of
+    /// them to its peer manager
+    pub async fn run(self, mut port: watch::Receiver<u16>, torrents: LocalTorrents) {
+        let mut interval = tokio::time::interval(ANNOUNCE_INTERVAL);
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    let port = *port.borrow();
+                    self.announce_all(port, &torrents).await;
+                }
+                Ok(()) = port.changed() => {
+                    let port = *port.borrow_and_update();
+                    self.announce_all(port, &torrents).await;
+                }
+                result = self.recv() => match result {
+                    Ok((from, announce)) => {
+                        let addr = SocketAddr::new(from.ip(), announce.port);
+                        let torrents = torrents.lock().unwrap();
+                        for info_hash in &announce.info_hashes {
+                            if let Some(peers) = torrents.get(info_hash) {
+                                debug!("Found local peer {addr}");
+                                // Only fails once the torrent is gone
+                                let _ = peers.send((PeerSource::LocalDiscovery, vec![addr]));
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        warn!("Local discovery stopped: {err}");
+                        return;
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// Parses an info hash written as 40 hex digits
+fn parse_info_hash(value: &str) -> Result<[u8; 20], LsdErr> {
+    let invalid = || LsdErr::InvalidAnnounce(format!("invalid info hash {value:?}"));
+    if value.len() != 40 || !value.is_ascii() {
+        return Err(invalid());
+    }
+
+    let mut info_hash = [0u8; 20];
+    for (byte, digits) in info_hash.iter_mut().zip(value.as_bytes().chunks(2)) {
+        let digits = std::str::from_utf8(digits).map_err(|_| invalid())?;
+        *byte = u8::from_str_radix(digits, 16).map_err(|_| invalid())?;
+    }
+    Ok(info_hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn announce_encoded_per_bep_14() {
+        let announce = LsdAnnounce {
+            port: 6881,
+            info_hashes: vec![[0xab; 20]],
+            cookie: Some("c00k1e".to_string()),
+        };
+
+        assert_eq!(
+            String::from_utf8(announce.to_bytes()).unwrap(),
+            format!(
+                "BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\nPort: 6881\r\n\
+                 Infohash: {}\r\ncookie: c00k1e\r\n\r\n\r\n",
+                "ab".repeat(20)
+            )
+        );
+        assert_eq!(LsdAnnounce::parse(&announce.to_bytes()), Ok(announce));
+    }
+
+    #[test]
+    fn announce_from_other_clients_parsed() {
+        let message = format!(
+            "BT-SEARCH * HTTP/1.1\r\nhost: 239.192.152.143:6771\r\nport: 51413\r\n\
+             infohash: {}\r\nInfohash: {}\r\n\r\n\r\n",
+            "0A".repeat(20),
+            "ff".repeat(20)
+        );
+
+        assert_eq!(
+            LsdAnnounce::parse(message.as_bytes()),
+            Ok(LsdAnnounce {
+                port: 51413,
+                info_hashes: vec![[0x0a; 20], [0xff; 20]],
+                cookie: None,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn port_shared_with_other_clients() {
+        let _first = LocalDiscovery::bind().await.unwrap();
+
+        assert!(LocalDiscovery::bind().await.is_ok());
+    }
+
+    #[test]
+    fn invalid_announces_rejected() {
+        let hash = "ab".repeat(20);
+        for message in [
+            format!("GET / HTTP/1.1\r\nPort: 6881\r\nInfohash: {hash}\r\n\r\n"),
+            format!("BT-SEARCH * HTTP/1.1\r\nInfohash: {hash}\r\n\r\n"),
+            "BT-SEARCH * HTTP/1.1\r\nPort: 6881\r\n\r\n".to_string(),
+            format!("BT-SEARCH * HTTP/1.1\r\nPort: 70000\r\nInfohash: {hash}\r\n\r\n"),
+            "BT-SEARCH * HTTP/1.1\r\nPort: 6881\r\nInfohash: abcd\r\n\r\n".to_string(),
+        ] {
+            assert!(
+                matches!(
+                    LsdAnnounce::parse(message.as_bytes()),
+                    Err(LsdErr::InvalidAnnounce(_))
+                ),
+                "{message:?}"
+            );
+        }
+    }
+}
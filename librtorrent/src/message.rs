@@ -1,6 +1,6 @@
 use bytes::{BufMut, Bytes, BytesMut};
 use thiserror::Error;
-use tokio::{io::AsyncReadExt, net::TcpStream};
+use tokio::io::{AsyncRead, AsyncReadExt};
 
 const LENGTH_SIZE: usize = 4;
 const ID_SIZE: usize = 1;
@@ -24,6 +24,14 @@ pub enum MessageType {
     Piece = 7,
     Cancel = 8,
     Port = 9,
+    // Fast extension (BEP-6), only valid when both peers set the reserved bit
+    SuggestPiece = 13,
+    HaveAll = 14,
+    HaveNone = 15,
+    RejectRequest = 16,
+    AllowedFast = 17,
+    // Extension protocol (BEP-10), only valid when both peers set the reserved bit
+    Extended = 20,
 }
 
 #[derive(Debug, Error)]
@@ -47,6 +55,99 @@ impl Message {
         }
     }
 
+    /// Zero-length message sent to keep an idle connection open
+    pub fn keep_alive() -> Self {
+        Message::new(0, None, None)
+    }
+
+    pub fn is_keep_alive(&self) -> bool {
+        self.length == 0
+    }
+
+    /// Size of the block carried by a `Piece` message, `None` for other messages
+    pub fn block_length(&self) -> Option<usize> {
+        if self.id != Some(MessageType::Piece as u8) {
+            return None;
+        }
+        // The block follows the piece index and offset
+        Some(
+            self.payload
+                .as_ref()
+                .map_or(0, |payload| payload.len().saturating_sub(8)),
+        )
+    }
+
+    /// Requests `length` bytes at `begin` of piece `index`
+    pub fn request(index: u32, begin: u32, length: u32) -> Self {
+        Self::block_message(MessageType::Request, index, begin, length)
+    }
+
+    /// Cancels a previously sent request for `length` bytes at `begin` of piece `index`
+    pub fn cancel(index: u32, begin: u32, length: u32) -> Self {
+        Self::block_message(MessageType::Cancel, index, begin, length)
+    }
+
+    /// Tells the peer we now have piece `index`
+    pub fn have(index: u32) -> Self {
+        Self::piece_message(MessageType::Have, index)
+    }
+
+    /// Tells the peer we have every piece, instead of a bitfield
+    pub fn have_all() -> Self {
+        Message::new(1, Some(MessageType::HaveAll as u8), None)
+    }
+
+    /// Tells the peer we have no pieces, instead of a bitfield
+    pub fn have_none() -> Self {
+        Message::new(1, Some(MessageType::HaveNone as u8), None)
+    }
+
+    /// Suggests the peer downloads piece `index` from us
+    pub fn suggest_piece(index: u32) -> Self {
+        Self::piece_message(MessageType::SuggestPiece, index)
+    }
+
+    /// Tells the peer its request for `length` bytes at `begin` of piece `index` won't be served
+    pub fn reject_request(index: u32, begin: u32, length: u32) -> Self {
+        Self::block_message(MessageType::RejectRequest, index, begin, length)
+    }
+
+    /// Tells the peer it may request piece `index` even while choked
+    pub fn allowed_fast(index: u32) -> Self {
+        Self::piece_message(MessageType::AllowedFast, index)
+    }
+
+    /// Extension protocol message with the id the receiver assigned to the
+    /// extension, 0 being the extension handshake
+    pub fn extended(extension_id: u8, payload: &[u8]) -> Self {
+        let mut buf = BytesMut::with_capacity(1 + payload.len());
+        buf.put_u8(extension_id);
+        buf.extend_from_slice(payload);
+
+        Message::new(
+            (buf.len() + ID_SIZE) as u32,
+            Some(MessageType::Extended as u8),
+            Some(buf.freeze()),
+        )
+    }
+
+    fn piece_message(message_type: MessageType, index: u32) -> Self {
+        Message::new(
+            5,
+            Some(message_type as u8),
+            Some(Bytes::copy_from_slice(&index.to_be_bytes())),
+        )
+    }
+
+    fn block_message(message_type: MessageType, index: u32, begin: u32, length: u32) -> Self {
+        let mut payload = BytesMut::with_capacity(12);
+        payload.put_u32(index);
+        payload.put_u32(begin);
+        payload.put_u32(length);
+
+        Message::new(13, Some(message_type as u8), Some(payload.freeze()))
+    }
+
     pub fn to_bytes(&self) -> Bytes {
         let mut buf = BytesMut::with_capacity(LENGTH_SIZE + self.length as usize);
 
@@ -57,7 +158,7 @@ impl Message {
         }
 
         if let Some(bytes) = &self.payload {
-            buf.extend_from_slice(&bytes);
+            buf.extend_from_slice(bytes);
         }
 
         buf.freeze()
@@ -70,7 +171,7 @@ impl Message {
 
         let length = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
 
-        if length == 0 as u32 {
+        if length == 0 {
             return Ok(Message {
                 length,
                 id: None,
@@ -80,7 +181,7 @@ impl Message {
 
         let id = bytes[4];
 
-        if id > 9 {
+        if !matches!(id, 0..=9 | 13..=17 | 20) {
             return Err(MessageErr::InvalidMessageId);
         }
 
@@ -103,7 +204,32 @@ impl Message {
         })
     }
 
-    pub async fn from_stream(stream: &mut TcpStream) -> Result<Message, MessageErr> {
+    /// Decodes every complete message at the start of `bytes`, returning
+    /// them with the number of bytes they took up. A trailing partial
+    /// message is left for the next read, and parsing stops before a message
+    /// that fails to decode so `from_bytes` can report why.
+    pub fn parse_many(bytes: &[u8]) -> (Vec<Message>, usize) {
+        let mut messages = Vec::new();
+        let mut consumed = 0;
+
+        while let Some(len_bytes) = bytes.get(consumed..consumed + LENGTH_SIZE) {
+            let length = u32::from_be_bytes(len_bytes.try_into().unwrap()) as usize;
+            let end = consumed + LENGTH_SIZE + length;
+            if end > bytes.len() {
+                break;
+            }
+
+            match Self::from_bytes(&bytes[consumed..end]) {
+                Ok(message) => messages.push(message),
+                Err(_) => break,
+            }
+            consumed = end;
+        }
+
+        (messages, consumed)
+    }
+
+    pub async fn from_stream<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Message, MessageErr> {
         let mut len_buf = [0u8; LENGTH_SIZE];
         stream.read_exact(&mut len_buf).await?;
 
@@ -153,4 +279,74 @@ mod tests {
         let expected = Bytes::copy_from_slice(&[0, 0, 0, 5, 5, 1, 1, 1, 1]);
         assert_eq!(serialized, expected);
     }
+
+    #[test]
+    fn fast_extension_messages_round_trip() {
+        let messages = [
+            (Message::suggest_piece(7), MessageType::SuggestPiece),
+            (Message::have_all(), MessageType::HaveAll),
+            (Message::have_none(), MessageType::HaveNone),
+            (
+                Message::reject_request(7, 16384, 512),
+                MessageType::RejectRequest,
+            ),
+            (Message::allowed_fast(7), MessageType::AllowedFast),
+        ];
+
+        for (message, message_type) in messages {
+            let parsed = Message::from_bytes(&message.to_bytes()).unwrap();
+
+            assert_eq!(parsed.id, Some(message_type as u8));
+            assert_eq!(parsed.length, message.length);
+            assert_eq!(parsed.payload, message.payload);
+        }
+
+        let reject =
+            Message::from_bytes(&Message::reject_request(7, 16384, 512).to_bytes()).unwrap();
+        assert_eq!(
+            reject.payload.unwrap().as_ref(),
+            [0, 0, 0, 7, 0, 0, 0x40, 0, 0, 0, 2, 0]
+        );
+    }
+
+    #[test]
+    fn unknown_message_id_rejected() {
+        assert!(matches!(
+            Message::from_bytes(&[0, 0, 0, 1, 10]),
+            Err(MessageErr::InvalidMessageId)
+        ));
+        assert!(matches!(
+            Message::from_bytes(&[0, 0, 0, 1, 18]),
+            Err(MessageErr::InvalidMessageId)
+        ));
+    }
+
+    #[test]
+    fn concatenated_messages_parsed() {
+        let mut bytes = BytesMut::new();
+        bytes.extend_from_slice(&Message::have_all().to_bytes());
+        bytes.extend_from_slice(&Message::keep_alive().to_bytes());
+        bytes.extend_from_slice(&Message::cancel(1, 0, 16384).to_bytes());
+        let consumed_expected = bytes.len();
+        // First 7 of the 9 bytes of a have message
+        bytes.extend_from_slice(&[0, 0, 0, 5, 4, 0, 0]);
+
+        let (messages, consumed) = Message::parse_many(&bytes);
+
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0].id, Some(MessageType::HaveAll as u8));
+        assert!(messages[1].is_keep_alive());
+        assert_eq!(messages[2].id, Some(MessageType::Cancel as u8));
+        assert_eq!(consumed, consumed_expected);
+    }
+
+    #[test]
+    fn extended_message_round_trip() {
+        let message = Message::extended(1, b"de");
+        let parsed = Message::from_bytes(&message.to_bytes()).unwrap();
+
+        assert_eq!(parsed.length, 4);
+        assert_eq!(parsed.id, Some(MessageType::Extended as u8));
+        assert_eq!(parsed.payload.unwrap().as_ref(), b"\x01de");
+    }
 }
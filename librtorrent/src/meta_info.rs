@@ -1,6 +1,18 @@
-use crate::bencode::{BencodeGetErr, BencodeMap, BencodeMapDecoder, BencodeMapEncoder};
+use crate::{
+    bencode::{BencodeGetErr, BencodeMap, BencodeMapDecoder, BencodeMapEncoder, BencodeType},
+    piece_hash::{PieceDigest, PieceHasher},
+    tracker::percent_encode,
+};
+use serde::Serialize;
 use sha1::{Digest, Sha1};
-use std::path::PathBuf;
+use sha2::Sha256;
+use std::{
+    fmt::Display,
+    fs::{self, File},
+    io::{self, Read},
+    path::{Component, Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
 use thiserror::Error;
 
 // Keys for the root of the meta info file
@@ -9,7 +21,21 @@ const INFO_KEY: &str = "info";
 const NODES_KEY: &str = "nodes";
 const ANNOUNCE_LIST_KEY: &str = "announce-list";
 const URL_LIST_KEY: &str = "url-list";
-const ANNOUNCE_VALUES: [&str; 4] = [ANNOUNCE_KEY, NODES_KEY, ANNOUNCE_LIST_KEY, URL_LIST_KEY];
+const HTTPSEEDS_KEY: &str = "httpseeds";
+const CREATION_DATE_KEY: &str = "creation date";
+const COMMENT_KEY: &str = "comment";
+const CREATED_BY_KEY: &str = "created by";
+const ENCODING_KEY: &str = "encoding";
+
+/// Written as `created by` in torrents made by `MetaInfo::create`
+const CREATED_BY: &str = concat!("rTorrent/", env!("CARGO_PKG_VERSION"));
+const ANNOUNCE_VALUES: [&str; 5] = [
+    ANNOUNCE_KEY,
+    NODES_KEY,
+    ANNOUNCE_LIST_KEY,
+    URL_LIST_KEY,
+    HTTPSEEDS_KEY,
+];
 
 // Keys for the info dict in the file
 const NAME_KEY: &str = "name";
@@ -18,11 +44,26 @@ const PIECES_KEY: &str = "pieces";
 const LENGTH_KEY: &str = "length";
 const FILES_KEY: &str = "files";
 const PRIVATE_KEY: &str = "private";
+const META_VERSION_KEY: &str = "meta version";
+const FILE_TREE_KEY: &str = "file tree";
+const SOURCE_KEY: &str = "source";
 
-const INFO_XOR_VALUES: [&str; 2] = [LENGTH_KEY, FILES_KEY];
+const INFO_XOR_VALUES: [&str; 3] = [LENGTH_KEY, FILES_KEY, FILE_TREE_KEY];
 
 // Heys for the files dict
 const PATH_KEY: &str = "path";
+const ATTR_KEY: &str = "attr";
+
+/// `attr` flag marking a padding file (BEP-47)
+const PADDING_ATTR: char = 'p';
+/// Name prefix of padding files from before BEP-47
+const LEGACY_PADDING_PREFIX: &str = "_____padding_file_";
+
+// Keys for the file tree dict (BEP-0052)
+const FILE_TREE_LEAF_KEY: &str = "";
+const PIECES_ROOT_KEY: &str = "pieces root";
+
+const META_VERSION_2: i64 = 2;
 
 const HASH_SIZE: usize = 20;
 
@@ -34,9 +75,23 @@ pub enum FromBencodeTypeErr {
     MissingValue(String),
     #[error("Failed to get bencode")]
     BencodeGetErr(#[from] BencodeGetErr),
+    #[error("Invalid value: {0}")]
+    InvalidValue(String),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Error)]
+pub enum CreateErr {
+    #[error("Failed to read source files: {0}")]
+    IoErr(#[from] io::Error),
+    #[error("Piece length must be positive, got {0}")]
+    InvalidPieceLength(i64),
+    #[error("Source path has no file name")]
+    MissingName,
+    #[error("No files found to create a torrent from")]
+    NoFiles,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct MetaInfo {
     pub announce: Option<String>,
     pub info: TorrentInfo,
@@ -46,10 +101,21 @@ pub struct MetaInfo {
     pub announce_list: Option<Vec<String>>,
     //BEP-0019
     pub url_list: Option<Vec<String>>,
+    //BEP-0017
+    pub httpseeds: Option<Vec<String>>,
     pub hash: [u8; 20],
+    //BEP-0052, SHA-256 of the info dict for v2 and hybrid torrents
+    pub hash_v2: Option<[u8; 32]>,
+    /// Unix timestamp of when the torrent was created
+    pub creation_date: Option<i64>,
+    pub comment: Option<String>,
+    /// Program used to create the torrent
+    pub created_by: Option<String>,
+    /// Encoding of the strings in the info dict
+    pub encoding: Option<String>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct TorrentInfo {
     pub name: String,
     pub piece_length: i64,
@@ -58,12 +124,28 @@ pub struct TorrentInfo {
     pub files: Option<Vec<FileInfo>>,
     //BEP-0027
     pub private: Option<i64>,
+    //BEP-0052
+    pub meta_version: Option<i64>,
+    pub file_tree: Option<Vec<FileTreeEntry>>,
+    /// Set by some private trackers so their torrents get a distinct info hash
+    pub source: Option<String>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct FileInfo {
     pub length: i64,
     pub path: Vec<PathBuf>,
+    //BEP-0047, e.g. "p" for padding files
+    pub attr: Option<String>,
+}
+
+/// A file of a v2 `file tree`, flattened to its full path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileTreeEntry {
+    pub length: i64,
+    pub path: Vec<PathBuf>,
+    /// Root of the file's merkle tree, absent for empty files
+    pub pieces_root: Option<[u8; 32]>,
 }
 
 #[derive(Debug)]
@@ -73,19 +155,58 @@ pub enum TorrentType {
 }
 
 impl TorrentInfo {
-    pub fn is_single_or_multi_file(self: &Self) -> TorrentType {
+    pub fn is_single_or_multi_file(&self) -> TorrentType {
         match self.files.is_none() {
             true => TorrentType::SingleFile,
             false => TorrentType::MultiFile,
         }
     }
 
+    /// Total size in bytes of all the files in the torrent
+    pub fn total_length(&self) -> i64 {
+        match (&self.files, &self.file_tree) {
+            (Some(files), _) => files.iter().map(|file| file.length).sum(),
+            (None, Some(tree)) if self.length.is_none() => {
+                tree.iter().map(|file| file.length).sum()
+            }
+            _ => self.length.unwrap_or(0),
+        }
+    }
+
+    /// Whether the torrent carries v2 metadata, either v2-only or hybrid
+    pub fn is_v2(&self) -> bool {
+        self.meta_version == Some(META_VERSION_2)
+    }
+
+    /// Number of pieces, as given by the piece hashes
+    pub fn num_pieces(&self) -> usize {
+        self.pieces.len() / HASH_SIZE
+    }
+
+    /// Number of pieces the total length splits into, which matches
+    /// `num_pieces` for every well formed torrent
+    pub fn num_pieces_from_length(&self) -> usize {
+        if self.piece_length <= 0 {
+            return 0;
+        }
+        (self.total_length().max(0) as u64).div_ceil(self.piece_length as u64) as usize
+    }
+
     pub fn get_piece_hash(&self, piece_index: usize) -> Option<[u8; HASH_SIZE]> {
         let start = piece_index * HASH_SIZE;
         let end = start + HASH_SIZE;
 
         self.pieces.get(start..end)?.try_into().ok()
     }
+
+    /// Files of a multi-file torrent without the padding files, which only
+    /// exist to align the real files to piece boundaries
+    pub fn visible_files(&self) -> impl Iterator<Item = &FileInfo> {
+        self.files
+            .iter()
+            .flatten()
+            .filter(|file| !file.is_padding())
+    }
 }
 
 pub trait FromBencodemap: Sized {
@@ -93,6 +214,28 @@ pub trait FromBencodemap: Sized {
     fn is_valid_bencodemap(bencode_map: &BencodeMap) -> bool;
 }
 
+/// What tools usually want to know about a torrent, leaving out the piece
+/// hashes
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TorrentMetadata {
+    pub name: String,
+    pub info_hash: String,
+    pub total_size: i64,
+    pub piece_length: i64,
+    pub piece_count: usize,
+    pub trackers: Vec<String>,
+    /// Every file but padding files, a single one named after the torrent
+    /// for single file torrents
+    pub files: Vec<FileMetadata>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileMetadata {
+    /// Path within the torrent with `/` separated components
+    pub path: String,
+    pub length: i64,
+}
+
 impl FromBencodemap for FileInfo {
     fn from_bencodemap(bencode_map: &BencodeMap) -> Result<Self, FromBencodeTypeErr> {
         if !Self::is_valid_bencodemap(bencode_map) {
@@ -104,14 +247,17 @@ impl FromBencodemap for FileInfo {
         let length: i64 = bencode_map
             .get_decode(LENGTH_KEY)
             .ok_or(FromBencodeTypeErr::MissingValue(String::from(LENGTH_KEY)))?;
-        let path: Vec<PathBuf> = bencode_map
-            .get_decode(PATH_KEY)
+        validate_length(LENGTH_KEY, length, 0)?;
+        let path = bencode_map
+            .get(PATH_KEY.as_bytes())
             .ok_or(FromBencodeTypeErr::MissingValue(String::from(PATH_KEY)))?;
+        let path = Vec::<PathBuf>::try_from(path)
+            .map_err(|e| FromBencodeTypeErr::InvalidValue(format!("{PATH_KEY}: {e}")))?;
+        validate_file_path(&path)?;
 
-        Ok(FileInfo {
-            length: length,
-            path: path,
-        })
+        let attr: Option<String> = bencode_map.get_decode(ATTR_KEY);
+
+        Ok(FileInfo { length, path, attr })
     }
 
     fn is_valid_bencodemap(bencode_map: &BencodeMap) -> bool {
@@ -120,6 +266,50 @@ impl FromBencodemap for FileInfo {
     }
 }
 
+impl FileTreeEntry {
+    /// Flattens a BEP-52 `file tree` dict into its files. Directories are
+    /// nested dicts and files are dicts holding a single `""` key.
+    pub fn from_file_tree(file_tree: &BencodeMap) -> Result<Vec<Self>, FromBencodeTypeErr> {
+        let mut entries = Vec::new();
+        Self::collect_file_tree(file_tree, &mut Vec::new(), &mut entries)?;
+        Ok(entries)
+    }
+
+    fn collect_file_tree(
+        tree: &BencodeMap,
+        path: &mut Vec<PathBuf>,
+        entries: &mut Vec<Self>,
+    ) -> Result<(), FromBencodeTypeErr> {
+        for (name, node) in tree {
+            let node = BencodeMap::try_from(node)?;
+
+            if name.as_slice() == FILE_TREE_LEAF_KEY.as_bytes() {
+                let length: i64 = node
+                    .get_decode(LENGTH_KEY)
+                    .ok_or(FromBencodeTypeErr::MissingValue(String::from(LENGTH_KEY)))?;
+                validate_length(LENGTH_KEY, length, 0)?;
+                let pieces_root = node
+                    .get_decode::<Vec<u8>>(PIECES_ROOT_KEY)
+                    .and_then(|root| root.try_into().ok());
+
+                entries.push(FileTreeEntry {
+                    length,
+                    path: path.clone(),
+                    pieces_root,
+                });
+            } else {
+                let name = PathBuf::from(String::from_utf8_lossy(name).into_owned());
+                validate_file_path(std::slice::from_ref(&name))?;
+                path.push(name);
+                Self::collect_file_tree(&node, path, entries)?;
+                path.pop();
+            }
+        }
+
+        Ok(())
+    }
+}
+
 impl FromBencodemap for TorrentInfo {
     fn from_bencodemap(bencode_map: &BencodeMap) -> Result<TorrentInfo, FromBencodeTypeErr> {
         if !Self::is_valid_bencodemap(bencode_map) {
@@ -137,37 +327,53 @@ impl FromBencodemap for TorrentInfo {
                 .ok_or(FromBencodeTypeErr::MissingValue(String::from(
                     PIECE_LENGTH_KEY,
                 )))?;
-        let pieces: Vec<u8> = bencode_map
-            .get_decode(PIECES_KEY)
-            .ok_or(FromBencodeTypeErr::MissingValue(String::from(PIECES_KEY)))?;
+        validate_length(PIECE_LENGTH_KEY, piece_length, 1)?;
+        let meta_version: Option<i64> = bencode_map.get_decode(META_VERSION_KEY);
+        let file_tree = match bencode_map.get_decode::<BencodeMap>(FILE_TREE_KEY) {
+            Some(tree) => Some(FileTreeEntry::from_file_tree(&tree)?),
+            None => None,
+        };
+        // v2-only torrents have no v1 piece hashes
+        let pieces: Vec<u8> = match bencode_map.get_decode(PIECES_KEY) {
+            Some(pieces) => pieces,
+            None if file_tree.is_some() => Vec::new(),
+            None => Err(FromBencodeTypeErr::MissingValue(String::from(PIECES_KEY)))?,
+        };
         let length: Option<i64> = bencode_map.get_decode(LENGTH_KEY);
+        if let Some(length) = length {
+            validate_length(LENGTH_KEY, length, 0)?;
+        }
         let files: Option<Vec<BencodeMap>> = bencode_map.get_decode(FILES_KEY);
         let private: Option<i64> = bencode_map.get_decode(PRIVATE_KEY);
+        let source: Option<String> = bencode_map.get_decode(SOURCE_KEY);
 
         // TODO: rewrite this logic
         let mut final_vec = Vec::new();
         if let Some(files_vec) = files {
-            let mut iter = files_vec.iter();
-            while let Some(x) = iter.next() {
+            for x in files_vec.iter() {
                 final_vec.push(FileInfo::from_bencodemap(x)?);
             }
         }
 
-        let final_file;
-
-        match final_vec.is_empty() {
-            true => final_file = None,
-            false => final_file = Some(final_vec),
-        }
+        let final_file = match final_vec.is_empty() {
+            true => None,
+            false => Some(final_vec),
+        };
 
-        Ok(TorrentInfo {
-            name: name,
-            piece_length: piece_length,
-            pieces: pieces,
-            length: length,
+        let info = TorrentInfo {
+            name,
+            piece_length,
+            pieces,
+            length,
             files: final_file,
-            private: private,
-        })
+            private,
+            meta_version,
+            file_tree,
+            source,
+        };
+        validate_pieces(&info)?;
+
+        Ok(info)
     }
 
     fn is_valid_bencodemap(bencode_map: &BencodeMap) -> bool {
@@ -203,18 +409,35 @@ impl FromBencodemap for MetaInfo {
         let nodes: Option<Vec<String>> = bencode_map.get_decode(NODES_KEY);
         let announce_list: Option<Vec<String>> = bencode_map.get_decode(ANNOUNCE_LIST_KEY);
         let url_list: Option<Vec<String>> = bencode_map.get_decode(URL_LIST_KEY);
+        let httpseeds: Option<Vec<String>> = bencode_map.get_decode(HTTPSEEDS_KEY);
+        let creation_date: Option<i64> = bencode_map.get_decode(CREATION_DATE_KEY);
+        let comment: Option<String> = bencode_map.get_decode(COMMENT_KEY);
+        let created_by: Option<String> = bencode_map.get_decode(CREATED_BY_KEY);
+        let encoding: Option<String> = bencode_map.get_decode(ENCODING_KEY);
 
         let info: BencodeMap = bencode_map
             .get_decode(INFO_KEY)
             .ok_or(FromBencodeTypeErr::MissingValue(String::from(INFO_KEY)))?;
 
+        let torrent_info = TorrentInfo::from_bencodemap(&info)?;
+        let encoded_info = info.get_encode();
+        let hash_v2 = torrent_info
+            .is_v2()
+            .then(|| Sha256::digest(&encoded_info).into());
+
         Ok(MetaInfo {
-            announce: announce,
-            info: TorrentInfo::from_bencodemap(&info)?,
-            nodes: nodes,
-            announce_list: announce_list,
-            url_list: url_list,
-            hash: Sha1::digest(info.get_encode()).into(),
+            announce,
+            info: torrent_info,
+            nodes,
+            announce_list,
+            url_list,
+            httpseeds,
+            hash: Sha1::digest(&encoded_info).into(),
+            hash_v2,
+            creation_date,
+            comment,
+            created_by,
+            encoding,
         })
     }
 
@@ -241,8 +464,9 @@ impl FromBencodemap for MetaInfo {
 
 impl TorrentInfo {
     pub fn get_piece_hashes(&self) -> Vec<[u8; 20]> {
+        // A truncated trailing hash is not a piece
         self.pieces
-            .chunks(HASH_SIZE)
+            .chunks_exact(HASH_SIZE)
             .map(|chunk| {
                 let mut hash = [0u8; HASH_SIZE];
                 hash.copy_from_slice(chunk);
@@ -250,4 +474,884 @@ impl TorrentInfo {
             })
             .collect()
     }
+
+    /// Encodes the v1 fields of the info dict
+    pub fn to_bencodemap(&self) -> BencodeMap {
+        let mut map = BencodeMap::new();
+        insert_string(&mut map, NAME_KEY, self.name.as_bytes());
+        map.insert(
+            PIECE_LENGTH_KEY.into(),
+            BencodeType::Integer(self.piece_length),
+        );
+        insert_string(&mut map, PIECES_KEY, &self.pieces);
+
+        if let Some(length) = self.length {
+            map.insert(LENGTH_KEY.into(), BencodeType::Integer(length));
+        }
+        if let Some(files) = &self.files {
+            map.insert(
+                FILES_KEY.into(),
+                BencodeType::List(
+                    files
+                        .iter()
+                        .map(|file| BencodeType::Dictionary(file.to_bencodemap()))
+                        .collect(),
+                ),
+            );
+        }
+        if let Some(private) = self.private {
+            map.insert(PRIVATE_KEY.into(), BencodeType::Integer(private));
+        }
+        if let Some(source) = &self.source {
+            insert_string(&mut map, SOURCE_KEY, source.as_bytes());
+        }
+
+        map
+    }
+}
+
+impl FileInfo {
+    /// Whether the file is padding, either flagged through `attr` or named
+    /// like the padding files of older clients
+    pub fn is_padding(&self) -> bool {
+        let flagged = self
+            .attr
+            .as_ref()
+            .is_some_and(|attr| attr.contains(PADDING_ATTR));
+        let legacy = self
+            .path
+            .last()
+            .is_some_and(|name| name.to_string_lossy().starts_with(LEGACY_PADDING_PREFIX));
+
+        flagged || legacy
+    }
+
+    pub fn to_bencodemap(&self) -> BencodeMap {
+        let mut map = BencodeMap::new();
+        map.insert(LENGTH_KEY.into(), BencodeType::Integer(self.length));
+        map.insert(
+            PATH_KEY.into(),
+            BencodeType::List(
+                self.path
+                    .iter()
+                    .map(|part| BencodeType::String(part.to_string_lossy().as_bytes().to_vec()))
+                    .collect(),
+            ),
+        );
+        if let Some(attr) = &self.attr {
+            insert_string(&mut map, ATTR_KEY, attr.as_bytes());
+        }
+        map
+    }
+}
+
+impl MetaInfo {
+    /// Whether peers may only be obtained from the torrent's trackers (BEP-27)
+    pub fn is_private(&self) -> bool {
+        self.info.private == Some(1)
+    }
+
+    /// Every distinct tracker URL, `announce` first followed by `announce-list`
+    pub fn trackers(&self) -> Vec<&String> {
+        let mut trackers: Vec<&String> = Vec::new();
+        for tracker in self
+            .announce
+            .iter()
+            .chain(self.announce_list.iter().flatten())
+        {
+            if !trackers.contains(&tracker) {
+                trackers.push(tracker);
+            }
+        }
+        trackers
+    }
+
+    /// The v1 info hash as 40 lowercase hex digits
+    pub fn info_hash_hex(&self) -> String {
+        self.hash.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    /// Summary of the torrent for other tools, see `to_json`
+    pub fn metadata(&self) -> TorrentMetadata {
+        let files = match &self.info.files {
+            Some(_) => self
+                .info
+                .visible_files()
+                .map(|file| FileMetadata {
+                    path: file
+                        .path
+                        .iter()
+                        .map(|part| part.to_string_lossy())
+                        .collect::<Vec<_>>()
+                        .join("/"),
+                    length: file.length,
+                })
+                .collect(),
+            None => vec![FileMetadata {
+                path: self.info.name.clone(),
+                length: self.info.total_length(),
+            }],
+        };
+
+        TorrentMetadata {
+            name: self.info.name.clone(),
+            info_hash: self.info_hash_hex(),
+            total_size: self.info.total_length(),
+            piece_length: self.info.piece_length,
+            piece_count: self.info.num_pieces(),
+            trackers: self.trackers().into_iter().cloned().collect(),
+            files,
+        }
+    }
+
+    /// The torrent's `metadata` as pretty-printed JSON
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(&self.metadata()).expect("metadata is always serializable")
+    }
+
+    /// Magnet link (BEP-9) for the torrent with its name and every tracker
+    pub fn to_magnet(&self) -> String {
+        let mut magnet = format!(
+            "magnet:?xt=urn:btih:{}&dn={}",
+            self.info_hash_hex(),
+            percent_encode(self.info.name.as_bytes())
+        );
+        for tracker in self.trackers() {
+            magnet.push_str("&tr=");
+            magnet.push_str(&percent_encode(tracker.as_bytes()));
+        }
+        magnet
+    }
+
+    /// Creates a v1 torrent from a file or directory, hashing its contents in
+    /// `piece_length` chunks. The first tracker is used as `announce`.
+    pub fn create(
+        path: &Path,
+        piece_length: i64,
+        trackers: &[String],
+    ) -> Result<MetaInfo, CreateErr> {
+        if piece_length <= 0 {
+            return Err(CreateErr::InvalidPieceLength(piece_length));
+        }
+
+        let name = path
+            .file_name()
+            .ok_or(CreateErr::MissingName)?
+            .to_string_lossy()
+            .into_owned();
+
+        let (length, files, sources) = if path.is_dir() {
+            let mut relative_paths = Vec::new();
+            collect_files(path, &mut Vec::new(), &mut relative_paths)?;
+            if relative_paths.is_empty() {
+                return Err(CreateErr::NoFiles);
+            }
+
+            let mut files = Vec::new();
+            let mut sources = Vec::new();
+            for relative in relative_paths {
+                let source: PathBuf = std::iter::once(path.to_path_buf())
+                    .chain(relative.iter().cloned())
+                    .collect();
+                files.push(FileInfo {
+                    length: fs::metadata(&source)?.len() as i64,
+                    path: relative,
+                    attr: None,
+                });
+                sources.push(source);
+            }
+            (None, Some(files), sources)
+        } else {
+            let length = fs::metadata(path)?.len() as i64;
+            (Some(length), None, vec![path.to_path_buf()])
+        };
+
+        let info = TorrentInfo {
+            name,
+            piece_length,
+            pieces: hash_pieces(&sources, piece_length as usize)?,
+            length,
+            files,
+            private: None,
+            meta_version: None,
+            file_tree: None,
+            source: None,
+        };
+
+        let announce_list = (trackers.len() > 1).then(|| trackers.to_vec());
+
+        Ok(MetaInfo {
+            announce: trackers.first().cloned(),
+            hash: Sha1::digest(info.to_bencodemap().get_encode()).into(),
+            info,
+            nodes: None,
+            announce_list,
+            url_list: None,
+            httpseeds: None,
+            hash_v2: None,
+            creation_date: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .ok()
+                .map(|elapsed| elapsed.as_secs() as i64),
+            comment: None,
+            created_by: Some(CREATED_BY.to_string()),
+            encoding: None,
+        })
+    }
+
+    pub fn to_bencodemap(&self) -> BencodeMap {
+        let mut map = BencodeMap::new();
+        if let Some(announce) = &self.announce {
+            insert_string(&mut map, ANNOUNCE_KEY, announce.as_bytes());
+        }
+        if let Some(announce_list) = &self.announce_list {
+            // Each tracker gets its own tier
+            map.insert(
+                ANNOUNCE_LIST_KEY.into(),
+                BencodeType::List(
+                    announce_list
+                        .iter()
+                        .map(|url| BencodeType::List(vec![string_value(url)]))
+                        .collect(),
+                ),
+            );
+        }
+        if let Some(nodes) = &self.nodes {
+            map.insert(NODES_KEY.into(), string_list(nodes));
+        }
+        if let Some(url_list) = &self.url_list {
+            map.insert(URL_LIST_KEY.into(), string_list(url_list));
+        }
+        if let Some(httpseeds) = &self.httpseeds {
+            map.insert(HTTPSEEDS_KEY.into(), string_list(httpseeds));
+        }
+        if let Some(creation_date) = self.creation_date {
+            map.insert(
+                CREATION_DATE_KEY.into(),
+                BencodeType::Integer(creation_date),
+            );
+        }
+        if let Some(comment) = &self.comment {
+            insert_string(&mut map, COMMENT_KEY, comment.as_bytes());
+        }
+        if let Some(created_by) = &self.created_by {
+            insert_string(&mut map, CREATED_BY_KEY, created_by.as_bytes());
+        }
+        if let Some(encoding) = &self.encoding {
+            insert_string(&mut map, ENCODING_KEY, encoding.as_bytes());
+        }
+        map.insert(
+            INFO_KEY.into(),
+            BencodeType::Dictionary(self.info.to_bencodemap()),
+        );
+        map
+    }
+
+    /// Encodes the meta info as the contents of a `.torrent` file
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.to_bencodemap().get_encode()
+    }
+}
+
+impl Display for TorrentInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Name:         {}", self.name)?;
+        writeln!(f, "Size:         {}", format_size(self.total_length()))?;
+        writeln!(f, "Piece length: {}", format_size(self.piece_length))?;
+        write!(f, "Pieces:       {}", self.num_pieces())
+    }
+}
+
+impl Display for MetaInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", self.info)?;
+        if let Some(comment) = &self.comment {
+            writeln!(f, "Comment:      {comment}")?;
+        }
+        if let Some(created_by) = &self.created_by {
+            writeln!(f, "Created by:   {created_by}")?;
+        }
+        if let Some(creation_date) = self.creation_date {
+            writeln!(f, "Created on:   {}", format_unix_time(creation_date))?;
+        }
+        if let Some(source) = &self.info.source {
+            writeln!(f, "Source:       {source}")?;
+        }
+        writeln!(f, "Trackers:     {}", self.trackers().len())?;
+        write!(f, "Info hash:    {}", self.info_hash_hex())
+    }
+}
+
+/// Formats a size in bytes using binary units, e.g. `1.50 MiB`
+pub fn format_size(bytes: i64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{size:.2} {}", UNITS[unit])
+}
+
+/// Formats a Unix timestamp as a UTC date and time, e.g. `2025-09-06 12:31:52 UTC`
+pub fn format_unix_time(timestamp: i64) -> String {
+    let days = timestamp.div_euclid(86_400);
+    let seconds = timestamp.rem_euclid(86_400);
+
+    // Civil from days, see https://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02} UTC",
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+fn insert_string(map: &mut BencodeMap, key: &str, value: &[u8]) {
+    map.insert(key.into(), BencodeType::String(value.to_vec()));
+}
+
+fn string_value(value: &str) -> BencodeType {
+    BencodeType::String(value.as_bytes().to_vec())
+}
+
+fn string_list(values: &[String]) -> BencodeType {
+    BencodeType::List(values.iter().map(|value| string_value(value)).collect())
+}
+
+/// Collects the paths of all files below `dir` relative to it, sorted by name
+fn collect_files(
+    dir: &Path,
+    prefix: &mut Vec<PathBuf>,
+    files: &mut Vec<Vec<PathBuf>>,
+) -> Result<(), io::Error> {
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        prefix.push(PathBuf::from(entry.file_name()));
+        if entry.file_type()?.is_dir() {
+            collect_files(&entry.path(), prefix, files)?;
+        } else {
+            files.push(prefix.clone());
+        }
+        prefix.pop();
+    }
+
+    Ok(())
+}
+
+/// Hashes the concatenation of `sources` in pieces of `piece_length` bytes
+fn hash_pieces(sources: &[PathBuf], piece_length: usize) -> Result<Vec<u8>, io::Error> {
+    let mut pieces = Vec::new();
+    let mut piece = Vec::with_capacity(piece_length);
+
+    for source in sources {
+        let mut file = File::open(source)?;
+        loop {
+            let filled = piece.len();
+            piece.resize(piece_length, 0);
+            let read = file.read(&mut piece[filled..])?;
+            piece.truncate(filled + read);
+
+            if read == 0 {
+                break;
+            }
+            if piece.len() == piece_length {
+                pieces.extend_from_slice(&PieceHasher::digest(&piece));
+                piece.clear();
+            }
+        }
+    }
+
+    if !piece.is_empty() {
+        pieces.extend_from_slice(&PieceHasher::digest(&piece));
+    }
+
+    Ok(pieces)
+}
+
+/// Rejects sizes below `min`, which can only come from a broken torrent
+fn validate_length(key: &str, length: i64, min: i64) -> Result<(), FromBencodeTypeErr> {
+    if length < min {
+        return Err(FromBencodeTypeErr::InvalidValue(format!(
+            "{key} {length} is less than {min}"
+        )));
+    }
+
+    Ok(())
+}
+
+/// Rejects v1 piece hashes that don't come to one hash per piece of the
+/// torrent's length. v2-only torrents have no v1 hashes to check.
+fn validate_pieces(info: &TorrentInfo) -> Result<(), FromBencodeTypeErr> {
+    if info.pieces.is_empty() && info.file_tree.is_some() {
+        return Ok(());
+    }
+    if !info.pieces.len().is_multiple_of(HASH_SIZE) {
+        return Err(FromBencodeTypeErr::InvalidValue(format!(
+            "{PIECES_KEY} length {} is not a multiple of {HASH_SIZE}",
+            info.pieces.len()
+        )));
+    }
+    if info.num_pieces() != info.num_pieces_from_length() {
+        return Err(FromBencodeTypeErr::InvalidValue(format!(
+            "{PIECES_KEY} has {} hashes but the length makes {} pieces",
+            info.num_pieces(),
+            info.num_pieces_from_length()
+        )));
+    }
+
+    Ok(())
+}
+
+/// Rejects file paths that are empty or have components that aren't a
+/// single plain name, such as `..`, `/` or `a/b`, so files can't be written
+/// outside of the torrent's directory
+fn validate_file_path(path: &[PathBuf]) -> Result<(), FromBencodeTypeErr> {
+    if path.is_empty() {
+        return Err(FromBencodeTypeErr::InvalidValue(String::from(
+            "File path is empty",
+        )));
+    }
+
+    for part in path {
+        let mut components = part.components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(name)), None) if name == part.as_os_str() => {}
+            _ => {
+                return Err(FromBencodeTypeErr::InvalidValue(format!(
+                    "Unsafe file path component {part:?}"
+                )))
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        bencode::{self, BencodeType},
+        test_util::temp_path,
+    };
+
+    use super::*;
+
+    fn to_hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    #[test]
+    fn hybrid_torrent_hashes() {
+        let info = [
+            b"d9:file treed5:a.txtd0:d6:lengthi5e11:pieces root32:".as_slice(),
+            &[b'A'; 32],
+            b"eee6:lengthi5e12:meta versioni2e4:name5:a.txt12:piece lengthi16384e6:pieces20:",
+            &[b'B'; 20],
+            b"e",
+        ]
+        .concat();
+        let torrent = [
+            b"d8:announce27:http://example.com/announce4:info".as_slice(),
+            &info,
+            b"e",
+        ]
+        .concat();
+
+        let map = match bencode::decode_to_vec(&torrent).unwrap().remove(0) {
+            BencodeType::Dictionary(map) => map,
+            other => panic!("Expected a dictionary, got {other}"),
+        };
+        let meta_info = MetaInfo::from_bencodemap(&map).unwrap();
+
+        assert!(meta_info.info.is_v2());
+        assert_eq!(
+            meta_info.info.file_tree,
+            Some(vec![FileTreeEntry {
+                length: 5,
+                path: vec![PathBuf::from("a.txt")],
+                pieces_root: Some([b'A'; 32]),
+            }])
+        );
+        assert_eq!(
+            to_hex(&meta_info.hash),
+            "6b80f48511d5c9b9b1deb7e77627bb9fb5870348"
+        );
+        assert_eq!(
+            to_hex(&meta_info.hash_v2.unwrap()),
+            "7e622459163f7ba5d78c09953452bbd973055eec9f0ca717b2268702df3a8e8b"
+        );
+    }
+
+    fn decode(bytes: &[u8]) -> MetaInfo {
+        match bencode::decode_to_vec(bytes).unwrap().remove(0) {
+            BencodeType::Dictionary(map) => MetaInfo::from_bencodemap(&map).unwrap(),
+            other => panic!("Expected a dictionary, got {other}"),
+        }
+    }
+
+    #[test]
+    fn optional_metadata_decoded() {
+        let info = [
+            b"4:infod6:lengthi4e4:name4:test12:piece lengthi4e6:pieces20:".as_slice(),
+            &[b'A'; 20],
+            b"e",
+        ]
+        .concat();
+        let with_metadata = [
+            b"d8:announce4:test7:comment5:hello10:created by9:mktorrent13:creation datei1757161912e8:encoding5:UTF-8".as_slice(),
+            &info,
+            b"e",
+        ]
+        .concat();
+        let without_metadata = [b"d8:announce4:test".as_slice(), &info, b"e"].concat();
+
+        let meta_info = decode(&with_metadata);
+        assert_eq!(meta_info.creation_date, Some(1757161912));
+        assert_eq!(meta_info.comment.as_deref(), Some("hello"));
+        assert_eq!(meta_info.created_by.as_deref(), Some("mktorrent"));
+        assert_eq!(meta_info.encoding.as_deref(), Some("UTF-8"));
+        assert!(meta_info
+            .to_string()
+            .contains("Created on:   2025-09-06 12:31:52 UTC"));
+
+        let meta_info = decode(&without_metadata);
+        assert_eq!(meta_info.creation_date, None);
+        assert_eq!(meta_info.comment, None);
+        assert_eq!(meta_info.created_by, None);
+        assert_eq!(meta_info.encoding, None);
+    }
+
+    #[test]
+    fn httpseeds_decoded() {
+        let meta_info = decode(
+            &[
+                b"d9:httpseedsl28:http://seed.example/seed.php21:http://other.example/e4:infod6:lengthi4e4:name4:test12:piece lengthi4e6:pieces20:".as_slice(),
+                &[b'A'; 20],
+                b"ee",
+            ]
+            .concat(),
+        );
+
+        assert_eq!(meta_info.announce, None);
+        assert_eq!(
+            meta_info.httpseeds,
+            Some(vec![
+                "http://seed.example/seed.php".to_string(),
+                "http://other.example/".to_string(),
+            ])
+        );
+        assert_eq!(decode(&meta_info.to_bytes()), meta_info);
+    }
+
+    #[test]
+    fn source_decoded_and_hashed() {
+        let torrent = |info: &[u8]| {
+            [
+                b"d8:announce4:test4:infod6:lengthi4e4:name4:test12:piece lengthi4e6:pieces20:"
+                    .as_slice(),
+                &[b'A'; 20],
+                info,
+                b"ee",
+            ]
+            .concat()
+        };
+
+        let with_source = decode(&torrent(b"6:source3:PTP"));
+        let without_source = decode(&torrent(b""));
+
+        assert_eq!(with_source.info.source.as_deref(), Some("PTP"));
+        assert_eq!(without_source.info.source, None);
+        assert_ne!(with_source.hash, without_source.hash);
+        let reencoded: [u8; 20] =
+            Sha1::digest(with_source.info.to_bencodemap().get_encode()).into();
+        assert_eq!(reencoded, with_source.hash);
+    }
+
+    #[test]
+    fn padding_files_hidden_but_counted() {
+        let meta_info = decode(
+            &[
+                b"d8:announce4:test4:infod5:filesl".as_slice(),
+                b"d6:lengthi3e4:pathl5:a.txtee",
+                b"d4:attr1:p6:lengthi13e4:pathl4:.pad2:13ee",
+                b"d6:lengthi2e4:pathl21:_____padding_file_0__ee",
+                b"d6:lengthi4e4:pathl5:b.txtee",
+                b"e4:name5:multi12:piece lengthi16e6:pieces40:",
+                &[b'A'; 40],
+                b"ee",
+            ]
+            .concat(),
+        );
+
+        let visible: Vec<&PathBuf> = meta_info
+            .info
+            .visible_files()
+            .flat_map(|file| file.path.last())
+            .collect();
+        assert_eq!(
+            visible,
+            vec![&PathBuf::from("a.txt"), &PathBuf::from("b.txt")]
+        );
+        assert_eq!(meta_info.info.total_length(), 22);
+        assert_eq!(meta_info.info.num_pieces_from_length(), 2);
+    }
+
+    fn decode_file(bytes: &[u8]) -> Result<FileInfo, FromBencodeTypeErr> {
+        FileInfo::from_bencodemap(&BencodeMap::try_decode(bytes).unwrap())
+    }
+
+    #[test]
+    fn nested_file_path_accepted() {
+        let file = decode_file(b"d6:lengthi1e4:pathl3:sub3:dir5:a.txtee").unwrap();
+
+        assert_eq!(
+            file.path,
+            vec![
+                PathBuf::from("sub"),
+                PathBuf::from("dir"),
+                PathBuf::from("a.txt")
+            ]
+        );
+    }
+
+    #[test]
+    fn traversing_file_paths_rejected() {
+        for bytes in [
+            b"d6:lengthi1e4:pathl2:..2:..3:etc6:passwdee".as_slice(),
+            b"d6:lengthi1e4:pathl16:../../etc/passwdee",
+            b"d6:lengthi1e4:pathl1:/3:etc6:passwdee",
+            b"d6:lengthi1e4:pathl3:sub0:5:a.txtee",
+            b"d6:lengthi1e4:pathlee",
+            b"d6:lengthi1e4:pathl1:\xffee",
+        ] {
+            assert!(
+                matches!(decode_file(bytes), Err(FromBencodeTypeErr::InvalidValue(_))),
+                "{}",
+                String::from_utf8_lossy(bytes)
+            );
+        }
+    }
+
+    #[test]
+    fn piece_count_checked_against_length() {
+        let hash = [b'a'; HASH_SIZE];
+        let info = |length: i64, pieces: &[u8]| {
+            let mut bytes =
+                format!("d6:lengthi{length}e4:name4:test12:piece lengthi4e6:pieces").into_bytes();
+            bytes.extend(format!("{}:", pieces.len()).bytes());
+            bytes.extend(pieces);
+            bytes.push(b'e');
+            TorrentInfo::from_bencodemap(&BencodeMap::try_decode(&bytes).unwrap())
+        };
+
+        assert!(info(8, &hash.repeat(2)).is_ok());
+        for (length, pieces) in [(20, hash.repeat(2)), (8, hash.repeat(3)), (8, vec![0; 30])] {
+            assert!(matches!(
+                info(length, &pieces),
+                Err(FromBencodeTypeErr::InvalidValue(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn negative_lengths_rejected() {
+        assert!(matches!(
+            decode_file(b"d6:lengthi-1e4:pathl5:a.txtee"),
+            Err(FromBencodeTypeErr::InvalidValue(_))
+        ));
+
+        for bytes in [
+            b"d6:lengthi-4e4:name4:test12:piece lengthi4e6:pieces0:e".as_slice(),
+            b"d6:lengthi4e4:name4:test12:piece lengthi0e6:pieces0:e",
+        ] {
+            let info = TorrentInfo::from_bencodemap(&BencodeMap::try_decode(bytes).unwrap());
+            assert!(
+                matches!(info, Err(FromBencodeTypeErr::InvalidValue(_))),
+                "{}",
+                String::from_utf8_lossy(bytes)
+            );
+        }
+    }
+
+    #[test]
+    fn magnet_link_for_checked_in_torrent() {
+        let meta_info = crate::torrent::read_meta_info(&PathBuf::from(
+            "../test/torrent_files/debian-13.1.0-amd64-netinst.iso.torrent",
+        ))
+        .unwrap();
+
+        let magnet = meta_info.to_magnet();
+
+        assert_eq!(meta_info.info_hash_hex(), to_hex(&meta_info.hash));
+        assert!(magnet.starts_with(&format!(
+            "magnet:?xt=urn:btih:{}&dn=debian-13.1.0-amd64-netinst.iso&tr=",
+            to_hex(&meta_info.hash)
+        )));
+        assert!(magnet.contains("&tr=http%3A%2F%2Fbttracker.debian.org%3A6969%2Fannounce"));
+    }
+
+    #[test]
+    fn json_metadata_for_checked_in_torrent() {
+        let meta_info = crate::torrent::read_meta_info(&PathBuf::from(
+            "../test/torrent_files/debian-13.1.0-amd64-netinst.iso.torrent",
+        ))
+        .unwrap();
+
+        let json: serde_json::Value = serde_json::from_str(&meta_info.to_json()).unwrap();
+
+        assert_eq!(json["info_hash"], to_hex(&meta_info.hash));
+        assert_eq!(json["name"], "debian-13.1.0-amd64-netinst.iso");
+        assert_eq!(json["total_size"], 821_035_008);
+        assert_eq!(json["piece_count"], meta_info.info.num_pieces());
+        assert_eq!(json["files"][0]["length"], 821_035_008);
+        assert!(json.get("pieces").is_none());
+    }
+
+    #[test]
+    fn magnet_name_percent_encoded() {
+        let mut meta_info = decode(
+            &[
+                b"d8:announce4:test4:infod6:lengthi1e4:name5:a b&c".as_slice(),
+                b"12:piece lengthi16e6:pieces20:",
+                &[b'A'; 20],
+                b"ee",
+            ]
+            .concat(),
+        );
+        meta_info.announce = Some("http://t.example/a?x=1".to_string());
+
+        assert!(meta_info
+            .to_magnet()
+            .ends_with("&dn=a%20b%26c&tr=http%3A%2F%2Ft.example%2Fa%3Fx%3D1"));
+    }
+
+    #[test]
+    fn summary_omits_pieces() {
+        let meta_info = crate::torrent::read_meta_info(&PathBuf::from(
+            "../test/torrent_files/debian-13.1.0-amd64-netinst.iso.torrent",
+        ))
+        .unwrap();
+        let summary = meta_info.to_string();
+
+        assert!(summary.contains("debian-13.1.0-amd64-netinst.iso"));
+        assert!(summary.contains("MiB"));
+        assert!(summary.contains("2ced861966e919e5ca9e35d27dc23e0b02fb7ff8"));
+        let first_piece = String::from_utf8_lossy(&meta_info.info.pieces[..HASH_SIZE]);
+        assert!(!summary.contains(first_piece.as_ref()));
+        assert!(summary.len() < 512);
+    }
+
+    #[test]
+    fn num_pieces_matches_length() {
+        for file in [
+            "debian-13.1.0-amd64-netinst.iso.torrent",
+            "multi_file.torrent",
+        ] {
+            let meta_info = crate::torrent::read_meta_info(&PathBuf::from(format!(
+                "../test/torrent_files/{file}"
+            )))
+            .unwrap();
+
+            assert!(meta_info.info.num_pieces() > 0);
+            assert_eq!(
+                meta_info.info.num_pieces(),
+                meta_info.info.num_pieces_from_length(),
+                "{file}"
+            );
+            assert_eq!(
+                meta_info.info.get_piece_hashes().len(),
+                meta_info.info.num_pieces()
+            );
+        }
+    }
+
+    #[test]
+    fn truncated_hash_is_not_a_piece() {
+        let info = TorrentInfo {
+            name: "test".to_string(),
+            piece_length: 4,
+            pieces: vec![0; HASH_SIZE + 5],
+            length: Some(4),
+            files: None,
+            private: None,
+            meta_version: None,
+            file_tree: None,
+            source: None,
+        };
+
+        assert_eq!(info.num_pieces(), 1);
+        assert_eq!(info.get_piece_hashes().len(), 1);
+    }
+
+    #[test]
+    fn sizes_use_binary_units() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.50 KiB");
+        assert_eq!(format_size(262_144), "256.00 KiB");
+        assert_eq!(format_size(3 * 1024 * 1024 * 1024), "3.00 GiB");
+    }
+
+    #[test]
+    fn create_single_file_round_trip() {
+        let path = temp_path("single.bin");
+        let data: Vec<u8> = (0..40_000).map(|i| (i % 251) as u8).collect();
+        fs::write(&path, &data).unwrap();
+
+        let trackers = vec!["http://tracker.example.com/announce".to_string()];
+        let created = MetaInfo::create(&path, 16384, &trackers).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let expected_pieces: Vec<u8> = data.chunks(16384).flat_map(Sha1::digest).collect();
+        assert_eq!(created.info.pieces, expected_pieces);
+        assert_eq!(created.info.length, Some(40_000));
+
+        assert_eq!(decode(&created.to_bytes()), created);
+    }
+
+    #[test]
+    fn create_directory_lists_files_in_order() {
+        let dir = temp_path("dir");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("b.txt"), b"bbbb").unwrap();
+        fs::write(dir.join("sub").join("a.txt"), b"aaaaaa").unwrap();
+
+        let trackers = vec!["http://tracker.example.com/announce".to_string()];
+        let created = MetaInfo::create(&dir, 4, &trackers).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let files = created.info.files.clone().unwrap();
+        assert_eq!(files[0].path, vec![PathBuf::from("b.txt")]);
+        assert_eq!(
+            files[1].path,
+            vec![PathBuf::from("sub"), PathBuf::from("a.txt")]
+        );
+        let expected_pieces: Vec<u8> = [b"bbbb".as_slice(), b"aaaa", b"aa"]
+            .iter()
+            .flat_map(Sha1::digest)
+            .collect();
+        assert_eq!(created.info.pieces, expected_pieces);
+
+        assert_eq!(decode(&created.to_bytes()), created);
+    }
 }
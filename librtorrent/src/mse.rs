@@ -0,0 +1,612 @@
+use std::{
+    io,
+    pin::Pin,
+    sync::LazyLock,
+    task::{ready, Context, Poll},
+};
+
+use num_bigint::BigUint;
+use sha1::{Digest, Sha1};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::transport::PeerTransport;
+
+/// 768 bit prime all Diffie-Hellman exchanges of Message Stream Encryption use
+const PRIME_HEX: &[u8] = b"FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74\
+020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+const GENERATOR: u32 = 2;
+static PRIME: LazyLock<BigUint> =
+    LazyLock::new(|| BigUint::parse_bytes(PRIME_HEX, 16).expect("prime is valid hex"));
+
+/// Size of a public key and of the shared secret
+pub const KEY_SIZE: usize = 96;
+/// Size of the random private keys we generate
+const PRIVATE_KEY_SIZE: usize = 20;
+/// Most padding either side may send
+const MAX_PAD: usize = 512;
+/// Verification constant both sides send encrypted to synchronize on
+const VC: [u8; 8] = [0; 8];
+/// Keystream bytes dropped before RC4 is used, as the start is weak
+const RC4_DISCARD: usize = 1024;
+
+/// Only the handshake is obfuscated, the connection continues in plaintext
+pub const CRYPTO_PLAINTEXT: u32 = 0x01;
+/// The whole connection is RC4 encrypted
+pub const CRYPTO_RC4: u32 = 0x02;
+
+/// When outgoing connections use Message Stream Encryption
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EncryptionMode {
+    /// Only plaintext handshakes are attempted
+    #[default]
+    Disabled,
+    /// Plaintext is attempted first, then encryption if the peer refuses it
+    Enabled,
+    /// Encryption is attempted first, then plaintext if the peer refuses it
+    Preferred,
+    /// Only encrypted handshakes are attempted
+    Required,
+}
+
+#[derive(Debug, Error)]
+pub enum MseErr {
+    #[error("Peer sent an invalid public key")]
+    InvalidPublicKey,
+    #[error("Verification constant not found")]
+    SyncFailed,
+    #[error("Peer selected unsupported crypto method {0:#x}")]
+    UnsupportedCrypto(u32),
+    #[error("Peer sent {0} bytes of padding")]
+    InvalidPadding(usize),
+    #[error("IO error {0}")]
+    IoError(#[from] io::Error),
+}
+
+/// RC4 stream cipher, encrypting and decrypting are the same operation
+#[derive(Debug, Clone)]
+pub struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    pub fn new(key: &[u8]) -> Self {
+        let mut state = [0u8; 256];
+        for (i, byte) in state.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+
+        let mut j: u8 = 0;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+
+        Rc4 { state, i: 0, j: 0 }
+    }
+
+    /// XORs `data` with the next bytes of the keystream
+    pub fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let index = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+            *byte ^= self.state[index as usize];
+        }
+    }
+}
+
+/// Diffie-Hellman key pair
+#[derive(Debug, Clone)]
+pub struct DhKeys {
+    private: BigUint,
+    public: [u8; KEY_SIZE],
+}
+
+impl DhKeys {
+    pub fn generate() -> Self {
+        Self::from_private(&rand::random::<[u8; PRIVATE_KEY_SIZE]>())
+    }
+
+    /// Key pair for the big endian private key `private`
+    pub fn from_private(private: &[u8]) -> Self {
+        let private = BigUint::from_bytes_be(private);
+        let public = BigUint::from(GENERATOR).modpow(&private, &PRIME);
+
+        DhKeys {
+            public: to_key_bytes(&public),
+            private,
+        }
+    }
+
+    pub fn public_key(&self) -> &[u8; KEY_SIZE] {
+        &self.public
+    }
+
+    /// Secret shared with the owner of `their_public`
+    pub fn shared_secret(&self, their_public: &[u8]) -> Result<[u8; KEY_SIZE], MseErr> {
+        let their_public = BigUint::from_bytes_be(their_public);
+        // 1 and P - 1 would make the secret predictable
+        if their_public <= BigUint::from(1u32) || their_public >= &*PRIME - 1u32 {
+            return Err(MseErr::InvalidPublicKey);
+        }
+
+        Ok(to_key_bytes(&their_public.modpow(&self.private, &PRIME)))
+    }
+}
+
+/// Ciphers of both directions of an RC4 encrypted connection
+#[derive(Debug, Clone)]
+pub struct StreamCipher {
+    pub encrypt: Rc4,
+    pub decrypt: Rc4,
+}
+
+/// Outcome of a completed handshake
+#[derive(Debug)]
+pub struct Negotiated {
+    /// Set when the peer selected RC4 for the rest of the connection
+    pub cipher: Option<StreamCipher>,
+    /// Data the peer sent after the handshake, already decrypted
+    pub remaining: Vec<u8>,
+}
+
+/// What to do after feeding the initiator data from the peer
+#[derive(Debug, Default)]
+pub struct InitiatorStep {
+    /// Bytes to send to the peer
+    pub send: Vec<u8>,
+    /// Set once the handshake is complete
+    pub negotiated: Option<Negotiated>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum InitiatorState {
+    AwaitingKey,
+    AwaitingVc,
+    AwaitingSelect,
+    AwaitingPad { crypto: u32, length: usize },
+    Done,
+}
+
+/// State machine of the connecting side of the handshake. It is fed the
+/// bytes received from the peer and returns the bytes to answer with.
+#[derive(Debug)]
+pub struct Initiator {
+    keys: DhKeys,
+    info_hash: [u8; 20],
+    crypto_provide: u32,
+    state: InitiatorState,
+    buffer: Vec<u8>,
+    cipher: Option<StreamCipher>,
+}
+
+impl Initiator {
+    /// Starts a handshake for the torrent `info_hash` offering the crypto
+    /// methods set in `crypto_provide`
+    pub fn new(keys: DhKeys, info_hash: [u8; 20], crypto_provide: u32) -> Self {
+        Initiator {
+            keys,
+            info_hash,
+            crypto_provide,
+            state: InitiatorState::AwaitingKey,
+            buffer: Vec::new(),
+            cipher: None,
+        }
+    }
+
+    /// First message, our public key followed by random padding
+    pub fn start(&self) -> Vec<u8> {
+        let pad_length = rand::random_range(0..=MAX_PAD);
+        let mut message = self.keys.public_key().to_vec();
+        message.extend((0..pad_length).map(|_| rand::random::<u8>()));
+        message
+    }
+
+    pub fn receive(&mut self, data: &[u8]) -> Result<InitiatorStep, MseErr> {
+        self.buffer.extend_from_slice(data);
+        let mut step = InitiatorStep::default();
+
+        loop {
+            match self.state {
+                InitiatorState::AwaitingKey => {
+                    if self.buffer.len() < KEY_SIZE {
+                        break;
+                    }
+                    let their_key: Vec<u8> = self.buffer.drain(..KEY_SIZE).collect();
+                    step.send = self.key_exchanged(&their_key)?;
+                    self.state = InitiatorState::AwaitingVc;
+                }
+                InitiatorState::AwaitingVc => {
+                    let cipher = self.cipher.as_mut().expect("set once keys are exchanged");
+
+                    // The peer's padding is skipped by looking for the
+                    // encrypted VC
+                    let mut expected = VC;
+                    cipher.decrypt.clone().apply(&mut expected);
+                    let Some(position) = self
+                        .buffer
+                        .windows(VC.len())
+                        .position(|window| window == expected)
+                    else {
+                        if self.buffer.len() >= MAX_PAD + VC.len() {
+                            return Err(MseErr::SyncFailed);
+                        }
+                        break;
+                    };
+
+                    let mut vc: Vec<u8> = self.buffer.drain(..position + VC.len()).collect();
+                    cipher.decrypt.apply(&mut vc[position..]);
+                    self.state = InitiatorState::AwaitingSelect;
+                }
+                InitiatorState::AwaitingSelect => {
+                    // crypto_select (4 bytes) and len(padD) (2 bytes)
+                    if self.buffer.len() < 6 {
+                        break;
+                    }
+                    let mut header: Vec<u8> = self.buffer.drain(..6).collect();
+                    let cipher = self.cipher.as_mut().expect("set once keys are exchanged");
+                    cipher.decrypt.apply(&mut header);
+
+                    let crypto = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+                    let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+                    if crypto.count_ones() != 1 || crypto & self.crypto_provide == 0 {
+                        return Err(MseErr::UnsupportedCrypto(crypto));
+                    }
+                    if length > MAX_PAD {
+                        return Err(MseErr::InvalidPadding(length));
+                    }
+                    self.state = InitiatorState::AwaitingPad { crypto, length };
+                }
+                InitiatorState::AwaitingPad { crypto, length } => {
+                    if self.buffer.len() < length {
+                        break;
+                    }
+                    let mut cipher = self.cipher.take().expect("set once keys are exchanged");
+                    let mut pad: Vec<u8> = self.buffer.drain(..length).collect();
+                    cipher.decrypt.apply(&mut pad);
+
+                    let mut remaining = std::mem::take(&mut self.buffer);
+                    let cipher = if crypto == CRYPTO_RC4 {
+                        cipher.decrypt.apply(&mut remaining);
+                        Some(cipher)
+                    } else {
+                        None
+                    };
+
+                    self.state = InitiatorState::Done;
+                    step.negotiated = Some(Negotiated { cipher, remaining });
+                    break;
+                }
+                InitiatorState::Done => break,
+            }
+        }
+
+        Ok(step)
+    }
+
+    /// Derives the ciphers from the peer's public key and returns the
+    /// message proving we know the torrent and offering our crypto methods
+    fn key_exchanged(&mut self, their_key: &[u8]) -> Result<Vec<u8>, MseErr> {
+        let secret = self.keys.shared_secret(their_key)?;
+        let mut encrypt = derive_cipher(b"keyA", &secret, &self.info_hash);
+        let decrypt = derive_cipher(b"keyB", &secret, &self.info_hash);
+
+        let mut message = hash(&[b"req1", &secret]).to_vec();
+        let req2 = hash(&[b"req2", &self.info_hash]);
+        let req3 = hash(&[b"req3", &secret]);
+        message.extend(req2.iter().zip(req3).map(|(a, b)| a ^ b));
+
+        // VC, crypto_provide, len(padC), padC (empty) and len(IA), IA (empty)
+        let mut payload = VC.to_vec();
+        payload.extend_from_slice(&self.crypto_provide.to_be_bytes());
+        payload.extend_from_slice(&0u16.to_be_bytes());
+        payload.extend_from_slice(&0u16.to_be_bytes());
+        encrypt.apply(&mut payload);
+        message.extend(payload);
+
+        self.cipher = Some(StreamCipher { encrypt, decrypt });
+        Ok(message)
+    }
+}
+
+/// Runs the connecting side of the handshake for the torrent `info_hash`
+/// over `stream`, offering plaintext and RC4
+pub async fn handshake_outgoing<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    info_hash: &[u8; 20],
+) -> Result<Negotiated, MseErr> {
+    let mut initiator = Initiator::new(
+        DhKeys::generate(),
+        *info_hash,
+        CRYPTO_PLAINTEXT | CRYPTO_RC4,
+    );
+    stream.write_all(&initiator.start()).await?;
+
+    let mut buf = [0u8; 1024];
+    loop {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+
+        let step = initiator.receive(&buf[..read])?;
+        if !step.send.is_empty() {
+            stream.write_all(&step.send).await?;
+        }
+        if let Some(negotiated) = step.negotiated {
+            return Ok(negotiated);
+        }
+    }
+}
+
+/// Connection to a peer, encrypting and decrypting the data when RC4 was
+/// negotiated
+#[derive(Debug)]
+pub struct PeerStream {
+    stream: Box<dyn PeerTransport>,
+    cipher: Option<StreamCipher>,
+    /// Data received during the handshake that still has to be read
+    pending: Vec<u8>,
+}
+
+impl PeerStream {
+    pub fn plain(stream: Box<dyn PeerTransport>) -> Self {
+        PeerStream {
+            stream,
+            cipher: None,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn negotiated(stream: Box<dyn PeerTransport>, negotiated: Negotiated) -> Self {
+        PeerStream {
+            stream,
+            cipher: negotiated.cipher,
+            pending: negotiated.remaining,
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    pub async fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        match &mut self.cipher {
+            Some(cipher) => {
+                let mut data = data.to_vec();
+                cipher.encrypt.apply(&mut data);
+                self.stream.write_all(&data).await
+            }
+            None => self.stream.write_all(data).await,
+        }
+    }
+
+    /// Closes our side of the connection once everything written is sent
+    pub async fn shutdown(&mut self) -> io::Result<()> {
+        self.stream.shutdown().await
+    }
+}
+
+impl AsyncRead for PeerStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if !this.pending.is_empty() {
+            let length = buf.remaining().min(this.pending.len());
+            buf.put_slice(&this.pending[..length]);
+            this.pending.drain(..length);
+            return Poll::Ready(Ok(()));
+        }
+
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.stream).poll_read(cx, buf))?;
+        if let Some(cipher) = &mut this.cipher {
+            cipher.decrypt.apply(&mut buf.filled_mut()[filled..]);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// RC4 cipher keyed with HASH(`label`, `secret`, SKEY) that already
+/// discarded the start of its keystream
+pub fn derive_cipher(label: &[u8], secret: &[u8], info_hash: &[u8; 20]) -> Rc4 {
+    let mut cipher = Rc4::new(&hash(&[label, secret, info_hash]));
+    cipher.apply(&mut [0u8; RC4_DISCARD]);
+    cipher
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+/// Big endian bytes of `value`, left padded to `KEY_SIZE`
+fn to_key_bytes(value: &BigUint) -> [u8; KEY_SIZE] {
+    let bytes = value.to_bytes_be();
+    let mut key = [0u8; KEY_SIZE];
+    key[KEY_SIZE - bytes.len()..].copy_from_slice(&bytes);
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INFO_HASH: [u8; 20] = [7; 20];
+
+    #[test]
+    fn rc4_matches_known_vectors() {
+        let mut data = *b"Plaintext";
+        Rc4::new(b"Key").apply(&mut data);
+        assert_eq!(data, [0xbb, 0xf3, 0x16, 0xe8, 0xd9, 0x40, 0xaf, 0x0a, 0xd3]);
+
+        let mut data = *b"pedia";
+        Rc4::new(b"Wiki").apply(&mut data);
+        assert_eq!(data, [0x10, 0x21, 0xbf, 0x04, 0x20]);
+    }
+
+    #[test]
+    fn prime_is_the_768_bit_mse_prime() {
+        let bytes = PRIME.to_bytes_be();
+        assert_eq!(bytes.len(), KEY_SIZE);
+        assert_eq!(bytes[..8], [0xff; 8]);
+        assert_eq!(bytes[KEY_SIZE - 3..], [0x09, 0x05, 0x63]);
+        // Fermat test, holds for every prime
+        let one = BigUint::from(1u32);
+        assert_eq!(BigUint::from(2u32).modpow(&(&*PRIME - 1u32), &PRIME), one);
+    }
+
+    #[test]
+    fn dh_public_keys_and_shared_secret() {
+        let a = DhKeys::from_private(&[3]);
+        let b = DhKeys::from_private(&[5]);
+
+        let mut eight = [0u8; KEY_SIZE];
+        eight[KEY_SIZE - 1] = 8;
+        assert_eq!(a.public_key(), &eight);
+
+        // 2^(3 * 5)
+        let mut expected = [0u8; KEY_SIZE];
+        expected[KEY_SIZE - 2..].copy_from_slice(&32768u16.to_be_bytes());
+        assert_eq!(a.shared_secret(b.public_key()).unwrap(), expected);
+        assert_eq!(b.shared_secret(a.public_key()).unwrap(), expected);
+
+        let random = DhKeys::generate();
+        assert_eq!(
+            a.shared_secret(random.public_key()).unwrap(),
+            random.shared_secret(a.public_key()).unwrap()
+        );
+    }
+
+    #[test]
+    fn degenerate_public_keys_rejected() {
+        let keys = DhKeys::generate();
+        let prime_minus_one = to_key_bytes(&(&*PRIME - 1u32));
+
+        assert!(matches!(
+            keys.shared_secret(&[1]),
+            Err(MseErr::InvalidPublicKey)
+        ));
+        assert!(matches!(
+            keys.shared_secret(&prime_minus_one),
+            Err(MseErr::InvalidPublicKey)
+        ));
+    }
+
+    /// Answer of a responder with `keys` selecting `crypto` after `pad_b`
+    /// bytes of padding, followed by `payload`
+    fn responder_reply(
+        keys: &DhKeys,
+        secret: &[u8],
+        crypto: u32,
+        pad_b: usize,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let mut encrypt = derive_cipher(b"keyB", secret, &INFO_HASH);
+
+        let mut reply = keys.public_key().to_vec();
+        reply.extend(vec![0xaa; pad_b]);
+
+        let mut select = VC.to_vec();
+        select.extend_from_slice(&crypto.to_be_bytes());
+        select.extend_from_slice(&2u16.to_be_bytes());
+        select.extend_from_slice(&[0xbb, 0xcc]);
+        encrypt.apply(&mut select);
+        reply.extend(select);
+
+        let mut payload = payload.to_vec();
+        if crypto == CRYPTO_RC4 {
+            encrypt.apply(&mut payload);
+        }
+        reply.extend(payload);
+        reply
+    }
+
+    #[test]
+    fn initiator_negotiates_rc4() {
+        let responder = DhKeys::from_private(&[0x42; 20]);
+        let mut initiator = Initiator::new(
+            DhKeys::from_private(&[0x24; 20]),
+            INFO_HASH,
+            CRYPTO_PLAINTEXT | CRYPTO_RC4,
+        );
+        assert_eq!(&initiator.start()[..KEY_SIZE], initiator.keys.public_key());
+        let secret = responder
+            .shared_secret(initiator.keys.public_key())
+            .unwrap();
+
+        let reply = responder_reply(&responder, &secret, CRYPTO_RC4, 100, b"handshake");
+
+        // Fed in two parts, the second starting within the encrypted VC
+        let step = initiator.receive(&reply[..KEY_SIZE + 104]).unwrap();
+        assert!(step.negotiated.is_none());
+
+        let sent = step.send;
+        assert_eq!(sent[..20], hash(&[b"req1", &secret]));
+        let req2 = hash(&[b"req2", &INFO_HASH]);
+        let req3 = hash(&[b"req3", &secret]);
+        let obfuscated: Vec<u8> = req2.iter().zip(req3).map(|(a, b)| a ^ b).collect();
+        assert_eq!(sent[20..40], obfuscated);
+        let mut payload = sent[40..].to_vec();
+        derive_cipher(b"keyA", &secret, &INFO_HASH).apply(&mut payload);
+        assert_eq!(payload, [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0]);
+
+        let step = initiator.receive(&reply[KEY_SIZE + 104..]).unwrap();
+        assert!(step.send.is_empty());
+        let negotiated = step.negotiated.unwrap();
+        assert_eq!(negotiated.remaining, b"handshake");
+        assert!(negotiated.cipher.is_some());
+    }
+
+    #[test]
+    fn initiator_negotiates_plaintext() {
+        let responder = DhKeys::generate();
+        let mut initiator = Initiator::new(DhKeys::generate(), INFO_HASH, CRYPTO_PLAINTEXT);
+        let secret = responder
+            .shared_secret(initiator.keys.public_key())
+            .unwrap();
+
+        let reply = responder_reply(&responder, &secret, CRYPTO_PLAINTEXT, 0, b"handshake");
+        let negotiated = initiator.receive(&reply).unwrap().negotiated.unwrap();
+
+        assert_eq!(negotiated.remaining, b"handshake");
+        assert!(negotiated.cipher.is_none());
+    }
+
+    #[test]
+    fn initiator_rejects_unoffered_crypto() {
+        let responder = DhKeys::generate();
+        let mut initiator = Initiator::new(DhKeys::generate(), INFO_HASH, CRYPTO_PLAINTEXT);
+        let secret = responder
+            .shared_secret(initiator.keys.public_key())
+            .unwrap();
+
+        let reply = responder_reply(&responder, &secret, CRYPTO_RC4, 0, b"");
+        assert!(matches!(
+            initiator.receive(&reply),
+            Err(MseErr::UnsupportedCrypto(CRYPTO_RC4))
+        ));
+    }
+
+    #[test]
+    fn initiator_gives_up_without_vc() {
+        let responder = DhKeys::generate();
+        let mut initiator = Initiator::new(DhKeys::generate(), INFO_HASH, CRYPTO_RC4);
+
+        let mut reply = responder.public_key().to_vec();
+        reply.extend(vec![0xaa; MAX_PAD + VC.len()]);
+        assert!(matches!(initiator.receive(&reply), Err(MseErr::SyncFailed)));
+    }
+}
@@ -1,18 +1,40 @@
-use std::sync::Arc;
+use std::{
+    collections::{HashSet, VecDeque},
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
 
-use bytes::{BufMut, Bytes, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use log::{warn, Level};
 use thiserror::Error;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
+    io::AsyncReadExt,
+    sync::{broadcast, mpsc, watch, OwnedSemaphorePermit, Semaphore},
+    time::Instant,
 };
 
 use crate::{
-    bencode::{BencodeMap, BencodeMapDecoder},
-    handshake::Handshake,
+    ban_list::{CORRUPT_PIECES_BEFORE_BAN, INVALID_REQUESTS_BEFORE_BAN},
+    bencode::{BencodeMap, BencodeMapDecoder, BencodeMapEncoder, BencodeType},
+    event::TorrentEvent,
+    handshake::{Handshake, HandshakeErr},
     message::{Message, MessageErr, MessageType},
     meta_info::{FromBencodeTypeErr, FromBencodemap},
-    piece_manager::PieceManager,
+    mse::{self, EncryptionMode, MseErr, PeerStream},
+    peer_id::PeerId,
+    pex::{
+        ExtendedHandshake, PexMessage, EXTENDED_HANDSHAKE_ID, LOCAL_PEX_ID, MAX_PEX_PEERS,
+        PEX_INTERVAL,
+    },
+    piece_manager::{
+        is_bit_set, AssembledPiece, BlockOutcome, PieceManager, PieceOutcome, BLOCK_SIZE,
+        MAX_BLOCK_SIZE,
+    },
+    rate_limiter::RateLimits,
+    rate_tracker::TransferRates,
+    transport::{PeerTransport, TransportKind},
 };
 
 // Peer keys
@@ -20,14 +42,78 @@ const PEER_ID_KEY: &str = "peer id";
 const IP_KEY: &str = "ip";
 const PORT_KEY: &str = "port";
 
+/// Size of a single peer in a compact IPv4 peer list
+const COMPACT_V4_SIZE: usize = 6;
+/// Size of a single peer in a compact IPv6 peer list
+const COMPACT_V6_SIZE: usize = 18;
+
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Send a keep alive when nothing else was sent for this long
+pub const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(120);
+/// Disconnect peers that sent nothing, not even a keep alive, for this long
+pub const INACTIVITY_TIMEOUT: Duration = Duration::from_secs(150);
+/// How long a peer that choked us mid-download may take to unchoke us again
+pub const UNCHOKE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Longest message accepted from a peer, enough for a piece message with
+/// the largest block we request
+pub const MAX_MESSAGE_LENGTH: usize = MAX_BLOCK_SIZE + 13;
+/// How often an interested peer choked for lack of an upload slot checks
+/// for a free one
+pub const UNCHOKE_INTERVAL: Duration = Duration::from_secs(10);
+
 #[derive(Debug)]
 pub struct Peer {
     pub peer_id: Option<String>,
-    pub ip: String,
-    pub port: i64,
-    pub socket: Option<TcpStream>,
-    pub my_state: PeerState,
-    pub their_state: PeerState,
+    pub addr: SocketAddr,
+    pub socket: Option<PeerStream>,
+    pub state: PeerState,
+    pub rate_limits: RateLimits,
+    /// Time allowed for connecting and exchanging handshakes
+    pub connect_timeout: Duration,
+    /// Whether connecting uses Message Stream Encryption
+    pub encryption: EncryptionMode,
+    /// Protocol the connection is made over
+    pub transport: TransportKind,
+    /// Size of the blocks pieces are requested in
+    pub block_size: usize,
+    /// Channel events are reported on, tagged with this peer's address
+    pub events: Option<mpsc::Sender<(SocketAddr, PeerEvent)>>,
+    /// Whether both sides set the fast extension bit in their handshakes
+    pub fast_extension: bool,
+    /// Addresses of the torrent's other peers, shared through PEX when set.
+    /// Left unset for private torrents.
+    pub pex: Option<watch::Receiver<HashSet<SocketAddr>>>,
+    /// Events of the torrent, from which the pieces we complete are
+    /// announced to the peer with `Have` messages when set
+    pub torrent_events: Option<broadcast::Receiver<TorrentEvent>>,
+    /// Upload slots shared by the torrent's peers, an interested peer is
+    /// only unchoked while holding one. Unlimited when unset.
+    pub upload_slots: Option<Arc<Semaphore>>,
+    /// Slot held while the peer is unchoked
+    upload_slot: Option<OwnedSemaphorePermit>,
+    /// Pieces the peer has and the one we download from it, shared with the
+    /// guard giving them back to the piece manager
+    pieces: Arc<std::sync::Mutex<PeerPieces>>,
+    /// Bytes received from the peer that don't make up a whole message yet
+    read_buf: BytesMut,
+    /// Messages decoded from the read buffer that weren't handled yet
+    received: VecDeque<Message>,
+    /// Message already read and applied to our state, returned by the next
+    /// read, e.g. one a peer without pieces sent instead of its bitfield
+    read_ahead: Option<Message>,
+    /// Id the peer wants `ut_pex` messages sent with
+    their_pex_id: Option<u8>,
+    /// Addresses the peer knows about from our previous `ut_pex` messages
+    pex_sent: HashSet<SocketAddr>,
+    next_pex: Instant,
+    /// Throughput of blocks to and from the peer
+    rates: TransferRates,
+    /// Pieces from this connection that failed verification
+    corrupt_pieces: u32,
+    /// Requests from this connection for blocks we can't serve
+    invalid_requests: u32,
+    last_sent: Instant,
+    last_received: Instant,
 }
 
 #[derive(Debug, Clone)]
@@ -38,15 +124,89 @@ pub enum PeerEvent {
     HandshakeSent(Handshake),
     MessageReceived(Message),
     MessageSent(Message),
+    /// Addresses of other peers learned through PEX
+    PeersDiscovered(Vec<SocketAddr>),
 }
 
-#[derive(Debug, Clone)]
-pub enum PeerState {
-    Disconnected,
-    Choked,
-    Interested,
-    Downloading,
-    Idle,
+/// Pieces a connection registered with the piece manager
+#[derive(Debug, Default)]
+struct PeerPieces {
+    /// Pieces of the peer counted as available, from its bitfield and the
+    /// `Have` messages since
+    bitfield: Bytes,
+    /// Piece handed out to us by `get_next_piece`
+    downloading: Option<usize>,
+}
+
+/// Gives the pieces a connection registered back to the piece manager when
+/// dropped, which also happens when the task running it is aborted
+struct RegisteredPieces<'a> {
+    piece_manager: &'a PieceManager,
+    pieces: Arc<std::sync::Mutex<PeerPieces>>,
+}
+
+impl Drop for RegisteredPieces<'_> {
+    fn drop(&mut self) {
+        let pieces = std::mem::take(&mut *self.pieces.lock().unwrap());
+        self.piece_manager.remove_available(&pieces.bitfield);
+        if let Some(index) = pieces.downloading {
+            self.piece_manager.cancel_piece(&index);
+        }
+    }
+}
+
+/// Choke and interest flags of both sides of a connection. Connections
+/// start out choked and not interested both ways.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerState {
+    /// We won't answer the peer's requests
+    pub am_choking: bool,
+    /// The peer has pieces we want
+    pub am_interested: bool,
+    /// The peer won't answer our requests
+    pub peer_choking: bool,
+    /// We have pieces the peer wants
+    pub peer_interested: bool,
+}
+
+impl Default for PeerState {
+    fn default() -> Self {
+        PeerState {
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+        }
+    }
+}
+
+impl PeerState {
+    /// Whether blocks may be requested from the peer
+    pub fn can_request(&self) -> bool {
+        self.am_interested && !self.peer_choking
+    }
+
+    /// Updates the flags changed by a message the peer sent us
+    pub fn received(&mut self, message: &Message) {
+        match message.id {
+            Some(id) if id == MessageType::Choke as u8 => self.peer_choking = true,
+            Some(id) if id == MessageType::Unchoke as u8 => self.peer_choking = false,
+            Some(id) if id == MessageType::Interested as u8 => self.peer_interested = true,
+            Some(id) if id == MessageType::NotInterested as u8 => self.peer_interested = false,
+            _ => {}
+        }
+    }
+
+    /// Updates the flags changed by a message we sent the peer
+    pub fn sent(&mut self, message: &Message) {
+        match message.id {
+            Some(id) if id == MessageType::Choke as u8 => self.am_choking = true,
+            Some(id) if id == MessageType::Unchoke as u8 => self.am_choking = false,
+            Some(id) if id == MessageType::Interested as u8 => self.am_interested = true,
+            Some(id) if id == MessageType::NotInterested as u8 => self.am_interested = false,
+            _ => {}
+        }
+    }
 }
 
 impl FromBencodemap for Peer {
@@ -59,10 +219,16 @@ impl FromBencodemap for Peer {
 
         // Safe unwraps because we checked the values exist in the map above
         let peer_id: Option<String> = bencode_map.get_decode(PEER_ID_KEY);
-        let ip: String = bencode_map.get_decode(IP_KEY).unwrap();
+        let ip: Vec<u8> = bencode_map.get_decode(IP_KEY).unwrap();
         let port: i64 = bencode_map.get_decode(PORT_KEY).unwrap();
 
-        Ok(Peer::new(peer_id, ip, port))
+        let ip = parse_peer_ip(&ip).ok_or_else(|| {
+            FromBencodeTypeErr::InvalidValue(format!("peer ip {}", String::from_utf8_lossy(&ip)))
+        })?;
+        let port = u16::try_from(port)
+            .map_err(|_| FromBencodeTypeErr::InvalidValue(format!("peer port {port}")))?;
+
+        Ok(Peer::new(peer_id, SocketAddr::new(ip, port)))
     }
 
     fn is_valid_bencodemap(bencode_map: &BencodeMap) -> bool {
@@ -70,6 +236,68 @@ impl FromBencodemap for Peer {
     }
 }
 
+/// Dictionary model entry of the peer, as trackers send in `peers`. The
+/// `peer id` key is left out when the ID is unknown.
+impl From<&Peer> for BencodeMap {
+    fn from(peer: &Peer) -> Self {
+        let mut map = BencodeMap::new();
+        if let Some(peer_id) = &peer.peer_id {
+            map.insert(
+                PEER_ID_KEY.into(),
+                BencodeType::String(peer_id.as_bytes().to_vec()),
+            );
+        }
+        map.insert(
+            IP_KEY.into(),
+            BencodeType::String(peer.addr.ip().to_string().into_bytes()),
+        );
+        map.insert(
+            PORT_KEY.into(),
+            BencodeType::Integer(peer.addr.port().into()),
+        );
+        map
+    }
+}
+
+impl From<Peer> for BencodeMap {
+    fn from(peer: Peer) -> Self {
+        BencodeMap::from(&peer)
+    }
+}
+
+/// Parses the `ip` of a dictionary model peer. Trackers may send IPv4 or
+/// IPv6 literals, or the 4 or 16 raw bytes of the address. Literals are
+/// tried first, as some of them are 4 or 16 characters long.
+fn parse_peer_ip(ip: &[u8]) -> Option<IpAddr> {
+    if let Some(ip) = std::str::from_utf8(ip).ok().and_then(|ip| ip.parse().ok()) {
+        return Some(ip);
+    }
+
+    match ip.len() {
+        4 => Some(Ipv4Addr::from(<[u8; 4]>::try_from(ip).unwrap()).into()),
+        16 => Some(Ipv6Addr::from(<[u8; 16]>::try_from(ip).unwrap()).into()),
+        _ => None,
+    }
+}
+
+/// Resolves to the index of the next piece completed according to `events`,
+/// or never if there are none
+async fn next_completed_piece(events: &mut Option<broadcast::Receiver<TorrentEvent>>) -> usize {
+    let Some(receiver) = events else {
+        return std::future::pending().await;
+    };
+
+    loop {
+        match receiver.recv().await {
+            Ok(TorrentEvent::PieceCompleted(index)) => return index,
+            // Pieces missed by lagging behind are not announced, peers
+            // learn about them when reconnecting
+            Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+            Err(broadcast::error::RecvError::Closed) => return std::future::pending().await,
+        }
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum ConnectionErr {
     #[error("Tokio write error: {0}")]
@@ -78,152 +306,808 @@ pub enum ConnectionErr {
     TokioReadError(std::io::Error),
     #[error("Tokio connect error: {0}")]
     TokioConnectError(std::io::Error),
+    #[error("Timed out connecting to peer")]
+    ConnectTimeout,
+    #[error("Timed out waiting for the peer's handshake")]
+    HandshakeTimeout,
+    #[error("Peer was silent for too long")]
+    Inactive,
+    #[error("Peer sent {0} pieces that failed verification")]
+    CorruptPieces(u32),
+    #[error("Peer sent {0} requests for blocks we can't serve")]
+    InvalidRequests(u32),
+    #[error("Peer kept us choked for too long")]
+    ChokeTimeout,
+    #[error("Blocks may only be requested while interested and unchoked")]
+    RequestNotAllowed,
     #[error("Invalid connection")]
     InvalidConnection,
     #[error("Invalid handshake")]
     InvalidHandshake,
+    #[error("Encryption handshake failed: {0}")]
+    EncryptionFailed(#[from] MseErr),
     #[error("Invalid message {0}")]
     InvalidMessage(#[from] MessageErr),
     #[error("Unexpected message: {0}")]
     UnexpectedMessage(String),
+    #[error("Invalid bitfield: {0}")]
+    InvalidBitfield(String),
     #[error("Unexpected IO error {0}")]
     UnexpectedIoError(#[from] std::io::Error),
 }
 
+impl From<HandshakeErr> for ConnectionErr {
+    fn from(err: HandshakeErr) -> Self {
+        match err {
+            HandshakeErr::Timeout => ConnectionErr::HandshakeTimeout,
+            HandshakeErr::ReadFailed(err) => ConnectionErr::TokioReadError(err),
+            HandshakeErr::InvalidSize | HandshakeErr::InvalidProtocol => {
+                ConnectionErr::InvalidHandshake
+            }
+        }
+    }
+}
+
+impl ConnectionErr {
+    /// Whether the error happened while connecting or exchanging handshakes,
+    /// as opposed to after the connection was established
+    pub fn is_connect_failure(&self) -> bool {
+        matches!(
+            self,
+            ConnectionErr::TokioConnectError(_)
+                | ConnectionErr::TokioWriteError(_)
+                | ConnectionErr::TokioReadError(_)
+                | ConnectionErr::ConnectTimeout
+                | ConnectionErr::HandshakeTimeout
+                | ConnectionErr::InvalidHandshake
+                | ConnectionErr::EncryptionFailed(_)
+        )
+    }
+
+    /// Whether the peer closed or reset the connection
+    fn is_closed_by_peer(&self) -> bool {
+        let (ConnectionErr::InvalidMessage(MessageErr::IoError(err))
+        | ConnectionErr::UnexpectedIoError(err)) = self
+        else {
+            return false;
+        };
+        matches!(
+            err.kind(),
+            io::ErrorKind::UnexpectedEof
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::BrokenPipe
+        )
+    }
+
+    /// Whether the peer accepted the connection but hung up on our
+    /// handshake, which may mean it only accepts the other kind
+    fn is_handshake_refused(&self) -> bool {
+        matches!(
+            self,
+            ConnectionErr::TokioWriteError(_)
+                | ConnectionErr::TokioReadError(_)
+                | ConnectionErr::EncryptionFailed(_)
+        )
+    }
+}
+
 impl Peer {
-    pub fn new(peer_id: Option<String>, ip: String, port: i64) -> Self {
+    pub fn new(peer_id: Option<String>, addr: SocketAddr) -> Self {
         Peer {
             peer_id,
-            ip,
-            port,
+            addr,
             socket: None,
-            my_state: PeerState::Disconnected,
-            their_state: PeerState::Disconnected,
+            state: PeerState::default(),
+            rate_limits: RateLimits::default(),
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            encryption: EncryptionMode::default(),
+            transport: TransportKind::default(),
+            block_size: BLOCK_SIZE,
+            events: None,
+            fast_extension: false,
+            pex: None,
+            torrent_events: None,
+            upload_slots: None,
+            upload_slot: None,
+            pieces: Arc::default(),
+            read_buf: BytesMut::new(),
+            received: VecDeque::new(),
+            read_ahead: None,
+            their_pex_id: None,
+            pex_sent: HashSet::new(),
+            next_pex: Instant::now(),
+            rates: TransferRates::default(),
+            corrupt_pieces: 0,
+            invalid_requests: 0,
+            last_sent: Instant::now(),
+            last_received: Instant::now(),
         }
     }
 
-    pub fn from_bencodemap_list(
-        bencode_map: &Vec<BencodeMap>,
-    ) -> Result<Vec<Self>, FromBencodeTypeErr> {
+    /// Decodes a dictionary model `peers` list. Malformed entries are
+    /// logged and skipped so one bad peer doesn't discard the others.
+    pub fn from_bencodemap_list(bencode_map: &[BencodeMap]) -> Vec<Self> {
         bencode_map
             .iter()
-            .map(|x| Peer::from_bencodemap(x))
+            .filter_map(|map| match Peer::from_bencodemap(map) {
+                Ok(peer) => Some(peer),
+                Err(err) => {
+                    warn!("Skipping malformed peer entry: {err}");
+                    None
+                }
+            })
             .collect()
     }
 
+    /// Decodes a compact `peers` string, 4 bytes of IPv4 address followed by
+    /// a 2 byte port per peer, all in network byte order (BEP-23)
+    pub fn from_compact_v4(bytes: &[u8]) -> Result<Vec<Self>, FromBencodeTypeErr> {
+        if !bytes.len().is_multiple_of(COMPACT_V4_SIZE) {
+            return Err(FromBencodeTypeErr::InvalidValue(String::from(
+                "Compact peers length is not a multiple of 6",
+            )));
+        }
+
+        Ok(bytes
+            .chunks_exact(COMPACT_V4_SIZE)
+            .map(|chunk| {
+                let ip: [u8; 4] = chunk[..4].try_into().unwrap();
+                let port = u16::from_be_bytes([chunk[4], chunk[5]]);
+                Peer::new(None, SocketAddr::new(Ipv4Addr::from(ip).into(), port))
+            })
+            .collect())
+    }
+
+    /// Decodes a compact `peers6` string, 16 bytes of IPv6 address followed
+    /// by a 2 byte port per peer, all in network byte order (BEP-7)
+    pub fn from_compact_v6(bytes: &[u8]) -> Result<Vec<Self>, FromBencodeTypeErr> {
+        if !bytes.len().is_multiple_of(COMPACT_V6_SIZE) {
+            return Err(FromBencodeTypeErr::InvalidValue(String::from(
+                "Compact peers6 length is not a multiple of 18",
+            )));
+        }
+
+        Ok(bytes
+            .chunks_exact(COMPACT_V6_SIZE)
+            .map(|chunk| {
+                let ip: [u8; 16] = chunk[..16].try_into().unwrap();
+                let port = u16::from_be_bytes([chunk[16], chunk[17]]);
+                Peer::new(None, SocketAddr::new(Ipv6Addr::from(ip).into(), port))
+            })
+            .collect())
+    }
+
+    /// Bytes per second of blocks received from the peer over the last few seconds
+    pub fn download_rate(&self) -> u64 {
+        self.rates.download.rate()
+    }
+
+    /// Bytes per second of blocks sent to the peer over the last few seconds
+    pub fn upload_rate(&self) -> u64 {
+        self.rates.upload.rate()
+    }
+
     pub async fn start(
         &mut self,
         piece_manager: &PieceManager,
         torrent_hash: Arc<[u8; 20]>,
+        peer_id: &PeerId,
     ) -> Result<(), ConnectionErr> {
-        self.connect(&Handshake::new(*torrent_hash, [0u8; 20]))
-            .await?;
-        self.log("Connected to peer");
+        let handshake = self.handshake(&torrent_hash, peer_id);
+        let connected = self.connect(&handshake).await;
+        self.run(piece_manager, connected).await
+    }
+
+    /// Like `start`, but over a stream that is already open, such as an
+    /// in-memory one. Handshakes are exchanged in plaintext.
+    pub async fn start_over(
+        &mut self,
+        stream: impl PeerTransport + 'static,
+        piece_manager: &PieceManager,
+        torrent_hash: Arc<[u8; 20]>,
+        peer_id: &PeerId,
+    ) -> Result<(), ConnectionErr> {
+        let handshake = self.handshake(&torrent_hash, peer_id);
+        let connected = self.connect_over(stream, &handshake).await;
+        self.run(piece_manager, connected).await
+    }
+
+    /// Like `start_over`, for a peer that connected to us over `stream` and
+    /// sent `their_handshake`, which was read already
+    pub async fn start_incoming(
+        &mut self,
+        stream: impl PeerTransport + 'static,
+        their_handshake: Handshake,
+        piece_manager: &PieceManager,
+        torrent_hash: Arc<[u8; 20]>,
+        peer_id: &PeerId,
+    ) -> Result<(), ConnectionErr> {
+        let handshake = self.handshake(&torrent_hash, peer_id);
+        let accepted = tokio::time::timeout(
+            self.connect_timeout,
+            self.accept_over(stream, their_handshake, &handshake),
+        )
+        .await
+        .unwrap_or(Err(ConnectionErr::ConnectTimeout));
+        self.run(piece_manager, accepted).await
+    }
+
+    /// Our handshake, advertising the extensions this peer may use
+    fn handshake(&self, torrent_hash: &[u8; 20], peer_id: &PeerId) -> Handshake {
+        let handshake = Handshake::new(*torrent_hash, *peer_id.as_bytes()).with_fast_extension();
+        if self.pex.is_some() {
+            handshake.with_extension_protocol()
+        } else {
+            handshake
+        }
+    }
+
+    /// Downloads from and seeds to the peer once `connected`, then disconnects
+    async fn run(
+        &mut self,
+        piece_manager: &PieceManager,
+        connected: Result<Handshake, ConnectionErr>,
+    ) -> Result<(), ConnectionErr> {
+        let their_handshake = match connected {
+            Ok(handshake) => handshake,
+            Err(err) => {
+                self.log(Level::Error, &format!("Failed to connect: {err}"));
+                return Err(err);
+            }
+        };
+        self.log(
+            Level::Info,
+            &format!(
+                "Connected to peer {}",
+                String::from_utf8_lossy(&their_handshake.peer_id)
+            ),
+        );
+
+        let mut result = Ok(());
+        if self.pex.is_some() && their_handshake.supports_extension_protocol() {
+            result = self.send_extended_handshake().await;
+        }
+        if result.is_ok() {
+            result = self.download(piece_manager).await;
+        }
+
+        if result.is_err() {
+            // Nobody is left to say goodbye to on a broken connection
+            self.socket = None;
+        }
+        self.disconnect().await;
+
+        result
+    }
+
+    /// Tells the peer we are no longer interested and chokes it if we were
+    /// serving it, then closes the connection
+    pub async fn disconnect(&mut self) {
+        if self.socket.is_some() {
+            let mut goodbye = Vec::new();
+            if self.state.am_interested {
+                goodbye.push(Message::new(
+                    1,
+                    Some(MessageType::NotInterested as u8),
+                    None,
+                ));
+            }
+            if !self.state.am_choking {
+                goodbye.push(Message::new(1, Some(MessageType::Choke as u8), None));
+            }
+
+            for message in goodbye {
+                if let Err(err) = self.write_message(&message).await {
+                    self.log(Level::Debug, &format!("Failed to say goodbye: {err}"));
+                    break;
+                }
+            }
+            if let Some(mut socket) = self.socket.take() {
+                let _ = socket.shutdown().await;
+            }
+        }
+
+        self.state = PeerState::default();
+        self.upload_slot = None;
+        *self.pieces.lock().unwrap() = PeerPieces::default();
+        self.read_buf.clear();
+        self.received.clear();
+        self.read_ahead = None;
+        self.emit(PeerEvent::Disconnected).await;
+    }
 
+    /// Downloads the pieces we need from the connected peer and seeds to it
+    /// while it has none, until either side hangs up. The piece being
+    /// downloaded is re-queued if the connection fails or the task running
+    /// it is aborted.
+    async fn download(&mut self, piece_manager: &PieceManager) -> Result<(), ConnectionErr> {
         let bitfield = piece_manager.get_bitfield();
 
-        self.log("Sending bitfield!");
-        let their_bitfield = self.send_bitfield(&bitfield).await?;
-        self.log("Bitfield received!");
+        self.log(Level::Trace, "Sending bitfield");
+        let their_bitfield = self
+            .send_bitfield(&bitfield, piece_manager.get_piece_count())
+            .await?;
+        self.log(Level::Trace, "Bitfield received");
 
-        let piece_length = piece_manager.get_piece_length();
+        piece_manager.add_available(&their_bitfield);
+        *self.pieces.lock().unwrap() = PeerPieces {
+            bitfield: their_bitfield,
+            downloading: None,
+        };
+        let _registered = RegisteredPieces {
+            piece_manager,
+            pieces: self.pieces.clone(),
+        };
+        loop {
+            if !piece_manager.is_complete() {
+                self.download_pieces(piece_manager).await?;
+            }
+            match self.seed(piece_manager).await {
+                Ok(true) => {}
+                Ok(false) => return Ok(()),
+                // Leechers hang up once they have what they wanted
+                Err(err) if err.is_closed_by_peer() => {
+                    self.log(Level::Debug, "Peer closed the connection");
+                    self.socket = None;
+                    return Ok(());
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
 
-        while let Some(index) = piece_manager.get_next_piece(&their_bitfield) {
-            self.log(&format!("Attempting to download piece {index}"));
-            if matches!(self.my_state, PeerState::Choked) {
-                self.log("Peer is chocking us, sending interested");
-                self.send_interested().await?;
+    /// Serves the peer's requests while it has no piece we need, unchoking
+    /// it while it is interested and holds an upload slot. Returns `true`
+    /// once the peer announces a piece we don't have, or `false` once both
+    /// sides are seeds.
+    async fn seed(&mut self, piece_manager: &PieceManager) -> Result<bool, ConnectionErr> {
+        if self.state.am_interested {
+            let message = Message::new(1, Some(MessageType::NotInterested as u8), None);
+            self.write_message(&message).await?;
+        }
 
-                self.my_state = PeerState::Interested;
+        loop {
+            if piece_manager.is_complete() && self.peer_is_seed(piece_manager) {
+                self.log(Level::Debug, "Peer and we are both seeds, disconnecting");
+                return Ok(false);
             }
 
-            let result = self.download_piece(index, piece_length as u64).await?;
-            if piece_manager.add_piece(&index, result).await {
-                self.log(&format!(
-                    "Piece {index} successfully downloaded and verified!"
-                ));
+            let waiting_for_slot = !self.update_choke().await?;
+            let read = if waiting_for_slot {
+                self.read_message_until(tokio::time::sleep(UNCHOKE_INTERVAL))
+                    .await?
             } else {
-                self.log(&format!("Piece {index} download failed!"));
+                Some(self.read_message().await?)
+            };
+            let Some(message) = read else {
+                continue;
+            };
+
+            match message.id {
+                Some(id) if id == MessageType::Request as u8 => {
+                    self.serve_request(piece_manager, &message).await?;
+                }
+                Some(id) if id == MessageType::Have as u8 => {
+                    let index = self.received_have(piece_manager, &message)?;
+                    if !piece_manager.has_piece(index) {
+                        return Ok(true);
+                    }
+                }
+                // Choke and interest were applied to our state when read,
+                // and blocks of cancelled requests may still arrive
+                _ => {}
             }
         }
+    }
 
-        Ok(())
+    /// Unchokes the peer once it is interested and an upload slot is free,
+    /// and chokes it again when it loses interest. Returns `false` while an
+    /// interested peer waits for a slot.
+    async fn update_choke(&mut self) -> Result<bool, ConnectionErr> {
+        if self.state.peer_interested && self.state.am_choking {
+            if let Some(slots) = &self.upload_slots {
+                let Ok(slot) = slots.clone().try_acquire_owned() else {
+                    return Ok(false);
+                };
+                self.upload_slot = Some(slot);
+            }
+            self.log(Level::Trace, "Unchoking peer");
+            let message = Message::new(1, Some(MessageType::Unchoke as u8), None);
+            self.write_message(&message).await?;
+        } else if !self.state.peer_interested && !self.state.am_choking {
+            self.log(Level::Trace, "Choking peer that lost interest");
+            let message = Message::new(1, Some(MessageType::Choke as u8), None);
+            self.write_message(&message).await?;
+            self.upload_slot = None;
+        }
+
+        Ok(true)
     }
 
-    pub async fn send_interested(&mut self) -> Result<(), ConnectionErr> {
-        let message = Message::new(1, Some(MessageType::Interested as u8), None);
+    /// Whether the peer has every piece of the torrent
+    fn peer_is_seed(&self, piece_manager: &PieceManager) -> bool {
+        let pieces = self.pieces.lock().unwrap();
+        (0..piece_manager.get_piece_count()).all(|index| is_bit_set(&pieces.bitfield, index))
+    }
 
-        self.log("Sending interested message");
+    /// Downloads the pieces the picker chooses among those the peer has
+    async fn download_pieces(&mut self, piece_manager: &PieceManager) -> Result<(), ConnectionErr> {
+        loop {
+            let their_bitfield = self.pieces.lock().unwrap().bitfield.clone();
+            let Some(index) = piece_manager.get_next_piece(&their_bitfield) else {
+                return Ok(());
+            };
+            self.pieces.lock().unwrap().downloading = Some(index);
+            self.log(
+                Level::Debug,
+                &format!("Attempting to download piece {index}"),
+            );
+            let result = match self.request_piece(piece_manager, index).await {
+                Ok(result) => result,
+                Err(err) => {
+                    self.log(
+                        Level::Debug,
+                        &format!("Connection failed during piece {index}, re-queuing it"),
+                    );
+                    self.cancel_piece(piece_manager, index);
+                    return Err(err);
+                }
+            };
+            let Some(result) = result else {
+                self.log(
+                    Level::Debug,
+                    &format!("Piece {index} was completed by another peer or re-queued"),
+                );
+                // A piece another holder sent corrupt may still miss the
+                // blocks we sent before, so give it up unless already done
+                let downloading = self.pieces.lock().unwrap().downloading;
+                if downloading == Some(index) {
+                    self.cancel_piece(piece_manager, index);
+                }
+                continue;
+            };
+            let only_sender = result.senders.iter().all(|sender| *sender == self.addr);
+            let outcome = piece_manager.add_piece(&index, result.data).await;
+            // Added or re-queued by `add_piece` either way
+            self.pieces.lock().unwrap().downloading = None;
+            match outcome {
+                PieceOutcome::Added => self.log(
+                    Level::Debug,
+                    &format!("Piece {index} successfully downloaded and verified"),
+                ),
+                PieceOutcome::OutOfRange => {
+                    self.log(Level::Warn, &format!("Piece {index} is out of range"))
+                }
+                // Any of the peers that sent blocks may have corrupted it
+                PieceOutcome::HashMismatch if !only_sender => self.log(
+                    Level::Warn,
+                    &format!("Piece {index} from several peers failed verification"),
+                ),
+                PieceOutcome::HashMismatch => {
+                    self.log(Level::Warn, &format!("Piece {index} failed verification"));
+                    self.corrupt_pieces += 1;
+                    if self.corrupt_pieces >= CORRUPT_PIECES_BEFORE_BAN {
+                        return Err(ConnectionErr::CorruptPieces(self.corrupt_pieces));
+                    }
+                }
+            }
+        }
+    }
 
-        let res = self.send_message(&message).await?;
+    /// Gives up piece `index` so it is requested again, also from other peers
+    fn cancel_piece(&self, piece_manager: &PieceManager, index: usize) {
+        let mut pieces = self.pieces.lock().unwrap();
+        if pieces.downloading == Some(index) {
+            pieces.downloading = None;
+        }
+        piece_manager.cancel_piece(&index);
+    }
 
-        if res.id != Some(MessageType::Unchoke as u8) {
-            return Err(ConnectionErr::UnexpectedMessage(
-                "Expected unchoke message".to_string(),
-            ));
+    /// Makes sure we are interested and unchoked, then downloads piece `index`
+    async fn request_piece(
+        &mut self,
+        piece_manager: &PieceManager,
+        index: usize,
+    ) -> Result<Option<AssembledPiece>, ConnectionErr> {
+        if !self.state.am_interested {
+            self.send_interested().await?;
+        }
+        if self.state.peer_choking {
+            self.log(Level::Debug, "Peer is choking us, waiting for unchoke");
+            self.wait_for_unchoke(piece_manager).await?;
         }
 
-        self.log("Recieved unchoke message");
+        let piece_length = piece_manager.get_piece_size(index);
+        self.download_piece(piece_manager, index, piece_length as u64)
+            .await
+    }
+
+    /// Tells the peer we want its pieces, which it may answer by unchoking us
+    pub async fn send_interested(&mut self) -> Result<(), ConnectionErr> {
+        let message = Message::new(1, Some(MessageType::Interested as u8), None);
 
-        Ok(())
+        self.log(Level::Trace, "Sending interested message");
+
+        self.write_message(&message).await
     }
 
+    /// Requests every block of a piece and assembles it with the blocks other
+    /// peers sent. Returns `None` after cancelling the outstanding request if
+    /// another peer completes the piece first, which happens in endgame mode.
+    /// Also returns `None` if the peer chokes us, after re-queuing the piece
+    /// and waiting to be unchoked.
+    /// Fails with `RequestNotAllowed` unless we are interested and unchoked.
     pub async fn download_piece(
         &mut self,
+        piece_manager: &PieceManager,
         piece_index: usize,
         piece_length: u64,
-    ) -> Result<Bytes, ConnectionErr> {
+    ) -> Result<Option<AssembledPiece>, ConnectionErr> {
+        if !self.state.can_request() {
+            return Err(ConnectionErr::RequestNotAllowed);
+        }
+
         // Send request for piece
-        const MAX_BLOCK_SIZE: usize = (2 as usize).pow(14);
-        let num_blocks = (piece_length as usize + MAX_BLOCK_SIZE - 1) / MAX_BLOCK_SIZE;
-        let mut piece_buffer = BytesMut::with_capacity(piece_length as usize);
+        let num_blocks = (piece_length as usize).div_ceil(self.block_size);
         let mut remaining = piece_length as usize;
 
-        self.log(&format!(
-            "Downloading piece {piece_index} with {num_blocks} blocks"
-        ));
+        self.log(
+            Level::Debug,
+            &format!("Downloading piece {piece_index} with {num_blocks} blocks"),
+        );
         for block_index in 0..num_blocks {
-            let offset = block_index * MAX_BLOCK_SIZE;
-            let block_size = MAX_BLOCK_SIZE.min(remaining);
-            remaining = remaining - block_size;
-
-            let mut buf = BytesMut::with_capacity(12);
-            buf.put_u32(piece_index as u32); // index
-            buf.put_u32(offset as u32); // begin
-            buf.put_u32(block_size as u32); // length
-
-            let message = Message {
-                length: 13,
-                id: Some(MessageType::Request as u8),
-                payload: Some(buf.freeze()),
-            };
+            let offset = block_index * self.block_size;
+            let block_size = self.block_size.min(remaining);
+            remaining -= block_size;
 
-            self.log("Sending request message");
+            self.rate_limits.download.acquire(block_size).await;
 
-            let res = self.send_message(&message).await?;
+            let message = Message::request(piece_index as u32, offset as u32, block_size as u32);
 
-            if res.id != Some(MessageType::Piece as u8) {
-                return Err(ConnectionErr::UnexpectedMessage(
-                    "Expected piece message".to_string(),
-                ));
-            }
+            self.log(Level::Trace, "Sending request message");
+
+            self.write_message(&message).await?;
+
+            loop {
+                let Some(res) = self
+                    .read_message_until(piece_manager.wait_for_piece(piece_index))
+                    .await?
+                else {
+                    self.log(
+                        Level::Debug,
+                        &format!("Cancelling block {block_index} of piece {piece_index}"),
+                    );
+                    let cancel =
+                        Message::cancel(piece_index as u32, offset as u32, block_size as u32);
+                    self.write_message(&cancel).await?;
+                    return Ok(None);
+                };
+
+                // Requests are dropped once choked, so start the piece over later
+                if res.id == Some(MessageType::Choke as u8) {
+                    self.log(
+                        Level::Debug,
+                        &format!("Choked during piece {piece_index}, re-queuing it"),
+                    );
+                    self.cancel_piece(piece_manager, piece_index);
+                    self.wait_for_unchoke(piece_manager).await?;
+                    return Ok(None);
+                }
+
+                if self.fast_extension && res.id == Some(MessageType::RejectRequest as u8) {
+                    return Err(ConnectionErr::UnexpectedMessage(format!(
+                        "Peer rejected request for piece {piece_index}"
+                    )));
+                }
+
+                if res.id == Some(MessageType::Request as u8) {
+                    self.serve_request(piece_manager, &res).await?;
+                    continue;
+                }
+
+                if res.id == Some(MessageType::Have as u8) {
+                    self.received_have(piece_manager, &res)?;
+                    continue;
+                }
+
+                // Already applied to our state when they were read
+                if res.id == Some(MessageType::Interested as u8)
+                    || res.id == Some(MessageType::NotInterested as u8)
+                    || res.id == Some(MessageType::Unchoke as u8)
+                {
+                    continue;
+                }
+
+                // Hints from the fast extension are not acted upon yet
+                if self.fast_extension
+                    && (res.id == Some(MessageType::SuggestPiece as u8)
+                        || res.id == Some(MessageType::AllowedFast as u8))
+                {
+                    continue;
+                }
+
+                if res.id != Some(MessageType::Piece as u8) {
+                    return Err(ConnectionErr::UnexpectedMessage(
+                        "Expected piece message".to_string(),
+                    ));
+                }
+
+                let Some(payload) = res.payload else {
+                    break;
+                };
+
+                // Blocks of earlier cancelled requests may still arrive
+                if payload.len() < 8
+                    || payload[..4] != (piece_index as u32).to_be_bytes()
+                    || payload[4..8] != (offset as u32).to_be_bytes()
+                {
+                    self.log(Level::Trace, "Ignoring block that was not requested");
+                    continue;
+                }
 
-            if let Some(payload) = res.payload {
                 // Skip the first 8 bytes (piece index and offset)
-                piece_buffer.extend_from_slice(&payload[8..]);
+                let block = &payload[8..];
+                self.rates.download.record(block.len() as u64);
+                match piece_manager.add_block(piece_index, offset, block, self.addr) {
+                    BlockOutcome::Stored => {}
+                    BlockOutcome::Completed(piece) => {
+                        self.log(Level::Debug, &format!("Piece {piece_index} received"));
+                        return Ok(Some(piece));
+                    }
+                    BlockOutcome::Duplicate => self.log(
+                        Level::Trace,
+                        &format!("Block {block_index} was already received from another peer"),
+                    ),
+                    BlockOutcome::PieceDownloaded => return Ok(None),
+                    BlockOutcome::OutOfRange => {
+                        return Err(ConnectionErr::UnexpectedMessage(format!(
+                            "Block does not fit in piece {piece_index}"
+                        )))
+                    }
+                }
+                break;
             }
 
-            self.log(&format!(
-                "Block {block_index} of {num_blocks} for piece {piece_index} recieved"
+            self.log(
+                Level::Trace,
+                &format!("Block {block_index} of {num_blocks} for piece {piece_index} received"),
+            );
+        }
+
+        // Every block was stored, but the one completing the piece came from
+        // another peer
+        Ok(None)
+    }
+
+    /// Adds the piece announced by a `Have` from the peer to its bitfield
+    /// and counts it as available. Returns the index of the piece.
+    fn received_have(
+        &mut self,
+        piece_manager: &PieceManager,
+        have: &Message,
+    ) -> Result<usize, ConnectionErr> {
+        let index = have
+            .payload
+            .as_ref()
+            .and_then(|payload| payload.as_ref().try_into().ok())
+            .map(|index: [u8; 4]| u32::from_be_bytes(index) as usize)
+            .filter(|&index| index < piece_manager.get_piece_count())
+            .ok_or_else(|| {
+                ConnectionErr::UnexpectedMessage("Malformed have message".to_string())
+            })?;
+        let mut pieces = self.pieces.lock().unwrap();
+        if is_bit_set(&pieces.bitfield, index) {
+            return Ok(index);
+        }
+
+        let mut bitfield = BytesMut::from(pieces.bitfield.as_ref());
+        bitfield.resize(piece_manager.get_piece_count().div_ceil(8), 0);
+        bitfield[index / 8] |= 1 << (7 - index % 8);
+        pieces.bitfield = bitfield.freeze();
+        piece_manager.add_have(index);
+        Ok(index)
+    }
+
+    /// Answers a block request from the peer. Requests while we choke the
+    /// peer are dropped. Ones we can't serve are dropped too, or rejected
+    /// with the fast extension, and count towards banning the peer. Returns
+    /// whether the block was sent.
+    pub async fn serve_request(
+        &mut self,
+        piece_manager: &PieceManager,
+        request: &Message,
+    ) -> Result<bool, ConnectionErr> {
+        let Some(mut payload) = request
+            .payload
+            .clone()
+            .filter(|payload| payload.len() == 12)
+        else {
+            return Err(ConnectionErr::UnexpectedMessage(
+                "Malformed request message".to_string(),
             ));
+        };
+        let (index, begin, length) = (payload.get_u32(), payload.get_u32(), payload.get_u32());
+
+        if self.state.am_choking {
+            self.log(Level::Trace, "Ignoring request while choking the peer");
+            return Ok(false);
+        }
+
+        if let Err(err) =
+            piece_manager.check_request(index as usize, begin as usize, length as usize)
+        {
+            self.log(Level::Debug, &format!("Ignoring invalid request: {err}"));
+            self.invalid_requests += 1;
+            if self.invalid_requests >= INVALID_REQUESTS_BEFORE_BAN {
+                return Err(ConnectionErr::InvalidRequests(self.invalid_requests));
+            }
+            if self.fast_extension {
+                self.write_message(&Message::reject_request(index, begin, length))
+                    .await?;
+            }
+            return Ok(false);
         }
 
-        self.log(&format!("Piece {piece_index} recieved"));
+        let offset = index as u64 * piece_manager.get_piece_length() as u64 + begin as u64;
+        let Some(block) = piece_manager.read_range(offset, length as usize).await else {
+            self.log(Level::Warn, &format!("Failed to read piece {index}"));
+            if self.fast_extension {
+                self.write_message(&Message::reject_request(index, begin, length))
+                    .await?;
+            }
+            return Ok(false);
+        };
+
+        self.rate_limits.upload.acquire(block.len()).await;
+        let mut buf = BytesMut::with_capacity(8 + block.len());
+        buf.put_u32(index);
+        buf.put_u32(begin);
+        buf.put_slice(&block);
+        let piece = Message::new(
+            9 + length,
+            Some(MessageType::Piece as u8),
+            Some(buf.freeze()),
+        );
+        self.write_message(&piece).await?;
+
+        Ok(true)
+    }
+
+    /// Waits up to `UNCHOKE_TIMEOUT` for the peer to unchoke us again.
+    /// Requests and `Have`s of the peer are handled meanwhile, blocks still
+    /// in flight and other messages are dropped.
+    async fn wait_for_unchoke(
+        &mut self,
+        piece_manager: &PieceManager,
+    ) -> Result<(), ConnectionErr> {
+        let deadline = tokio::time::sleep(UNCHOKE_TIMEOUT);
+        tokio::pin!(deadline);
+
+        while self.state.peer_choking {
+            let Some(message) = self.read_message_until(&mut deadline).await? else {
+                return Err(ConnectionErr::ChokeTimeout);
+            };
+            if message.id == Some(MessageType::Request as u8) {
+                self.serve_request(piece_manager, &message).await?;
+            } else if message.id == Some(MessageType::Have as u8) {
+                self.received_have(piece_manager, &message)?;
+            }
+        }
 
-        Ok(piece_buffer.freeze())
+        self.log(Level::Trace, "Unchoked");
+        Ok(())
     }
 
-    pub async fn send_bitfield(&mut self, bitfield: &Bytes) -> Result<Bytes, ConnectionErr> {
+    /// Exchanges bitfields with the peer. When the fast extension was
+    /// negotiated the peer may answer with `HaveAll` or `HaveNone` instead,
+    /// which are expanded to a bitfield of `piece_count` pieces. A peer
+    /// without pieces may skip its bitfield, so any other message counts as
+    /// an empty bitfield and is left for the next read.
+    pub async fn send_bitfield(
+        &mut self,
+        bitfield: &Bytes,
+        piece_count: usize,
+    ) -> Result<Bytes, ConnectionErr> {
         let msg = Message {
             length: (bitfield.len() + 1) as u32,
             id: Some(MessageType::Bitfield as u8),
@@ -235,6 +1119,7 @@ impl Peer {
         match result {
             Ok(msg) if msg.id == Some(MessageType::Bitfield as u8) => {
                 if let Some(payload) = msg.payload {
+                    check_bitfield(&payload, piece_count)?;
                     Ok(payload)
                 } else {
                     Err(ConnectionErr::UnexpectedMessage(
@@ -242,48 +1127,1792 @@ impl Peer {
                     ))
                 }
             }
+            Ok(msg) if self.fast_extension && msg.id == Some(MessageType::HaveAll as u8) => {
+                Ok(full_bitfield(piece_count))
+            }
+            Ok(msg) if self.fast_extension && msg.id == Some(MessageType::HaveNone as u8) => {
+                Ok(Bytes::from(vec![0; piece_count.div_ceil(8)]))
+            }
+            Ok(msg)
+                if msg.id == Some(MessageType::HaveAll as u8)
+                    || msg.id == Some(MessageType::HaveNone as u8) =>
+            {
+                Err(ConnectionErr::UnexpectedMessage(
+                    "Fast extension message without the fast extension".to_string(),
+                ))
+            }
+            Ok(msg) => {
+                self.read_ahead = Some(msg);
+                Ok(Bytes::from(vec![0; piece_count.div_ceil(8)]))
+            }
             Err(e) => Err(e),
-            _ => Err(ConnectionErr::UnexpectedMessage(
-                "Expected Bitfield message".to_string(),
-            )),
         }
     }
 
-    /// Establishes a connection and performs handshake with peer
-    pub async fn connect(self: &mut Self, handshake: &Handshake) -> Result<(), ConnectionErr> {
-        let mut stream = TcpStream::connect(format!("{}:{}", self.ip, self.port))
+    /// Connects and exchanges handshakes with the peer, returning the peer's
+    /// handshake so its id and reserved capability bits can be inspected
+    pub async fn connect(&mut self, handshake: &Handshake) -> Result<Handshake, ConnectionErr> {
+        let encrypted_first = match self.encryption {
+            EncryptionMode::Disabled => return self.try_connect(handshake, false).await,
+            EncryptionMode::Enabled => false,
+            EncryptionMode::Preferred => true,
+            EncryptionMode::Required => return self.try_connect(handshake, true).await,
+        };
+
+        match self.try_connect(handshake, encrypted_first).await {
+            Err(err) if err.is_handshake_refused() => {
+                let fallback = if encrypted_first {
+                    "plaintext"
+                } else {
+                    "encryption"
+                };
+                self.log(
+                    Level::Debug,
+                    &format!("Handshake refused ({err}), retrying with {fallback}"),
+                );
+                self.try_connect(handshake, !encrypted_first).await
+            }
+            result => result,
+        }
+    }
+
+    /// A single attempt at connecting, with or without encryption
+    async fn try_connect(
+        &mut self,
+        handshake: &Handshake,
+        encrypted: bool,
+    ) -> Result<Handshake, ConnectionErr> {
+        tokio::time::timeout(
+            self.connect_timeout,
+            self.open_connection(handshake, encrypted),
+        )
+        .await
+        .map_err(|_| ConnectionErr::ConnectTimeout)?
+    }
+
+    async fn open_connection(
+        &mut self,
+        handshake: &Handshake,
+        encrypted: bool,
+    ) -> Result<Handshake, ConnectionErr> {
+        let mut stream = self
+            .transport
+            .connect(self.addr)
             .await
-            .map_err(|e| ConnectionErr::TokioConnectError(e))?;
+            .map_err(ConnectionErr::TokioConnectError)?;
+
+        let stream = if encrypted {
+            let negotiated = mse::handshake_outgoing(&mut stream, &handshake.info_hash).await?;
+            PeerStream::negotiated(stream, negotiated)
+        } else {
+            PeerStream::plain(stream)
+        };
+        if stream.is_encrypted() {
+            self.log(Level::Debug, "Connection is RC4 encrypted");
+        }
 
-        stream.write_all(&handshake.to_bytes()).await?;
+        self.exchange_handshakes(stream, handshake).await
+    }
 
-        let mut buf: [u8; crate::handshake::TOTAL_SIZE] = [0; crate::handshake::TOTAL_SIZE];
-        stream.read_exact(&mut buf).await?;
+    /// Exchanges plaintext handshakes with the peer over `stream`, which is
+    /// already open
+    pub async fn connect_over(
+        &mut self,
+        stream: impl PeerTransport + 'static,
+        handshake: &Handshake,
+    ) -> Result<Handshake, ConnectionErr> {
+        let stream = PeerStream::plain(Box::new(stream));
+        tokio::time::timeout(
+            self.connect_timeout,
+            self.exchange_handshakes(stream, handshake),
+        )
+        .await
+        .map_err(|_| ConnectionErr::ConnectTimeout)?
+    }
 
-        if let Ok(hs) = Handshake::from_bytes(&buf) {
-            if hs.is_valid(&handshake) {
-                self.socket = Some(stream);
-                self.my_state = PeerState::Choked;
-                self.their_state = PeerState::Choked;
-                return Ok(());
-            }
+    async fn exchange_handshakes(
+        &mut self,
+        mut stream: PeerStream,
+        handshake: &Handshake,
+    ) -> Result<Handshake, ConnectionErr> {
+        stream
+            .write_all(&handshake.to_bytes())
+            .await
+            .map_err(ConnectionErr::TokioWriteError)?;
+        self.emit(PeerEvent::HandshakeSent(handshake.clone())).await;
+
+        let hs = Handshake::from_stream(&mut stream).await?;
+        if !hs.is_valid(handshake) {
+            return Err(ConnectionErr::InvalidHandshake);
         }
 
-        Err(ConnectionErr::InvalidHandshake)
+        self.emit(PeerEvent::HandshakeReceived(hs.clone())).await;
+        self.established(stream, handshake, &hs).await;
+        Ok(hs)
     }
 
-    async fn send_message(&mut self, message: &Message) -> Result<Message, ConnectionErr> {
-        let stream = match self.socket.as_mut() {
-            Some(stream) => stream,
-            None => return Err(ConnectionErr::InvalidConnection),
-        };
+    /// Answers `their_handshake`, with which the peer opened `stream`,
+    /// with ours if it is for the same torrent
+    async fn accept_over(
+        &mut self,
+        stream: impl PeerTransport + 'static,
+        their_handshake: Handshake,
+        handshake: &Handshake,
+    ) -> Result<Handshake, ConnectionErr> {
+        if !their_handshake.is_valid(handshake) {
+            return Err(ConnectionErr::InvalidHandshake);
+        }
+        self.emit(PeerEvent::HandshakeReceived(their_handshake.clone()))
+            .await;
 
-        stream.write_all(&message.to_bytes()).await?;
+        let mut stream = PeerStream::plain(Box::new(stream));
+        stream
+            .write_all(&handshake.to_bytes())
+            .await
+            .map_err(ConnectionErr::TokioWriteError)?;
+        self.emit(PeerEvent::HandshakeSent(handshake.clone())).await;
 
-        Ok(Message::from_stream(stream).await?)
+        self.established(stream, handshake, &their_handshake).await;
+        Ok(their_handshake)
     }
 
-    fn log(&self, message: &str) {
-        println!("Peer @ {}:{}:\t{}", self.ip, self.port, message);
+    /// Starts talking to the peer over `stream` once handshakes are exchanged
+    async fn established(
+        &mut self,
+        stream: PeerStream,
+        handshake: &Handshake,
+        their_handshake: &Handshake,
+    ) {
+        self.socket = Some(stream);
+        self.fast_extension =
+            handshake.supports_fast_extension() && their_handshake.supports_fast_extension();
+        self.last_sent = Instant::now();
+        self.last_received = Instant::now();
+        self.state = PeerState::default();
+        *self.pieces.lock().unwrap() = PeerPieces::default();
+        self.read_buf.clear();
+        self.received.clear();
+        self.read_ahead = None;
+        self.emit(PeerEvent::Connected).await;
+    }
+
+    async fn send_message(&mut self, message: &Message) -> Result<Message, ConnectionErr> {
+        self.write_message(message).await?;
+        self.read_message().await
+    }
+
+    async fn write_message(&mut self, message: &Message) -> Result<(), ConnectionErr> {
+        let stream = self
+            .socket
+            .as_mut()
+            .ok_or(ConnectionErr::InvalidConnection)?;
+
+        stream.write_all(&message.to_bytes()).await?;
+        self.last_sent = Instant::now();
+        self.state.sent(message);
+        if let Some(length) = message.block_length() {
+            self.rates.upload.record(length as u64);
+        }
+        self.emit(PeerEvent::MessageSent(message.clone())).await;
+
+        Ok(())
+    }
+
+    /// Waits for the next message from the peer, skipping keep alives.
+    /// Sends keep alives of our own while waiting and disconnects if the
+    /// peer stays silent for longer than `INACTIVITY_TIMEOUT`.
+    pub async fn read_message(&mut self) -> Result<Message, ConnectionErr> {
+        match self.read_message_until(std::future::pending()).await? {
+            Some(message) => Ok(message),
+            None => unreachable!("pending never completes"),
+        }
+    }
+
+    /// Like `read_message`, but gives up and returns `None` once `interrupt`
+    /// completes while no message is being received
+    async fn read_message_until(
+        &mut self,
+        interrupt: impl std::future::Future<Output = ()>,
+    ) -> Result<Option<Message>, ConnectionErr> {
+        tokio::pin!(interrupt);
+
+        if let Some(message) = self.read_ahead.take() {
+            return Ok(Some(message));
+        }
+
+        loop {
+            if let Some(message) = self.buffered_message()? {
+                if message.is_keep_alive() {
+                    self.log(Level::Trace, "Keep alive received");
+                } else if message.id == Some(MessageType::Extended as u8) {
+                    // Extension messages are handled here so they can
+                    // arrive at any point of the exchange
+                    self.emit(PeerEvent::MessageReceived(message.clone())).await;
+                    self.handle_extended(&message).await;
+                } else {
+                    self.state.received(&message);
+                    self.emit(PeerEvent::MessageReceived(message.clone())).await;
+                    return Ok(Some(message));
+                }
+                continue;
+            }
+
+            let stream = self
+                .socket
+                .as_mut()
+                .ok_or(ConnectionErr::InvalidConnection)?;
+
+            // Reading into the buffer is cancel safe, so nothing is lost
+            // when a timer fires first
+            tokio::select! {
+                read = stream.read_buf(&mut self.read_buf) => {
+                    if read? == 0 {
+                        return Err(closed_by_peer());
+                    }
+                    self.last_received = Instant::now();
+                }
+                _ = tokio::time::sleep_until(self.last_sent + KEEP_ALIVE_INTERVAL) => {
+                    self.log(Level::Trace, "Sending keep alive");
+                    self.write_message(&Message::keep_alive()).await?;
+                }
+                _ = tokio::time::sleep_until(self.last_received + INACTIVITY_TIMEOUT) => {
+                    self.log(Level::Warn, "Peer inactive, disconnecting");
+                    return Err(ConnectionErr::Inactive);
+                }
+                _ = tokio::time::sleep_until(self.next_pex), if self.their_pex_id.is_some() => {
+                    self.send_pex().await?;
+                }
+                index = next_completed_piece(&mut self.torrent_events) => {
+                    self.log(Level::Trace, &format!("Sending have for piece {index}"));
+                    self.write_message(&Message::have(index as u32)).await?;
+                }
+                _ = &mut interrupt => return Ok(None),
+            }
+        }
+    }
+
+    /// Takes the next message out of the read buffer if it holds a whole
+    /// one. Bytes past the end of the message stay buffered for the next
+    /// call. Fails once the buffer starts with a message longer than
+    /// `MAX_MESSAGE_LENGTH` instead of buffering it.
+    fn buffered_message(&mut self) -> Result<Option<Message>, ConnectionErr> {
+        if self.received.is_empty() {
+            let (messages, consumed) = Message::parse_many(&self.read_buf);
+            self.read_buf.advance(consumed);
+            self.received.extend(messages);
+
+            // Parsing stops before a message that can't be decoded, which
+            // waiting for more bytes won't fix
+            if self.received.is_empty() {
+                if let Err(err @ MessageErr::InvalidMessageId) = Message::from_bytes(&self.read_buf)
+                {
+                    return Err(err.into());
+                }
+                if let Some(length) = self.read_buf.get(..4) {
+                    let length = u32::from_be_bytes(length.try_into().unwrap()) as usize;
+                    if length > MAX_MESSAGE_LENGTH {
+                        return Err(ConnectionErr::UnexpectedMessage(format!(
+                            "Message of {length} bytes is too long"
+                        )));
+                    }
+                }
+            }
+        }
+
+        Ok(self.received.pop_front())
+    }
+
+    /// Tells the peer which extensions we support and the ids to use for them
+    async fn send_extended_handshake(&mut self) -> Result<(), ConnectionErr> {
+        let handshake = ExtendedHandshake {
+            pex_id: Some(LOCAL_PEX_ID),
+        };
+
+        self.log(Level::Trace, "Sending extension handshake");
+        let message = Message::extended(
+            EXTENDED_HANDSHAKE_ID,
+            &handshake.to_bencodemap().get_encode(),
+        );
+        self.write_message(&message).await
+    }
+
+    /// Processes an extension protocol message, reporting peers received
+    /// through PEX. Malformed messages are logged and otherwise ignored.
+    async fn handle_extended(&mut self, message: &Message) {
+        let Some((&extension_id, payload)) = message
+            .payload
+            .as_ref()
+            .and_then(|payload| payload.split_first())
+        else {
+            self.log(Level::Debug, "Ignoring empty extension message");
+            return;
+        };
+
+        let map = match BencodeMap::try_decode(payload) {
+            Ok(map) => map,
+            Err(err) => {
+                self.log(Level::Debug, &format!("Invalid extension message: {err}"));
+                return;
+            }
+        };
+
+        match extension_id {
+            EXTENDED_HANDSHAKE_ID => match ExtendedHandshake::from_bencodemap(&map) {
+                Ok(handshake) if self.pex.is_some() => {
+                    self.their_pex_id = handshake.pex_id;
+                    self.next_pex = Instant::now();
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    self.log(Level::Debug, &format!("Invalid extension handshake: {err}"));
+                }
+            },
+            LOCAL_PEX_ID if self.pex.is_some() => match PexMessage::from_bencodemap(&map) {
+                Ok(mut pex) => {
+                    self.log(
+                        Level::Debug,
+                        &format!("Received {} peers through PEX", pex.added.len()),
+                    );
+                    // No more than we would send in one message ourselves
+                    pex.added.truncate(MAX_PEX_PEERS);
+                    if !pex.added.is_empty() {
+                        self.emit(PeerEvent::PeersDiscovered(pex.added)).await;
+                    }
+                }
+                Err(err) => self.log(Level::Debug, &format!("Invalid ut_pex message: {err}")),
+            },
+            _ => self.log(
+                Level::Trace,
+                &format!("Ignoring unknown extension message {extension_id}"),
+            ),
+        }
+    }
+
+    /// Sends the peer the changes to our peer list since the last `ut_pex`
+    /// message, if there are any
+    async fn send_pex(&mut self) -> Result<(), ConnectionErr> {
+        self.next_pex = Instant::now() + PEX_INTERVAL;
+        let (Some(pex_id), Some(pex)) = (self.their_pex_id, &self.pex) else {
+            return Ok(());
+        };
+
+        let mut current = pex.borrow().clone();
+        current.remove(&self.addr);
+
+        let message = PexMessage::diff(&self.pex_sent, &current);
+        if message.is_empty() {
+            return Ok(());
+        }
+        for addr in &message.dropped {
+            self.pex_sent.remove(addr);
+        }
+        self.pex_sent.extend(&message.added);
+
+        self.log(
+            Level::Trace,
+            &format!(
+                "Sending PEX with {} added and {} dropped peers",
+                message.added.len(),
+                message.dropped.len()
+            ),
+        );
+        self.write_message(&Message::extended(
+            pex_id,
+            &message.to_bencodemap().get_encode(),
+        ))
+        .await
+    }
+
+    /// Reports `event` to whoever is listening on `events`
+    async fn emit(&self, event: PeerEvent) {
+        if let Some(events) = &self.events {
+            // The manager no longer listening is not an error for the peer
+            let _ = events.send((self.addr, event)).await;
+        }
+    }
+
+    /// Logs `message` prefixed with this peer's address. `log` has no
+    /// spans, so the prefix is what ties the lines of a connection together.
+    fn log(&self, level: Level, message: &str) {
+        log::log!(level, "Peer @ {}: {}", self.addr, message);
+    }
+}
+
+/// Checks that a bitfield received from a peer has exactly one bit per
+/// piece, rounded up to whole bytes, with the spare bits of the last byte
+/// clear. Anything else would be misread as pieces the peer doesn't have.
+fn check_bitfield(bitfield: &[u8], piece_count: usize) -> Result<(), ConnectionErr> {
+    let expected_len = piece_count.div_ceil(8);
+    if bitfield.len() != expected_len {
+        return Err(ConnectionErr::InvalidBitfield(format!(
+            "{} bytes for {piece_count} pieces, expected {expected_len}",
+            bitfield.len()
+        )));
+    }
+
+    let spare_bits = expected_len * 8 - piece_count;
+    if let Some(last) = bitfield.last() {
+        if spare_bits > 0 && last & ((1 << spare_bits) - 1) != 0 {
+            return Err(ConnectionErr::InvalidBitfield(
+                "spare bits are set".to_string(),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Bitfield with a bit set for each of `piece_count` pieces and the spare
+/// bits of the last byte left clear
+pub fn full_bitfield(piece_count: usize) -> Bytes {
+    let mut bitfield = vec![0xff; piece_count.div_ceil(8)];
+    let spare_bits = bitfield.len() * 8 - piece_count;
+    if let Some(last) = bitfield.last_mut() {
+        *last <<= spare_bits;
+    }
+
+    Bytes::from(bitfield)
+}
+
+/// Error for a connection the peer closed, possibly in the middle of a message
+fn closed_by_peer() -> ConnectionErr {
+    MessageErr::from(io::Error::from(io::ErrorKind::UnexpectedEof)).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::Future,
+        sync::{Mutex, Once},
+    };
+
+    use log::{LevelFilter, Log, Metadata, Record};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use crate::test_util::{
+        fake_remote_peer, fake_remote_peer_replying, temp_path, test_meta_info,
+    };
+
+    use super::*;
+
+    static RECORDS: Mutex<Vec<(Level, String)>> = Mutex::new(Vec::new());
+
+    struct CaptureLogger;
+
+    impl Log for CaptureLogger {
+        fn enabled(&self, _metadata: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            RECORDS
+                .lock()
+                .unwrap()
+                .push((record.level(), record.args().to_string()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn init_logger() {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            log::set_logger(&CaptureLogger).unwrap();
+            log::set_max_level(LevelFilter::Trace);
+        });
+    }
+
+    #[tokio::test]
+    async fn failed_handshake_logged_as_error() {
+        init_logger();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; crate::handshake::TOTAL_SIZE];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream
+                .write_all(&[0u8; crate::handshake::TOTAL_SIZE])
+                .await
+                .unwrap();
+        });
+
+        let meta_info = test_meta_info().build();
+        let piece_manager = PieceManager::new(&meta_info, &std::env::temp_dir()).await;
+        let mut peer = Peer::new(None, addr);
+
+        let result = peer
+            .start(
+                &piece_manager,
+                Arc::new(meta_info.hash),
+                &PeerId::generate(),
+            )
+            .await;
+        assert!(matches!(result, Err(ConnectionErr::InvalidHandshake)));
+
+        let context = format!("Peer @ {addr}:");
+        let records = RECORDS.lock().unwrap();
+        assert!(records.iter().any(|(level, message)| {
+            *level == Level::Error
+                && message.starts_with(&context)
+                && message.contains("Invalid handshake")
+        }));
+    }
+
+    #[tokio::test]
+    async fn mismatched_info_hash_rejected() {
+        let reply = Handshake::new([2u8; 20], [3u8; 20]);
+        let (addr, remote_peer) = fake_remote_peer_replying(reply).await;
+        tokio::spawn(remote_peer);
+
+        let mut peer = Peer::new(None, addr);
+        let result = peer
+            .connect(&Handshake::new([1u8; 20], *PeerId::generate().as_bytes()))
+            .await;
+
+        assert!(matches!(result, Err(ConnectionErr::InvalidHandshake)));
+    }
+
+    #[tokio::test]
+    async fn connect_returns_peer_handshake() {
+        let (addr, remote_peer) = fake_remote_peer().await;
+        tokio::spawn(remote_peer);
+
+        let mut peer = Peer::new(None, addr);
+        let handshake = peer
+            .connect(&Handshake::new([1u8; 20], *PeerId::generate().as_bytes()))
+            .await
+            .unwrap();
+
+        assert_eq!(handshake.peer_id, [3u8; 20]);
+        assert_eq!(peer.state, PeerState::default());
+    }
+
+    #[tokio::test]
+    async fn ipv6_peer_formats_and_connects() {
+        // Skip on hosts without IPv6 loopback
+        let Ok(listener) = TcpListener::bind("[::1]:0").await else {
+            return;
+        };
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; crate::handshake::TOTAL_SIZE];
+            stream.read_exact(&mut buf).await.unwrap();
+            let reply = Handshake::new([1u8; 20], [3u8; 20]);
+            stream.write_all(&reply.to_bytes()).await.unwrap();
+        });
+
+        let mut peer = Peer::new(None, SocketAddr::new(Ipv6Addr::LOCALHOST.into(), port));
+        assert_eq!(peer.addr.to_string(), format!("[::1]:{port}"));
+
+        let handshake = peer
+            .connect(&Handshake::new([1u8; 20], *PeerId::generate().as_bytes()))
+            .await
+            .unwrap();
+
+        assert_eq!(handshake.peer_id, [3u8; 20]);
+    }
+
+    #[test]
+    fn ipv6_peer_decoded_from_dictionary() {
+        let mut map = BencodeMap::new();
+        map.insert(IP_KEY.into(), BencodeType::String(b"2001:db8::1".to_vec()));
+        map.insert(PORT_KEY.into(), BencodeType::Integer(6881));
+
+        let peer = Peer::from_bencodemap(&map).unwrap();
+
+        assert_eq!(peer.addr.to_string(), "[2001:db8::1]:6881");
+    }
+
+    #[test]
+    fn peer_round_trips_through_dictionary() {
+        let with_id = Peer::new(
+            Some("-RT0100-abcdefghijkl".to_string()),
+            "10.0.0.1:6881".parse().unwrap(),
+        );
+        let without_id = Peer::new(None, "[2001:db8::1]:51413".parse().unwrap());
+
+        for peer in [with_id, without_id] {
+            let map = BencodeMap::from(&peer);
+            let decoded = Peer::from_bencodemap(&map).unwrap();
+
+            assert_eq!(
+                map.contains_key(PEER_ID_KEY.as_bytes()),
+                peer.peer_id.is_some()
+            );
+            assert_eq!(decoded.peer_id, peer.peer_id);
+            assert_eq!(decoded.addr, peer.addr);
+        }
+    }
+
+    #[test]
+    fn binary_peer_ips_decoded() {
+        let decode = |ip: &[u8]| {
+            let mut map = BencodeMap::new();
+            map.insert(IP_KEY.into(), BencodeType::String(ip.to_vec()));
+            map.insert(PORT_KEY.into(), BencodeType::Integer(6881));
+            Peer::from_bencodemap(&map).map(|peer| peer.addr.to_string())
+        };
+        let ipv6 = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets();
+
+        assert_eq!(decode(b"10.0.0.1").unwrap(), "10.0.0.1:6881");
+        assert_eq!(decode(&[10, 0, 0, 2]).unwrap(), "10.0.0.2:6881");
+        assert_eq!(decode(&ipv6).unwrap(), "[2001:db8::1]:6881");
+        assert!(decode(&[10, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn malformed_peer_entries_skipped() {
+        let mut valid = BencodeMap::new();
+        valid.insert(IP_KEY.into(), BencodeType::String(b"10.0.0.1".to_vec()));
+        valid.insert(PORT_KEY.into(), BencodeType::Integer(6881));
+        let mut without_ip = BencodeMap::new();
+        without_ip.insert(PEER_ID_KEY.into(), BencodeType::String(vec![b'a'; 20]));
+        without_ip.insert(PORT_KEY.into(), BencodeType::Integer(6882));
+
+        let peers = Peer::from_bencodemap_list(&[valid, without_ip]);
+
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].addr.to_string(), "10.0.0.1:6881");
+    }
+
+    #[test]
+    fn unparsable_peer_address_is_invalid() {
+        let mut bad_ip = BencodeMap::new();
+        bad_ip.insert(IP_KEY.into(), BencodeType::String(b"not an ip".to_vec()));
+        bad_ip.insert(PORT_KEY.into(), BencodeType::Integer(6881));
+        let mut bad_port = BencodeMap::new();
+        bad_port.insert(IP_KEY.into(), BencodeType::String(b"10.0.0.1".to_vec()));
+        bad_port.insert(PORT_KEY.into(), BencodeType::Integer(70000));
+
+        for map in [bad_ip, bad_port] {
+            assert!(matches!(
+                Peer::from_bencodemap(&map),
+                Err(FromBencodeTypeErr::InvalidValue(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn silent_peer_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            // Accept the connection but never answer the handshake
+            let (_stream, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await;
+        });
+
+        let mut peer = Peer::new(None, addr);
+        peer.connect_timeout = Duration::from_millis(100);
+        let start = std::time::Instant::now();
+        let result = peer
+            .connect(&Handshake::new([1u8; 20], *PeerId::generate().as_bytes()))
+            .await;
+
+        assert!(matches!(result, Err(ConnectionErr::ConnectTimeout)));
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn start_emits_connection_events() {
+        let (addr, remote_peer) = fake_remote_peer().await;
+        // Hang up instead of answering the bitfield
+        tokio::spawn(remote_peer);
+
+        let meta_info = test_meta_info().build();
+        let piece_manager = PieceManager::new(&meta_info, &std::env::temp_dir()).await;
+        let (tx, mut rx) = mpsc::channel(16);
+        let mut peer = Peer::new(None, addr);
+        peer.events = Some(tx);
+
+        let result = peer
+            .start(
+                &piece_manager,
+                Arc::new(meta_info.hash),
+                &PeerId::generate(),
+            )
+            .await;
+        assert!(result.is_err());
+        drop(peer);
+
+        let mut events = Vec::new();
+        while let Some((from, event)) = rx.recv().await {
+            assert_eq!(from, addr);
+            events.push(event);
+        }
+
+        assert!(matches!(events[0], PeerEvent::HandshakeSent(_)));
+        assert!(matches!(events[1], PeerEvent::HandshakeReceived(_)));
+        assert!(matches!(events[2], PeerEvent::Connected));
+        assert!(matches!(events.last(), Some(PeerEvent::Disconnected)));
+    }
+
+    #[tokio::test]
+    async fn idle_connection_sends_keep_alive() {
+        let (addr, remote_peer) = fake_remote_peer().await;
+        let remote = tokio::spawn(async move {
+            let mut stream = remote_peer.await;
+
+            let mut keep_alive = [1u8; 4];
+            stream.read_exact(&mut keep_alive).await.unwrap();
+            (keep_alive, Instant::now(), stream)
+        });
+
+        let mut peer = Peer::new(None, addr);
+        peer.connect(&Handshake::new([1u8; 20], *PeerId::generate().as_bytes()))
+            .await
+            .unwrap();
+
+        tokio::time::pause();
+        let start = Instant::now();
+        let result = peer.read_message().await;
+
+        let (keep_alive, received_at, _stream) = remote.await.unwrap();
+        assert_eq!(keep_alive, [0u8; 4]);
+        assert!(received_at - start >= KEEP_ALIVE_INTERVAL);
+        assert!(matches!(result, Err(ConnectionErr::Inactive)));
+        assert!(start.elapsed() >= INACTIVITY_TIMEOUT);
+    }
+
+    #[test]
+    fn full_bitfield_clears_spare_bits() {
+        assert_eq!(full_bitfield(10).as_ref(), [0xff, 0b11000000]);
+        assert_eq!(full_bitfield(16).as_ref(), [0xff, 0xff]);
+        assert!(full_bitfield(0).is_empty());
+    }
+
+    /// Connects to a remote that answers our bitfield of 10 pieces with
+    /// `reply`, setting the fast extension bit in its handshake when `fast`
+    /// is set
+    async fn exchange_bitfields(
+        fast: bool,
+        reply: Message,
+    ) -> (Peer, Result<Bytes, ConnectionErr>) {
+        let mut handshake = Handshake::new([1u8; 20], [3u8; 20]);
+        if fast {
+            handshake = handshake.with_fast_extension();
+        }
+        let (addr, remote_peer) = fake_remote_peer_replying(handshake).await;
+        tokio::spawn(async move {
+            let mut stream = remote_peer.await;
+            Message::from_stream(&mut stream).await.unwrap();
+            stream.write_all(&reply.to_bytes()).await.unwrap();
+            stream
+        });
+
+        let mut peer = Peer::new(None, addr);
+        peer.connect(
+            &Handshake::new([1u8; 20], *PeerId::generate().as_bytes()).with_fast_extension(),
+        )
+        .await
+        .unwrap();
+        let result = peer.send_bitfield(&Bytes::from_static(&[0, 0]), 10).await;
+
+        (peer, result)
+    }
+
+    #[tokio::test]
+    async fn have_all_sets_every_piece() {
+        let (peer, result) = exchange_bitfields(true, Message::have_all()).await;
+
+        assert!(peer.fast_extension);
+        assert_eq!(result.unwrap(), full_bitfield(10));
+    }
+
+    #[tokio::test]
+    async fn have_all_rejected_without_fast_extension() {
+        let (peer, result) = exchange_bitfields(false, Message::have_all()).await;
+
+        assert!(!peer.fast_extension);
+        assert!(matches!(result, Err(ConnectionErr::UnexpectedMessage(_))));
+    }
+
+    fn bitfield_message(bitfield: &'static [u8]) -> Message {
+        Message::new(
+            bitfield.len() as u32 + 1,
+            Some(MessageType::Bitfield as u8),
+            Some(Bytes::from_static(bitfield)),
+        )
+    }
+
+    #[tokio::test]
+    async fn correct_bitfield_accepted() {
+        let reply = bitfield_message(&[0xff, 0b01000000]);
+        let (_, result) = exchange_bitfields(false, reply).await;
+
+        assert_eq!(result.unwrap().as_ref(), [0xff, 0b01000000]);
+    }
+
+    #[tokio::test]
+    async fn wrong_length_bitfield_rejected() {
+        for bitfield in [&[0xff][..], &[0xff, 0, 0]] {
+            let (_, result) = exchange_bitfields(false, bitfield_message(bitfield)).await;
+
+            assert!(
+                matches!(result, Err(ConnectionErr::InvalidBitfield(_))),
+                "{bitfield:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn bitfield_with_spare_bits_rejected() {
+        let reply = bitfield_message(&[0xff, 0b11100000]);
+        let (_, result) = exchange_bitfields(false, reply).await;
+
+        assert!(matches!(result, Err(ConnectionErr::InvalidBitfield(_))));
+    }
+
+    #[tokio::test]
+    async fn peers_exchanged_through_pex() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let remote = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; crate::handshake::TOTAL_SIZE];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert!(Handshake::from_bytes(&buf)
+                .unwrap()
+                .supports_extension_protocol());
+            let reply = Handshake::new([1u8; 20], [3u8; 20]).with_extension_protocol();
+            stream.write_all(&reply.to_bytes()).await.unwrap();
+
+            let handshake = Message::from_stream(&mut stream).await.unwrap();
+            assert_eq!(handshake.id, Some(MessageType::Extended as u8));
+
+            let their_handshake = ExtendedHandshake { pex_id: Some(2) };
+            let messages = [
+                Message::extended(0, &their_handshake.to_bencodemap().get_encode()),
+                Message::extended(
+                    LOCAL_PEX_ID,
+                    &PexMessage {
+                        added: vec!["10.0.0.1:6881".parse().unwrap()],
+                        dropped: vec![],
+                    }
+                    .to_bencodemap()
+                    .get_encode(),
+                ),
+                Message::new(1, Some(MessageType::Unchoke as u8), None),
+            ];
+            for message in messages {
+                stream.write_all(&message.to_bytes()).await.unwrap();
+            }
+
+            let pex = Message::from_stream(&mut stream).await.unwrap();
+            let unchoke = Message::new(1, Some(MessageType::Unchoke as u8), None);
+            stream.write_all(&unchoke.to_bytes()).await.unwrap();
+            (pex, stream)
+        });
+
+        let other: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        let (_addresses, addresses_rx) = watch::channel(HashSet::from([other]));
+        let (events_tx, mut events_rx) = mpsc::channel(16);
+        let mut peer = Peer::new(None, addr);
+        peer.pex = Some(addresses_rx);
+        peer.events = Some(events_tx);
+
+        let handshake =
+            Handshake::new([1u8; 20], *PeerId::generate().as_bytes()).with_extension_protocol();
+        peer.connect(&handshake).await.unwrap();
+        peer.send_extended_handshake().await.unwrap();
+        peer.read_message().await.unwrap();
+        peer.read_message().await.unwrap();
+
+        let (pex, _stream) = remote.await.unwrap();
+        let payload = pex.payload.unwrap();
+        assert_eq!(payload[0], 2);
+        let sent = PexMessage::from_bencodemap(&BencodeMap::try_decode(&payload[1..]).unwrap());
+        assert_eq!(sent.unwrap().added, vec![other]);
+
+        drop(peer);
+        let mut discovered = Vec::new();
+        while let Some((_, event)) = events_rx.recv().await {
+            if let PeerEvent::PeersDiscovered(addrs) = event {
+                discovered.extend(addrs);
+            }
+        }
+        assert_eq!(discovered, vec!["10.0.0.1:6881".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn preferred_encryption_falls_back_to_plaintext() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let remote = tokio::spawn(async move {
+            // A plaintext only peer hangs up on the encryption handshake
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut public_key = [0u8; mse::KEY_SIZE];
+            stream.read_exact(&mut public_key).await.unwrap();
+            drop(stream);
+
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; crate::handshake::TOTAL_SIZE];
+            stream.read_exact(&mut buf).await.unwrap();
+            let reply = Handshake::new([1u8; 20], [3u8; 20]);
+            stream.write_all(&reply.to_bytes()).await.unwrap();
+            stream
+        });
+
+        let mut peer = Peer::new(None, addr);
+        peer.encryption = EncryptionMode::Preferred;
+        let result = peer
+            .connect(&Handshake::new([1u8; 20], *PeerId::generate().as_bytes()))
+            .await;
+        let _stream = remote.await.unwrap();
+
+        assert!(result.is_ok());
+        assert!(!peer.socket.unwrap().is_encrypted());
+    }
+
+    /// Remote that only has piece 1 of `endgame_meta_info`. Once both remotes
+    /// got a request the fast one answers it, the slow one returns the next
+    /// message it receives.
+    async fn endgame_remote(
+        remote_peer: impl Future<Output = TcpStream>,
+        requested: Arc<tokio::sync::Barrier>,
+        fast: bool,
+    ) -> Option<Message> {
+        let mut stream = remote_peer.await;
+
+        Message::from_stream(&mut stream).await.unwrap();
+        let bitfield = Message::new(
+            2,
+            Some(MessageType::Bitfield as u8),
+            Some(vec![0b01000000].into()),
+        );
+        stream.write_all(&bitfield.to_bytes()).await.unwrap();
+
+        Message::from_stream(&mut stream).await.unwrap();
+        let unchoke = Message::new(1, Some(MessageType::Unchoke as u8), None);
+        stream.write_all(&unchoke.to_bytes()).await.unwrap();
+
+        let request = Message::from_stream(&mut stream).await.unwrap();
+        assert_eq!(request.id, Some(MessageType::Request as u8));
+        requested.wait().await;
+
+        if fast {
+            let payload = [&1u32.to_be_bytes()[..], &0u32.to_be_bytes(), b"efgh"].concat();
+            let piece = Message::new(13, Some(MessageType::Piece as u8), Some(payload.into()));
+            stream.write_all(&piece.to_bytes()).await.unwrap();
+            None
+        } else {
+            Some(Message::from_stream(&mut stream).await.unwrap())
+        }
+    }
+
+    #[tokio::test]
+    async fn endgame_cancels_slower_peer() {
+        let meta_info = test_meta_info().data(b"abcdefgh").build();
+        let piece_manager = PieceManager::new(&meta_info, &std::env::temp_dir()).await;
+        assert!(piece_manager.in_endgame());
+
+        let requested = Arc::new(tokio::sync::Barrier::new(2));
+        let (fast_addr, fast_remote_peer) = fake_remote_peer().await;
+        let (slow_addr, slow_remote_peer) = fake_remote_peer().await;
+        let mut fast_peer = Peer::new(None, fast_addr);
+        let mut slow_peer = Peer::new(None, slow_addr);
+        let fast_remote = tokio::spawn(endgame_remote(fast_remote_peer, requested.clone(), true));
+        let slow_remote = tokio::spawn(endgame_remote(slow_remote_peer, requested, false));
+
+        let hash = Arc::new(meta_info.hash);
+        let peer_id = PeerId::generate();
+        let (fast_result, slow_result) = tokio::join!(
+            fast_peer.start(&piece_manager, hash.clone(), &peer_id),
+            slow_peer.start(&piece_manager, hash, &peer_id),
+        );
+
+        assert!(fast_result.is_ok());
+        assert!(slow_result.is_ok());
+        assert!(piece_manager.has_piece(1));
+        assert!(fast_remote.await.unwrap().is_none());
+
+        let cancel = slow_remote.await.unwrap().unwrap();
+        assert_eq!(cancel.id, Some(MessageType::Cancel as u8));
+        assert_eq!(
+            cancel.payload,
+            Some(Message::cancel(1, 0, 4).payload.unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn full_download_over_in_memory_stream() {
+        let data: [&[u8]; 3] = [b"abcd", b"efgh", b"ij"];
+        let meta_info = test_meta_info()
+            .name("in-memory.bin")
+            .data(&data.concat())
+            .build();
+        let download_dir = temp_path("in-memory");
+        let piece_manager = PieceManager::new(&meta_info, &download_dir).await;
+
+        let (ours, mut theirs) = tokio::io::duplex(1 << 16);
+        let remote = tokio::spawn(async move {
+            let mut buf = [0u8; crate::handshake::TOTAL_SIZE];
+            theirs.read_exact(&mut buf).await.unwrap();
+            let reply = Handshake::new([1u8; 20], [3u8; 20]);
+            theirs.write_all(&reply.to_bytes()).await.unwrap();
+
+            let mut served = 0;
+            while let Ok(message) = Message::from_stream(&mut theirs).await {
+                let id = message.id.unwrap();
+                let reply = if id == MessageType::Bitfield as u8 {
+                    Message::new(2, Some(MessageType::Bitfield as u8), Some(full_bitfield(3)))
+                } else if id == MessageType::Interested as u8 {
+                    Message::new(1, Some(MessageType::Unchoke as u8), None)
+                } else if id == MessageType::Request as u8 {
+                    let payload = message.payload.unwrap();
+                    let index = u32::from_be_bytes(payload[..4].try_into().unwrap());
+                    let block = [&payload[..8], data[index as usize]].concat();
+                    served += 1;
+                    Message::new(
+                        block.len() as u32 + 1,
+                        Some(MessageType::Piece as u8),
+                        Some(block.into()),
+                    )
+                } else {
+                    continue;
+                };
+                theirs.write_all(&reply.to_bytes()).await.unwrap();
+            }
+            served
+        });
+
+        let mut peer = Peer::new(None, SocketAddr::from(([127, 0, 0, 1], 6881)));
+        let result = peer
+            .start_over(
+                ours,
+                &piece_manager,
+                Arc::new(meta_info.hash),
+                &PeerId::generate(),
+            )
+            .await;
+        drop(peer);
+        let served = remote.await.unwrap();
+        let downloaded = piece_manager.read_range(0, 10).await;
+        tokio::fs::remove_dir_all(&download_dir).await.unwrap();
+
+        assert!(result.is_ok(), "{result:?}");
+        assert_eq!(served, 3);
+        assert!(piece_manager.is_complete());
+        assert_eq!(downloaded.as_deref(), Some(b"abcdefghij".as_slice()));
+    }
+
+    #[tokio::test]
+    async fn nothing_requested_when_already_complete() {
+        let data: [&[u8]; 3] = [b"abcd", b"efgh", b"ij"];
+        let meta_info = test_meta_info()
+            .name("complete.bin")
+            .data(&data.concat())
+            .build();
+        let download_dir = temp_path("complete");
+        let downloaded = PieceManager::new(&meta_info, &download_dir).await;
+        for (index, piece) in data.iter().enumerate() {
+            downloaded
+                .add_piece(&index, Bytes::copy_from_slice(piece))
+                .await;
+        }
+        drop(downloaded);
+        // Loaded from what is already on disk
+        let piece_manager = PieceManager::new(&meta_info, &download_dir).await;
+        assert!(piece_manager.is_complete());
+
+        let (ours, mut theirs) = tokio::io::duplex(1 << 16);
+        let remote = tokio::spawn(async move {
+            let mut buf = [0u8; crate::handshake::TOTAL_SIZE];
+            theirs.read_exact(&mut buf).await.unwrap();
+            let reply = Handshake::new([1u8; 20], [3u8; 20]);
+            theirs.write_all(&reply.to_bytes()).await.unwrap();
+
+            let mut received = Vec::new();
+            while let Ok(message) = Message::from_stream(&mut theirs).await {
+                let id = message.id.unwrap();
+                received.push(id);
+                if id == MessageType::Bitfield as u8 {
+                    let bitfield =
+                        Message::new(2, Some(MessageType::Bitfield as u8), Some(full_bitfield(3)));
+                    let unchoke = Message::new(1, Some(MessageType::Unchoke as u8), None);
+                    theirs.write_all(&bitfield.to_bytes()).await.unwrap();
+                    theirs.write_all(&unchoke.to_bytes()).await.unwrap();
+                }
+            }
+            received
+        });
+
+        let mut peer = Peer::new(None, SocketAddr::from(([127, 0, 0, 1], 6881)));
+        let result = peer
+            .start_over(
+                ours,
+                &piece_manager,
+                Arc::new(meta_info.hash),
+                &PeerId::generate(),
+            )
+            .await;
+        drop(peer);
+        let received = remote.await.unwrap();
+        tokio::fs::remove_dir_all(&download_dir).await.unwrap();
+
+        assert!(result.is_ok(), "{result:?}");
+        assert!(received.contains(&(MessageType::Bitfield as u8)));
+        assert!(!received.contains(&(MessageType::Interested as u8)));
+        assert!(!received.contains(&(MessageType::Request as u8)));
+    }
+
+    #[tokio::test]
+    async fn seed_unchokes_and_serves_interested_leecher() {
+        let meta_info = test_meta_info()
+            .name("seeding.bin")
+            .data(b"abcdefgh")
+            .build();
+        let download_dir = temp_path("seeding");
+        let downloaded = PieceManager::new(&meta_info, &download_dir).await;
+        downloaded.add_piece(&0, Bytes::from_static(b"abcd")).await;
+        downloaded.add_piece(&1, Bytes::from_static(b"efgh")).await;
+        drop(downloaded);
+        let piece_manager = PieceManager::new(&meta_info, &download_dir).await;
+        assert!(piece_manager.is_complete());
+
+        let (ours, mut theirs) = tokio::io::duplex(1 << 16);
+        let remote = tokio::spawn(async move {
+            let mut buf = [0u8; crate::handshake::TOTAL_SIZE];
+            theirs.read_exact(&mut buf).await.unwrap();
+            let reply = Handshake::new([1u8; 20], [3u8; 20]);
+            theirs.write_all(&reply.to_bytes()).await.unwrap();
+
+            Message::from_stream(&mut theirs).await.unwrap();
+            let bitfield = Message::new(2, Some(MessageType::Bitfield as u8), Some(vec![0].into()));
+            let interested = Message::new(1, Some(MessageType::Interested as u8), None);
+            theirs.write_all(&bitfield.to_bytes()).await.unwrap();
+            theirs.write_all(&interested.to_bytes()).await.unwrap();
+
+            let unchoke = Message::from_stream(&mut theirs).await.unwrap();
+            assert_eq!(unchoke.id, Some(MessageType::Unchoke as u8));
+
+            // The connection stays open for one request after another
+            let mut blocks = Vec::new();
+            for index in [1, 0] {
+                let request = Message::request(index, 0, 4);
+                theirs.write_all(&request.to_bytes()).await.unwrap();
+                let piece = Message::from_stream(&mut theirs).await.unwrap();
+                assert_eq!(piece.id, Some(MessageType::Piece as u8));
+                blocks.push(piece.payload.unwrap().slice(8..));
+            }
+            blocks
+        });
+
+        let mut peer = Peer::new(None, SocketAddr::from(([127, 0, 0, 1], 6881)));
+        peer.upload_slots = Some(Arc::new(Semaphore::new(1)));
+        let result = peer
+            .start_over(
+                ours,
+                &piece_manager,
+                Arc::new(meta_info.hash),
+                &PeerId::generate(),
+            )
+            .await;
+        let blocks = remote.await.unwrap();
+        tokio::fs::remove_dir_all(&download_dir).await.unwrap();
+
+        // The leecher hanging up ends seeding without an error
+        assert!(result.is_ok(), "{result:?}");
+        assert_eq!(blocks, vec![&b"efgh"[..], &b"abcd"[..]]);
+    }
+
+    #[tokio::test]
+    async fn seed_serves_leecher_that_skips_bitfield() {
+        let meta_info = test_meta_info()
+            .name("seeding-no-bitfield.bin")
+            .data(b"abcdefgh")
+            .build();
+        let download_dir = temp_path("seeding-no-bitfield");
+        let downloaded = PieceManager::new(&meta_info, &download_dir).await;
+        downloaded.add_piece(&0, Bytes::from_static(b"abcd")).await;
+        downloaded.add_piece(&1, Bytes::from_static(b"efgh")).await;
+        drop(downloaded);
+        let piece_manager = PieceManager::new(&meta_info, &download_dir).await;
+
+        let (ours, mut theirs) = tokio::io::duplex(1 << 16);
+        let remote = tokio::spawn(async move {
+            let mut buf = [0u8; crate::handshake::TOTAL_SIZE];
+            theirs.read_exact(&mut buf).await.unwrap();
+            let reply = Handshake::new([1u8; 20], [3u8; 20]);
+            theirs.write_all(&reply.to_bytes()).await.unwrap();
+
+            // A peer without pieces may go straight to Interested
+            Message::from_stream(&mut theirs).await.unwrap();
+            let interested = Message::new(1, Some(MessageType::Interested as u8), None);
+            theirs.write_all(&interested.to_bytes()).await.unwrap();
+
+            let unchoke = Message::from_stream(&mut theirs).await.unwrap();
+            assert_eq!(unchoke.id, Some(MessageType::Unchoke as u8));
+
+            theirs
+                .write_all(&Message::request(0, 0, 4).to_bytes())
+                .await
+                .unwrap();
+            let piece = Message::from_stream(&mut theirs).await.unwrap();
+            assert_eq!(piece.id, Some(MessageType::Piece as u8));
+            piece.payload.unwrap().slice(8..)
+        });
+
+        let mut peer = Peer::new(None, SocketAddr::from(([127, 0, 0, 1], 6881)));
+        peer.upload_slots = Some(Arc::new(Semaphore::new(1)));
+        let result = peer
+            .start_over(
+                ours,
+                &piece_manager,
+                Arc::new(meta_info.hash),
+                &PeerId::generate(),
+            )
+            .await;
+        let block = remote.await.unwrap();
+        tokio::fs::remove_dir_all(&download_dir).await.unwrap();
+
+        assert!(result.is_ok(), "{result:?}");
+        assert_eq!(block.as_ref(), b"abcd");
+    }
+
+    #[tokio::test]
+    async fn partial_last_piece_requests_only_its_blocks() {
+        let data: Vec<u8> = (0..BLOCK_SIZE * 2 + 100).map(|i| (i % 251) as u8).collect();
+        let meta_info = test_meta_info()
+            .piece_length(BLOCK_SIZE * 2)
+            .data(&data)
+            .build();
+        let piece_manager = PieceManager::new(&meta_info, &std::env::temp_dir()).await;
+
+        let (addr, remote_peer) = fake_remote_peer().await;
+        let last_piece = data[BLOCK_SIZE * 2..].to_vec();
+        let remote = tokio::spawn(async move {
+            let mut stream = remote_peer.await;
+
+            let mut requested = Vec::new();
+            while let Ok(request) = Message::from_stream(&mut stream).await {
+                let payload = request.payload.unwrap();
+                let begin = u32::from_be_bytes(payload[4..8].try_into().unwrap()) as usize;
+                let length = u32::from_be_bytes(payload[8..12].try_into().unwrap()) as usize;
+                requested.push((begin, length));
+
+                let block = [&payload[..8], &last_piece[begin..begin + length]].concat();
+                let piece = Message::new(
+                    9 + length as u32,
+                    Some(MessageType::Piece as u8),
+                    Some(block.into()),
+                );
+                stream.write_all(&piece.to_bytes()).await.unwrap();
+            }
+            requested
+        });
+
+        let mut peer = Peer::new(None, addr);
+        peer.connect(&Handshake::new([1u8; 20], *PeerId::generate().as_bytes()))
+            .await
+            .unwrap();
+        // Pretend we are interested and the peer unchoked us
+        peer.state.am_interested = true;
+        peer.state.peer_choking = false;
+        let piece_length = piece_manager.get_piece_size(1);
+        let piece = peer
+            .download_piece(&piece_manager, 1, piece_length as u64)
+            .await
+            .unwrap()
+            .unwrap();
+        let download_rate = peer.download_rate();
+        drop(peer);
+
+        assert_eq!(piece_length, 100);
+        // 100 bytes averaged over the 10 second window
+        assert_eq!(download_rate, 10);
+        assert_eq!(remote.await.unwrap(), vec![(0, 100)]);
+        assert!(piece_manager.is_piece_valid(&1, &piece.data));
+    }
+
+    #[tokio::test]
+    async fn configured_block_size_used_for_requests() {
+        let block_size = 1 << 13;
+        let meta_info = test_meta_info()
+            .piece_length(BLOCK_SIZE * 2 + 100)
+            .length(BLOCK_SIZE * 2 + 100)
+            .build();
+        let piece_manager = PieceManager::new(&meta_info, &std::env::temp_dir()).await;
+
+        let (addr, remote_peer) = fake_remote_peer().await;
+        let remote = tokio::spawn(async move {
+            let mut stream = remote_peer.await;
+
+            let mut requested = Vec::new();
+            while let Ok(request) = Message::from_stream(&mut stream).await {
+                let payload = request.payload.unwrap();
+                let length = u32::from_be_bytes(payload[8..12].try_into().unwrap()) as usize;
+                requested.push(length);
+
+                let block = [&payload[..8], &vec![0u8; length]].concat();
+                let piece = Message::new(
+                    9 + length as u32,
+                    Some(MessageType::Piece as u8),
+                    Some(block.into()),
+                );
+                stream.write_all(&piece.to_bytes()).await.unwrap();
+            }
+            requested
+        });
+
+        let mut peer = Peer::new(None, addr);
+        peer.block_size = block_size;
+        peer.connect(&Handshake::new([1u8; 20], *PeerId::generate().as_bytes()))
+            .await
+            .unwrap();
+        // Pretend we are interested and the peer unchoked us
+        peer.state.am_interested = true;
+        peer.state.peer_choking = false;
+        let piece = peer
+            .download_piece(&piece_manager, 0, BLOCK_SIZE as u64 * 2 + 100)
+            .await
+            .unwrap()
+            .unwrap();
+        drop(peer);
+
+        assert_eq!(piece.data.len(), BLOCK_SIZE * 2 + 100);
+        assert_eq!(
+            remote.await.unwrap(),
+            vec![block_size, block_size, block_size, block_size, 100]
+        );
+    }
+
+    #[tokio::test]
+    async fn disconnect_says_goodbye() {
+        let (addr, remote_peer) = fake_remote_peer().await;
+        let remote = tokio::spawn(async move {
+            let mut stream = remote_peer.await;
+
+            let mut received = Vec::new();
+            while let Ok(message) = Message::from_stream(&mut stream).await {
+                received.push(message.id);
+            }
+            received
+        });
+
+        let mut peer = Peer::new(None, addr);
+        peer.connect(&Handshake::new([1u8; 20], *PeerId::generate().as_bytes()))
+            .await
+            .unwrap();
+        peer.state.am_interested = true;
+        // Pretend we unchoked the peer to serve it
+        peer.state.am_choking = false;
+        peer.disconnect().await;
+
+        assert_eq!(
+            remote.await.unwrap(),
+            [
+                Some(MessageType::NotInterested as u8),
+                Some(MessageType::Choke as u8)
+            ]
+        );
+        assert!(peer.socket.is_none());
+        assert_eq!(peer.state, PeerState::default());
+    }
+
+    #[tokio::test]
+    async fn invalid_requests_not_served() {
+        let download_dir = temp_path("serve");
+        tokio::fs::create_dir_all(&download_dir).await.unwrap();
+        let piece_manager = PieceManager::new(&test_meta_info().build(), &download_dir).await;
+        assert_eq!(
+            piece_manager
+                .add_piece(&0, Bytes::from_static(b"abcd"))
+                .await,
+            PieceOutcome::Added
+        );
+
+        let (addr, remote_peer) = fake_remote_peer().await;
+        let remote = tokio::spawn(async move {
+            let mut stream = remote_peer.await;
+
+            let mut received = Vec::new();
+            while let Ok(message) = Message::from_stream(&mut stream).await {
+                received.push(message);
+            }
+            received
+        });
+
+        let mut peer = Peer::new(None, addr);
+        peer.connect(&Handshake::new([1u8; 20], *PeerId::generate().as_bytes()))
+            .await
+            .unwrap();
+        // Pretend we unchoked the peer to serve it
+        peer.state.am_choking = false;
+        let request = |index, length| Message::request(index, 0, length);
+        let over_length = request(0, MAX_BLOCK_SIZE as u32 + 1);
+        let out_of_range = request(1, 4);
+        let past_end = request(0, 5);
+        for invalid in [over_length, out_of_range, past_end] {
+            assert!(!peer.serve_request(&piece_manager, &invalid).await.unwrap());
+        }
+        assert!(peer
+            .serve_request(&piece_manager, &request(0, 4))
+            .await
+            .unwrap());
+        peer.disconnect().await;
+        tokio::fs::remove_dir_all(&download_dir).await.unwrap();
+
+        // Only the valid request was answered before the choke on disconnect
+        let received = remote.await.unwrap();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0].id, Some(MessageType::Piece as u8));
+        assert_eq!(
+            received[0].payload.as_deref(),
+            Some(&b"\0\0\0\0\0\0\0\0abcd"[..])
+        );
+        assert_eq!(received[1].id, Some(MessageType::Choke as u8));
+        assert_eq!(peer.invalid_requests, 3);
+    }
+
+    #[tokio::test]
+    async fn unreadable_block_rejected() {
+        let download_dir = temp_path("unreadable");
+        let piece_manager = PieceManager::new(&test_meta_info().build(), &download_dir).await;
+        piece_manager
+            .add_piece(&0, Bytes::from_static(b"abcd"))
+            .await;
+        piece_manager.flush().await.unwrap();
+        // Cut short behind the piece manager's back
+        tokio::fs::write(download_dir.join("test"), b"ab")
+            .await
+            .unwrap();
+
+        let (addr, remote_peer) = fake_remote_peer().await;
+        let remote = tokio::spawn(async move {
+            let mut stream = remote_peer.await;
+            Message::from_stream(&mut stream).await.unwrap()
+        });
+
+        let mut peer = Peer::new(None, addr);
+        peer.connect(&Handshake::new([1u8; 20], *PeerId::generate().as_bytes()))
+            .await
+            .unwrap();
+        peer.fast_extension = true;
+        peer.state.am_choking = false;
+        let served = peer
+            .serve_request(&piece_manager, &Message::request(0, 0, 4))
+            .await;
+        tokio::fs::remove_dir_all(&download_dir).await.unwrap();
+
+        assert!(!served.unwrap());
+        let reject = remote.await.unwrap();
+        assert_eq!(reject.id, Some(MessageType::RejectRequest as u8));
+        assert_eq!(reject.payload, Message::reject_request(0, 0, 4).payload);
+    }
+
+    #[tokio::test]
+    async fn requests_served_while_choked() {
+        let download_dir = temp_path("serve-choked");
+        let piece_manager = PieceManager::new(&test_meta_info().build(), &download_dir).await;
+        piece_manager
+            .add_piece(&0, Bytes::from_static(b"abcd"))
+            .await;
+
+        let (addr, remote_peer) = fake_remote_peer().await;
+        let remote = tokio::spawn(async move {
+            let mut stream = remote_peer.await;
+            let request = Message::request(0, 0, 4);
+            let unchoke = Message::new(1, Some(MessageType::Unchoke as u8), None);
+            stream.write_all(&request.to_bytes()).await.unwrap();
+            let piece = Message::from_stream(&mut stream).await.unwrap();
+            stream.write_all(&unchoke.to_bytes()).await.unwrap();
+            (piece, stream)
+        });
+
+        let mut peer = Peer::new(None, addr);
+        peer.connect(&Handshake::new([1u8; 20], *PeerId::generate().as_bytes()))
+            .await
+            .unwrap();
+        peer.state.am_choking = false;
+        let unchoked = peer.wait_for_unchoke(&piece_manager).await;
+        let _ = tokio::fs::remove_dir_all(&download_dir).await;
+
+        assert!(unchoked.is_ok());
+        let (piece, _stream) = remote.await.unwrap();
+        assert_eq!(piece.id, Some(MessageType::Piece as u8));
+        assert_eq!(piece.payload.as_deref(), Some(&b"\0\0\0\0\0\0\0\0abcd"[..]));
+    }
+
+    #[tokio::test]
+    async fn choke_mid_piece_requeues_it() {
+        // Enough two block pieces to stay out of endgame mode
+        let meta_info = test_meta_info()
+            .piece_length(BLOCK_SIZE * 2)
+            .length(BLOCK_SIZE * 20)
+            .build();
+        let piece_manager = PieceManager::new(&meta_info, &std::env::temp_dir()).await;
+        let their_bitfield = full_bitfield(10);
+
+        let (addr, remote_peer) = fake_remote_peer().await;
+        let remote = tokio::spawn(async move {
+            let mut stream = remote_peer.await;
+
+            // Answer the first block, then choke instead of sending the second
+            let request = Message::from_stream(&mut stream).await.unwrap();
+            let block = [&request.payload.unwrap()[..8], &[0u8; BLOCK_SIZE]].concat();
+            let length = 9 + BLOCK_SIZE as u32;
+            let piece = Message::new(length, Some(MessageType::Piece as u8), Some(block.into()));
+            stream.write_all(&piece.to_bytes()).await.unwrap();
+
+            Message::from_stream(&mut stream).await.unwrap();
+            let choke = Message::new(1, Some(MessageType::Choke as u8), None);
+            let unchoke = Message::new(1, Some(MessageType::Unchoke as u8), None);
+            stream.write_all(&choke.to_bytes()).await.unwrap();
+            stream.write_all(&unchoke.to_bytes()).await.unwrap();
+            stream
+        });
+
+        let mut peer = Peer::new(None, addr);
+        peer.connect(&Handshake::new([1u8; 20], *PeerId::generate().as_bytes()))
+            .await
+            .unwrap();
+        // Pretend we are interested and the peer unchoked us
+        peer.state.am_interested = true;
+        peer.state.peer_choking = false;
+        assert_eq!(piece_manager.get_next_piece(&their_bitfield), Some(0));
+        let result = peer
+            .download_piece(&piece_manager, 0, BLOCK_SIZE as u64 * 2)
+            .await;
+        remote.await.unwrap();
+
+        assert!(result.unwrap().is_none());
+        assert!(peer.state.can_request());
+        // Piece 0 is handed out again rather than staying in progress
+        assert_eq!(piece_manager.get_next_piece(&their_bitfield), Some(0));
+    }
+
+    #[test]
+    fn requests_allowed_only_when_interested_and_unchoked() {
+        for am_interested in [false, true] {
+            for peer_choking in [false, true] {
+                // Our side of the connection has no say in what we may request
+                for (am_choking, peer_interested) in [(false, false), (true, true)] {
+                    let state = PeerState {
+                        am_choking,
+                        am_interested,
+                        peer_choking,
+                        peer_interested,
+                    };
+
+                    assert_eq!(
+                        state.can_request(),
+                        am_interested && !peer_choking,
+                        "{state:?}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn state_follows_choke_and_interest_messages() {
+        let message = |id: MessageType| Message::new(1, Some(id as u8), None);
+        let mut state = PeerState::default();
+
+        state.sent(&message(MessageType::Interested));
+        assert!(!state.can_request());
+        state.received(&message(MessageType::Unchoke));
+        assert!(state.can_request());
+        state.received(&message(MessageType::Interested));
+        state.sent(&message(MessageType::Unchoke));
+        state.received(&message(MessageType::Choke));
+        assert!(!state.can_request());
+        assert!(state.peer_interested && !state.am_choking);
+        state.sent(&message(MessageType::NotInterested));
+        state.received(&message(MessageType::Unchoke));
+        assert!(!state.can_request());
+    }
+
+    #[tokio::test]
+    async fn no_requests_while_choked() {
+        let piece_manager =
+            PieceManager::new(&test_meta_info().build(), &std::env::temp_dir()).await;
+        let mut peer = Peer::new(None, SocketAddr::from(([127, 0, 0, 1], 1)));
+        peer.state.am_interested = true;
+
+        let result = peer.download_piece(&piece_manager, 0, 1).await;
+
+        assert!(matches!(result, Err(ConnectionErr::RequestNotAllowed)));
+    }
+
+    #[tokio::test]
+    async fn have_sent_for_completed_pieces() {
+        let meta_info = test_meta_info().data(b"abcdefgh").build();
+        let piece_manager = PieceManager::new(&meta_info, &std::env::temp_dir()).await;
+
+        let (addr, remote_peer) = fake_remote_peer().await;
+        let remote = tokio::spawn(async move {
+            let mut stream = remote_peer.await;
+
+            // Only answer the request once told about the other piece
+            let request = Message::from_stream(&mut stream).await.unwrap();
+            let have = Message::from_stream(&mut stream).await.unwrap();
+            let block = [&request.payload.unwrap()[..8], b"abcd"].concat();
+            let piece = Message::new(13, Some(MessageType::Piece as u8), Some(block.into()));
+            stream.write_all(&piece.to_bytes()).await.unwrap();
+            have
+        });
+
+        let mut peer = Peer::new(None, addr);
+        peer.torrent_events = Some(piece_manager.subscribe_events());
+        peer.connect(&Handshake::new([1u8; 20], *PeerId::generate().as_bytes()))
+            .await
+            .unwrap();
+        // Pretend we are interested and the peer unchoked us
+        peer.state.am_interested = true;
+        peer.state.peer_choking = false;
+        let (piece, _) = tokio::join!(
+            peer.download_piece(&piece_manager, 0, 4),
+            piece_manager.add_piece(&1, Bytes::from_static(b"efgh"))
+        );
+
+        assert_eq!(piece.unwrap().unwrap().data, Bytes::from_static(b"abcd"));
+        let have = remote.await.unwrap();
+        assert_eq!(have.id, Some(MessageType::Have as u8));
+        assert_eq!(have.payload.unwrap().as_ref(), [0, 0, 0, 1]);
+    }
+
+    #[tokio::test]
+    async fn have_during_piece_counted_as_available() {
+        let meta_info = test_meta_info().data(b"abcdefgh").build();
+        let piece_manager = PieceManager::new(&meta_info, &std::env::temp_dir()).await;
+
+        let (addr, remote_peer) = fake_remote_peer().await;
+        let remote = tokio::spawn(async move {
+            let mut stream = remote_peer.await;
+
+            let request = Message::from_stream(&mut stream).await.unwrap();
+            let block = [&request.payload.unwrap()[..8], b"abcd"].concat();
+            for message in [
+                Message::have(1),
+                Message::new(1, Some(MessageType::Interested as u8), None),
+                Message::new(1, Some(MessageType::Unchoke as u8), None),
+                Message::new(13, Some(MessageType::Piece as u8), Some(block.into())),
+            ] {
+                stream.write_all(&message.to_bytes()).await.unwrap();
+            }
+            stream
+        });
+
+        let mut peer = Peer::new(None, addr);
+        peer.connect(&Handshake::new([1u8; 20], *PeerId::generate().as_bytes()))
+            .await
+            .unwrap();
+        peer.state.am_interested = true;
+        peer.state.peer_choking = false;
+        let piece = peer.download_piece(&piece_manager, 0, 4).await;
+
+        assert_eq!(piece.unwrap().unwrap().data, Bytes::from_static(b"abcd"));
+        assert!(is_bit_set(&peer.pieces.lock().unwrap().bitfield, 1));
+        assert_eq!(piece_manager.piece_availability(1), 1);
+        assert!(peer.state.peer_interested);
+        drop(remote.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn messages_split_across_reads_reassembled() {
+        let sent = [
+            Message::new(1, Some(MessageType::Unchoke as u8), None),
+            Message::keep_alive(),
+            Message::have(7),
+            Message::new(
+                11,
+                Some(MessageType::Piece as u8),
+                Some(Bytes::from_static(&[0, 0, 0, 1, 0, 0, 0, 0, b'a', b'b'])),
+            ),
+            Message::cancel(1, 0, 2),
+        ];
+        let bytes: Vec<u8> = sent.iter().flat_map(|message| message.to_bytes()).collect();
+
+        let (addr, remote_peer) = fake_remote_peer().await;
+        tokio::spawn(async move {
+            let mut stream = remote_peer.await;
+
+            // Split messages and bundle the end of one with the start of the next
+            for chunk in bytes.chunks(3) {
+                stream.write_all(chunk).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        });
+
+        let mut peer = Peer::new(None, addr);
+        peer.connect(&Handshake::new([1u8; 20], *PeerId::generate().as_bytes()))
+            .await
+            .unwrap();
+
+        // Keep alives are consumed while reading
+        for expected in sent.iter().filter(|message| !message.is_keep_alive()) {
+            let message = peer.read_message().await.unwrap();
+            assert_eq!(message.to_bytes(), expected.to_bytes());
+        }
+        assert!(peer.read_buf.is_empty());
+    }
+
+    #[tokio::test]
+    async fn overlong_message_rejected() {
+        let (addr, remote_peer) = fake_remote_peer().await;
+        let remote = tokio::spawn(async move {
+            let mut stream = remote_peer.await;
+            let length = MAX_MESSAGE_LENGTH as u32 + 1;
+            stream.write_all(&length.to_be_bytes()).await.unwrap();
+            stream.write_all(&[MessageType::Piece as u8]).await.unwrap();
+            stream
+        });
+
+        let mut peer = Peer::new(None, addr);
+        peer.connect(&Handshake::new([1u8; 20], *PeerId::generate().as_bytes()))
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            peer.read_message().await,
+            Err(ConnectionErr::UnexpectedMessage(_))
+        ));
+        drop(remote.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn disconnect_mid_piece_requeues_it() {
+        // Enough two block pieces to stay out of endgame mode
+        let meta_info = test_meta_info()
+            .piece_length(BLOCK_SIZE * 2)
+            .length(BLOCK_SIZE * 20)
+            .build();
+        let piece_manager = PieceManager::new(&meta_info, &std::env::temp_dir()).await;
+        let their_bitfield = full_bitfield(10);
+
+        let (addr, remote_peer) = fake_remote_peer().await;
+        let bitfield = their_bitfield.clone();
+        let remote = tokio::spawn(async move {
+            let mut stream = remote_peer.await;
+
+            Message::from_stream(&mut stream).await.unwrap();
+            let length = bitfield.len() as u32 + 1;
+            let bitfield = Message::new(length, Some(MessageType::Bitfield as u8), Some(bitfield));
+            stream.write_all(&bitfield.to_bytes()).await.unwrap();
+            Message::from_stream(&mut stream).await.unwrap();
+            let unchoke = Message::new(1, Some(MessageType::Unchoke as u8), None);
+            stream.write_all(&unchoke.to_bytes()).await.unwrap();
+
+            // Hang up instead of answering the first request
+            Message::from_stream(&mut stream).await.unwrap()
+        });
+
+        let mut peer = Peer::new(None, addr);
+        let result = peer
+            .start(&piece_manager, Arc::new([1u8; 20]), &PeerId::generate())
+            .await;
+        let request = remote.await.unwrap();
+
+        assert!(result.is_err());
+        assert_eq!(request.payload.unwrap()[..4], [0, 0, 0, 0]);
+        assert_eq!(piece_manager.get_next_piece(&their_bitfield), Some(0));
     }
 }
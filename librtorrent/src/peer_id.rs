@@ -0,0 +1,70 @@
+use std::fmt::Display;
+
+/// Azureus-style client prefix: `-` + client code + 4 digit version + `-`
+pub const CLIENT_PREFIX: &[u8; 8] = b"-RB0001-";
+
+pub const PEER_ID_SIZE: usize = 20;
+
+/// The 20 byte ID sent to trackers and peers to identify this client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerId([u8; PEER_ID_SIZE]);
+
+impl PeerId {
+    /// Generates a new ID made of `CLIENT_PREFIX` followed by 12 random bytes
+    pub fn generate() -> Self {
+        Self::with_prefix(CLIENT_PREFIX)
+    }
+
+    /// Generates a new ID made of `prefix` followed by 12 random bytes
+    pub fn with_prefix(prefix: &[u8; 8]) -> Self {
+        let mut id = [0u8; PEER_ID_SIZE];
+        id[..prefix.len()].copy_from_slice(prefix);
+        id[prefix.len()..].copy_from_slice(&rand::random::<[u8; 12]>());
+        PeerId(id)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; PEER_ID_SIZE] {
+        &self.0
+    }
+}
+
+impl Default for PeerId {
+    fn default() -> Self {
+        Self::generate()
+    }
+}
+
+impl From<[u8; PEER_ID_SIZE]> for PeerId {
+    fn from(value: [u8; PEER_ID_SIZE]) -> Self {
+        PeerId(value)
+    }
+}
+
+impl Display for PeerId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            String::from_utf8_lossy(&self.0[..CLIENT_PREFIX.len()])
+        )?;
+        for byte in &self.0[CLIENT_PREFIX.len()..] {
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_ids_are_unique() {
+        let first = PeerId::generate();
+        let second = PeerId::generate();
+
+        assert_ne!(first, second);
+        assert!(first.as_bytes().starts_with(CLIENT_PREFIX));
+        assert!(second.as_bytes().starts_with(CLIENT_PREFIX));
+    }
+}
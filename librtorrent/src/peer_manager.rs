@@ -1,15 +1,50 @@
-use std::sync::Arc;
+use log::{debug, info, trace, warn};
+use std::{
+    collections::{HashMap, HashSet},
+    future::poll_fn,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
 use thiserror::Error;
-use tokio::sync::{mpsc, Mutex};
+use tokio::{
+    net::TcpStream,
+    sync::{broadcast, mpsc, watch, Mutex, OwnedSemaphorePermit, Semaphore},
+    task::{JoinError, JoinHandle, JoinSet},
+    time::Instant,
+};
 
 use crate::{
+    ban_list::BanList,
+    config::SessionConfig,
+    event::TorrentEvent,
+    handshake::Handshake,
     meta_info::MetaInfo,
-    peer::{Peer, PeerEvent},
+    mse::EncryptionMode,
+    peer::{ConnectionErr, Peer, PeerEvent, PeerState},
+    peer_id::PeerId,
     piece_manager::PieceManager,
-    tracker::{self, TrackerErr},
+    rate_limiter::RateLimits,
+    rate_tracker::TransferRates,
+    tracker::{self, Announce, GetResponse, TrackerClient, TrackerErr, TrackerEvent},
+    transport::TransportKind,
 };
 
 const DEFAULT_INTERVAL: usize = 600;
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(30);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(600);
+const MAX_ANNOUNCE_ATTEMPTS: usize = 4;
+/// Time peers get to disconnect cleanly when the manager stops
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+pub const DEFAULT_MAX_CONNECTIONS: usize = 50;
+/// Number of interested peers a torrent uploads to at once
+pub const UPLOAD_SLOTS: usize = 4;
+/// Peers found through PEX or local discovery are only queued while fewer
+/// than `max_connections` plus this many peers are connected or waiting
+const MAX_QUEUED_PEERS: usize = 50;
 
 #[derive(Debug, Error)]
 pub enum PeerManagerError {
@@ -19,89 +54,1715 @@ pub enum PeerManagerError {
     PeerStartFailed,
     #[error("Tracker error {0}")]
     TrackerError(#[from] TrackerErr),
+    #[error("Torrent has no tracker to announce to")]
+    NoTracker,
+}
+
+/// Mechanisms peers can be discovered through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerSource {
+    Tracker,
+    Dht,
+    Pex,
+    LocalDiscovery,
+}
+
+impl PeerSource {
+    /// Private torrents may only use the trackers listed in their meta info
+    pub fn is_allowed(&self, meta_info: &MetaInfo) -> bool {
+        matches!(self, PeerSource::Tracker) || !meta_info.is_private()
+    }
+}
+
+/// Feeds addresses of peers found outside the tracker to a `PeerManager`
+pub type DiscoveredPeers = mpsc::UnboundedSender<(PeerSource, Vec<SocketAddr>)>;
+type DiscoveredReceiver = mpsc::UnboundedReceiver<(PeerSource, Vec<SocketAddr>)>;
+
+/// Feeds connections peers opened to us to a `PeerManager`, along with the
+/// handshake read from them
+pub type IncomingPeers = mpsc::UnboundedSender<(SocketAddr, TcpStream, Handshake)>;
+type IncomingReceiver = mpsc::UnboundedReceiver<(SocketAddr, TcpStream, Handshake)>;
+
+/// What the manager knows about a connected peer, kept up to date from its events
+#[derive(Debug, Clone)]
+pub struct ConnectedPeer {
+    pub peer_id: Option<[u8; 20]>,
+    pub state: PeerState,
+    pub rates: TransferRates,
 }
 
 #[derive(Debug)]
 pub struct PeerManager {
-    peers: Arc<Mutex<Vec<Peer>>>,
-    sender: mpsc::Sender<PeerEvent>,
-    receiver: mpsc::Receiver<PeerEvent>,
+    peers: Arc<Mutex<HashMap<SocketAddr, ConnectedPeer>>>,
+    /// Taken by the event loop once it is started
+    receiver: Option<mpsc::Receiver<(SocketAddr, PeerEvent)>>,
+    event_loop: Option<JoinHandle<()>>,
+    /// Combined throughput of every peer of the torrent
+    rates: Arc<std::sync::Mutex<TransferRates>>,
+    /// Peers learned through PEX, forwarded by the event loop, or local
+    /// discovery
+    discovered_sender: DiscoveredPeers,
+    /// Taken by the dispatcher while the manager is started
+    discovered: Option<DiscoveredReceiver>,
+    incoming_sender: IncomingPeers,
+    /// Taken by the dispatcher while the manager is started, connections
+    /// arriving while stopped are dropped
+    incoming: Option<IncomingReceiver>,
+    /// Connects to discovered peers and accepts incoming ones until `stop`,
+    /// then hands back the receivers
+    dispatcher: Option<JoinHandle<(DiscoveredReceiver, IncomingReceiver)>>,
+    completion: Option<JoinHandle<()>>,
+    /// Announces to the tracker until `stop`, see `Announcer::find_peers`
+    announcing: Option<JoinHandle<()>>,
+    meta_info: Arc<MetaInfo>,
+    peer_id: PeerId,
+    announcer: Announcer,
+    piece_manager: Arc<PieceManager>,
+    spawner: PeerSpawner,
+    /// Set by `start` and cleared by `stop`
+    running: bool,
+    /// Whether the tracker should be told when we stop
+    announced: bool,
+    /// Set while re-announcing because the tracker had no peers for us
+    searching: watch::Sender<bool>,
+    encryption: EncryptionMode,
+}
+
+/// Spawns the tasks of a torrent's peers. Clones share the tasks and
+/// connection slots, so background tasks can connect to the peers they
+/// find without going through the manager.
+#[derive(Debug, Clone)]
+struct PeerSpawner {
+    meta_info: Arc<MetaInfo>,
+    peer_id: PeerId,
+    piece_manager: Arc<PieceManager>,
+    events: mpsc::Sender<(SocketAddr, PeerEvent)>,
+    /// Addresses of the connected peers, shared with peers for PEX
+    addresses: watch::Sender<HashSet<SocketAddr>>,
+    /// Set by `stop` to have peer tasks disconnect and finish
+    stopping: watch::Sender<bool>,
+    /// Addresses of peers with a task, whether connecting or connected, so
+    /// peers returned by several sources are only connected to once
+    known: Arc<std::sync::Mutex<HashSet<SocketAddr>>>,
+    /// Peers that failed to connect, shared with the session's other torrents
+    bans: Arc<BanList>,
+    tasks: Arc<std::sync::Mutex<JoinSet<()>>>,
+    rate_limits: RateLimits,
+    active_peers: Arc<AtomicUsize>,
+    max_connections: Arc<AtomicUsize>,
+    connection_slots: Arc<Semaphore>,
+    /// Slots to remove once their peers release them, as lowering the
+    /// connection limit can only remove free slots right away
+    surplus_slots: Arc<AtomicUsize>,
+    /// Shared by the peers we upload to, see `Peer::upload_slots`
+    upload_slots: Arc<Semaphore>,
+    encryption: EncryptionMode,
+    transport: TransportKind,
+    block_size: usize,
+}
+
+/// What the trackers answered last, updated by the tasks announcing in the
+/// background
+#[derive(Debug)]
+struct AnnounceState {
+    interval: usize,
+    /// When the tracker last answered an announce
+    last_announce: Option<SystemTime>,
+    /// Number of peers the tracker returned in its last answer
+    peer_count: usize,
+    /// When each tracker may be asked for peers again, when announcing to all
+    next_announce: HashMap<String, Instant>,
+}
+
+/// Announces a torrent to its trackers. Clones share the state of the last
+/// answers, so announces can run in tasks of their own.
+#[derive(Debug, Clone)]
+struct Announcer {
+    tracker: TrackerClient,
     meta_info: Arc<MetaInfo>,
-    new_peer_interval: usize,
     piece_manager: Arc<PieceManager>,
+    peer_id: PeerId,
+    /// Port announced to the tracker
+    port: u16,
+    /// Number of peers asked for in each announce
+    numwant: u32,
+    encryption: EncryptionMode,
+    /// Announce to every tracker at once and merge the peers they return
+    announce_to_all_trackers: bool,
+    retry_delay: Duration,
+    state: Arc<std::sync::Mutex<AnnounceState>>,
 }
 
 impl PeerManager {
-    pub async fn new(meta_info: Arc<MetaInfo>) -> Self {
-        let (tx, rx) = mpsc::channel::<PeerEvent>(64);
-        PeerManager {
-            peers: Arc::new(Mutex::new(Vec::new())),
-            sender: tx,
-            receiver: rx,
+    pub async fn new(
+        meta_info: Arc<MetaInfo>,
+        peer_id: PeerId,
+        tracker: TrackerClient,
+        rate_limits: RateLimits,
+        config: &SessionConfig,
+        bans: Arc<BanList>,
+    ) -> Self {
+        let (tx, rx) = mpsc::channel::<(SocketAddr, PeerEvent)>(64);
+        let (discovered_sender, discovered) = mpsc::unbounded_channel();
+        let (incoming_sender, incoming) = mpsc::unbounded_channel();
+        let piece_manager =
+            PieceManager::with_state_dir(&meta_info, &config.download_dir, &config.state_dir).await;
+        piece_manager.set_max_pieces_in_progress(config.max_pieces_in_progress());
+        piece_manager.set_block_size(config.block_size());
+        piece_manager.set_strategy(config.piece_strategy);
+        let piece_manager = Arc::new(piece_manager);
+        let announcer = Announcer {
+            tracker,
             meta_info: meta_info.clone(),
-            new_peer_interval: DEFAULT_INTERVAL,
-            piece_manager: Arc::new(PieceManager::new(&meta_info.clone()).await),
+            piece_manager: piece_manager.clone(),
+            peer_id,
+            port: config.port,
+            numwant: config.numwant,
+            encryption: config.encryption,
+            announce_to_all_trackers: config.announce_to_all_trackers,
+            retry_delay: MIN_RETRY_DELAY,
+            state: Arc::new(std::sync::Mutex::new(AnnounceState {
+                interval: DEFAULT_INTERVAL,
+                last_announce: None,
+                peer_count: 0,
+                next_announce: HashMap::new(),
+            })),
+        };
+        let spawner = PeerSpawner {
+            meta_info: meta_info.clone(),
+            peer_id,
+            piece_manager: piece_manager.clone(),
+            events: tx,
+            addresses: watch::Sender::new(HashSet::new()),
+            stopping: watch::Sender::new(false),
+            known: Arc::new(std::sync::Mutex::new(HashSet::new())),
+            bans,
+            tasks: Arc::new(std::sync::Mutex::new(JoinSet::new())),
+            rate_limits,
+            active_peers: Arc::new(AtomicUsize::new(0)),
+            max_connections: Arc::new(AtomicUsize::new(config.max_connections)),
+            connection_slots: Arc::new(Semaphore::new(config.max_connections)),
+            surplus_slots: Arc::new(AtomicUsize::new(0)),
+            upload_slots: Arc::new(Semaphore::new(UPLOAD_SLOTS)),
+            encryption: config.encryption,
+            transport: config.transport,
+            block_size: config.block_size(),
+        };
+        PeerManager {
+            peers: Arc::new(Mutex::new(HashMap::new())),
+            receiver: Some(rx),
+            event_loop: None,
+            rates: Arc::new(std::sync::Mutex::new(TransferRates::default())),
+            discovered_sender,
+            discovered: Some(discovered),
+            incoming_sender,
+            incoming: Some(incoming),
+            dispatcher: None,
+            completion: None,
+            announcing: None,
+            meta_info,
+            peer_id,
+            announcer,
+            piece_manager,
+            spawner,
+            running: false,
+            announced: false,
+            searching: watch::Sender::new(false),
+            encryption: config.encryption,
+        }
+    }
+
+    /// Announces to the tracker in the background every interval it asks
+    /// for and connects to the peers it returns, and to discovered peers,
+    /// until `stop`. Use `wait` to block until the peer tasks finish.
+    pub fn start(&mut self) {
+        let complete = self.piece_manager.is_complete();
+        let has_tracker = !self.meta_info.trackers().is_empty();
+        if complete {
+            info!(
+                "Torrent {} already complete, seeding",
+                self.meta_info.info.name
+            );
+        } else if has_tracker {
+            self.completion = Some(tokio::spawn(Self::announce_completion(
+                self.piece_manager.subscribe_complete(),
+                self.announcer.tracker.clone(),
+                self.meta_info.clone(),
+                self.peer_id,
+                self.announcer.port,
+                self.encryption,
+            )));
+        }
+
+        self.running = true;
+        self.announced = has_tracker;
+        self.start_event_loop();
+        self.start_dispatcher();
+        self.searching.send_replace(true);
+        if let Some(announcing) = self.announcing.take() {
+            announcing.abort();
+        }
+        let find_peers = self
+            .announcer
+            .clone()
+            .find_peers(self.spawner.clone(), self.searching.clone());
+        // Ends along with the manager even if it is dropped without `stop`
+        let mut stopping = self.spawner.stopping.subscribe();
+        self.announcing = Some(tokio::spawn(async move {
+            tokio::select! {
+                _ = find_peers => {}
+                _ = stopped(&mut stopping) => {}
+            }
+        }));
+    }
+
+    /// Whether the tracker had no peers for us yet and we are still asking it
+    pub fn is_searching(&self) -> bool {
+        *self.searching.borrow()
+    }
+
+    /// Whether `source` may be queried for peers of this torrent.
+    /// DHT, PEX and local discovery should check this before doing any work.
+    pub fn wants_peers_from(&self, source: PeerSource) -> bool {
+        source.is_allowed(&self.meta_info)
+    }
+
+    /// Connects to peers found through `source`, returning how many were accepted.
+    /// Peers from sources not allowed for this torrent are dropped.
+    pub fn add_peers(&mut self, source: PeerSource, peers: Vec<Peer>) -> usize {
+        self.start_event_loop();
+        self.spawner.add(source, peers)
+    }
+
+    /// Waits for the download to complete and tells the tracker about it
+    async fn announce_completion(
+        mut complete: watch::Receiver<bool>,
+        tracker: TrackerClient,
+        meta_info: Arc<MetaInfo>,
+        peer_id: PeerId,
+        port: u16,
+        encryption: EncryptionMode,
+    ) {
+        if complete.wait_for(|complete| *complete).await.is_err() {
+            return;
+        }
+
+        info!("Torrent {} completed, now seeding", meta_info.info.name);
+        // No peers are needed once the download is done
+        if let Err(err) = tracker
+            .send_get_request(
+                &meta_info,
+                &peer_id,
+                port,
+                0,
+                encryption,
+                Announce {
+                    event: Some(TrackerEvent::Completed),
+                    left: 0,
+                },
+            )
+            .await
+        {
+            warn!("Failed to send completed event to tracker: {err}");
+        }
+    }
+
+    /// Sets how many peers may be connected at once. Lowering the limit
+    /// does not drop existing connections, it only delays new ones.
+    pub fn set_max_connections(&mut self, max_connections: usize) {
+        let spawner = &self.spawner;
+        let current = spawner
+            .max_connections
+            .swap(max_connections, Ordering::Relaxed);
+        if max_connections > current {
+            let added = max_connections - current;
+            let kept = spawner
+                .surplus_slots
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |surplus| {
+                    Some(surplus.saturating_sub(added))
+                })
+                .unwrap();
+            spawner
+                .connection_slots
+                .add_permits(added - kept.min(added));
+        } else {
+            let removed = current - max_connections;
+            let forgotten = spawner.connection_slots.forget_permits(removed);
+            spawner
+                .surplus_slots
+                .fetch_add(removed - forgotten, Ordering::Relaxed);
+        }
+    }
+
+    /// Sets the port announced to the tracker from the next announce on
+    pub fn set_port(&mut self, port: u16) {
+        self.announcer.port = port;
+    }
+
+    /// Number of peers currently being communicated with
+    pub fn peer_count(&self) -> usize {
+        self.spawner.active_peers.load(Ordering::Relaxed)
+    }
+
+    /// Bytes per second downloaded from all peers over the last few seconds
+    pub fn download_rate(&self) -> u64 {
+        self.rates.lock().unwrap().download.rate()
+    }
+
+    /// Bytes per second uploaded to all peers over the last few seconds
+    pub fn upload_rate(&self) -> u64 {
+        self.rates.lock().unwrap().upload.rate()
+    }
+
+    /// When the tracker last answered an announce
+    pub fn last_announce(&self) -> Option<SystemTime> {
+        self.announcer.state.lock().unwrap().last_announce
+    }
+
+    /// When the tracker may be asked for peers again
+    pub fn next_announce(&self) -> Option<SystemTime> {
+        let state = self.announcer.state.lock().unwrap();
+        state
+            .last_announce
+            .map(|last| last + Duration::from_secs(state.interval as u64))
+    }
+
+    /// Number of peers the tracker returned in its last answer
+    pub fn tracker_peer_count(&self) -> usize {
+        self.announcer.state.lock().unwrap().peer_count
+    }
+
+    /// Whether the manager was started and has not stopped since, whether
+    /// or not the torrent has a tracker
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Snapshot of the peers currently connected, keyed by address
+    pub async fn connected_peers(&self) -> HashMap<SocketAddr, ConnectedPeer> {
+        self.peers.lock().await.clone()
+    }
+
+    pub fn piece_manager(&self) -> &Arc<PieceManager> {
+        &self.piece_manager
+    }
+
+    /// Sender for peers found by other means than the tracker, which are
+    /// connected to while the manager is started or `wait` runs
+    pub fn discovered_peers(&self) -> DiscoveredPeers {
+        self.discovered_sender.clone()
+    }
+
+    /// Sender for connections peers opened to us, which are accepted while
+    /// the manager is started and connection slots are free
+    pub fn incoming_peers(&self) -> IncomingPeers {
+        self.incoming_sender.clone()
+    }
+
+    /// Waits for the tracker to return peers and every running peer task to
+    /// finish, connecting to peers discovered through PEX or local
+    /// discovery in the meantime
+    pub async fn wait(&mut self) {
+        // Gives up once the manager is stopped, which clears the flag
+        let _ = self
+            .searching
+            .subscribe()
+            .wait_for(|searching| !*searching)
+            .await;
+        loop {
+            tokio::select! {
+                // Peers sent by a task that just finished are connected to
+                // before running out of tasks ends the wait
+                biased;
+                Some((source, addrs)) = recv_discovered(&mut self.discovered) => {
+                    self.connect_discovered(source, addrs).await;
+                }
+                result = self.spawner.join_next() => match result {
+                    Some(Err(err)) if err.is_panic() => panic!("Task panicked: {err}"),
+                    Some(_) => {}
+                    None => break,
+                },
+            }
+        }
+    }
+
+    /// Connects to the peers learned through `source` that we aren't
+    /// connected to yet, see `PeerSpawner::connect`
+    async fn connect_discovered(&mut self, source: PeerSource, addrs: Vec<SocketAddr>) -> usize {
+        self.start_event_loop();
+        self.spawner.connect(source, addrs)
+    }
+
+    /// Disconnects from all peers, aborting the tasks that don't finish in
+    /// time, and, if we announced ourselves, tells the tracker we are stopping.
+    pub async fn stop(&mut self) {
+        let spawner = &self.spawner;
+        spawner.stopping.send_replace(true);
+        if let Some(dispatcher) = self.dispatcher.take() {
+            if let Ok((discovered, mut incoming)) = dispatcher.await {
+                while incoming.try_recv().is_ok() {}
+                self.discovered = Some(discovered);
+                self.incoming = Some(incoming);
+            }
+        }
+        let _ = tokio::time::timeout(DISCONNECT_TIMEOUT, async {
+            while spawner.join_next().await.is_some() {}
+        })
+        .await;
+        spawner.tasks.lock().unwrap().abort_all();
+        while spawner.join_next().await.is_some() {}
+        // Pieces of peers that were cut off are picked up after a restart
+        self.piece_manager.requeue_in_progress();
+        spawner.stopping.send_replace(false);
+        spawner.known.lock().unwrap().clear();
+        for task in [self.completion.take(), self.announcing.take()]
+            .into_iter()
+            .flatten()
+        {
+            task.abort();
+        }
+        spawner.active_peers.store(0, Ordering::Relaxed);
+        self.searching.send_replace(false);
+        self.peers.lock().await.clear();
+        spawner.addresses.send_replace(HashSet::new());
+
+        self.running = false;
+        if self.announced {
+            self.announced = false;
+            let announcer = &self.announcer;
+            if let Err(err) = announcer
+                .tracker
+                .send_get_request(
+                    &self.meta_info,
+                    &self.peer_id,
+                    announcer.port,
+                    0,
+                    self.encryption,
+                    announcer.announce_state(Some(TrackerEvent::Stopped)),
+                )
+                .await
+            {
+                warn!("Failed to send stopped event to tracker: {err}");
+            }
         }
     }
 
-    pub async fn start(&mut self) -> Result<(), PeerManagerError> {
-        //todo!("Add peer manager start function")
-        let peers = self.get_new_peers().await?;
-        let hash = Arc::new(self.meta_info.hash);
+    /// Spawns `main_loop` if it is not running yet
+    fn start_event_loop(&mut self) {
+        if let Some(receiver) = self.receiver.take() {
+            self.event_loop = Some(tokio::spawn(Self::main_loop(
+                receiver,
+                self.peers.clone(),
+                self.rates.clone(),
+                self.spawner.addresses.clone(),
+                self.discovered_sender.clone(),
+                self.piece_manager.events(),
+            )));
+        }
+    }
+
+    /// Spawns the task connecting to discovered peers and accepting
+    /// incoming ones if it is not running yet
+    fn start_dispatcher(&mut self) {
+        let (Some(mut discovered), Some(mut incoming)) =
+            (self.discovered.take(), self.incoming.take())
+        else {
+            return;
+        };
+        let spawner = self.spawner.clone();
+        let mut stopping = spawner.stopping.subscribe();
+        self.dispatcher = Some(tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    Some((source, addrs)) = discovered.recv() => {
+                        spawner.connect(source, addrs);
+                    }
+                    Some((addr, stream, handshake)) = incoming.recv() => {
+                        spawner.accept(addr, stream, handshake);
+                    }
+                    _ = stopped(&mut stopping) => break,
+                }
+            }
+            (discovered, incoming)
+        }));
+    }
+
+    /// Processes the events reported by peer tasks until every sender is dropped
+    async fn main_loop(
+        mut receiver: mpsc::Receiver<(SocketAddr, PeerEvent)>,
+        peers: Arc<Mutex<HashMap<SocketAddr, ConnectedPeer>>>,
+        rates: Arc<std::sync::Mutex<TransferRates>>,
+        addresses: watch::Sender<HashSet<SocketAddr>>,
+        discovered: DiscoveredPeers,
+        events: broadcast::Sender<TorrentEvent>,
+    ) {
+        while let Some((addr, event)) = receiver.recv().await {
+            trace!("Peer @ {addr}: {event:?}");
+            let mut peers = peers.lock().await;
 
-        let mut handles = Vec::new();
-        for mut peer in peers {
-            let pm = self.piece_manager.clone();
-            let h = hash.clone();
-            handles.push(tokio::spawn(async move {
-                match peer.start(&pm, h).await {
-                    Ok(_) => {}
-                    Err(err) => {
-                        println!("Error starting peer: {}", err);
+            match event {
+                PeerEvent::HandshakeReceived(handshake) => {
+                    peers.insert(
+                        addr,
+                        ConnectedPeer {
+                            peer_id: Some(handshake.peer_id),
+                            state: PeerState::default(),
+                            rates: TransferRates::default(),
+                        },
+                    );
+                }
+                PeerEvent::Connected => {
+                    debug!("Peer @ {addr}: connected");
+                    peers
+                        .entry(addr)
+                        .or_insert(ConnectedPeer {
+                            peer_id: None,
+                            state: PeerState::default(),
+                            rates: TransferRates::default(),
+                        })
+                        .state = PeerState::default();
+                    addresses.send_modify(|addresses| {
+                        addresses.insert(addr);
+                    });
+                    let _ = events.send(TorrentEvent::PeerConnected(addr));
+                }
+                PeerEvent::Disconnected => {
+                    debug!("Peer @ {addr}: disconnected");
+                    peers.remove(&addr);
+                    addresses.send_modify(|addresses| {
+                        addresses.remove(&addr);
+                    });
+                    let _ = events.send(TorrentEvent::PeerDisconnected(addr));
+                }
+                PeerEvent::PeersDiscovered(addrs) => {
+                    // Only fails once the manager is gone
+                    let _ = discovered.send((PeerSource::Pex, addrs));
+                }
+                PeerEvent::MessageReceived(message) => {
+                    if let Some(length) = message.block_length() {
+                        rates.lock().unwrap().download.record(length as u64);
+                        if let Some(peer) = peers.get_mut(&addr) {
+                            peer.rates.download.record(length as u64);
+                        }
+                    }
+                    if let Some(peer) = peers.get_mut(&addr) {
+                        peer.state.received(&message);
                     }
                 }
-            }));
+                PeerEvent::MessageSent(message) => {
+                    if let Some(length) = message.block_length() {
+                        rates.lock().unwrap().upload.record(length as u64);
+                        if let Some(peer) = peers.get_mut(&addr) {
+                            peer.rates.upload.record(length as u64);
+                        }
+                    }
+                    if let Some(peer) = peers.get_mut(&addr) {
+                        peer.state.sent(&message);
+                    }
+                }
+                PeerEvent::HandshakeSent(_) => {}
+            }
         }
+    }
+}
 
-        for handle in handles {
-            handle.await.expect("Task panicked");
+impl Announcer {
+    /// Announces that we started, then announces again every interval the
+    /// tracker asks for, which already honours its min interval, and
+    /// connects to the peers returned. `searching` is cleared once the
+    /// tracker returned peers or the torrent is complete. Failed announces
+    /// are retried with exponential backoff, ones the tracker refused end
+    /// announcing.
+    async fn find_peers(self, spawner: PeerSpawner, searching: watch::Sender<bool>) {
+        let mut event = Some(TrackerEvent::Started);
+        let mut backoff = self.retry_delay;
+        loop {
+            let delay = match self.get_new_peers(event).await {
+                Ok(peers) => {
+                    if !peers.is_empty() || self.piece_manager.is_complete() {
+                        searching.send_replace(false);
+                    } else if event.is_some() {
+                        info!("Tracker returned no peers, searching");
+                    }
+                    spawner.spawn(peers);
+                    event = None;
+                    backoff = self.retry_delay;
+                    self.interval_delay(self.state.lock().unwrap().interval)
+                }
+                // Web seeded torrents may only be found through other sources
+                Err(PeerManagerError::NoTracker) => {
+                    info!("Torrent {} has no tracker", self.meta_info.info.name);
+                    searching.send_replace(false);
+                    return;
+                }
+                Err(PeerManagerError::TrackerError(err)) if !err.is_transient() => {
+                    warn!("Announce failed: {err}, no longer announcing");
+                    searching.send_replace(false);
+                    return;
+                }
+                Err(err) => {
+                    let delay = backoff;
+                    backoff = (backoff * 2).min(MAX_RETRY_DELAY);
+                    warn!("Announce failed: {err}, retrying in {delay:?}");
+                    delay
+                }
+            };
+            trace!("Announcing again in {delay:?}");
+            tokio::time::sleep(delay).await;
         }
+    }
 
-        // tokio::spawn(async {
-        //     self.main_loop().await;
-        // });
-        //
-        Ok(())
+    /// Time to wait for an announce interval of `interval` seconds, never
+    /// less than `retry_delay`
+    fn interval_delay(&self, interval: usize) -> Duration {
+        Duration::from_secs(interval as u64).max(self.retry_delay)
     }
 
-    async fn main_loop(&mut self) {
-        // Main loop for the peer manager
+    /// Sends a peer request to the tracker and returns a vector of Peers,
+    /// recording how many there were and when.
+    /// Transient failures are retried with exponential backoff.
+    async fn get_new_peers(
+        &self,
+        event: Option<TrackerEvent>,
+    ) -> Result<Vec<Peer>, PeerManagerError> {
+        let mut delay = self.retry_delay;
+        let mut attempt = 1;
+
         loop {
-            if let Some(event) = self.receiver.recv().await {
-                match event {
-                    _ => todo!("Add peer manager reciever event handling"),
+            match self.announce(event).await {
+                Err(PeerManagerError::TrackerError(err))
+                    if err.is_transient() && attempt < MAX_ANNOUNCE_ATTEMPTS =>
+                {
+                    warn!("Announce failed: {err}, retrying in {delay:?}");
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(MAX_RETRY_DELAY);
+                    attempt += 1;
+                }
+                Ok(peers) => {
+                    let mut state = self.state.lock().unwrap();
+                    info!(
+                        "Tracker returned {} peers, next announce in {}s",
+                        peers.len(),
+                        state.interval
+                    );
+                    state.last_announce = Some(SystemTime::now());
+                    state.peer_count = peers.len();
+                    return Ok(peers);
                 }
+                result => return result,
+            }
+        }
+    }
+
+    /// `event` along with how much of the torrent we still need
+    fn announce_state(&self, event: Option<TrackerEvent>) -> Announce {
+        Announce {
+            event,
+            left: self.piece_manager.left_bytes(),
+        }
+    }
+
+    /// Sends a single peer request to the tracker and returns a vector of Peers
+    /// Also updates the announce interval (in seconds) from tracker response
+    async fn announce(&self, event: Option<TrackerEvent>) -> Result<Vec<Peer>, PeerManagerError> {
+        if self.meta_info.trackers().is_empty() {
+            return Err(PeerManagerError::NoTracker);
+        }
+        if self.announce_to_all_trackers && self.meta_info.trackers().len() > 1 {
+            return self.announce_to_all(event).await;
+        }
+
+        let response = self
+            .tracker
+            .send_get_request(
+                &self.meta_info,
+                &self.peer_id,
+                self.port,
+                self.numwant,
+                self.encryption,
+                self.announce_state(event),
+            )
+            .await;
+        let result = response
+            .map_err(PeerManagerError::from)
+            .and_then(Self::read_response);
+        self.piece_manager.emit(announce_event(&result));
+        let (peers, interval) = result?;
+        if let Some(interval) = interval {
+            self.state.lock().unwrap().interval = interval;
+        }
+
+        Ok(peers)
+    }
+
+    /// Announces to every tracker concurrently, skipping those whose interval
+    /// has not passed yet unless `event` is set, and merges the peers they
+    /// return. Fails only if every tracker announced to fails.
+    async fn announce_to_all(
+        &self,
+        event: Option<TrackerEvent>,
+    ) -> Result<Vec<Peer>, PeerManagerError> {
+        let now = Instant::now();
+        let mut requests = JoinSet::new();
+        for announce in self.meta_info.trackers() {
+            let due = self
+                .state
+                .lock()
+                .unwrap()
+                .next_announce
+                .get(announce)
+                .is_none_or(|next| *next <= now);
+            if event.is_none() && !due {
+                trace!("Not announcing to {announce} before its interval");
+                continue;
+            }
+
+            let url = tracker::announce_url(
+                announce,
+                &self.meta_info,
+                &self.peer_id,
+                self.port,
+                self.numwant,
+                self.encryption,
+                self.announce_state(event),
+            )?;
+            let client = self.tracker.clone();
+            let announce = announce.clone();
+            requests.spawn(async move { (announce, client.send_announce(url).await) });
+        }
+
+        let mut peers = Vec::new();
+        let mut seen = HashSet::new();
+        let mut error = None;
+        let mut succeeded = false;
+        while let Some(Ok((announce, response))) = requests.join_next().await {
+            let result = response
+                .map_err(PeerManagerError::from)
+                .and_then(Self::read_response);
+            self.piece_manager.emit(announce_event(&result));
+            let (tracker_peers, interval) = match result {
+                Ok(result) => result,
+                Err(err) => {
+                    warn!("Announce to {announce} failed: {err}");
+                    error.get_or_insert(err);
+                    continue;
+                }
+            };
+
+            succeeded = true;
+            let interval = interval.unwrap_or(DEFAULT_INTERVAL);
+            self.state
+                .lock()
+                .unwrap()
+                .next_announce
+                .insert(announce, now + Duration::from_secs(interval as u64));
+            peers.extend(
+                tracker_peers
+                    .into_iter()
+                    .filter(|peer| seen.insert(peer.addr)),
+            );
+        }
+
+        // Announce again as soon as the first tracker allows it
+        let mut state = self.state.lock().unwrap();
+        if let Some(next) = state.next_announce.values().min() {
+            state.interval = next.saturating_duration_since(now).as_secs() as usize;
+        }
+
+        match error {
+            Some(err) if !succeeded => Err(err),
+            _ => Ok(peers),
+        }
+    }
+
+    /// Checks a tracker's response, returning its peers and the seconds until
+    /// it may be announced to again
+    fn read_response(res: GetResponse) -> Result<(Vec<Peer>, Option<usize>), PeerManagerError> {
+        if let Some(reason) = res.failure_reason {
+            return Err(TrackerErr::Failure(reason).into());
+        }
+
+        if let Some(message) = res.warning_message {
+            warn!("Tracker warning: {message}");
+        }
+
+        // The min interval is a hard floor on how often we may announce
+        let interval = res
+            .interval
+            .max(res.min_interval)
+            .map(|interval| interval.max(0) as usize);
+
+        match res.peers {
+            Some(peers) => Ok((peers, interval)),
+            None => Err(PeerManagerError::ConnectionFailed),
+        }
+    }
+}
+
+impl PeerSpawner {
+    /// Connects to peers found through `source`, returning how many were accepted.
+    /// Peers from sources not allowed for this torrent are dropped.
+    fn add(&self, source: PeerSource, peers: Vec<Peer>) -> usize {
+        if !source.is_allowed(&self.meta_info) {
+            debug!(
+                "Ignoring {} peers from {source:?} for private torrent",
+                peers.len()
+            );
+            return 0;
+        }
+
+        self.spawn(peers)
+    }
+
+    /// Connects to the peers learned through `source` that we aren't
+    /// connected to yet and that aren't banned, leaving out the ones past
+    /// `MAX_QUEUED_PEERS`
+    fn connect(&self, source: PeerSource, addrs: Vec<SocketAddr>) -> usize {
+        let peers: Vec<Peer> = {
+            let known = self.known.lock().unwrap();
+            let max_connections = self.max_connections.load(Ordering::Relaxed);
+            let room = (max_connections + MAX_QUEUED_PEERS).saturating_sub(known.len());
+            let new: HashSet<SocketAddr> = addrs
+                .into_iter()
+                .filter(|addr| !known.contains(addr) && !self.bans.is_banned(addr))
+                .collect();
+            if new.len() > room {
+                debug!(
+                    "Dropping {} peers discovered through {source:?}, too many queued",
+                    new.len() - room
+                );
+            }
+            new.into_iter()
+                .take(room)
+                .map(|addr| Peer::new(None, addr))
+                .collect()
+        };
+
+        let added = self.add(source, peers);
+        debug!("Connecting to {added} peers discovered through {source:?}");
+        added
+    }
+
+    /// Spawns a task for every peer that isn't banned, returning how many were
+    /// spawned. At most `max_connections` peers are connected at once, the
+    /// rest wait for a connection to drop.
+    fn spawn(&self, peers: Vec<Peer>) -> usize {
+        let mut spawned = 0;
+
+        for peer in peers {
+            if self.bans.is_banned(&peer.addr) {
+                debug!("Skipping banned peer {}", peer.addr);
+                continue;
+            }
+            if !self.known.lock().unwrap().insert(peer.addr) {
+                trace!("Skipping known peer {}", peer.addr);
+                continue;
             }
+            spawned += 1;
+            self.spawn_task(peer, None);
         }
+
+        spawned
     }
 
-    /// Sends a peer request to the tracker and returns a vector of Peers
-    /// Also updates self.new_peer_inverval (in seconds) from tracker response
-    async fn get_new_peers(&mut self) -> Result<Vec<Peer>, PeerManagerError> {
-        let response = tracker::send_get_request(&self.meta_info).await;
-        match response {
-            Ok(res) => {
-                if let Some(interval) = res.interval {
-                    self.new_peer_interval = interval as usize;
+    /// Spawns a task for the peer that connected to us from `addr`, unless
+    /// it is banned, already known or every connection slot is taken.
+    /// Returns whether it was accepted.
+    fn accept(&self, addr: SocketAddr, stream: TcpStream, handshake: Handshake) -> bool {
+        if self.bans.is_banned(&addr) {
+            debug!("Refusing banned peer {addr}");
+            return false;
+        }
+        let Ok(permit) = self.connection_slots.clone().try_acquire_owned() else {
+            debug!("Refusing peer {addr}, too many connections");
+            return false;
+        };
+        if !self.known.lock().unwrap().insert(addr) {
+            trace!("Refusing known peer {addr}");
+            return false;
+        }
+
+        self.spawn_task(Peer::new(None, addr), Some((permit, stream, handshake)));
+        true
+    }
+
+    /// Runs `peer` in a task once it has a connection slot. A peer that
+    /// connected to us comes with its slot, stream and handshake.
+    fn spawn_task(
+        &self,
+        mut peer: Peer,
+        incoming: Option<(OwnedSemaphorePermit, TcpStream, Handshake)>,
+    ) {
+        peer.rate_limits = self.rate_limits.clone();
+        peer.events = Some(self.events.clone());
+        peer.encryption = self.encryption;
+        peer.transport = self.transport;
+        peer.block_size = self.block_size;
+        peer.upload_slots = Some(self.upload_slots.clone());
+        peer.torrent_events = Some(self.piece_manager.subscribe_events());
+        if PeerSource::Pex.is_allowed(&self.meta_info) {
+            peer.pex = Some(self.addresses.subscribe());
+        }
+        let pm = self.piece_manager.clone();
+        let h = Arc::new(self.meta_info.hash);
+        let active_peers = self.active_peers.clone();
+        let peer_id = self.peer_id;
+        let connection_slots = self.connection_slots.clone();
+        let surplus_slots = self.surplus_slots.clone();
+        let bans = self.bans.clone();
+        let mut stopping = self.stopping.subscribe();
+        let known = self.known.clone();
+        self.tasks.lock().unwrap().spawn(async move {
+            let (permit, incoming) = match incoming {
+                Some((permit, stream, handshake)) => (permit, Some((stream, handshake))),
+                None => {
+                    let Ok(permit) = connection_slots.acquire_owned().await else {
+                        known.lock().unwrap().remove(&peer.addr);
+                        return;
+                    };
+                    (permit, None)
+                }
+            };
+            let _slot = ConnectionSlot {
+                permit: Some(permit),
+                surplus: surplus_slots,
+            };
+            active_peers.fetch_add(1, Ordering::Relaxed);
+            let result = tokio::select! {
+                result = async {
+                    match incoming {
+                        Some((stream, handshake)) => {
+                            peer.start_incoming(stream, handshake, &pm, h, &peer_id).await
+                        }
+                        None => peer.start(&pm, h, &peer_id).await,
+                    }
+                } => result,
+                _ = stopped(&mut stopping) => {
+                    peer.disconnect().await;
+                    Ok(())
                 }
+            };
+            match result {
+                Ok(_) => bans.record_success(peer.addr),
+                Err(err) if err.is_connect_failure() => bans.record_failure(peer.addr),
+                Err(
+                    err @ (ConnectionErr::CorruptPieces(_) | ConnectionErr::InvalidRequests(_)),
+                ) => {
+                    warn!("Banning peer {}: {err}", peer.addr);
+                    bans.ban(peer.addr);
+                }
+                Err(err) => {
+                    bans.record_success(peer.addr);
+                    warn!("Peer disconnected with error: {err}");
+                }
+            }
+            // Forgotten so it can be rediscovered and connected to again
+            known.lock().unwrap().remove(&peer.addr);
+            active_peers.fetch_sub(1, Ordering::Relaxed);
+        });
+    }
+
+    /// Waits for the next peer task to finish, `None` once there are none
+    async fn join_next(&self) -> Option<Result<(), JoinError>> {
+        poll_fn(|cx| self.tasks.lock().unwrap().poll_join_next(cx)).await
+    }
+}
+
+/// A connection slot held by a peer task. Dropping it frees the slot, or
+/// removes it if lowering the connection limit left too many.
+struct ConnectionSlot {
+    permit: Option<OwnedSemaphorePermit>,
+    surplus: Arc<AtomicUsize>,
+}
 
-                if let Some(peers_vec) = res.peers {
-                    Ok(peers_vec)
-                } else {
-                    Err(PeerManagerError::ConnectionFailed)
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let surplus = self
+            .surplus
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |surplus| {
+                surplus.checked_sub(1)
+            });
+        if let (Ok(_), Some(permit)) = (surplus, self.permit.take()) {
+            permit.forget();
+        }
+    }
+}
+
+/// Event reporting the outcome of an announce checked by `read_response`
+fn announce_event(result: &Result<(Vec<Peer>, Option<usize>), PeerManagerError>) -> TorrentEvent {
+    match result {
+        Ok((peers, _)) => TorrentEvent::Announced { peers: peers.len() },
+        Err(err) => TorrentEvent::AnnounceFailed(err.to_string()),
+    }
+}
+
+/// Receives from `discovered` unless the dispatcher took it
+async fn recv_discovered(
+    discovered: &mut Option<DiscoveredReceiver>,
+) -> Option<(PeerSource, Vec<SocketAddr>)> {
+    match discovered {
+        Some(discovered) => discovered.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Resolves once `stop` asks the manager's tasks to finish
+async fn stopped(stopping: &mut watch::Receiver<bool>) {
+    // An error means the manager is gone, which is as good as stopping
+    let _ = stopping.wait_for(|stopping| *stopping).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use crate::{
+        ban_list::{BAN_DURATIONS, CORRUPT_PIECES_BEFORE_BAN, FAILURES_BEFORE_BAN},
+        bencode::{BencodeMap, BencodeMapEncoder, BencodeType},
+        config::DEFAULT_PORT,
+        message::{Message, MessageType},
+        test_util::{fake_remote_peer, test_meta_info},
+    };
+
+    use super::*;
+
+    fn tracker_body(entries: Vec<(&str, BencodeType)>) -> Vec<u8> {
+        let mut map = BencodeMap::new();
+        for (key, value) in entries {
+            map.insert(key.into(), value);
+        }
+        map.get_encode()
+    }
+
+    async fn test_peer_manager(announce: String) -> PeerManager {
+        peer_manager_for(test_meta_info().announce(announce).build()).await
+    }
+
+    async fn peer_manager_for(meta_info: MetaInfo) -> PeerManager {
+        PeerManager::new(
+            Arc::new(meta_info),
+            PeerId::generate(),
+            TrackerClient::default(),
+            RateLimits::default(),
+            &SessionConfig::builder()
+                .state_dir(std::env::temp_dir())
+                .build()
+                .unwrap(),
+            Arc::new(BanList::new()),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn announce_retries_transient_failures() {
+        let mut peer = BencodeMap::new();
+        peer.insert("ip".into(), BencodeType::String(b"10.0.0.1".to_vec()));
+        peer.insert("port".into(), BencodeType::Integer(6881));
+        let responses = [
+            Some(tracker_body(vec![(
+                "failure reason",
+                BencodeType::String(b"overloaded".to_vec()),
+            )])),
+            None,
+            Some(tracker_body(vec![
+                ("interval", BencodeType::Integer(900)),
+                (
+                    "peers",
+                    BencodeType::List(vec![BencodeType::Dictionary(peer)]),
+                ),
+            ])),
+        ];
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            for response in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 1024];
+                let n = stream.read(&mut buf).await.unwrap();
+                assert!(n > 0);
+                // Dropping the connection without a response simulates a network error
+                if let Some(body) = response {
+                    let header = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        body.len()
+                    );
+                    stream.write_all(header.as_bytes()).await.unwrap();
+                    stream.write_all(&body).await.unwrap();
                 }
             }
-            Err(err) => Err(PeerManagerError::TrackerError(err)),
+        });
+
+        let mut peer_manager = test_peer_manager(format!("http://127.0.0.1:{port}/announce")).await;
+        peer_manager.announcer.retry_delay = Duration::from_millis(10);
+
+        let peers = peer_manager
+            .announcer
+            .get_new_peers(Some(TrackerEvent::Started))
+            .await
+            .unwrap();
+
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].addr.ip().to_string(), "10.0.0.1");
+        assert_eq!(peer_manager.announcer.state.lock().unwrap().interval, 900);
+    }
+
+    #[tokio::test]
+    async fn keeps_announcing_until_peers_found() {
+        let peer_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer_port = peer_listener.local_addr().unwrap().port();
+        let peer = tokio::spawn(async move { peer_listener.accept().await.unwrap().1 });
+
+        let empty = || {
+            tracker_body(vec![
+                ("interval", BencodeType::Integer(0)),
+                ("peers", BencodeType::String(vec![])),
+            ])
+        };
+        let responses = [
+            empty(),
+            empty(),
+            tracker_body(vec![
+                ("interval", BencodeType::Integer(0)),
+                (
+                    "peers",
+                    BencodeType::String([&[127, 0, 0, 1], &peer_port.to_be_bytes()[..]].concat()),
+                ),
+            ]),
+        ];
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let tracker = tokio::spawn(async move {
+            for body in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf).await.unwrap();
+                let header = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                stream.write_all(header.as_bytes()).await.unwrap();
+                stream.write_all(&body).await.unwrap();
+            }
+        });
+
+        let mut peer_manager = test_peer_manager(format!("http://127.0.0.1:{port}/announce")).await;
+        peer_manager.announcer.retry_delay = Duration::from_millis(10);
+        peer_manager.start();
+        assert!(peer_manager.is_searching());
+
+        tokio::time::timeout(Duration::from_secs(10), peer_manager.wait())
+            .await
+            .unwrap();
+        tracker.await.unwrap();
+
+        assert!(!peer_manager.is_searching());
+        // The announce that found the peer is the one recorded
+        assert_eq!(peer_manager.tracker_peer_count(), 1);
+        assert!(peer.await.unwrap().ip().is_loopback());
+    }
+
+    #[tokio::test]
+    async fn reannounces_after_interval_once_peers_found() {
+        let peer_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer_port = peer_listener.local_addr().unwrap().port();
+        let body = tracker_body(vec![
+            ("interval", BencodeType::Integer(0)),
+            (
+                "peers",
+                BencodeType::String([&[127, 0, 0, 1], &peer_port.to_be_bytes()[..]].concat()),
+            ),
+        ]);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let tracker = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 1024];
+                let n = stream.read(&mut buf).await.unwrap();
+                requests.push(String::from_utf8_lossy(&buf[..n]).to_string());
+                let header = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                stream.write_all(header.as_bytes()).await.unwrap();
+                stream.write_all(&body).await.unwrap();
+            }
+            requests
+        });
+
+        let mut peer_manager = test_peer_manager(format!("http://127.0.0.1:{port}/announce")).await;
+        peer_manager.announcer.retry_delay = Duration::from_millis(10);
+        peer_manager.start();
+        let requests = tokio::time::timeout(Duration::from_secs(10), tracker)
+            .await
+            .unwrap()
+            .unwrap();
+        peer_manager.stop().await;
+
+        assert!(requests[0].contains("event=started"));
+        // The second announce is a regular one, not another start
+        assert!(!requests[1].contains("event="));
+    }
+
+    #[tokio::test]
+    async fn started_manager_connects_without_waiting() {
+        let tracker_peer = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let discovered_peer = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = tracker_peer.local_addr().unwrap().port();
+        let tracker = mock_tracker(tracker_body(vec![
+            ("interval", BencodeType::Integer(900)),
+            (
+                "peers",
+                BencodeType::String([&[127, 0, 0, 1], &port.to_be_bytes()[..]].concat()),
+            ),
+        ]))
+        .await;
+
+        let mut peer_manager = test_peer_manager(tracker).await;
+        peer_manager.start();
+        peer_manager
+            .discovered_peers()
+            .send((
+                PeerSource::LocalDiscovery,
+                vec![discovered_peer.local_addr().unwrap()],
+            ))
+            .unwrap();
+
+        for listener in [tracker_peer, discovered_peer] {
+            tokio::time::timeout(Duration::from_secs(10), listener.accept())
+                .await
+                .unwrap()
+                .unwrap();
         }
+        peer_manager.stop().await;
+        assert!(peer_manager.discovered.is_some());
+    }
+
+    #[tokio::test]
+    async fn httpseed_only_torrent_starts_without_tracker() {
+        let meta_info = test_meta_info()
+            .no_announce()
+            .httpseeds(&["http://seed.example/seed.php"])
+            .build();
+        let mut peer_manager = peer_manager_for(meta_info).await;
+
+        assert!(matches!(
+            peer_manager.announcer.announce(None).await,
+            Err(PeerManagerError::NoTracker)
+        ));
+        peer_manager.start();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while peer_manager.is_searching() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+        tokio::time::timeout(Duration::from_secs(5), peer_manager.stop())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn search_waits_for_tracker_interval() {
+        let peer_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer_port = peer_listener.local_addr().unwrap().port();
+        let responses = [
+            tracker_body(vec![
+                ("interval", BencodeType::Integer(0)),
+                ("min interval", BencodeType::Integer(1)),
+                ("peers", BencodeType::String(vec![])),
+            ]),
+            tracker_body(vec![
+                ("interval", BencodeType::Integer(900)),
+                (
+                    "peers",
+                    BencodeType::String([&[127, 0, 0, 1], &peer_port.to_be_bytes()[..]].concat()),
+                ),
+            ]),
+        ];
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let tracker = tokio::spawn(async move {
+            let mut announced = Vec::new();
+            for body in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                announced.push(Instant::now());
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf).await.unwrap();
+                let header = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                stream.write_all(header.as_bytes()).await.unwrap();
+                stream.write_all(&body).await.unwrap();
+            }
+            announced
+        });
+
+        let mut peer_manager = test_peer_manager(format!("http://127.0.0.1:{port}/announce")).await;
+        peer_manager.announcer.retry_delay = Duration::from_millis(10);
+        peer_manager.start();
+        tokio::time::timeout(Duration::from_secs(10), peer_listener.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        peer_manager.stop().await;
+
+        let announced = tracker.await.unwrap();
+        assert!(announced[1] - announced[0] >= Duration::from_secs(1));
+    }
+
+    /// Answers a single announce with `body`, returning the tracker's URL
+    async fn mock_tracker(body: Vec<u8>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf).await.unwrap();
+            let header = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            stream.write_all(header.as_bytes()).await.unwrap();
+            stream.write_all(&body).await.unwrap();
+        });
+
+        format!("http://127.0.0.1:{port}/announce")
+    }
+
+    #[tokio::test]
+    async fn peers_of_all_trackers_merged() {
+        let peers = |peers: &[[u8; 6]]| {
+            tracker_body(vec![
+                ("interval", BencodeType::Integer(900)),
+                ("peers", BencodeType::String(peers.concat())),
+            ])
+        };
+        let first = mock_tracker(peers(&[
+            [10, 0, 0, 1, 0x1a, 0xe1],
+            [10, 0, 0, 2, 0x1a, 0xe1],
+        ]))
+        .await;
+        let second = mock_tracker(peers(&[
+            [10, 0, 0, 2, 0x1a, 0xe1],
+            [10, 0, 0, 3, 0x1a, 0xe1],
+        ]))
+        .await;
+        let mut meta_info = test_meta_info().announce(first.clone()).build();
+        meta_info.announce_list = Some(vec![first, second]);
+
+        let mut peer_manager = peer_manager_for(meta_info).await;
+        peer_manager.announcer.announce_to_all_trackers = true;
+        let peers = peer_manager
+            .announcer
+            .get_new_peers(Some(TrackerEvent::Started))
+            .await
+            .unwrap();
+
+        let mut addrs: Vec<String> = peers.iter().map(|peer| peer.addr.to_string()).collect();
+        addrs.sort();
+        assert_eq!(addrs, ["10.0.0.1:6881", "10.0.0.2:6881", "10.0.0.3:6881"]);
+        assert_eq!(
+            peer_manager
+                .announcer
+                .state
+                .lock()
+                .unwrap()
+                .next_announce
+                .len(),
+            2
+        );
+        assert_eq!(peer_manager.announcer.state.lock().unwrap().interval, 900);
+    }
+
+    #[tokio::test]
+    async fn announce_outcome_recorded() {
+        let tracker = mock_tracker(tracker_body(vec![
+            ("interval", BencodeType::Integer(900)),
+            (
+                "peers",
+                BencodeType::String(
+                    [[10, 0, 0, 1, 0x1a, 0xe1], [10, 0, 0, 2, 0x1a, 0xe1]].concat(),
+                ),
+            ),
+        ]))
+        .await;
+        let peer_manager = test_peer_manager(tracker).await;
+        assert_eq!(peer_manager.last_announce(), None);
+
+        let before = SystemTime::now();
+        peer_manager
+            .announcer
+            .get_new_peers(Some(TrackerEvent::Started))
+            .await
+            .unwrap();
+
+        let last_announce = peer_manager.last_announce().unwrap();
+        assert!(last_announce >= before);
+        assert_eq!(
+            peer_manager.next_announce(),
+            Some(last_announce + Duration::from_secs(900))
+        );
+        assert_eq!(peer_manager.tracker_peer_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn limits_concurrent_connections() {
+        // Bound to every address so each peer can use its own loopback address
+        let listener = TcpListener::bind("0.0.0.0:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let open = Arc::new(AtomicUsize::new(0));
+        let max_open = Arc::new(AtomicUsize::new(0));
+
+        let (open_clone, max_open_clone) = (open.clone(), max_open.clone());
+        let server = tokio::spawn(async move {
+            let mut handlers = JoinSet::new();
+            for _ in 0..100 {
+                let (stream, _) = listener.accept().await.unwrap();
+                let count = open_clone.fetch_add(1, Ordering::SeqCst) + 1;
+                max_open_clone.fetch_max(count, Ordering::SeqCst);
+                let open = open_clone.clone();
+                handlers.spawn(async move {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                    open.fetch_sub(1, Ordering::SeqCst);
+                    // Closing without a handshake makes the peer task end
+                    drop(stream);
+                });
+            }
+            handlers.join_all().await;
+        });
+
+        let mut peer_manager = test_peer_manager("http://127.0.0.1/announce".to_string()).await;
+        peer_manager.set_max_connections(10);
+        let peers = (0..100)
+            .map(|i| Peer::new(None, SocketAddr::from(([127, 0, 0, i + 1], port))))
+            .collect();
+
+        peer_manager.add_peers(PeerSource::Tracker, peers);
+        peer_manager.wait().await;
+        server.await.unwrap();
+
+        let max_open = max_open.load(Ordering::SeqCst);
+        assert!(max_open > 0 && max_open <= 10, "{max_open}");
+    }
+
+    #[tokio::test]
+    async fn lowered_limit_removes_busy_slots_once_released() {
+        let mut peer_manager = test_peer_manager("http://127.0.0.1/announce".to_string()).await;
+        peer_manager.set_max_connections(4);
+        let slots: Vec<_> = (0..4)
+            .map(|_| ConnectionSlot {
+                permit: Some(
+                    peer_manager
+                        .spawner
+                        .connection_slots
+                        .clone()
+                        .try_acquire_owned()
+                        .unwrap(),
+                ),
+                surplus: peer_manager.spawner.surplus_slots.clone(),
+            })
+            .collect();
+
+        // Every slot is busy, so none can be removed yet
+        peer_manager.set_max_connections(1);
+        drop(slots);
+        assert_eq!(peer_manager.spawner.connection_slots.available_permits(), 1);
+
+        peer_manager.set_max_connections(3);
+        assert_eq!(peer_manager.spawner.connection_slots.available_permits(), 3);
+    }
+
+    #[tokio::test]
+    async fn completion_announced_to_tracker() {
+        let body = tracker_body(vec![
+            ("interval", BencodeType::Integer(900)),
+            ("peers", BencodeType::List(vec![])),
+        ]);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let n = stream.read(&mut buf).await.unwrap();
+            let header = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            stream.write_all(header.as_bytes()).await.unwrap();
+            stream.write_all(&body).await.unwrap();
+            String::from_utf8_lossy(&buf[..n]).to_string()
+        });
+
+        let (complete, receiver) = watch::channel(false);
+        let task = tokio::spawn(PeerManager::announce_completion(
+            receiver,
+            TrackerClient::default(),
+            Arc::new(
+                test_meta_info()
+                    .announce(format!("http://127.0.0.1:{port}/announce"))
+                    .build(),
+            ),
+            PeerId::generate(),
+            DEFAULT_PORT,
+            EncryptionMode::Disabled,
+        ));
+
+        complete.send_replace(true);
+        task.await.unwrap();
+
+        let request = server.await.unwrap();
+        assert!(request.contains("event=completed"), "{request}");
+    }
+
+    #[tokio::test]
+    async fn private_torrent_only_uses_trackers() {
+        let mut meta_info = test_meta_info()
+            .announce("http://127.0.0.1/announce")
+            .build();
+        meta_info.info.private = Some(1);
+        let mut peer_manager = peer_manager_for(meta_info).await;
+
+        assert!(peer_manager.wants_peers_from(PeerSource::Tracker));
+        for source in [PeerSource::Dht, PeerSource::Pex, PeerSource::LocalDiscovery] {
+            assert!(!peer_manager.wants_peers_from(source));
+            let peers = vec![Peer::new(None, SocketAddr::from(([127, 0, 0, 1], 1)))];
+            assert_eq!(peer_manager.add_peers(source, peers), 0);
+        }
+        assert!(peer_manager.spawner.tasks.lock().unwrap().is_empty());
+
+        let public = test_peer_manager("http://127.0.0.1/announce".to_string()).await;
+        assert!(public.wants_peers_from(PeerSource::Dht));
+        assert!(public.wants_peers_from(PeerSource::Pex));
+    }
+
+    #[tokio::test]
+    async fn discovered_peers_limited_and_filtered() {
+        let mut peer_manager = test_peer_manager("http://127.0.0.1/announce".to_string()).await;
+        peer_manager.set_max_connections(1);
+        let banned = SocketAddr::from(([127, 0, 0, 1], 1));
+        peer_manager.spawner.bans.ban(banned);
+
+        let addrs: Vec<SocketAddr> = (1..=200)
+            .map(|port| SocketAddr::from(([127, 0, 0, 1], port)))
+            .collect();
+        let added = peer_manager
+            .connect_discovered(PeerSource::LocalDiscovery, addrs.clone())
+            .await;
+
+        assert_eq!(added, 1 + MAX_QUEUED_PEERS);
+        assert!(!peer_manager.spawner.known.lock().unwrap().contains(&banned));
+        // Nothing more is queued while the queue is full
+        assert_eq!(
+            peer_manager
+                .connect_discovered(PeerSource::Pex, addrs)
+                .await,
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn peer_from_several_sources_connected_once() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut peer_manager = test_peer_manager("http://127.0.0.1/announce".to_string()).await;
+
+        let peers = vec![Peer::new(None, addr), Peer::new(None, addr)];
+        assert_eq!(peer_manager.add_peers(PeerSource::Tracker, peers), 1);
+        assert_eq!(
+            peer_manager
+                .connect_discovered(PeerSource::Pex, vec![addr])
+                .await,
+            0
+        );
+
+        // Hang up so the peer is gone and can be found again
+        drop(listener.accept().await.unwrap());
+        peer_manager.wait().await;
+        let second = tokio::time::timeout(Duration::from_millis(100), listener.accept()).await;
+        assert!(second.is_err());
+
+        assert!(peer_manager.spawner.known.lock().unwrap().is_empty());
+        assert_eq!(
+            peer_manager
+                .connect_discovered(PeerSource::Pex, vec![addr])
+                .await,
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn failing_peer_banned_until_expiry() {
+        // Nothing listens on the port once the listener is dropped
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut peer_manager = test_peer_manager("http://127.0.0.1/announce".to_string()).await;
+
+        for _ in 0..FAILURES_BEFORE_BAN {
+            let peers = vec![Peer::new(None, addr)];
+            assert_eq!(peer_manager.add_peers(PeerSource::Tracker, peers), 1);
+            peer_manager.wait().await;
+        }
+
+        tokio::time::pause();
+        let peers = vec![Peer::new(None, addr)];
+        assert_eq!(peer_manager.add_peers(PeerSource::Tracker, peers), 0);
+        assert!(peer_manager.spawner.tasks.lock().unwrap().is_empty());
+
+        tokio::time::advance(BAN_DURATIONS[0]).await;
+        assert!(!peer_manager.spawner.bans.is_banned(&addr));
+    }
+
+    #[tokio::test]
+    async fn peer_sending_corrupt_pieces_banned() {
+        let (addr, remote_peer) = fake_remote_peer().await;
+        let remote = tokio::spawn(async move {
+            let mut stream = remote_peer.await;
+
+            let mut requests = 0;
+            while let Ok(message) = Message::from_stream(&mut stream).await {
+                let reply = match message.id {
+                    Some(id) if id == MessageType::Bitfield as u8 => Message::new(
+                        2,
+                        Some(MessageType::Bitfield as u8),
+                        Some(vec![0x80].into()),
+                    ),
+                    Some(id) if id == MessageType::Interested as u8 => {
+                        Message::new(1, Some(MessageType::Unchoke as u8), None)
+                    }
+                    // Answer every request with data that doesn't match the hash
+                    Some(id) if id == MessageType::Request as u8 => {
+                        requests += 1;
+                        let block = [&message.payload.unwrap()[..8], b"dcba"].concat();
+                        Message::new(13, Some(MessageType::Piece as u8), Some(block.into()))
+                    }
+                    _ => continue,
+                };
+                stream.write_all(&reply.to_bytes()).await.unwrap();
+            }
+            requests
+        });
+
+        let mut peer_manager = test_peer_manager("http://127.0.0.1/announce".to_string()).await;
+        let peers = vec![Peer::new(None, addr)];
+        assert_eq!(peer_manager.add_peers(PeerSource::Tracker, peers), 1);
+        tokio::time::timeout(Duration::from_secs(10), peer_manager.wait())
+            .await
+            .unwrap();
+
+        assert_eq!(remote.await.unwrap(), CORRUPT_PIECES_BEFORE_BAN);
+        assert!(peer_manager.spawner.bans.is_banned(&addr));
+        let peers = vec![Peer::new(None, addr)];
+        assert_eq!(peer_manager.add_peers(PeerSource::Tracker, peers), 0);
     }
 }
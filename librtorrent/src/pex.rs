@@ -0,0 +1,266 @@
+use std::{
+    collections::HashSet,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use crate::{
+    bencode::{BencodeMap, BencodeMapDecoder, BencodeType},
+    meta_info::{FromBencodeTypeErr, FromBencodemap},
+    peer::Peer,
+};
+
+// Extension handshake keys
+const EXTENSIONS_KEY: &str = "m";
+const UT_PEX_KEY: &str = "ut_pex";
+
+// ut_pex keys
+const ADDED_KEY: &str = "added";
+const ADDED_FLAGS_KEY: &str = "added.f";
+const ADDED6_KEY: &str = "added6";
+const ADDED6_FLAGS_KEY: &str = "added6.f";
+const DROPPED_KEY: &str = "dropped";
+const DROPPED6_KEY: &str = "dropped6";
+
+/// Extension message id of the extension handshake (BEP-10)
+pub const EXTENDED_HANDSHAKE_ID: u8 = 0;
+/// Id peers are asked to send us `ut_pex` messages with
+pub const LOCAL_PEX_ID: u8 = 1;
+/// Time between two `ut_pex` messages sent to the same peer
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+/// Most addresses sent in each list of a `ut_pex` message
+pub const MAX_PEX_PEERS: usize = 50;
+
+/// The parts of an extension handshake we make use of
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExtendedHandshake {
+    /// Id the sender wants `ut_pex` messages sent with, `None` if it doesn't support PEX
+    pub pex_id: Option<u8>,
+}
+
+/// Peer addresses gossiped through `ut_pex` (BEP-11)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PexMessage {
+    pub added: Vec<SocketAddr>,
+    pub dropped: Vec<SocketAddr>,
+}
+
+impl FromBencodemap for ExtendedHandshake {
+    fn from_bencodemap(bencode_map: &BencodeMap) -> Result<Self, FromBencodeTypeErr> {
+        if !Self::is_valid_bencodemap(bencode_map) {
+            return Err(FromBencodeTypeErr::MissingValue(String::from(
+                "Missing extensions in extension handshake",
+            )));
+        }
+
+        // Safe unwrap because we checked the value exists in the map above
+        let extensions: BencodeMap = bencode_map.get_decode(EXTENSIONS_KEY).unwrap();
+
+        // An id of 0 means the extension was disabled
+        let pex_id = extensions
+            .get_decode::<i64>(UT_PEX_KEY)
+            .and_then(|id| u8::try_from(id).ok())
+            .filter(|id| *id != 0);
+
+        Ok(ExtendedHandshake { pex_id })
+    }
+
+    fn is_valid_bencodemap(bencode_map: &BencodeMap) -> bool {
+        bencode_map
+            .get_decode::<BencodeMap>(EXTENSIONS_KEY)
+            .is_some()
+    }
+}
+
+impl ExtendedHandshake {
+    pub fn to_bencodemap(&self) -> BencodeMap {
+        let mut extensions = BencodeMap::new();
+        if let Some(pex_id) = self.pex_id {
+            extensions.insert(UT_PEX_KEY.into(), BencodeType::Integer(pex_id.into()));
+        }
+
+        let mut map = BencodeMap::new();
+        map.insert(EXTENSIONS_KEY.into(), BencodeType::Dictionary(extensions));
+        map
+    }
+}
+
+impl FromBencodemap for PexMessage {
+    fn from_bencodemap(bencode_map: &BencodeMap) -> Result<Self, FromBencodeTypeErr> {
+        if !Self::is_valid_bencodemap(bencode_map) {
+            return Err(FromBencodeTypeErr::MissingValue(String::from(
+                "ut_pex message has no peer lists",
+            )));
+        }
+
+        Ok(PexMessage {
+            added: decode_addrs(bencode_map, ADDED_KEY, ADDED6_KEY)?,
+            dropped: decode_addrs(bencode_map, DROPPED_KEY, DROPPED6_KEY)?,
+        })
+    }
+
+    fn is_valid_bencodemap(bencode_map: &BencodeMap) -> bool {
+        [ADDED_KEY, ADDED6_KEY, DROPPED_KEY, DROPPED6_KEY]
+            .iter()
+            .any(|key| bencode_map.contains_key(key.as_bytes()))
+    }
+}
+
+impl PexMessage {
+    /// Message telling a peer that was last sent `sent` about the changes
+    /// needed to get to `current`, at most `MAX_PEX_PEERS` of each kind
+    pub fn diff(sent: &HashSet<SocketAddr>, current: &HashSet<SocketAddr>) -> Self {
+        PexMessage {
+            added: current
+                .difference(sent)
+                .take(MAX_PEX_PEERS)
+                .copied()
+                .collect(),
+            dropped: sent
+                .difference(current)
+                .take(MAX_PEX_PEERS)
+                .copied()
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.dropped.is_empty()
+    }
+
+    pub fn to_bencodemap(&self) -> BencodeMap {
+        let mut map = BencodeMap::new();
+
+        let (added, added6) = to_compact(&self.added);
+        // No flags are known about the peers, so every flag byte is 0
+        let added_flags = vec![0; added.len() / 6];
+        let added6_flags = vec![0; added6.len() / 18];
+        map.insert(ADDED_KEY.into(), BencodeType::String(added));
+        map.insert(ADDED_FLAGS_KEY.into(), BencodeType::String(added_flags));
+        map.insert(ADDED6_KEY.into(), BencodeType::String(added6));
+        map.insert(ADDED6_FLAGS_KEY.into(), BencodeType::String(added6_flags));
+
+        let (dropped, dropped6) = to_compact(&self.dropped);
+        map.insert(DROPPED_KEY.into(), BencodeType::String(dropped));
+        map.insert(DROPPED6_KEY.into(), BencodeType::String(dropped6));
+
+        map
+    }
+}
+
+/// Decodes the compact IPv4 list at `key` and IPv6 list at `key6`
+fn decode_addrs(
+    bencode_map: &BencodeMap,
+    key: &str,
+    key6: &str,
+) -> Result<Vec<SocketAddr>, FromBencodeTypeErr> {
+    let mut peers = Vec::new();
+    if let Some(bytes) = bencode_map.get_decode::<Vec<u8>>(key) {
+        peers.extend(Peer::from_compact_v4(&bytes)?);
+    }
+    if let Some(bytes) = bencode_map.get_decode::<Vec<u8>>(key6) {
+        peers.extend(Peer::from_compact_v6(&bytes)?);
+    }
+
+    Ok(peers.into_iter().map(|peer| peer.addr).collect())
+}
+
+/// Encodes `addrs` as compact IPv4 and IPv6 peer lists
+fn to_compact(addrs: &[SocketAddr]) -> (Vec<u8>, Vec<u8>) {
+    let mut v4 = Vec::new();
+    let mut v6 = Vec::new();
+    for addr in addrs {
+        match addr.ip() {
+            IpAddr::V4(ip) => {
+                v4.extend_from_slice(&ip.octets());
+                v4.extend_from_slice(&addr.port().to_be_bytes());
+            }
+            IpAddr::V6(ip) => {
+                v6.extend_from_slice(&ip.octets());
+                v6.extend_from_slice(&addr.port().to_be_bytes());
+            }
+        }
+    }
+
+    (v4, v6)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bencode::BencodeMapEncoder;
+
+    use super::*;
+
+    #[test]
+    fn sample_pex_payload_decoded() {
+        let mut payload = b"d5:added12:".to_vec();
+        payload.extend_from_slice(&[10, 0, 0, 1, 0x1a, 0xe1, 192, 168, 1, 2, 0x1a, 0xe2]);
+        payload.extend_from_slice(b"7:added.f2:");
+        payload.extend_from_slice(&[0, 0]);
+        payload.extend_from_slice(b"6:added618:");
+        payload.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8]);
+        payload.extend_from_slice(&[0; 11]);
+        payload.extend_from_slice(&[1, 0x1a, 0xe1]);
+        payload.extend_from_slice(b"7:dropped6:");
+        payload.extend_from_slice(&[10, 0, 0, 9, 0x1a, 0xe1]);
+        payload.extend_from_slice(b"e");
+
+        let map = BencodeMap::try_decode(&payload).unwrap();
+        let message = PexMessage::from_bencodemap(&map).unwrap();
+
+        let added: HashSet<SocketAddr> = message.added.into_iter().collect();
+        let expected: HashSet<SocketAddr> = [
+            "10.0.0.1:6881".parse().unwrap(),
+            "192.168.1.2:6882".parse().unwrap(),
+            "[2001:db8::1]:6881".parse().unwrap(),
+        ]
+        .into();
+        assert_eq!(added, expected);
+        assert_eq!(message.dropped, vec!["10.0.0.9:6881".parse().unwrap()]);
+    }
+
+    #[test]
+    fn pex_message_round_trips() {
+        let message = PexMessage {
+            added: vec![
+                "10.0.0.1:6881".parse().unwrap(),
+                "[::1]:80".parse().unwrap(),
+            ],
+            dropped: vec!["10.0.0.2:6881".parse().unwrap()],
+        };
+
+        let encoded = message.to_bencodemap().get_encode();
+        let decoded = PexMessage::from_bencodemap(&BencodeMap::try_decode(&encoded).unwrap());
+
+        assert_eq!(decoded.unwrap(), message);
+    }
+
+    #[test]
+    fn diff_lists_added_and_dropped() {
+        let a: SocketAddr = "10.0.0.1:1".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:1".parse().unwrap();
+        let c: SocketAddr = "10.0.0.3:1".parse().unwrap();
+
+        let message = PexMessage::diff(&[a, b].into(), &[b, c].into());
+
+        assert_eq!(message.added, vec![c]);
+        assert_eq!(message.dropped, vec![a]);
+        assert!(PexMessage::diff(&[a].into(), &[a].into()).is_empty());
+    }
+
+    #[test]
+    fn extended_handshake_round_trips() {
+        let handshake = ExtendedHandshake {
+            pex_id: Some(LOCAL_PEX_ID),
+        };
+
+        let decoded = ExtendedHandshake::from_bencodemap(&handshake.to_bencodemap()).unwrap();
+        assert_eq!(decoded, handshake);
+
+        let disabled = ExtendedHandshake::from_bencodemap(
+            &BencodeMap::try_decode(b"d1:md6:ut_pexi0eee").unwrap(),
+        )
+        .unwrap();
+        assert_eq!(disabled.pex_id, None);
+    }
+}
@@ -0,0 +1,92 @@
+use sha1::{Digest, Sha1};
+
+/// SHA-1 implementation pieces are verified with
+pub trait PieceDigest: Default + Send {
+    fn update(&mut self, data: &[u8]);
+    fn finalize(self) -> [u8; 20];
+
+    fn digest(data: &[u8]) -> [u8; 20] {
+        let mut hasher = Self::default();
+        hasher.update(data);
+        hasher.finalize()
+    }
+}
+
+/// The `sha1` crate, which uses the SHA extensions of x86 CPUs that have
+/// them and portable code elsewhere
+#[derive(Debug, Clone, Default)]
+pub struct RustSha1(Sha1);
+
+impl PieceDigest for RustSha1 {
+    fn update(&mut self, data: &[u8]) {
+        Digest::update(&mut self.0, data);
+    }
+
+    fn finalize(self) -> [u8; 20] {
+        Digest::finalize(self.0).into()
+    }
+}
+
+/// OpenSSL's assembly implementation, which is also accelerated on ARM
+#[cfg(feature = "openssl-sha1")]
+#[derive(Clone, Default)]
+pub struct OpensslSha1(openssl::sha::Sha1);
+
+#[cfg(feature = "openssl-sha1")]
+impl PieceDigest for OpensslSha1 {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn finalize(self) -> [u8; 20] {
+        self.0.finish()
+    }
+}
+
+/// Implementation used for pieces, OpenSSL's with the `openssl-sha1`
+/// feature and the `sha1` crate's otherwise
+#[cfg(not(feature = "openssl-sha1"))]
+pub type PieceHasher = RustSha1;
+#[cfg(feature = "openssl-sha1")]
+pub type PieceHasher = OpensslSha1;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_data() -> Vec<u8> {
+        (0..100_000).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn incremental_matches_one_shot() {
+        let data = sample_data();
+        let mut hasher = PieceHasher::default();
+        for chunk in data.chunks(1000) {
+            hasher.update(chunk);
+        }
+
+        assert_eq!(hasher.finalize(), PieceHasher::digest(&data));
+        assert_eq!(
+            PieceHasher::digest(b"abc"),
+            [
+                0xa9, 0x99, 0x3e, 0x36, 0x47, 0x06, 0x81, 0x6a, 0xba, 0x3e, 0x25, 0x71, 0x78, 0x50,
+                0xc2, 0x6c, 0x9c, 0xd0, 0xd8, 0x9d
+            ]
+        );
+    }
+
+    #[cfg(feature = "openssl-sha1")]
+    #[test]
+    fn backends_agree() {
+        let data = sample_data();
+
+        for length in [0, 1, 55, 56, 64, 65, 1 << 14, data.len()] {
+            assert_eq!(
+                OpensslSha1::digest(&data[..length]),
+                RustSha1::digest(&data[..length]),
+                "{length}"
+            );
+        }
+    }
+}
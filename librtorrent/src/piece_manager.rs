@@ -1,81 +1,390 @@
 use std::{
-    collections::HashMap,
-    io::SeekFrom,
-    sync::{Mutex, RwLock},
+    collections::{HashMap, HashSet},
+    io::ErrorKind,
+    net::SocketAddr,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::Duration,
 };
 
 use bytes::{Bytes, BytesMut};
+use log::{debug, info, trace, warn};
 use sha1::{Digest, Sha1};
+use thiserror::Error;
 use tokio::{
-    fs::{File, OpenOptions},
-    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::{broadcast, watch, Notify},
+    task::JoinSet,
+    time::Instant,
 };
 
-use crate::meta_info::MetaInfo;
+use crate::{
+    event::{TorrentEvent, EVENT_CAPACITY},
+    file_manager::FileManager,
+    meta_info::MetaInfo,
+    piece_hash::{PieceDigest, PieceHasher},
+    piece_picker::{InOrder, PiecePicker, PieceState, RarestFirst, Sequential},
+};
 
 //const SAVE_BYTES_THRESHOLD: usize = 1 << 24; // 16 MB in bytes
 const SAVE_BYTES_THRESHOLD: usize = 1 << 20; // 8 MB in bytes
+/// Hard limit on completed pieces held in memory. Pieces that would exceed
+/// it are only accepted after a save has written others to disk.
+pub const MAX_BYTES_IN_RAM: usize = 4 * SAVE_BYTES_THRESHOLD;
+
+/// Appended to the download path, or the info hash in a state directory, to
+/// get the path of a resume file
+const RESUME_EXTENSION: &str = ".resume";
+/// Maximum number of pieces hashed concurrently when verifying a download
+const VERIFY_TASKS: usize = 4;
+/// Size of the reads pieces are hashed in when verifying a download
+const VERIFY_CHUNK_SIZE: usize = 1 << 16;
+
+/// Size of the blocks pieces are requested in when none is configured
+pub const BLOCK_SIZE: usize = 1 << 14;
+/// Largest block size peers can be expected to serve
+pub const MAX_BLOCK_SIZE: usize = 1 << 15;
+/// Once fewer blocks than this remain, pieces already being downloaded are
+/// handed out to other peers as well so one slow peer can't stall the end
+pub const ENDGAME_BLOCKS: usize = 20;
+/// Pieces from the playback position on that the sequential strategy hands
+/// out to several peers at once, so playback isn't held up by one slow peer
+pub const PRIORITY_WINDOW: usize = 4;
+/// Pieces downloaded at once when no limit is configured
+pub const DEFAULT_MAX_PIECES_IN_PROGRESS: usize = 64;
+/// Pieces in progress for longer than this are handed out to other peers
+/// as well, so a stalled peer can't hold a piece back indefinitely
+pub const STALLED_PIECE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// What became of a downloaded piece handed to `add_piece`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PieceOutcome {
+    /// Verified and stored, possibly by another peer first
+    Added,
+    /// Not a piece of the torrent
+    OutOfRange,
+    /// The data doesn't match the piece hash, so it was discarded
+    HashMismatch,
+}
+
+/// What became of a block handed to `add_block`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockOutcome {
+    /// Stored, but the piece still misses other blocks
+    Stored,
+    /// The last missing block arrived, completing the returned piece
+    Completed(AssembledPiece),
+    /// Every byte of the block was received before, so it was ignored
+    Duplicate,
+    /// The piece was downloaded already, so the block was ignored
+    PieceDownloaded,
+    /// Not inside a piece of the torrent
+    OutOfRange,
+}
+
+/// Piece put together from blocks by `add_block`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssembledPiece {
+    pub data: Bytes,
+    /// Peers whose blocks make up the piece, those who may have corrupted it
+    pub senders: HashSet<SocketAddr>,
+}
+
+/// Reasons a block requested by a peer is not served
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+pub enum RequestErr {
+    #[error("Piece {0} is not a piece of the torrent")]
+    OutOfRange(usize),
+    #[error("Block of {0} bytes is larger than {MAX_BLOCK_SIZE} bytes")]
+    TooLong(usize),
+    #[error("Block of {length} bytes at {begin} goes past the end of piece {index}")]
+    PastPieceEnd {
+        index: usize,
+        begin: usize,
+        length: usize,
+    },
+    #[error("Piece {0} has not been downloaded")]
+    Missing(usize),
+}
+
+/// Pieces of an existing download that do and don't match their hashes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VerifyReport {
+    pub valid: usize,
+    pub invalid: usize,
+}
+
+impl VerifyReport {
+    pub fn percent_complete(&self) -> f64 {
+        let total = self.valid + self.invalid;
+        if total == 0 {
+            return 100.0;
+        }
+        self.valid as f64 * 100.0 / total as f64
+    }
+}
+
+/// How much a piece is wanted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PiecePriority {
+    /// Never requested and counted as done when deciding if the torrent is
+    /// complete
+    Skip,
+    #[default]
+    Normal,
+    /// Requested before any `Normal` piece
+    High,
+}
+
+/// Order in which needed pieces are requested from peers, picked by one
+/// of the built-in `PiecePicker`s
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PieceStrategy {
+    /// The first needed piece found in the peer's bitfield
+    #[default]
+    Standard,
+    /// The lowest needed piece at or after the playback position, for
+    /// streaming media while it downloads
+    Sequential,
+    /// The needed piece the fewest connected peers have
+    RarestFirst,
+}
+
+impl PieceStrategy {
+    pub fn picker(self) -> Box<dyn PiecePicker> {
+        match self {
+            PieceStrategy::Standard => Box::new(InOrder),
+            PieceStrategy::Sequential => Box::new(Sequential),
+            PieceStrategy::RarestFirst => Box::new(RarestFirst),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct PieceManager {
-    bitfield: RwLock<BytesMut>,
+    /// Replaced rather than modified in place, so handing it out is only a
+    /// reference count increment
+    bitfield: RwLock<Bytes>,
     piece_hashes: Vec<[u8; 20]>,
     piece_length: usize,
+    total_length: u64,
     torrent_hash: [u8; 20],
     piece_map: Mutex<HashMap<usize, PieceStatus>>,
+    /// Blocks received so far of pieces that are being downloaded
+    partial_pieces: Mutex<HashMap<usize, PartialPiece>>,
+    /// Files the torrent is downloaded to
+    files: Arc<FileManager>,
+    complete: watch::Sender<bool>,
+    /// Events of the torrent, shared with its peer manager
+    events: broadcast::Sender<TorrentEvent>,
+    piece_added: Notify,
+    /// Total size of the `Completed` pieces in `piece_map`
+    bytes_in_ram: AtomicUsize,
+    /// Notified whenever a save moved pieces out of memory
+    pieces_saved: Notify,
+    picker: RwLock<Box<dyn PiecePicker>>,
+    /// Built-in strategy of `picker`, `None` for a picker set by the user
+    strategy: RwLock<Option<PieceStrategy>>,
+    /// Piece the sequential strategy starts from
+    playback_position: AtomicUsize,
+    priorities: RwLock<Vec<PiecePriority>>,
+    /// Number of connected peers that have each piece
+    availability: RwLock<Vec<u32>>,
+    /// No new pieces are handed out while this many are in progress
+    max_pieces_in_progress: AtomicUsize,
+    /// Size of the blocks peers request pieces in
+    block_size: AtomicUsize,
+    /// Where the pieces written to disk are recorded
+    resume_path: PathBuf,
+}
+
+/// Piece being assembled from blocks, possibly sent by several peers
+#[derive(Debug)]
+struct PartialPiece {
+    data: BytesMut,
+    /// Sorted, non-overlapping ranges of `data` that were received
+    received: Vec<Range<usize>>,
+    /// Peers that sent some of `received`
+    senders: HashSet<SocketAddr>,
+}
+
+impl PartialPiece {
+    fn new(len: usize) -> Self {
+        PartialPiece {
+            data: BytesMut::zeroed(len),
+            received: Vec::new(),
+            senders: HashSet::new(),
+        }
+    }
+
+    /// Copies the parts of `block` from `from` that weren't received yet to
+    /// `begin`, never overwriting earlier data. Returns false if none were new.
+    fn write(&mut self, begin: usize, block: &[u8], from: SocketAddr) -> bool {
+        let end = begin + block.len();
+        let mut copy = |from: usize, to: usize| {
+            self.data[from..to].copy_from_slice(&block[from - begin..to - begin]);
+        };
+
+        let mut start = begin;
+        let mut new = false;
+        for range in &self.received {
+            if range.start >= end {
+                break;
+            }
+            if range.start > start {
+                copy(start, range.start);
+                new = true;
+            }
+            start = start.max(range.end);
+        }
+        if start < end {
+            copy(start, end);
+            new = true;
+        }
+
+        if new {
+            self.senders.insert(from);
+            self.received.push(begin..end);
+            self.received.sort_by_key(|range| range.start);
+            let mut merged: Vec<Range<usize>> = Vec::with_capacity(self.received.len());
+            for range in self.received.drain(..) {
+                match merged.last_mut() {
+                    Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                    _ => merged.push(range),
+                }
+            }
+            self.received = merged;
+        }
+
+        new
+    }
+
+    fn is_complete(&self) -> bool {
+        matches!(self.received.as_slice(), [range] if *range == (0..self.data.len()))
+    }
 }
 
 #[derive(Debug)]
 enum PieceStatus {
     NotStarted,
-    InProgress,
+    /// Handed out to a peer at `since`, and to `holders` peers in total
+    /// counting those it was handed out to again
+    InProgress {
+        since: Instant,
+        holders: usize,
+    },
     Completed(Bytes),
     OnDisk,
 }
 
 impl PieceManager {
-    pub async fn new(meta_info: &MetaInfo) -> Self {
+    /// Creates a piece manager downloading to the torrent's name in
+    /// `download_dir`, with its resume file next to the download
+    pub async fn new(meta_info: &MetaInfo, download_dir: &Path) -> Self {
+        let files = FileManager::new(&meta_info.info, download_dir);
+        let resume_path = resume_path(files.root());
+        Self::with_files(meta_info, files, resume_path).await
+    }
+
+    /// Creates a piece manager downloading to the torrent's name in
+    /// `download_dir`, with its resume file in `state_dir`, named after the
+    /// torrent's info hash and where it is downloaded to
+    pub async fn with_state_dir(
+        meta_info: &MetaInfo,
+        download_dir: &Path,
+        state_dir: &Path,
+    ) -> Self {
+        let files = FileManager::new(&meta_info.info, download_dir);
+        let resume_path = state_resume_path(state_dir, meta_info, files.root());
+        Self::with_files(meta_info, files, resume_path).await
+    }
+
+    async fn with_files(meta_info: &MetaInfo, files: FileManager, resume_path: PathBuf) -> Self {
         let mut pm = PieceManager {
             bitfield: RwLock::new(Self::meta_info_to_bitfield(meta_info)),
             piece_hashes: meta_info.info.get_piece_hashes(),
             piece_length: meta_info.info.piece_length as usize,
-            torrent_hash: meta_info.hash.clone(),
+            total_length: meta_info.info.total_length() as u64,
+            torrent_hash: meta_info.hash,
             piece_map: Mutex::new(HashMap::new()),
+            partial_pieces: Mutex::new(HashMap::new()),
+            files: Arc::new(files),
+            complete: watch::Sender::new(false),
+            events: broadcast::Sender::new(EVENT_CAPACITY),
+            piece_added: Notify::new(),
+            bytes_in_ram: AtomicUsize::new(0),
+            pieces_saved: Notify::new(),
+            picker: RwLock::new(PieceStrategy::default().picker()),
+            strategy: RwLock::new(Some(PieceStrategy::default())),
+            playback_position: AtomicUsize::new(0),
+            priorities: RwLock::new(vec![PiecePriority::default(); meta_info.info.num_pieces()]),
+            availability: RwLock::new(vec![0; meta_info.info.num_pieces()]),
+            max_pieces_in_progress: AtomicUsize::new(DEFAULT_MAX_PIECES_IN_PROGRESS),
+            block_size: AtomicUsize::new(BLOCK_SIZE),
+            resume_path,
         };
 
         match pm.load_pieces().await {
-            Ok(_) => {
-                println!(
-                    "Pieces loaded from disk successfully with bitfield: {:#?}",
-                    pm.bitfield
-                )
-            }
-            Err(e) => println!("Failed to load pieces: {}", e),
+            Ok(_) => info!(
+                "Loaded {} of {} pieces from disk",
+                pm.completed_pieces(),
+                pm.get_piece_count()
+            ),
+            Err(e) if e.kind() == ErrorKind::NotFound => debug!("No existing download found"),
+            Err(e) => warn!("Failed to load pieces: {}", e),
         };
 
-        println!("Bitfield: {:#?}", pm.bitfield);
+        trace!("Bitfield: {:?}", pm.bitfield);
 
         pm
     }
 
-    fn meta_info_to_bitfield(meta_info: &MetaInfo) -> BytesMut {
-        let total_length = match meta_info.info.length {
-            Some(length) => length,
-            None => todo!("Mutli-file torrents are not yet supported!"),
+    /// Hashes every piece of the download at `path`, the file of a single
+    /// file torrent or the directory of a multi-file one. Resume files are
+    /// ignored and nothing is written.
+    pub async fn verify_download(
+        meta_info: &MetaInfo,
+        path: &Path,
+    ) -> Result<VerifyReport, std::io::Error> {
+        let files = FileManager::with_root(&meta_info.info, path.to_path_buf());
+        files.last_modified().await?;
+
+        let piece_length = meta_info.info.piece_length as u64;
+        let total_length = meta_info.info.total_length() as u64;
+        let mut report = VerifyReport {
+            valid: 0,
+            invalid: 0,
         };
-        let piece_length = meta_info.info.piece_length;
-        let num_pieces = (total_length + piece_length - 1) / piece_length;
+        for (index, hash) in meta_info.info.get_piece_hashes().iter().enumerate() {
+            let start = index as u64 * piece_length;
+            let length = piece_length.min(total_length.saturating_sub(start)) as usize;
+            if verify_piece(&files, index, length, hash).await? {
+                report.valid += 1;
+            } else {
+                report.invalid += 1;
+            }
+        }
+
+        Ok(report)
+    }
 
-        let num_bytes = (num_pieces + 7) / 8;
+    fn meta_info_to_bitfield(meta_info: &MetaInfo) -> Bytes {
+        let num_pieces = meta_info.info.num_pieces();
+        let from_length = meta_info.info.num_pieces_from_length();
+        if num_pieces != from_length {
+            warn!(
+                "Torrent has {num_pieces} piece hashes but its length makes {from_length} pieces"
+            );
+        }
 
-        // TODO: actually load already downloaded pieces into bitfield
-        let mut buf_bitfield = BytesMut::new();
-        buf_bitfield.resize(num_bytes as usize, 0);
-        buf_bitfield
+        Bytes::from(vec![0u8; num_pieces.div_ceil(8)])
     }
 
     pub fn is_piece_valid(&self, piece_index: &usize, piece: &Bytes) -> bool {
-        let downloaded_hash: [u8; 20] = Sha1::digest(&piece).into();
+        let downloaded_hash = PieceHasher::digest(piece);
 
         if let Some(hash) = self.piece_hashes.get(*piece_index) {
             downloaded_hash == *hash
@@ -84,136 +393,665 @@ impl PieceManager {
         }
     }
 
-    // TODO: improve performance; cloning bitfield: BytesMut is expensive
+    /// Same as `is_piece_valid`, but hashes on the blocking thread pool so
+    /// large pieces don't stall the other tasks of the worker thread
+    pub async fn verify_piece_hash(&self, piece_index: usize, piece: Bytes) -> bool {
+        let Some(hash) = self.piece_hashes.get(piece_index).copied() else {
+            return false;
+        };
+
+        tokio::task::spawn_blocking(move || PieceHasher::digest(&piece) == hash)
+            .await
+            .unwrap_or(false)
+    }
+
+    /// Snapshot of the pieces we have, shared with the piece manager until
+    /// the next piece is added
     pub fn get_bitfield(&self) -> Bytes {
-        self.bitfield.read().unwrap().clone().freeze()
+        self.bitfield.read().unwrap().clone()
     }
 
-    /// Return the index of the piece we need from a peer.
-    /// If peer has no pieces we need then we return None.
-    pub fn get_next_piece(&self, their_bitfield: &Bytes) -> Option<usize> {
-        for (index, (&my_byte, &their_byte)) in self
-            .bitfield
+    pub fn set_strategy(&self, strategy: PieceStrategy) {
+        self.set_picker(strategy.picker());
+        *self.strategy.write().unwrap() = Some(strategy);
+    }
+
+    /// Strategy set with `set_strategy`, or `None` once `set_picker`
+    /// replaced the built-in picker
+    pub fn strategy(&self) -> Option<PieceStrategy> {
+        *self.strategy.read().unwrap()
+    }
+
+    /// Replaces the picker deciding which piece is downloaded next, e.g.
+    /// with one implementing a strategy of the library's user
+    pub fn set_picker(&self, picker: Box<dyn PiecePicker>) {
+        *self.picker.write().unwrap() = picker;
+        *self.strategy.write().unwrap() = None;
+    }
+
+    /// Counts the pieces in `bitfield` as available from one more peer
+    pub fn add_available(&self, bitfield: &Bytes) {
+        let mut availability = self.availability.write().unwrap();
+        for (index, count) in availability.iter_mut().enumerate() {
+            if is_bit_set(bitfield, index) {
+                *count += 1;
+            }
+        }
+    }
+
+    /// Counts piece `index` as available from one more peer, after a peer
+    /// announced it with `Have`
+    pub fn add_have(&self, index: usize) {
+        if let Some(count) = self.availability.write().unwrap().get_mut(index) {
+            *count += 1;
+        }
+    }
+
+    /// Number of connected peers known to have piece `index`
+    pub fn piece_availability(&self, index: usize) -> u32 {
+        self.availability
             .read()
             .unwrap()
-            .iter()
-            .zip(their_bitfield.iter())
-            .enumerate()
+            .get(index)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Undoes `add_available` once the peer with `bitfield` is gone
+    pub fn remove_available(&self, bitfield: &Bytes) {
+        let mut availability = self.availability.write().unwrap();
+        for (index, count) in availability.iter_mut().enumerate() {
+            if is_bit_set(bitfield, index) {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+
+    /// Moves the piece the sequential strategy starts from, e.g. when a
+    /// media player seeks
+    pub fn set_playback_position(&self, index: usize) {
+        self.playback_position.store(index, Ordering::Relaxed);
+    }
+
+    pub fn piece_priority(&self, index: usize) -> Option<PiecePriority> {
+        self.priorities.read().unwrap().get(index).copied()
+    }
+
+    /// Sets the priority of piece `index`. Returns false if `index` is not a
+    /// piece of the torrent.
+    pub fn set_piece_priority(&self, index: usize, priority: PiecePriority) -> bool {
+        match self.priorities.write().unwrap().get_mut(index) {
+            Some(current) => *current = priority,
+            None => return false,
+        }
+
+        self.update_complete();
+        true
+    }
+
+    /// Sets the priority of the pieces of file `index` of the torrent's file
+    /// list. Pieces shared with other files are not skipped, as those files
+    /// still need them. Returns false if there is no such file.
+    pub fn set_file_priority(&self, index: usize, priority: PiecePriority) -> bool {
+        let exclusive = priority == PiecePriority::Skip;
+        let Some(pieces) = self.files.file_pieces(index, exclusive) else {
+            return false;
+        };
+
         {
-            // Get only the bits we don't have and they have set as 1
-            let diff = (!my_byte) & their_byte;
+            let mut priorities = self.priorities.write().unwrap();
+            for piece in pieces {
+                priorities[piece] = priority;
+            }
+        }
+
+        self.update_complete();
+        true
+    }
+
+    /// Sets how many pieces may be in progress at once, bounding the memory
+    /// and requests spent on partly downloaded pieces
+    pub fn set_max_pieces_in_progress(&self, max_pieces_in_progress: usize) {
+        self.max_pieces_in_progress
+            .store(max_pieces_in_progress, Ordering::Relaxed);
+    }
 
-            // If there are no differences, continue
-            if diff == 0 {
+    /// Sets the size of the blocks peers request pieces in, which decides
+    /// when endgame mode starts
+    pub fn set_block_size(&self, block_size: usize) {
+        self.block_size.store(block_size, Ordering::Relaxed);
+    }
+
+    /// Return the index of the piece we need from a peer, as chosen by the
+    /// picker. If peer has no pieces we need then we return None.
+    /// No new piece is started while `max_pieces_in_progress` are in
+    /// progress. A piece in progress is returned instead in endgame mode, if
+    /// the picker finds it urgent or once it has been in progress for
+    /// `STALLED_PIECE_TIMEOUT`, restarting its timeout.
+    pub fn get_next_piece(&self, their_bitfield: &Bytes) -> Option<usize> {
+        let endgame = self.in_endgame();
+        // Copied so the bitfield and piece map locks are never held together
+        let my_bitfield = self.get_bitfield();
+        let priorities = self.priorities.read().unwrap().clone();
+        let availability = self.availability.read().unwrap().clone();
+        let picker = self.picker.read().unwrap();
+        let now = Instant::now();
+
+        let mut map = self.piece_map.lock().unwrap();
+        let taken: Vec<bool> = (0..self.get_piece_count())
+            .map(|index| {
+                matches!(
+                    map.get(&index),
+                    Some(PieceStatus::InProgress { .. } | PieceStatus::Completed(_))
+                )
+            })
+            .collect();
+        let state = PieceState {
+            bitfield: &my_bitfield,
+            priorities: &priorities,
+            availability: &availability,
+            playback_position: self.playback_position.load(Ordering::Relaxed),
+            taken: &taken,
+        };
+
+        let in_progress = map
+            .values()
+            .filter(|status| matches!(status, PieceStatus::InProgress { .. }))
+            .count();
+        if in_progress < self.max_pieces_in_progress.load(Ordering::Relaxed) {
+            match picker.next_piece(their_bitfield, &state) {
+                Some(index) if state.is_wanted(index, their_bitfield) => {
+                    map.insert(
+                        index,
+                        PieceStatus::InProgress {
+                            since: now,
+                            holders: 1,
+                        },
+                    );
+                    return Some(index);
+                }
+                Some(index) => debug!("Picker chose piece {index}, which can't be started"),
+                None => {}
+            }
+        }
+
+        let mut again = None;
+        let mut stalled = None;
+        for (index, &priority) in priorities.iter().enumerate() {
+            let Some(PieceStatus::InProgress { since, .. }) = map.get(&index) else {
+                continue;
+            };
+            if priority == PiecePriority::Skip || !is_bit_set(their_bitfield, index) {
                 continue;
             }
 
-            // Iterate over the bits in the diff byte
-            for bit_index in 0..8 {
-                let mask = 1 << (7 - bit_index);
-
-                if diff & mask != 0 {
-                    // Calculate the piece index from the bit index
-                    let piece_index = index * 8 + bit_index;
-                    let mut map = self.piece_map.lock().unwrap();
-                    match map.get(&piece_index) {
-                        Some(PieceStatus::InProgress) => continue,
-                        Some(PieceStatus::Completed(_)) => continue,
-                        _ => {
-                            map.insert(piece_index, PieceStatus::InProgress);
-                            return Some(piece_index);
-                        }
-                    }
-                }
+            if (endgame || picker.is_urgent(index, &state)) && again.is_none() {
+                again = Some(index);
+            } else if now.duration_since(*since) >= STALLED_PIECE_TIMEOUT && stalled.is_none() {
+                stalled = Some(index);
             }
         }
 
-        None
+        let index = stalled.or(again)?;
+        if let Some(PieceStatus::InProgress { since, holders }) = map.get_mut(&index) {
+            if stalled.is_some() {
+                *since = now;
+            }
+            *holders += 1;
+        }
+        Some(index)
+    }
+
+    /// Whether fewer than `ENDGAME_BLOCKS` blocks are left to download
+    pub fn in_endgame(&self) -> bool {
+        let blocks_per_piece = self
+            .piece_length
+            .div_ceil(self.block_size.load(Ordering::Relaxed));
+
+        self.remaining_pieces() * blocks_per_piece < ENDGAME_BLOCKS
+    }
+
+    /// Number of pieces still to be downloaded, not counting skipped ones
+    pub fn remaining_pieces(&self) -> usize {
+        let bitfield = self.get_bitfield();
+        self.priorities
+            .read()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .filter(|&(index, &priority)| {
+                priority != PiecePriority::Skip && !is_bit_set(&bitfield, index)
+            })
+            .count()
+    }
+
+    /// Number of pieces handed out to peers that aren't downloaded yet
+    pub fn pieces_in_progress(&self) -> usize {
+        self.piece_map
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|status| matches!(status, PieceStatus::InProgress { .. }))
+            .count()
+    }
+
+    /// Resolves once piece `index` has been downloaded and verified, by any peer
+    pub async fn wait_for_piece(&self, index: usize) {
+        loop {
+            // Register before checking so an add in between isn't missed
+            let added = self.piece_added.notified();
+            if self.has_piece(index) {
+                return;
+            }
+            added.await;
+        }
     }
 
     pub fn get_piece_length(&self) -> usize {
         self.piece_length
     }
 
+    /// Length of piece `index`, which is shorter than the piece length for
+    /// the last piece unless the total length is a multiple of it
+    pub fn get_piece_size(&self, index: usize) -> usize {
+        let offset = index as u64 * self.piece_length as u64;
+        (self.piece_length as u64).min(self.total_length.saturating_sub(offset)) as usize
+    }
+
     pub fn get_torrent_hash(&self) -> &[u8; 20] {
         &self.torrent_hash
     }
 
-    /// Verify piece hash and, if valid, store it and update local bitfield
-    /// Returns true if the piece was successfully added, false otherwise.
-    pub async fn add_piece(&self, index: &usize, bytes: Bytes) -> bool {
-        if self.is_piece_valid(&index, &bytes) {
+    pub fn get_piece_count(&self) -> usize {
+        self.piece_hashes.len()
+    }
+
+    /// Number of verified pieces, derived from the bitfield
+    pub fn completed_pieces(&self) -> usize {
+        self.bitfield
+            .read()
+            .unwrap()
+            .iter()
+            .map(|byte| byte.count_ones() as usize)
+            .sum()
+    }
+
+    /// Number of bytes of verified pieces, accounting for a shorter last piece
+    pub fn downloaded_bytes(&self) -> u64 {
+        let piece_length = self.piece_length as u64;
+        let mut bytes = self.completed_pieces() as u64 * piece_length;
+
+        let piece_count = self.get_piece_count();
+        if piece_count > 0 && self.has_piece(piece_count - 1) {
+            let padding = (piece_count as u64 * piece_length).saturating_sub(self.total_length);
+            bytes = bytes.saturating_sub(padding);
+        }
+
+        bytes
+    }
+
+    /// Bytes still to download as reported to trackers, 0 once complete
+    /// even if some files are skipped
+    pub fn left_bytes(&self) -> u64 {
+        if self.is_complete() {
+            0
+        } else {
+            self.total_length.saturating_sub(self.downloaded_bytes())
+        }
+    }
+
+    /// Whether every piece that isn't skipped has been downloaded and verified
+    pub fn is_complete(&self) -> bool {
+        self.remaining_pieces() == 0
+    }
+
+    /// Brings the value seen by `subscribe_complete` receivers up to date
+    fn update_complete(&self) {
+        let complete = self.is_complete();
+        let finished = self.complete.send_if_modified(|current| {
+            let changed = *current != complete;
+            *current = complete;
+            changed
+        }) && complete;
+
+        if finished {
+            self.emit(TorrentEvent::DownloadFinished);
+        }
+    }
+
+    /// Returns a receiver that changes to `true` once the torrent is complete
+    pub fn subscribe_complete(&self) -> watch::Receiver<bool> {
+        self.complete.subscribe()
+    }
+
+    /// Returns a receiver for the events of the torrent from now on
+    pub fn subscribe_events(&self) -> broadcast::Receiver<TorrentEvent> {
+        self.events.subscribe()
+    }
+
+    /// Sender for events of the torrent that happen outside the piece manager
+    pub(crate) fn events(&self) -> broadcast::Sender<TorrentEvent> {
+        self.events.clone()
+    }
+
+    /// Sends `event` to the subscribers, if there are any
+    pub(crate) fn emit(&self, event: TorrentEvent) {
+        // Only fails when nobody is subscribed
+        let _ = self.events.send(event);
+    }
+
+    pub fn get_total_length(&self) -> u64 {
+        self.total_length
+    }
+
+    pub fn has_piece(&self, index: usize) -> bool {
+        is_bit_set(&self.bitfield.read().unwrap(), index)
+    }
+
+    /// Checks that a peer's request for `length` bytes at `begin` of piece
+    /// `index` is one we can and are willing to serve
+    pub fn check_request(
+        &self,
+        index: usize,
+        begin: usize,
+        length: usize,
+    ) -> Result<(), RequestErr> {
+        if !self.is_valid_index(index) {
+            return Err(RequestErr::OutOfRange(index));
+        }
+        if length > MAX_BLOCK_SIZE {
+            return Err(RequestErr::TooLong(length));
+        }
+        if begin + length > self.get_piece_size(index) {
+            return Err(RequestErr::PastPieceEnd {
+                index,
+                begin,
+                length,
+            });
+        }
+        if !self.has_piece(index) {
+            return Err(RequestErr::Missing(index));
+        }
+
+        Ok(())
+    }
+
+    /// Stores a block of piece `index` received from `from` at offset
+    /// `begin`. Parts of the piece that were received before, from this or
+    /// another peer, are not written again, so duplicate or overlapping
+    /// blocks can't corrupt it. Returns the piece once its last block
+    /// arrived, to be handed to `add_piece`.
+    pub fn add_block(
+        &self,
+        index: usize,
+        begin: usize,
+        block: &[u8],
+        from: SocketAddr,
+    ) -> BlockOutcome {
+        if !self.is_valid_index(index)
+            || begin
+                .checked_add(block.len())
+                .is_none_or(|end| end > self.get_piece_size(index))
+        {
+            return BlockOutcome::OutOfRange;
+        }
+
+        // Checked with the lock held so a block racing `add_piece` can't
+        // start the piece over once it was added
+        let mut partial_pieces = self.partial_pieces.lock().unwrap();
+        if self.has_piece(index) {
+            return BlockOutcome::PieceDownloaded;
+        }
+
+        let piece = partial_pieces
+            .entry(index)
+            .or_insert_with(|| PartialPiece::new(self.get_piece_size(index)));
+
+        if !piece.write(begin, block, from) {
+            BlockOutcome::Duplicate
+        } else if piece.is_complete() {
+            let piece = partial_pieces.remove(&index).unwrap();
+            BlockOutcome::Completed(AssembledPiece {
+                data: piece.data.freeze(),
+                senders: piece.senders,
+            })
+        } else {
+            BlockOutcome::Stored
+        }
+    }
+
+    /// Verify piece hash and, if valid, store it and update local bitfield.
+    /// A piece already downloaded by another peer during endgame counts as
+    /// added. Returns `HashMismatch` so the sender can be blamed otherwise,
+    /// in which case the piece is only started over once no other peer
+    /// holds it.
+    pub async fn add_piece(&self, index: &usize, bytes: Bytes) -> PieceOutcome {
+        if !self.is_valid_index(*index) {
+            warn!(
+                "Rejecting piece {index}, torrent only has {} pieces",
+                self.get_piece_count()
+            );
+            return PieceOutcome::OutOfRange;
+        }
+
+        if self.has_piece(*index) {
+            return PieceOutcome::Added;
+        }
+
+        // Blocks that arrived meanwhile are of no use now, and may be the
+        // corrupt ones if verification fails
+        self.partial_pieces.lock().unwrap().remove(index);
+
+        if self.verify_piece_hash(*index, bytes.clone()).await {
+            self.reserve_ram(bytes.len()).await;
             {
                 let mut map = self.piece_map.lock().unwrap();
-                map.insert(*index, PieceStatus::Completed(bytes));
+                // Another peer may have completed the piece while we waited
+                if let Some(PieceStatus::Completed(old)) =
+                    map.insert(*index, PieceStatus::Completed(bytes))
+                {
+                    self.bytes_in_ram.fetch_sub(old.len(), Ordering::SeqCst);
+                }
             }
 
+            // Emitted first so it comes before `DownloadFinished`
+            self.emit(TorrentEvent::PieceCompleted(*index));
             self.update_bitfield(index);
+            // Blocks stored while the piece was verified
+            self.partial_pieces.lock().unwrap().remove(index);
+            self.piece_added.notify_waiters();
+            // Pieces that failed to save stay in memory for the next attempt
             if self.should_save() {
-                self.save_to_disk().await.unwrap();
+                if let Err(err) = self.save_to_disk().await {
+                    warn!("Failed to save pieces to disk: {err}");
+                }
             }
 
-            true
+            PieceOutcome::Added
         } else {
             let mut map = self.piece_map.lock().unwrap();
-            map.insert(*index, PieceStatus::NotStarted);
-            false
+            match map.get_mut(index) {
+                // Other endgame holders keep downloading it
+                Some(PieceStatus::InProgress { holders, .. }) if *holders > 1 => *holders -= 1,
+                _ => {
+                    map.insert(*index, PieceStatus::NotStarted);
+                }
+            }
+            PieceOutcome::HashMismatch
         }
     }
 
-    pub fn cancel_piece(&mut self, index: &usize) {
-        let mut map = self.piece_map.lock().unwrap();
+    /// Reads `len` bytes of the torrent starting `offset` bytes in, from
+    /// memory or disk, e.g. to stream a file while it downloads. Returns
+    /// `None` if a piece the range covers hasn't been downloaded yet or the
+    /// range goes past the end of the torrent.
+    pub async fn read_range(&self, offset: u64, len: usize) -> Option<Bytes> {
+        let end = offset.checked_add(len as u64)?;
+        if end > self.total_length {
+            return None;
+        }
+        if len == 0 {
+            return Some(Bytes::new());
+        }
 
-        // We only need to update if the piece is in progress
-        map.entry(*index)
-            .and_modify(|status| {
-                if !matches!(status, PieceStatus::Completed(_)) {
-                    *status = PieceStatus::NotStarted;
+        let piece_length = self.piece_length as u64;
+        let first = (offset / piece_length) as usize;
+        let last = ((end - 1) / piece_length) as usize;
+        if !(first..=last).all(|index| self.has_piece(index)) {
+            return None;
+        }
+
+        let mut buf = BytesMut::with_capacity(len);
+        for index in first..=last {
+            let piece_start = index as u64 * piece_length;
+            let start = offset.max(piece_start) - piece_start;
+            let stop = end.min(piece_start + piece_length) - piece_start;
+
+            let in_ram = match self.piece_map.lock().unwrap().get(&index) {
+                Some(PieceStatus::Completed(bytes)) => {
+                    Some(bytes.slice(start as usize..stop as usize))
                 }
-            })
-            .or_insert(PieceStatus::NotStarted);
+                _ => None,
+            };
+            let block = match in_ram {
+                Some(block) => block,
+                None => match self
+                    .files
+                    .read_block(index, start, (stop - start) as usize)
+                    .await
+                {
+                    Ok(block) => block,
+                    Err(err) => {
+                        warn!("Failed to read piece {index} from disk: {err}");
+                        return None;
+                    }
+                },
+            };
+            buf.extend_from_slice(&block);
+        }
+
+        Some(buf.freeze())
+    }
+
+    /// Gives up piece `index`, handed out by `get_next_piece`, so it is
+    /// requested again once no other peer it was handed out to holds it,
+    /// dropping the blocks received for it. Returns false if the piece
+    /// isn't in progress.
+    pub fn cancel_piece(&self, index: &usize) -> bool {
+        if !self.is_valid_index(*index) {
+            return false;
+        }
+
+        let mut map = self.piece_map.lock().unwrap();
+        let Some(PieceStatus::InProgress { holders, .. }) = map.get_mut(index) else {
+            return false;
+        };
+
+        *holders -= 1;
+        if *holders == 0 {
+            map.insert(*index, PieceStatus::NotStarted);
+            self.partial_pieces.lock().unwrap().remove(index);
+        }
+        true
     }
 
-    fn update_bitfield(&self, index: &usize) {
+    /// Puts every piece in progress back to be requested again and drops
+    /// their blocks, once no peer is left downloading them
+    pub fn requeue_in_progress(&self) {
+        let mut map = self.piece_map.lock().unwrap();
+        for status in map.values_mut() {
+            if matches!(status, PieceStatus::InProgress { .. }) {
+                *status = PieceStatus::NotStarted;
+            }
+        }
+        self.partial_pieces.lock().unwrap().clear();
+    }
+
+    /// Sets the bit of piece `index`. Returns false if `index` is not a
+    /// piece of the torrent.
+    fn update_bitfield(&self, index: &usize) -> bool {
+        if !self.is_valid_index(*index) {
+            return false;
+        }
+
         let byte_index = index / 8;
         let bit_index = index % 8;
         let mask = 1 << (7 - bit_index);
 
-        let mut bitfield = self.bitfield.write().unwrap();
-        bitfield[byte_index] |= mask;
+        {
+            let mut bitfield = self.bitfield.write().unwrap();
+            let mut updated = BytesMut::from(bitfield.as_ref());
+            updated[byte_index] |= mask;
+            *bitfield = updated.freeze();
+        }
+
+        self.update_complete();
+
+        true
+    }
+
+    /// Whether `index` refers to one of the torrent's pieces
+    fn is_valid_index(&self, index: usize) -> bool {
+        index < self.get_piece_count()
+    }
+
+    /// Size of the completed pieces that have not been saved to disk yet
+    pub fn bytes_in_ram(&self) -> usize {
+        self.bytes_in_ram.load(Ordering::SeqCst)
+    }
+
+    /// Accounts for `len` more bytes held in memory, waiting for pieces to
+    /// be saved first if that would exceed `MAX_BYTES_IN_RAM`
+    async fn reserve_ram(&self, len: usize) {
+        loop {
+            // Register before checking so a save finishing in between
+            // isn't missed
+            let saved = self.pieces_saved.notified();
+            tokio::pin!(saved);
+            saved.as_mut().enable();
+
+            let current = self.bytes_in_ram.load(Ordering::SeqCst);
+            // A piece larger than the cap is still let through on its own
+            if current == 0 || current + len <= MAX_BYTES_IN_RAM {
+                if self
+                    .bytes_in_ram
+                    .compare_exchange(current, current + len, Ordering::SeqCst, Ordering::SeqCst)
+                    .is_ok()
+                {
+                    return;
+                }
+                continue;
+            }
+
+            trace!("{current} bytes of pieces in memory, waiting for a save");
+            saved.await;
+        }
     }
 
     fn should_save(&self) -> bool {
-        let map = self.piece_map.lock().unwrap();
+        self.bytes_in_ram() >= SAVE_BYTES_THRESHOLD || self.is_complete()
+    }
 
-        let bytes_in_ram: usize = map
+    /// Writes every completed piece still held in memory to disk, regardless
+    /// of `SAVE_BYTES_THRESHOLD`, so nothing is lost when stopping
+    pub async fn flush(&self) -> Result<(), std::io::Error> {
+        let has_pending = self
+            .piece_map
+            .lock()
+            .unwrap()
             .values()
-            .filter_map(|status| match status {
-                PieceStatus::Completed(bytes) => Some(bytes.len()),
-                _ => None,
-            })
-            .sum();
+            .any(|status| matches!(status, PieceStatus::Completed(_)));
 
-        let all_pieces_ready = map
-            .values()
-            .all(|status| matches!(status, PieceStatus::Completed(_) | PieceStatus::OnDisk));
+        if has_pending {
+            self.save_to_disk().await?;
+        }
 
-        bytes_in_ram >= SAVE_BYTES_THRESHOLD || all_pieces_ready
+        Ok(())
     }
 
     /// Save the pieces to disk
-    // TODO: Move to dedicated File Manager and use real file name
     pub async fn save_to_disk(&self) -> Result<(), std::io::Error> {
-        let mut file = OpenOptions::new()
-            .read(false)
-            .write(true)
-            .truncate(false)
-            .create(true)
-            .open("result.iso")
-            .await?;
-
         let piece_count = self.piece_hashes.len();
-        println!("Piece count {piece_count}");
         for index in 0..piece_count {
             let buf = {
                 let map = self.piece_map.lock().unwrap();
@@ -224,122 +1062,968 @@ impl PieceManager {
             };
 
             if let Some(data) = buf {
-                let file_offset = index as u64 * self.piece_length as u64;
-                file.seek(SeekFrom::Start(file_offset)).await?;
-
-                //println!("Saving piece {index} with data {data:#?}");
-
-                file.write_all(&data).await?;
-                println!("Piece {index} saved to disk!");
+                self.files.write_piece(index, &data).await?;
+                debug!("Piece {index} saved to disk");
 
                 let mut map = self.piece_map.lock().unwrap();
-                map.insert(index, PieceStatus::OnDisk);
+                if let Some(PieceStatus::Completed(bytes)) = map.insert(index, PieceStatus::OnDisk)
+                {
+                    self.bytes_in_ram.fetch_sub(bytes.len(), Ordering::SeqCst);
+                }
             }
         }
+        self.pieces_saved.notify_waiters();
+
+        self.files.sync_all().await?;
 
-        file.sync_all().await?;
+        // Record what is on disk so the next start can skip verification
+        if let Some(state_dir) = self.resume_path.parent() {
+            tokio::fs::create_dir_all(state_dir).await?;
+        }
+        tokio::fs::write(&self.resume_path, self.on_disk_bitfield()).await?;
 
         Ok(())
     }
 
-    // TODO: Move to dedicated File Manager and use real file name
+    /// Bitfield of the pieces that have been written to disk
+    fn on_disk_bitfield(&self) -> Vec<u8> {
+        let mut bitfield = vec![0u8; self.bitfield.read().unwrap().len()];
+        for (index, status) in self.piece_map.lock().unwrap().iter() {
+            if matches!(status, PieceStatus::OnDisk) {
+                bitfield[index / 8] |= 1 << (7 - index % 8);
+            }
+        }
+        bitfield
+    }
+
     async fn load_pieces(&mut self) -> Result<(), std::io::Error> {
-        println!("Loading pieces");
-        let mut file = File::open("result.iso").await?;
-        let piece_count = self.piece_hashes.len();
+        debug!("Loading pieces from {}", self.files.root().display());
 
-        for index in 0..piece_count {
-            let file_offset = index as u64 * self.piece_length as u64;
-            file.seek(SeekFrom::Start(file_offset)).await?;
+        if self.load_resume(&self.files).await? {
+            debug!("Pieces loaded from resume file");
+            return Ok(());
+        }
 
-            let mut buf = BytesMut::with_capacity(self.piece_length);
-            buf.resize(self.piece_length, 0);
-            file.read_exact(&mut buf).await?;
+        self.verify_pieces(&self.files).await
+    }
 
-            if self.is_piece_valid(&index, &buf.freeze()) {
-                {
-                    let mut map = self.piece_map.lock().unwrap();
-                    map.insert(index, PieceStatus::OnDisk);
-                }
+    /// Marks the pieces recorded in the resume file as on disk.
+    /// Returns false if there is no usable resume file, e.g. because the
+    /// download was modified after the resume file was written.
+    async fn load_resume(&self, files: &FileManager) -> Result<bool, std::io::Error> {
+        let data_modified = files.last_modified().await?;
+
+        let resume_path = &self.resume_path;
+        let resume_modified = match tokio::fs::metadata(resume_path).await {
+            Ok(metadata) => metadata.modified()?,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        if resume_modified < data_modified {
+            debug!("Resume file is older than the download, verifying all pieces");
+            return Ok(false);
+        }
+
+        let bitfield = tokio::fs::read(resume_path).await?;
+        if bitfield.len() != self.bitfield.read().unwrap().len() {
+            warn!("Resume file does not match the torrent, verifying all pieces");
+            return Ok(false);
+        }
+
+        let recorded: Vec<usize> = (0..self.get_piece_count())
+            .filter(|&index| is_bit_set(&bitfield, index))
+            .collect();
+        // A file cut short without touching its modification time
+        if !files.holds_pieces(&recorded).await? {
+            warn!("Download is missing data the resume file lists, verifying all pieces");
+            return Ok(false);
+        }
+
+        for index in recorded {
+            self.mark_on_disk(index);
+        }
+
+        Ok(true)
+    }
+
+    /// Hashes every piece stored in `files`, `VERIFY_TASKS` at a time, and
+    /// marks the valid ones as on disk
+    async fn verify_pieces(&self, files: &Arc<FileManager>) -> Result<(), std::io::Error> {
+        // Fail early, with NotFound, when there is no download at all
+        files.last_modified().await?;
 
-                self.update_bitfield(&index);
+        let mut tasks = JoinSet::new();
+        for index in 0..self.get_piece_count() {
+            if tasks.len() >= VERIFY_TASKS {
+                if let Some(result) = tasks.join_next().await {
+                    self.mark_verified(result?)?;
+                }
             }
+
+            let length = self.get_piece_size(index);
+            let hash = self.piece_hashes[index];
+            let files = Arc::clone(files);
+            tasks.spawn(async move {
+                let valid = verify_piece(&files, index, length, &hash).await?;
+                Ok::<_, std::io::Error>((index, valid))
+            });
+        }
+
+        while let Some(result) = tasks.join_next().await {
+            self.mark_verified(result?)?;
         }
 
         Ok(())
     }
+
+    fn mark_verified(
+        &self,
+        result: Result<(usize, bool), std::io::Error>,
+    ) -> Result<(), std::io::Error> {
+        let (index, valid) = result?;
+        if valid {
+            self.mark_on_disk(index);
+        }
+        Ok(())
+    }
+
+    fn mark_on_disk(&self, index: usize) {
+        {
+            let mut map = self.piece_map.lock().unwrap();
+            map.insert(index, PieceStatus::OnDisk);
+        }
+
+        self.update_bitfield(&index);
+    }
+}
+
+/// Whether the bit of piece `index` is set, false if it is past the end
+pub(crate) fn is_bit_set(bitfield: &[u8], index: usize) -> bool {
+    let mask = 1 << (7 - index % 8);
+    bitfield.get(index / 8).is_some_and(|byte| byte & mask != 0)
+}
+
+/// Path of the resume file kept next to the download at `path`
+fn resume_path(path: &Path) -> PathBuf {
+    let mut resume = path.as_os_str().to_owned();
+    resume.push(RESUME_EXTENSION);
+    PathBuf::from(resume)
+}
+
+/// Path of the resume file in `state_dir` for the torrent downloaded to
+/// `root`. The same torrent downloaded to two places gets two resume files.
+fn state_resume_path(state_dir: &Path, meta_info: &MetaInfo, root: &Path) -> PathBuf {
+    let root_hash = Sha1::digest(root.as_os_str().as_encoded_bytes());
+    let root_key: String = root_hash[..4]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    state_dir.join(format!(
+        "{}-{root_key}{RESUME_EXTENSION}",
+        meta_info.info_hash_hex()
+    ))
+}
+
+/// Whether the `length` bytes of piece `index` in `files` hash to `hash`,
+/// reading them in chunks so memory use doesn't grow with the piece. Chunks
+/// are hashed on the blocking thread pool, off the async workers.
+async fn verify_piece(
+    files: &FileManager,
+    index: usize,
+    length: usize,
+    hash: &[u8; 20],
+) -> Result<bool, std::io::Error> {
+    let mut hasher = PieceHasher::default();
+    let mut offset = 0;
+    while offset < length {
+        let to_read = (length - offset).min(VERIFY_CHUNK_SIZE);
+        match files.read_block(index, offset as u64, to_read).await {
+            Ok(chunk) => {
+                hasher = tokio::task::spawn_blocking(move || {
+                    hasher.update(&chunk);
+                    hasher
+                })
+                .await
+                .map_err(std::io::Error::other)?;
+            }
+            // A file is missing or ends before the piece does
+            Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::UnexpectedEof) => {
+                return Ok(false)
+            }
+            Err(e) => return Err(e),
+        }
+        offset += to_read;
+    }
+
+    Ok(hasher.finalize() == *hash)
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::meta_info::TorrentInfo;
+    use crate::{
+        meta_info::FileInfo,
+        test_util::{temp_path, test_meta_info},
+    };
 
     use super::*;
 
     #[tokio::test]
     async fn test_get_next_piece_index_0() {
-        let meta_info = MetaInfo {
-            announce: Some("test".to_string()),
-            nodes: None,
-            url_list: None,
-            announce_list: None,
-            hash: [0u8; 20],
-            info: TorrentInfo {
-                name: "test".to_string(),
-                piece_length: 2 << 14,
-                pieces: vec![],
-                length: Some(8),
-                files: None,
-                private: None,
-            },
-        };
-        let piece_manager = PieceManager::new(&meta_info).await;
+        let meta_info = test_meta_info()
+            .hash([0u8; 20])
+            .piece_length(1)
+            .length(8)
+            .build();
+        let piece_manager = PieceManager::new(&meta_info, &std::env::temp_dir()).await;
         let bitfield = Bytes::from(vec![0b10000000]);
         assert_eq!(piece_manager.get_next_piece(&bitfield), Some(0));
     }
 
     #[tokio::test]
     async fn test_get_next_piece_index_7() {
-        let meta_info = MetaInfo {
-            announce: Some("test".to_string()),
-            nodes: None,
-            url_list: None,
-            announce_list: None,
-            hash: [0u8; 20],
-            info: TorrentInfo {
-                name: "test".to_string(),
-                piece_length: 2 << 14,
-                pieces: vec![],
-                length: Some(8),
-                files: None,
-                private: None,
-            },
-        };
-        let piece_manager = PieceManager::new(&meta_info).await;
+        let meta_info = test_meta_info()
+            .hash([0u8; 20])
+            .piece_length(1)
+            .length(8)
+            .build();
+        let piece_manager = PieceManager::new(&meta_info, &std::env::temp_dir()).await;
         let bitfield = Bytes::from(vec![0b00000001]);
         assert_eq!(piece_manager.get_next_piece(&bitfield), Some(7));
     }
 
     #[tokio::test]
     async fn test_get_next_piece_mutliple_pieces() {
-        let meta_info = MetaInfo {
-            announce: Some("test".to_string()),
-            nodes: None,
-            url_list: None,
-            announce_list: None,
-            hash: [0u8; 20],
-            info: TorrentInfo {
-                name: "test".to_string(),
-                piece_length: 2 << 14,
-                pieces: vec![],
-                length: Some(8),
-                files: None,
-                private: None,
-            },
-        };
-        let piece_manager = PieceManager::new(&meta_info).await;
+        let meta_info = test_meta_info()
+            .hash([0u8; 20])
+            .piece_length(1)
+            .length(8)
+            .build();
+        let piece_manager = PieceManager::new(&meta_info, &std::env::temp_dir()).await;
         let bitfield = Bytes::from(vec![0b00000011]);
         assert_eq!(piece_manager.get_next_piece(&bitfield), Some(6));
         assert_eq!(piece_manager.get_next_piece(&bitfield), Some(7));
     }
+
+    #[tokio::test]
+    async fn complete_once_all_pieces_marked() {
+        let data: [&[u8]; 3] = [b"abcd", b"efgh", b"ij"];
+        let meta_info = test_meta_info()
+            .hash([2u8; 20])
+            .data(&data.concat())
+            .build();
+        let piece_manager = PieceManager::new(&meta_info, &std::env::temp_dir()).await;
+        let mut complete = piece_manager.subscribe_complete();
+
+        piece_manager.update_bitfield(&0);
+        piece_manager.update_bitfield(&1);
+        assert!(!piece_manager.is_complete());
+        assert!(!*complete.borrow_and_update());
+
+        piece_manager.update_bitfield(&2);
+        assert!(piece_manager.is_complete());
+        assert!(complete.has_changed().unwrap());
+        assert!(*complete.borrow());
+        assert_eq!(
+            piece_manager.get_next_piece(&Bytes::from(vec![0b11100000])),
+            None
+        );
+    }
+
+    fn three_piece_meta_info() -> MetaInfo {
+        test_meta_info().hash([3u8; 20]).data(b"abcdefghij").build()
+    }
+
+    fn sender(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[tokio::test]
+    async fn requests_checked_against_pieces() {
+        let download_dir = temp_path("requests");
+        let piece_manager = PieceManager::new(&three_piece_meta_info(), &download_dir).await;
+
+        // The last piece is only two bytes long
+        let past_end = piece_manager.check_request(2, 0, 4);
+        let missing = piece_manager.check_request(2, 0, 2);
+        let out_of_range = piece_manager.check_request(3, 0, 2);
+        let too_long = piece_manager.check_request(0, 0, MAX_BLOCK_SIZE + 1);
+        // Nothing was downloaded, so the directory may not even exist
+        let _ = tokio::fs::remove_dir_all(&download_dir).await;
+
+        assert_eq!(
+            past_end,
+            Err(RequestErr::PastPieceEnd {
+                index: 2,
+                begin: 0,
+                length: 4
+            })
+        );
+        assert_eq!(missing, Err(RequestErr::Missing(2)));
+        assert_eq!(out_of_range, Err(RequestErr::OutOfRange(3)));
+        assert_eq!(too_long, Err(RequestErr::TooLong(MAX_BLOCK_SIZE + 1)));
+    }
+
+    #[tokio::test]
+    async fn verify_marks_only_valid_pieces() {
+        let download_dir = temp_path("verify");
+        tokio::fs::create_dir_all(&download_dir).await.unwrap();
+        tokio::fs::write(download_dir.join("test"), b"abcdXXXXij")
+            .await
+            .unwrap();
+
+        let meta_info = three_piece_meta_info();
+        let piece_manager = PieceManager::new(&meta_info, &std::env::temp_dir()).await;
+        let files = Arc::new(FileManager::new(&meta_info.info, &download_dir));
+        piece_manager.verify_pieces(&files).await.unwrap();
+        tokio::fs::remove_dir_all(&download_dir).await.unwrap();
+
+        let map = piece_manager.piece_map.lock().unwrap();
+        assert!(matches!(map.get(&0), Some(PieceStatus::OnDisk)));
+        assert!(map.get(&1).is_none());
+        assert!(matches!(map.get(&2), Some(PieceStatus::OnDisk)));
+        drop(map);
+        assert!(piece_manager.has_piece(0));
+        assert!(!piece_manager.has_piece(1));
+        assert!(piece_manager.has_piece(2));
+    }
+
+    #[tokio::test]
+    async fn resume_file_skips_verification() {
+        let download_dir = temp_path("resume");
+        let path = download_dir.join("test");
+        tokio::fs::create_dir_all(&download_dir).await.unwrap();
+        tokio::fs::write(&path, b"XXXXXXXXXX").await.unwrap();
+        tokio::fs::write(resume_path(&path), [0b10100000])
+            .await
+            .unwrap();
+
+        let meta_info = three_piece_meta_info();
+        let piece_manager = PieceManager::new(&meta_info, &download_dir).await;
+        let files = FileManager::new(&meta_info.info, &download_dir);
+        let loaded = piece_manager.load_resume(&files).await.unwrap();
+        tokio::fs::remove_dir_all(&download_dir).await.unwrap();
+
+        // The resume file is trusted even though the data doesn't match
+        assert!(loaded);
+        assert!(piece_manager.has_piece(0));
+        assert!(!piece_manager.has_piece(1));
+        assert!(piece_manager.has_piece(2));
+    }
+
+    #[tokio::test]
+    async fn resume_file_written_to_state_dir() {
+        let download_dir = temp_path("state-download");
+        let state_dir = temp_path("state");
+        let meta_info = three_piece_meta_info();
+        let piece_manager =
+            PieceManager::with_state_dir(&meta_info, &download_dir, &state_dir).await;
+
+        for (index, piece) in [b"abcd".as_slice(), b"efgh", b"ij"].into_iter().enumerate() {
+            piece_manager
+                .add_piece(&index, Bytes::copy_from_slice(piece))
+                .await;
+        }
+        let in_state_dir = state_resume_path(&state_dir, &meta_info, &download_dir.join("test"));
+        let resume = tokio::fs::read(&in_state_dir).await;
+        let next_to_download = resume_path(&download_dir.join("test")).exists();
+        tokio::fs::remove_dir_all(&download_dir).await.unwrap();
+        tokio::fs::remove_dir_all(&state_dir).await.unwrap();
+
+        assert_eq!(resume.unwrap(), [0b11100000]);
+        assert!(!next_to_download);
+    }
+
+    #[test]
+    fn resume_files_keyed_by_download_location() {
+        let meta_info = three_piece_meta_info();
+        let state_dir = Path::new("/state");
+
+        let first = state_resume_path(state_dir, &meta_info, Path::new("/movies/test"));
+        let second = state_resume_path(state_dir, &meta_info, Path::new("/software/test"));
+
+        assert_ne!(first, second);
+        assert!(first.starts_with(state_dir));
+        assert!(first.to_string_lossy().contains(&meta_info.info_hash_hex()));
+    }
+
+    #[tokio::test]
+    async fn resume_file_ignored_for_truncated_download() {
+        let download_dir = temp_path("resume-truncated");
+        let path = download_dir.join("test");
+        tokio::fs::create_dir_all(&download_dir).await.unwrap();
+        // Too short to hold piece 2
+        tokio::fs::write(&path, b"abcdefgh").await.unwrap();
+        tokio::fs::write(resume_path(&path), [0b10100000])
+            .await
+            .unwrap();
+
+        let meta_info = three_piece_meta_info();
+        let piece_manager = PieceManager::new(&meta_info, &download_dir).await;
+        tokio::fs::remove_dir_all(&download_dir).await.unwrap();
+
+        // Verified instead, which finds the first two pieces
+        assert!(piece_manager.has_piece(0));
+        assert!(piece_manager.has_piece(1));
+        assert!(!piece_manager.has_piece(2));
+    }
+
+    #[tokio::test]
+    async fn out_of_range_piece_rejected() {
+        let piece_manager =
+            PieceManager::new(&three_piece_meta_info(), &std::env::temp_dir()).await;
+
+        // One past the last piece, but still inside the bitfield's last byte
+        assert_eq!(
+            piece_manager
+                .add_piece(&3, Bytes::from_static(b"abcd"))
+                .await,
+            PieceOutcome::OutOfRange
+        );
+        assert_eq!(
+            piece_manager
+                .add_piece(&64, Bytes::from_static(b"abcd"))
+                .await,
+            PieceOutcome::OutOfRange
+        );
+        assert!(!piece_manager.cancel_piece(&3));
+        assert!(!piece_manager.update_bitfield(&64));
+
+        assert_eq!(piece_manager.completed_pieces(), 0);
+        assert!(piece_manager.piece_map.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn piece_cancelled_through_shared_manager() {
+        let piece_manager =
+            Arc::new(PieceManager::new(&ten_piece_meta_info(), &std::env::temp_dir()).await);
+        let their_bitfield = Bytes::from(vec![0xff, 0b11000000]);
+        assert_eq!(piece_manager.get_next_piece(&their_bitfield), Some(0));
+
+        // Peer tasks only hold an Arc, as when their connection fails
+        let peer_task = tokio::spawn({
+            let piece_manager = piece_manager.clone();
+            async move { piece_manager.cancel_piece(&0) }
+        });
+
+        assert!(peer_task.await.unwrap());
+        assert!(matches!(
+            piece_manager.piece_map.lock().unwrap().get(&0),
+            Some(PieceStatus::NotStarted)
+        ));
+        assert_eq!(piece_manager.get_next_piece(&their_bitfield), Some(0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn piece_requeued_once_every_holder_cancels() {
+        let piece_manager = PieceManager::new(&ten_piece_meta_info(), &std::env::temp_dir()).await;
+        let their_bitfield = Bytes::from(vec![0b10000000, 0]);
+        assert!(!piece_manager.cancel_piece(&0));
+
+        assert_eq!(piece_manager.get_next_piece(&their_bitfield), Some(0));
+        tokio::time::advance(STALLED_PIECE_TIMEOUT).await;
+        assert_eq!(piece_manager.get_next_piece(&their_bitfield), Some(0));
+
+        // The peer it stalled with gives up while the other still has it
+        assert!(piece_manager.cancel_piece(&0));
+        assert_eq!(piece_manager.get_next_piece(&their_bitfield), None);
+        assert!(piece_manager.cancel_piece(&0));
+        assert_eq!(piece_manager.get_next_piece(&their_bitfield), Some(0));
+    }
+
+    #[tokio::test]
+    async fn endgame_counts_blocks_of_configured_size() {
+        let piece_manager = PieceManager::new(&ten_piece_meta_info(), &std::env::temp_dir()).await;
+        assert!(!piece_manager.in_endgame());
+
+        // Half the blocks per piece puts the 10 pieces' blocks under the threshold
+        piece_manager.set_block_size(BLOCK_SIZE * 2);
+        assert!(piece_manager.in_endgame());
+    }
+
+    #[tokio::test]
+    async fn last_piece_is_shorter() {
+        let piece_manager =
+            PieceManager::new(&three_piece_meta_info(), &std::env::temp_dir()).await;
+
+        assert_eq!(piece_manager.get_piece_size(0), 4);
+        assert_eq!(piece_manager.get_piece_size(1), 4);
+        assert_eq!(piece_manager.get_piece_size(2), 2);
+        assert!(piece_manager.is_piece_valid(&2, &Bytes::from_static(b"ij")));
+    }
+
+    #[tokio::test]
+    async fn flush_saves_pieces_below_threshold() {
+        let download_dir = temp_path("flush");
+        let piece_manager = PieceManager::new(&three_piece_meta_info(), &download_dir).await;
+
+        assert_eq!(
+            piece_manager
+                .add_piece(&1, Bytes::from_static(b"efgh"))
+                .await,
+            PieceOutcome::Added
+        );
+        let path = download_dir.join("test");
+        assert!(!path.exists());
+
+        piece_manager.flush().await.unwrap();
+        let written = tokio::fs::read(&path).await;
+        tokio::fs::remove_dir_all(&download_dir).await.unwrap();
+
+        assert_eq!(&written.unwrap()[4..8], b"efgh");
+        assert!(matches!(
+            piece_manager.piece_map.lock().unwrap().get(&1),
+            Some(PieceStatus::OnDisk)
+        ));
+    }
+
+    #[tokio::test]
+    async fn piece_kept_in_memory_when_saving_fails() {
+        // A file where the download directory should be can't hold the files
+        let blocker = temp_path("unwritable");
+        tokio::fs::write(&blocker, b"").await.unwrap();
+        let piece_manager =
+            PieceManager::new(&test_meta_info().build(), &blocker.join("dir")).await;
+
+        let outcome = piece_manager
+            .add_piece(&0, Bytes::from_static(b"abcd"))
+            .await;
+        let flushed = piece_manager.flush().await;
+        let read = piece_manager.read_range(0, 4).await;
+        tokio::fs::remove_file(&blocker).await.unwrap();
+
+        assert_eq!(outcome, PieceOutcome::Added);
+        assert!(flushed.is_err());
+        assert_eq!(read.as_deref(), Some(b"abcd".as_slice()));
+    }
+
+    #[tokio::test]
+    async fn ranges_read_across_pieces() {
+        let download_dir = temp_path("read-range");
+        let piece_manager = PieceManager::new(&three_piece_meta_info(), &download_dir).await;
+        piece_manager
+            .add_piece(&0, Bytes::from_static(b"abcd"))
+            .await;
+        piece_manager.flush().await.unwrap();
+        piece_manager
+            .add_piece(&1, Bytes::from_static(b"efgh"))
+            .await;
+
+        // Piece 0 is read from disk, piece 1 from memory
+        let spanning = piece_manager.read_range(2, 4).await;
+        let into_missing = piece_manager.read_range(6, 3).await;
+        let past_end = piece_manager.read_range(8, 4).await;
+        tokio::fs::remove_dir_all(&download_dir).await.unwrap();
+
+        assert_eq!(spanning.as_deref(), Some(b"cdef".as_slice()));
+        assert_eq!(into_missing, None);
+        assert_eq!(past_end, None);
+    }
+
+    #[tokio::test]
+    async fn pieces_hashed_off_the_runtime_thread() {
+        let piece_length = 1 << 18;
+        let data: Vec<u8> = (0..piece_length * 3).map(|i| (i % 251) as u8).collect();
+        let meta_info = test_meta_info()
+            .hash([3u8; 20])
+            .piece_length(piece_length)
+            .data(&data)
+            .build();
+        let download_dir = temp_path("hashing");
+        let piece_manager = Arc::new(PieceManager::new(&meta_info, &download_dir).await);
+
+        let corrupt = Bytes::from(vec![0; piece_length]);
+        assert_eq!(
+            piece_manager.add_piece(&0, corrupt).await,
+            PieceOutcome::HashMismatch
+        );
+
+        let additions: Vec<_> = data
+            .chunks(piece_length)
+            .enumerate()
+            .map(|(index, piece)| {
+                let piece_manager = piece_manager.clone();
+                let piece = Bytes::copy_from_slice(piece);
+                tokio::spawn(async move { piece_manager.add_piece(&index, piece).await })
+            })
+            .collect();
+        // The test runtime has a single thread, which is only free to count
+        // while the pieces are hashed somewhere else
+        let mut polls = 0;
+        while !additions.iter().all(|addition| addition.is_finished()) {
+            polls += 1;
+            tokio::task::yield_now().await;
+        }
+
+        for addition in additions {
+            assert_eq!(addition.await.unwrap(), PieceOutcome::Added);
+        }
+        let _ = tokio::fs::remove_dir_all(&download_dir).await;
+        assert!(piece_manager.is_complete());
+        assert!(polls > 3, "{polls}");
+    }
+
+    #[tokio::test]
+    async fn duplicate_blocks_ignored() {
+        let piece_manager =
+            PieceManager::new(&three_piece_meta_info(), &std::env::temp_dir()).await;
+
+        let peer = sender(1);
+
+        assert_eq!(
+            piece_manager.add_block(0, 0, b"ab", peer),
+            BlockOutcome::Stored
+        );
+        // Neither a repeated nor an overlapping block may overwrite "ab"
+        assert_eq!(
+            piece_manager.add_block(0, 0, b"xx", peer),
+            BlockOutcome::Duplicate
+        );
+        assert_eq!(
+            piece_manager.add_block(0, 1, b"ycd", peer),
+            BlockOutcome::Completed(AssembledPiece {
+                data: Bytes::from_static(b"abcd"),
+                senders: HashSet::from([peer]),
+            })
+        );
+        assert_eq!(
+            piece_manager.add_block(2, 1, b"jk", peer),
+            BlockOutcome::OutOfRange
+        );
+
+        let piece = Bytes::from_static(b"abcd");
+        assert_eq!(
+            piece_manager.add_piece(&0, piece).await,
+            PieceOutcome::Added
+        );
+        assert_eq!(
+            piece_manager.add_block(0, 2, b"cd", peer),
+            BlockOutcome::PieceDownloaded
+        );
+        assert!(piece_manager.partial_pieces.lock().unwrap().is_empty());
+        assert_eq!(
+            piece_manager.read_range(0, 4).await.as_deref(),
+            Some(b"abcd".as_slice())
+        );
+    }
+
+    #[tokio::test]
+    async fn assembled_piece_lists_every_sender() {
+        let piece_manager =
+            PieceManager::new(&three_piece_meta_info(), &std::env::temp_dir()).await;
+
+        piece_manager.add_block(0, 0, b"ab", sender(1));
+        // Only the peers whose bytes were written count
+        piece_manager.add_block(0, 0, b"ab", sender(2));
+        let outcome = piece_manager.add_block(0, 2, b"cd", sender(3));
+
+        assert_eq!(
+            outcome,
+            BlockOutcome::Completed(AssembledPiece {
+                data: Bytes::from_static(b"abcd"),
+                senders: HashSet::from([sender(1), sender(3)]),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn cancelled_piece_drops_its_blocks() {
+        let piece_manager =
+            PieceManager::new(&three_piece_meta_info(), &std::env::temp_dir()).await;
+        let their_bitfield = Bytes::from_static(&[0b1000_0000]);
+
+        assert_eq!(piece_manager.get_next_piece(&their_bitfield), Some(0));
+        piece_manager.add_block(0, 0, b"ab", sender(1));
+        assert!(piece_manager.cancel_piece(&0));
+
+        assert!(piece_manager.partial_pieces.lock().unwrap().is_empty());
+        assert_eq!(
+            piece_manager.add_block(0, 0, b"ab", sender(2)),
+            BlockOutcome::Stored
+        );
+    }
+
+    #[tokio::test]
+    async fn corrupt_piece_stays_with_other_endgame_holder() {
+        let meta_info = test_meta_info().data(b"abcdefgh").build();
+        let download_dir = temp_path("corrupt-endgame");
+        let piece_manager = PieceManager::new(&meta_info, &download_dir).await;
+        assert!(piece_manager.in_endgame());
+        let their_bitfield = Bytes::from_static(&[0b1000_0000]);
+        assert_eq!(piece_manager.get_next_piece(&their_bitfield), Some(0));
+        assert_eq!(piece_manager.get_next_piece(&their_bitfield), Some(0));
+
+        let corrupt = piece_manager
+            .add_piece(&0, Bytes::from_static(b"xxxx"))
+            .await;
+        let in_progress = piece_manager.pieces_in_progress();
+        // The other holder's copy completes the piece
+        let BlockOutcome::Completed(piece) = piece_manager.add_block(0, 0, b"abcd", sender(2))
+        else {
+            panic!("Expected the piece to be completed");
+        };
+        let valid = piece_manager.add_piece(&0, piece.data).await;
+        // Only created if the piece was saved
+        let _ = tokio::fs::remove_dir_all(&download_dir).await;
+
+        assert_eq!(corrupt, PieceOutcome::HashMismatch);
+        assert_eq!(in_progress, 1);
+        assert_eq!(valid, PieceOutcome::Added);
+        assert!(piece_manager.has_piece(0));
+    }
+
+    #[tokio::test]
+    async fn bitfield_sized_from_piece_hashes() {
+        let piece_manager =
+            PieceManager::new(&three_piece_meta_info(), &std::env::temp_dir()).await;
+        assert_eq!(piece_manager.get_bitfield().len(), 1);
+        assert_eq!(piece_manager.get_piece_count(), 3);
+
+        let meta_info = crate::torrent::read_meta_info(&PathBuf::from(
+            "../test/torrent_files/multi_file.torrent",
+        ))
+        .unwrap();
+        let piece_manager = PieceManager::new(&meta_info, &temp_path("multi")).await;
+        assert_eq!(
+            piece_manager.get_bitfield().len(),
+            meta_info.info.num_pieces().div_ceil(8)
+        );
+        assert_eq!(piece_manager.get_piece_count(), meta_info.info.num_pieces());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn ram_usage_stays_below_cap() {
+        const PIECE_LENGTH: usize = SAVE_BYTES_THRESHOLD;
+        const PIECE_COUNT: usize = 16;
+
+        let data: Vec<Bytes> = (0..PIECE_COUNT)
+            .map(|i| Bytes::from(vec![i as u8; PIECE_LENGTH]))
+            .collect();
+        let meta_info = test_meta_info()
+            .hash([3u8; 20])
+            .name("ram-cap")
+            .piece_length(PIECE_LENGTH)
+            .data(&data.concat())
+            .build();
+
+        let download_dir = temp_path("ram-cap");
+        let piece_manager = std::sync::Arc::new(PieceManager::new(&meta_info, &download_dir).await);
+
+        let mut tasks = JoinSet::new();
+        for (index, piece) in data.into_iter().enumerate() {
+            let piece_manager = piece_manager.clone();
+            tasks.spawn(async move { piece_manager.add_piece(&index, piece).await });
+        }
+
+        let mut peak = 0;
+        while !tasks.is_empty() {
+            peak = peak.max(piece_manager.bytes_in_ram());
+            tokio::select! {
+                result = tasks.join_next() => {
+                    assert_eq!(result.unwrap().unwrap(), PieceOutcome::Added);
+                }
+                _ = tokio::task::yield_now() => {}
+            }
+        }
+        tokio::fs::remove_dir_all(&download_dir).await.unwrap();
+
+        assert!(peak <= MAX_BYTES_IN_RAM, "{peak} bytes held in memory");
+        assert!(piece_manager.is_complete());
+        assert_eq!(piece_manager.bytes_in_ram(), 0);
+    }
+
+    /// Ten two block pieces, enough to stay out of endgame mode
+    fn ten_piece_meta_info() -> MetaInfo {
+        test_meta_info()
+            .hash([3u8; 20])
+            .piece_length(BLOCK_SIZE * 2)
+            .length(BLOCK_SIZE * 20)
+            .build()
+    }
+
+    #[tokio::test]
+    async fn sequential_returns_lowest_available_piece() {
+        let piece_manager = PieceManager::new(&ten_piece_meta_info(), &std::env::temp_dir()).await;
+        piece_manager.set_strategy(PieceStrategy::Sequential);
+        // The peer has pieces 2, 5 and 7
+        let their_bitfield = Bytes::from(vec![0b00100101, 0]);
+
+        assert_eq!(piece_manager.get_next_piece(&their_bitfield), Some(2));
+        assert_eq!(piece_manager.get_next_piece(&their_bitfield), Some(5));
+        assert_eq!(piece_manager.get_next_piece(&their_bitfield), Some(7));
+        // Piece 2 is still in progress and within the priority window
+        assert_eq!(piece_manager.get_next_piece(&their_bitfield), Some(2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stalled_piece_handed_out_again() {
+        let piece_manager = PieceManager::new(&ten_piece_meta_info(), &std::env::temp_dir()).await;
+        let their_bitfield = Bytes::from(vec![0b10000000, 0]);
+
+        assert_eq!(piece_manager.get_next_piece(&their_bitfield), Some(0));
+        assert_eq!(piece_manager.get_next_piece(&their_bitfield), None);
+
+        tokio::time::advance(STALLED_PIECE_TIMEOUT).await;
+        assert_eq!(piece_manager.get_next_piece(&their_bitfield), Some(0));
+        // Handing it out again restarts the timeout
+        assert_eq!(piece_manager.get_next_piece(&their_bitfield), None);
+    }
+
+    #[tokio::test]
+    async fn pieces_in_progress_limited() {
+        let piece_manager =
+            PieceManager::new(&three_piece_meta_info(), &temp_path("in-progress")).await;
+        piece_manager.set_max_pieces_in_progress(2);
+
+        assert_eq!(
+            piece_manager.get_next_piece(&Bytes::from(vec![0b10000000])),
+            Some(0)
+        );
+        assert_eq!(
+            piece_manager.get_next_piece(&Bytes::from(vec![0b01000000])),
+            Some(1)
+        );
+        assert_eq!(
+            piece_manager.get_next_piece(&Bytes::from(vec![0b00100000])),
+            None
+        );
+
+        piece_manager
+            .add_piece(&0, Bytes::from_static(b"abcd"))
+            .await;
+        assert_eq!(
+            piece_manager.get_next_piece(&Bytes::from(vec![0b00100000])),
+            Some(2)
+        );
+    }
+
+    #[tokio::test]
+    async fn sequential_starts_at_playback_position() {
+        let piece_manager = PieceManager::new(&ten_piece_meta_info(), &std::env::temp_dir()).await;
+        piece_manager.set_strategy(PieceStrategy::Sequential);
+        piece_manager.set_playback_position(6);
+        let their_bitfield = Bytes::from(vec![0xff, 0b11000000]);
+
+        let picked: Vec<Option<usize>> = (0..5)
+            .map(|_| piece_manager.get_next_piece(&their_bitfield))
+            .collect();
+
+        assert_eq!(picked, [Some(6), Some(7), Some(8), Some(9), Some(0)]);
+    }
+
+    #[derive(Debug)]
+    struct AlwaysThree;
+
+    impl PiecePicker for AlwaysThree {
+        fn next_piece(&self, _available: &Bytes, state: &PieceState) -> Option<usize> {
+            (!state.is_taken(3)).then_some(3)
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_picker_honored() {
+        let piece_manager = PieceManager::new(&ten_piece_meta_info(), &std::env::temp_dir()).await;
+        assert_eq!(piece_manager.strategy(), Some(PieceStrategy::Standard));
+        piece_manager.set_picker(Box::new(AlwaysThree));
+        assert_eq!(piece_manager.strategy(), None);
+        let their_bitfield = Bytes::from(vec![0xff, 0b11000000]);
+
+        assert_eq!(piece_manager.get_next_piece(&their_bitfield), Some(3));
+        // Piece 3 is in progress now, so it can't be started again
+        assert_eq!(piece_manager.get_next_piece(&their_bitfield), None);
+    }
+
+    #[tokio::test]
+    async fn rarest_piece_picked_first() {
+        let piece_manager = PieceManager::new(&ten_piece_meta_info(), &std::env::temp_dir()).await;
+        piece_manager.set_strategy(PieceStrategy::RarestFirst);
+        assert_eq!(piece_manager.strategy(), Some(PieceStrategy::RarestFirst));
+        piece_manager.add_available(&Bytes::from(vec![0xff, 0b11000000]));
+        piece_manager.add_available(&Bytes::from(vec![0b11011111, 0b11000000]));
+        let their_bitfield = Bytes::from(vec![0b00110000, 0]);
+
+        assert_eq!(piece_manager.get_next_piece(&their_bitfield), Some(2));
+        piece_manager.remove_available(&Bytes::from(vec![0xff, 0b11000000]));
+        assert_eq!(piece_manager.get_next_piece(&their_bitfield), Some(3));
+    }
+
+    /// Files of 4, 4 and 2 bytes, one per piece
+    fn three_file_meta_info() -> MetaInfo {
+        let mut meta_info = three_piece_meta_info();
+        meta_info.info.name = format!("rtorrent-{}-priority", std::process::id());
+        meta_info.info.length = None;
+        meta_info.info.files = Some(
+            [4, 4, 2]
+                .into_iter()
+                .enumerate()
+                .map(|(i, length)| FileInfo {
+                    length,
+                    path: vec![PathBuf::from(format!("{i}.txt"))],
+                    attr: None,
+                })
+                .collect(),
+        );
+        meta_info
+    }
+
+    #[tokio::test]
+    async fn skipped_file_never_requested() {
+        let meta_info = three_file_meta_info();
+        let download_dir = std::env::temp_dir();
+        let piece_manager = PieceManager::new(&meta_info, &download_dir).await;
+        let all = Bytes::from(vec![0b11100000]);
+
+        assert!(piece_manager.set_file_priority(1, PiecePriority::Skip));
+        assert_eq!(piece_manager.piece_priority(1), Some(PiecePriority::Skip));
+        assert_eq!(piece_manager.get_next_piece(&all), Some(0));
+        assert_eq!(piece_manager.get_next_piece(&all), Some(2));
+        assert_eq!(
+            piece_manager.get_next_piece(&Bytes::from(vec![0b01000000])),
+            None
+        );
+
+        piece_manager
+            .add_piece(&0, Bytes::from_static(b"abcd"))
+            .await;
+        piece_manager.add_piece(&2, Bytes::from_static(b"ij")).await;
+        let complete = piece_manager.is_complete();
+        tokio::fs::remove_dir_all(download_dir.join(&meta_info.info.name))
+            .await
+            .unwrap();
+        tokio::fs::remove_file(resume_path(piece_manager.files.root()))
+            .await
+            .unwrap();
+
+        assert!(complete);
+        assert!(*piece_manager.subscribe_complete().borrow());
+        assert_eq!(piece_manager.completed_pieces(), 2);
+    }
+
+    #[tokio::test]
+    async fn high_priority_pieces_requested_first() {
+        let piece_manager =
+            PieceManager::new(&three_piece_meta_info(), &std::env::temp_dir()).await;
+        let all = Bytes::from(vec![0b11100000]);
+
+        assert!(piece_manager.set_piece_priority(2, PiecePriority::High));
+        assert!(!piece_manager.set_piece_priority(3, PiecePriority::High));
+        assert_eq!(piece_manager.get_next_piece(&all), Some(2));
+        assert_eq!(piece_manager.get_next_piece(&all), Some(0));
+        assert_eq!(piece_manager.get_next_piece(&all), Some(1));
+    }
+
+    #[tokio::test]
+    async fn bitfield_shared_until_changed() {
+        let piece_manager =
+            PieceManager::new(&three_piece_meta_info(), &std::env::temp_dir()).await;
+
+        let first = piece_manager.get_bitfield();
+        let second = piece_manager.get_bitfield();
+        assert_eq!(first.as_ptr(), second.as_ptr());
+
+        piece_manager.update_bitfield(&1);
+        let third = piece_manager.get_bitfield();
+        assert_ne!(first.as_ptr(), third.as_ptr());
+        assert_eq!(first.as_ref(), [0]);
+        assert_eq!(third.as_ref(), [0b01000000]);
+    }
 }
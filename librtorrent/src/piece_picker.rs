@@ -0,0 +1,153 @@
+use std::fmt::Debug;
+
+use bytes::Bytes;
+
+use crate::piece_manager::{is_bit_set, PiecePriority, PRIORITY_WINDOW};
+
+/// What a `PiecePicker` gets to see of the download when picking a piece
+#[derive(Debug)]
+pub struct PieceState<'a> {
+    /// Pieces we have
+    pub bitfield: &'a Bytes,
+    pub priorities: &'a [PiecePriority],
+    /// Number of connected peers that have each piece
+    pub availability: &'a [u32],
+    /// Piece sequential downloading continues from
+    pub playback_position: usize,
+    /// Pieces being downloaded or waiting to be written to disk
+    pub(crate) taken: &'a [bool],
+}
+
+impl PieceState<'_> {
+    pub fn piece_count(&self) -> usize {
+        self.priorities.len()
+    }
+
+    /// Whether piece `index` is being downloaded or waiting to be written
+    pub fn is_taken(&self, index: usize) -> bool {
+        self.taken.get(index).copied().unwrap_or(false)
+    }
+
+    /// Whether piece `index` may be started: it isn't skipped, we don't
+    /// have it, nobody is downloading it yet and `available` has it
+    pub fn is_wanted(&self, index: usize, available: &Bytes) -> bool {
+        self.priorities
+            .get(index)
+            .is_some_and(|&priority| priority != PiecePriority::Skip)
+            && !self.is_taken(index)
+            && !is_bit_set(self.bitfield, index)
+            && is_bit_set(available, index)
+    }
+
+    /// First wanted piece of `order`, preferring `High` priority pieces
+    pub fn first_wanted(
+        &self,
+        order: impl Iterator<Item = usize>,
+        available: &Bytes,
+    ) -> Option<usize> {
+        let mut normal = None;
+        for index in order.filter(|&index| self.is_wanted(index, available)) {
+            if self.priorities[index] == PiecePriority::High {
+                return Some(index);
+            }
+            normal.get_or_insert(index);
+        }
+
+        normal
+    }
+}
+
+/// Decides which piece to download next from a peer. Pieces already in
+/// progress are handed out again by the piece manager itself, in endgame
+/// mode or when they stall.
+pub trait PiecePicker: Debug + Send + Sync {
+    /// Piece to start downloading from a peer that has the pieces in
+    /// `available`, which should be one `state` considers wanted
+    fn next_piece(&self, available: &Bytes, state: &PieceState) -> Option<usize>;
+
+    /// Whether piece `index`, which is in progress, is needed so soon that
+    /// it should be downloaded from several peers at once
+    fn is_urgent(&self, _index: usize, _state: &PieceState) -> bool {
+        false
+    }
+}
+
+/// The first wanted piece the peer has
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InOrder;
+
+impl PiecePicker for InOrder {
+    fn next_piece(&self, available: &Bytes, state: &PieceState) -> Option<usize> {
+        state.first_wanted(0..state.piece_count(), available)
+    }
+}
+
+/// Pieces in order starting from the playback position, wrapping around to
+/// the pieces before it once those after it are taken. Pieces within
+/// `PRIORITY_WINDOW` of the position are urgent.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sequential;
+
+impl PiecePicker for Sequential {
+    fn next_piece(&self, available: &Bytes, state: &PieceState) -> Option<usize> {
+        let piece_count = state.piece_count();
+        let position = state.playback_position.min(piece_count);
+
+        state.first_wanted((position..piece_count).chain(0..position), available)
+    }
+
+    fn is_urgent(&self, index: usize, state: &PieceState) -> bool {
+        index >= state.playback_position && index - state.playback_position < PRIORITY_WINDOW
+    }
+}
+
+/// The wanted piece the fewest connected peers have, so rare pieces spread
+/// before the peers that have them leave. Ties go to the lowest index.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RarestFirst;
+
+impl PiecePicker for RarestFirst {
+    fn next_piece(&self, available: &Bytes, state: &PieceState) -> Option<usize> {
+        (0..state.piece_count())
+            .filter(|&index| state.is_wanted(index, available))
+            .min_by_key(|&index| {
+                (
+                    state.priorities[index] != PiecePriority::High,
+                    state.availability[index],
+                    index,
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rarest_first_prefers_rare_and_high_priority_pieces() {
+        let bitfield = Bytes::from(vec![0b10000000]);
+        let mut priorities = vec![PiecePriority::Normal; 6];
+        let availability = [1, 5, 3, 2, 2, 4];
+        let mut taken = [false; 6];
+        taken[3] = true;
+        let available = Bytes::from(vec![0b11111100]);
+        let pick = |priorities: &[PiecePriority]| {
+            let state = PieceState {
+                bitfield: &bitfield,
+                priorities,
+                availability: &availability,
+                playback_position: 0,
+                taken: &taken,
+            };
+            RarestFirst.next_piece(&available, &state)
+        };
+
+        // Piece 0 is ours and piece 3 is in progress
+        assert_eq!(pick(&priorities), Some(4));
+        priorities[4] = PiecePriority::Skip;
+        assert_eq!(pick(&priorities), Some(2));
+        priorities[5] = PiecePriority::High;
+        assert_eq!(pick(&priorities), Some(5));
+    }
+}
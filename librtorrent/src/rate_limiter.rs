@@ -0,0 +1,158 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+/// Token bucket limiting throughput to a number of bytes per second.
+/// Tokens accumulate for at most one second, so bursts are smoothed over ~1s windows.
+/// A rate of 0 means unlimited.
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes_per_second: AtomicU64,
+    bucket: Mutex<Bucket>,
+    parent: Option<Arc<RateLimiter>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Download and upload limiters applied to peer connections
+#[derive(Debug, Clone)]
+pub struct RateLimits {
+    pub download: Arc<RateLimiter>,
+    pub upload: Arc<RateLimiter>,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_second: u64) -> Self {
+        RateLimiter {
+            bytes_per_second: AtomicU64::new(bytes_per_second),
+            bucket: Mutex::new(Bucket {
+                tokens: 0.0,
+                last_refill: Instant::now(),
+            }),
+            parent: None,
+        }
+    }
+
+    /// Creates a limiter that also consults `parent` on every acquire,
+    /// e.g. a per-torrent limiter nested under the session-wide one.
+    pub fn with_parent(bytes_per_second: u64, parent: Arc<RateLimiter>) -> Self {
+        RateLimiter {
+            parent: Some(parent),
+            ..RateLimiter::new(bytes_per_second)
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.bytes_per_second.load(Ordering::Relaxed)
+    }
+
+    pub fn set_rate(&self, bytes_per_second: u64) {
+        self.bytes_per_second
+            .store(bytes_per_second, Ordering::Relaxed);
+    }
+
+    /// Waits until `bytes` may be transferred under this limiter and its parents
+    pub async fn acquire(&self, bytes: usize) {
+        let wait = self.take(bytes);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+
+        if let Some(parent) = &self.parent {
+            Box::pin(parent.acquire(bytes)).await;
+        }
+    }
+
+    /// Removes `bytes` tokens from the bucket, returning how long the caller
+    /// has to wait for the bucket to pay off the resulting debt.
+    fn take(&self, bytes: usize) -> Duration {
+        let rate = self.rate();
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+
+        if rate == 0 {
+            bucket.tokens = 0.0;
+            bucket.last_refill = now;
+            return Duration::ZERO;
+        }
+
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate as f64).min(rate as f64);
+        bucket.last_refill = now;
+        bucket.tokens -= bytes as f64;
+
+        if bucket.tokens < 0.0 {
+            Duration::from_secs_f64(-bucket.tokens / rate as f64)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+impl RateLimits {
+    pub fn new(download: u64, upload: u64) -> Self {
+        RateLimits {
+            download: Arc::new(RateLimiter::new(download)),
+            upload: Arc::new(RateLimiter::new(upload)),
+        }
+    }
+
+    /// Creates unlimited limits nested under `parent`
+    pub fn child_of(parent: &RateLimits) -> Self {
+        RateLimits {
+            download: Arc::new(RateLimiter::with_parent(0, parent.download.clone())),
+            upload: Arc::new(RateLimiter::with_parent(0, parent.upload.clone())),
+        }
+    }
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits::new(0, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn limits_throughput() {
+        let limiter = RateLimiter::new(100 * 1024);
+        let start = Instant::now();
+
+        for _ in 0..500 {
+            limiter.acquire(1024).await;
+        }
+
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(4990), "{elapsed:?}");
+        assert!(elapsed < Duration::from_secs(6), "{elapsed:?}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unlimited_child_respects_parent() {
+        let global = RateLimits::new(100 * 1024, 0);
+        let torrent = RateLimits::child_of(&global);
+        let start = Instant::now();
+
+        for _ in 0..100 {
+            torrent.download.acquire(1024).await;
+            torrent.upload.acquire(1024).await;
+        }
+
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(990), "{elapsed:?}");
+        assert!(elapsed < Duration::from_secs(2), "{elapsed:?}");
+    }
+}
@@ -0,0 +1,86 @@
+use std::{collections::VecDeque, time::Duration};
+
+use tokio::time::Instant;
+
+/// Window transfer rates are averaged over
+pub const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Measures throughput as the bytes transferred over a rolling window
+#[derive(Debug, Clone)]
+pub struct RateTracker {
+    window: Duration,
+    /// Bytes transferred and when, oldest first
+    samples: VecDeque<(Instant, u64)>,
+}
+
+/// Download and upload rates of a peer or a whole torrent
+#[derive(Debug, Clone, Default)]
+pub struct TransferRates {
+    pub download: RateTracker,
+    pub upload: RateTracker,
+}
+
+impl RateTracker {
+    pub fn new(window: Duration) -> Self {
+        RateTracker {
+            window,
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// Records `bytes` transferred just now
+    pub fn record(&mut self, bytes: u64) {
+        let now = Instant::now();
+        self.samples.push_back((now, bytes));
+
+        while let Some(&(at, _)) = self.samples.front() {
+            if now.duration_since(at) <= self.window {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+
+    /// Average bytes per second over the window
+    pub fn rate(&self) -> u64 {
+        let now = Instant::now();
+        let bytes: u64 = self
+            .samples
+            .iter()
+            .filter(|(at, _)| now.duration_since(*at) <= self.window)
+            .map(|(_, bytes)| bytes)
+            .sum();
+
+        (bytes as f64 / self.window.as_secs_f64()) as u64
+    }
+}
+
+impl Default for RateTracker {
+    fn default() -> Self {
+        RateTracker::new(RATE_WINDOW)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_averaged_over_window() {
+        let mut tracker = RateTracker::default();
+        assert_eq!(tracker.rate(), 0);
+
+        for _ in 0..5 {
+            tracker.record(20_000);
+            tokio::time::advance(Duration::from_secs(1)).await;
+        }
+        assert_eq!(tracker.rate(), 10_000);
+
+        // The first two samples fall out of the window
+        tokio::time::advance(Duration::from_secs(7)).await;
+        assert_eq!(tracker.rate(), 6_000);
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(tracker.rate(), 0);
+    }
+}
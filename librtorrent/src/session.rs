@@ -1,39 +1,350 @@
-use std::path::PathBuf;
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use log::warn;
+use log::{debug, info, warn};
+use thiserror::Error;
+use tokio::{
+    net::TcpListener,
+    sync::{broadcast, watch},
+    task::JoinHandle,
+};
 
-use crate::torrent::Torrent;
+use crate::{
+    ban_list::BanList,
+    config::SessionConfig,
+    event::TorrentEvent,
+    handshake::Handshake,
+    lsd::{LocalDiscovery, LocalTorrents},
+    peer_id::PeerId,
+    peer_manager::{IncomingPeers, PeerSource},
+    rate_limiter::RateLimits,
+    torrent::{Torrent, TorrentErr, TorrentStatus},
+    tracker::TrackerClient,
+};
+
+/// Ports tried after the configured one when it is taken, giving the
+/// usual 6881-6889 range for the default port
+const LISTEN_PORT_ATTEMPTS: u16 = 9;
+/// Time to back off for when accepting a connection fails, e.g. because
+/// we ran out of file descriptors
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Started torrents accepting peer connections by info hash, shared with
+/// the task accepting them
+type IncomingTorrents = Arc<Mutex<HashMap<[u8; 20], IncomingPeers>>>;
+
+#[derive(Debug, Error)]
+pub enum SessionErr {
+    #[error("Torrent not found in session")]
+    TorrentNotFound([u8; 20]),
+}
 
 pub struct Session {
-    // TODO: this could be a HashMap with info_hash as key
-    torrents: Vec<Torrent>,
+    torrents: BTreeMap<[u8; 20], Torrent>,
+    peer_id: PeerId,
+    tracker: TrackerClient,
+    rate_limits: RateLimits,
+    config: SessionConfig,
+    bans: Arc<BanList>,
+    /// Task accepting peer connections, started by `listen`
+    listener: Option<JoinHandle<()>>,
+    /// Started torrents incoming peer connections are handed to
+    incoming_torrents: IncomingTorrents,
+    /// Started torrents announced to the local network
+    local_torrents: LocalTorrents,
+    /// Task started by `start_local_discovery`
+    local_discovery: Option<JoinHandle<()>>,
+    /// Port peers can connect to, watched by local discovery
+    listen_port: watch::Sender<u16>,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new(SessionConfig::default())
+    }
 }
 
 impl Session {
-    pub fn new() -> Self {
+    pub fn new(config: SessionConfig) -> Self {
+        Self::with_tracker_client(config, TrackerClient::default())
+    }
+
+    /// Creates a session whose torrents announce through `tracker`,
+    /// e.g. one built with a custom timeout
+    pub fn with_tracker_client(config: SessionConfig, tracker: TrackerClient) -> Self {
         Self {
-            torrents: Vec::new(),
+            torrents: BTreeMap::new(),
+            peer_id: PeerId::with_prefix(&config.peer_id_prefix),
+            tracker,
+            rate_limits: RateLimits::new(config.download_limit, config.upload_limit),
+            listen_port: watch::Sender::new(config.port),
+            config,
+            bans: Arc::new(BanList::new()),
+            listener: None,
+            incoming_torrents: IncomingTorrents::default(),
+            local_torrents: LocalTorrents::default(),
+            local_discovery: None,
+        }
+    }
+
+    /// Binds the listener for incoming peer connections to the configured
+    /// port, or the next free one of the following `LISTEN_PORT_ATTEMPTS`,
+    /// and starts handing the connections to started torrents.
+    /// The bound port is what every torrent announces from then on.
+    pub async fn listen(&mut self) -> Result<u16, io::Error> {
+        let first_port = self.config.port;
+        let last_port = first_port.saturating_add(LISTEN_PORT_ATTEMPTS - 1);
+
+        let mut last_err = None;
+        for port in first_port..=last_port {
+            match TcpListener::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port))).await {
+                Ok(listener) => {
+                    let port = listener.local_addr()?.port();
+                    info!("Listening for peers on port {port}");
+                    if let Some(task) = self.listener.take() {
+                        task.abort();
+                    }
+                    self.listener = Some(tokio::spawn(accept_peers(
+                        listener,
+                        self.incoming_torrents.clone(),
+                    )));
+                    self.set_port(port);
+                    return Ok(port);
+                }
+                Err(err) => {
+                    debug!("Failed to listen on port {port}: {err}");
+                    last_err = Some(err);
+                }
+            }
         }
+
+        Err(last_err.unwrap_or_else(|| io::Error::from(io::ErrorKind::AddrInUse)))
     }
 
+    /// Starts announcing started torrents to the local network and
+    /// connecting to the local peers that announce them too (BEP-14).
+    /// Announces carry the port bound by `listen`, announcing again once it
+    /// binds one. Private torrents are never announced.
+    pub async fn start_local_discovery(&mut self) -> Result<(), io::Error> {
+        if let Some(task) = self.local_discovery.take() {
+            task.abort();
+        }
+
+        let discovery = LocalDiscovery::bind().await?;
+        info!("Local peer discovery started");
+        self.local_discovery = Some(tokio::spawn(
+            discovery.run(self.listen_port.subscribe(), self.local_torrents.clone()),
+        ));
+
+        Ok(())
+    }
+
+    /// Port announced to trackers
+    pub fn port(&self) -> u16 {
+        self.config.port
+    }
+
+    fn set_port(&mut self, port: u16) {
+        self.config.port = port;
+        self.listen_port.send_replace(port);
+        for torrent in self.torrents.values_mut() {
+            torrent.set_port(port);
+        }
+    }
+
+    /// Starts every torrent, see `start_torrent`, then waits until their
+    /// peer connections finished
     pub async fn start(&mut self) {
-        for torrent in &mut self.torrents {
-            torrent.start().await;
+        let info_hashes: Vec<[u8; 20]> = self.torrents.keys().copied().collect();
+        for info_hash in info_hashes {
+            // Every hash was just taken from the session
+            let _ = self.start_torrent(&info_hash).await;
+        }
+
+        for torrent in self.torrents.values_mut() {
+            torrent.wait().await;
         }
     }
 
-    pub async fn add_torrent(self: &mut Self, path: &str) {
-        if Session::is_torrent_file(path) {
-            match Torrent::from_file(&PathBuf::from(path)).await {
-                Ok(torrent) => self.torrents.push(torrent),
-                Err(error) => warn!("Failed to add torrent with error: {error:#?}"),
-            }
+    /// Adds a torrent from a `.torrent` file path or magnet link.
+    /// Returns the info hash of the added torrent, or fails with
+    /// `TorrentErr::AlreadyAdded` if the session already has it.
+    /// Files are written to `download_dir`, or the session's download
+    /// directory if it is `None`.
+    pub async fn add_torrent(
+        &mut self,
+        path: &str,
+        download_dir: Option<&Path>,
+    ) -> Result<[u8; 20], TorrentErr> {
+        let config = self.torrent_config(download_dir);
+        let torrent = if Session::is_torrent_file(path) {
+            Torrent::from_file(
+                &PathBuf::from(path),
+                self.peer_id,
+                self.tracker.clone(),
+                &self.rate_limits,
+                &config,
+                self.bans.clone(),
+            )
+            .await?
         } else {
-            match Torrent::from_magnet(path) {
-                Ok(torrent) => self.torrents.push(torrent),
-                Err(error) => warn!("Failed to add torrent with error: {error:#?}"),
-            }
+            Torrent::from_magnet(
+                path,
+                self.peer_id,
+                self.tracker.clone(),
+                &self.rate_limits,
+                &config,
+                self.bans.clone(),
+            )?
+        };
+
+        self.insert_torrent(torrent)
+    }
+
+    /// Adds a torrent from the contents of a `.torrent` file, returning its
+    /// info hash. Files are written as with `add_torrent`.
+    pub async fn add_torrent_bytes(
+        &mut self,
+        bytes: &[u8],
+        download_dir: Option<&Path>,
+    ) -> Result<[u8; 20], TorrentErr> {
+        let config = self.torrent_config(download_dir);
+        let torrent = Torrent::from_bytes(
+            bytes,
+            self.peer_id,
+            self.tracker.clone(),
+            &self.rate_limits,
+            &config,
+            self.bans.clone(),
+        )
+        .await?;
+
+        self.insert_torrent(torrent)
+    }
+
+    /// Starts downloading the torrent with the given info hash, accepting
+    /// peer connections for it and announcing it to the local network
+    pub async fn start_torrent(&mut self, info_hash: &[u8; 20]) -> Result<(), SessionErr> {
+        let torrent = self
+            .torrents
+            .get_mut(info_hash)
+            .ok_or(SessionErr::TorrentNotFound(*info_hash))?;
+
+        torrent.start().await;
+        self.incoming_torrents
+            .lock()
+            .unwrap()
+            .insert(*info_hash, torrent.incoming_peers());
+        if torrent.wants_peers_from(PeerSource::LocalDiscovery) {
+            self.local_torrents
+                .lock()
+                .unwrap()
+                .insert(*info_hash, torrent.discovered_peers());
+        }
+
+        Ok(())
+    }
+
+    /// The peer ID shared by every torrent in this session
+    pub fn peer_id(&self) -> &PeerId {
+        &self.peer_id
+    }
+
+    pub fn config(&self) -> &SessionConfig {
+        &self.config
+    }
+
+    pub fn torrents(&self) -> impl Iterator<Item = &Torrent> {
+        self.torrents.values()
+    }
+
+    /// Returns the status of every torrent in the session, ordered by info hash
+    pub fn list(&self) -> Vec<TorrentStatus> {
+        self.torrents.values().map(Torrent::status).collect()
+    }
+
+    pub fn status(&self, info_hash: &[u8; 20]) -> Result<TorrentStatus, SessionErr> {
+        self.torrents
+            .get(info_hash)
+            .map(Torrent::status)
+            .ok_or(SessionErr::TorrentNotFound(*info_hash))
+    }
+
+    /// Returns a receiver for the events of the torrent with the given info
+    /// hash from now on
+    pub fn subscribe(
+        &self,
+        info_hash: &[u8; 20],
+    ) -> Result<broadcast::Receiver<TorrentEvent>, SessionErr> {
+        self.torrents
+            .get(info_hash)
+            .map(Torrent::subscribe)
+            .ok_or(SessionErr::TorrentNotFound(*info_hash))
+    }
+
+    /// Stops the torrent with the given info hash and removes it from the session
+    pub async fn remove_torrent(&mut self, info_hash: &[u8; 20]) -> Result<(), SessionErr> {
+        let mut torrent = self
+            .torrents
+            .remove(info_hash)
+            .ok_or(SessionErr::TorrentNotFound(*info_hash))?;
+        self.local_torrents.lock().unwrap().remove(info_hash);
+        self.incoming_torrents.lock().unwrap().remove(info_hash);
+
+        torrent.stop().await;
+
+        Ok(())
+    }
+
+    /// Stops every torrent, saving downloaded pieces and telling trackers
+    /// we stopped. Call before exiting so no downloaded data is lost.
+    pub async fn shutdown(&mut self) {
+        for task in [self.local_discovery.take(), self.listener.take()]
+            .into_iter()
+            .flatten()
+        {
+            task.abort();
         }
+        for torrent in self.torrents.values_mut() {
+            torrent.stop().await;
+        }
+    }
+
+    /// Sets the global download limit in bytes per second, 0 is unlimited
+    pub fn set_download_limit(&self, bytes_per_second: u64) {
+        self.rate_limits.download.set_rate(bytes_per_second);
+    }
+
+    /// Sets the global upload limit in bytes per second, 0 is unlimited
+    pub fn set_upload_limit(&self, bytes_per_second: u64) {
+        self.rate_limits.upload.set_rate(bytes_per_second);
+    }
+
+    /// The session's config with its download directory replaced by
+    /// `download_dir`, if given
+    fn torrent_config(&self, download_dir: Option<&Path>) -> SessionConfig {
+        let mut config = self.config.clone();
+        if let Some(download_dir) = download_dir {
+            config.download_dir = download_dir.to_path_buf();
+        }
+        config
+    }
+
+    /// Adds `torrent` unless the session already has it, which would
+    /// leave the running one downloading alongside its replacement
+    fn insert_torrent(&mut self, torrent: Torrent) -> Result<[u8; 20], TorrentErr> {
+        let info_hash = *torrent.info_hash();
+        if self.torrents.contains_key(&info_hash) {
+            return Err(TorrentErr::AlreadyAdded(info_hash));
+        }
+        self.torrents.insert(info_hash, torrent);
+        Ok(info_hash)
     }
 
     //TODO: better way to check if input is file or magnet
@@ -41,3 +352,305 @@ impl Session {
         path.ends_with(".torrent")
     }
 }
+
+/// Accepts peer connections on `listener`, handing each to the started
+/// torrent its handshake asks for. Connections for other torrents are dropped.
+async fn accept_peers(listener: TcpListener, torrents: IncomingTorrents) {
+    loop {
+        let (mut stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                warn!("Failed to accept peer connection: {err}");
+                tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                continue;
+            }
+        };
+
+        let torrents = torrents.clone();
+        tokio::spawn(async move {
+            let handshake = match Handshake::from_stream(&mut stream).await {
+                Ok(handshake) => handshake,
+                Err(err) => {
+                    debug!("Dropping connection from {addr}: {err}");
+                    return;
+                }
+            };
+            let peers = torrents.lock().unwrap().get(&handshake.info_hash).cloned();
+            match peers {
+                // Only fails once the torrent is gone
+                Some(peers) => {
+                    let _ = peers.send((addr, stream, handshake));
+                }
+                None => debug!("Dropping connection from {addr} for an unknown torrent"),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use crate::test_util::{temp_path, test_meta_info};
+
+    use super::*;
+
+    const TEST_TORRENT: &str = "../test/torrent_files/debian-13.1.0-amd64-netinst.iso.torrent";
+    const OTHER_TORRENT: &str = "../test/torrent_files/archlinux-2025.11.01-x86_64.iso.torrent";
+
+    #[tokio::test]
+    async fn magnet_link_rejected() {
+        let mut session = Session::default();
+        let result = session
+            .add_torrent(
+                "magnet:?xt=urn:btih:0000000000000000000000000000000000000000",
+                None,
+            )
+            .await;
+
+        assert!(matches!(result, Err(TorrentErr::MagnetUnsupported)));
+        assert!(session.torrents.is_empty());
+    }
+
+    #[tokio::test]
+    async fn add_torrent_returns_info_hash() {
+        let mut session = Session::default();
+        let info_hash = session.add_torrent(TEST_TORRENT, None).await.unwrap();
+
+        assert!(session.torrents.contains_key(&info_hash));
+        assert_eq!(session.torrents[&info_hash].info_hash(), &info_hash);
+    }
+
+    #[tokio::test]
+    async fn torrent_added_twice_rejected() {
+        let mut session = Session::default();
+        let info_hash = session.add_torrent(TEST_TORRENT, None).await.unwrap();
+        let bytes = tokio::fs::read(TEST_TORRENT).await.unwrap();
+
+        let again = session.add_torrent(TEST_TORRENT, None).await;
+        let from_bytes = session.add_torrent_bytes(&bytes, None).await;
+
+        assert!(matches!(again, Err(TorrentErr::AlreadyAdded(hash)) if hash == info_hash));
+        assert!(matches!(from_bytes, Err(TorrentErr::AlreadyAdded(hash)) if hash == info_hash));
+        assert_eq!(session.torrents.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn list_returns_every_torrent() {
+        let mut session = Session::default();
+        session.add_torrent(TEST_TORRENT, None).await.unwrap();
+        session.add_torrent(OTHER_TORRENT, None).await.unwrap();
+
+        let list = session.list();
+
+        assert_eq!(list.len(), 2);
+        assert_ne!(list[0].info_hash, list[1].info_hash);
+    }
+
+    #[tokio::test]
+    async fn add_torrent_missing_file() {
+        let mut session = Session::default();
+        let result = session.add_torrent("does/not/exist.torrent", None).await;
+
+        assert!(matches!(result, Err(TorrentErr::IoErr(_))));
+        assert!(session.torrents.is_empty());
+    }
+
+    #[tokio::test]
+    async fn torrents_written_to_their_own_dirs() {
+        let base = temp_path("dirs");
+        let config = SessionConfig::builder()
+            .state_dir(base.join("state"))
+            .build()
+            .unwrap();
+        let mut session = Session::new(config);
+
+        let mut added = Vec::new();
+        for name in ["movies", "software"] {
+            let meta_info = test_meta_info()
+                .hash([0u8; 20])
+                .name(format!("{name}.bin"))
+                .build();
+            let dir = base.join(name);
+            let info_hash = session
+                .add_torrent_bytes(&meta_info.to_bytes(), Some(&dir))
+                .await
+                .unwrap();
+            added.push((info_hash, dir, meta_info.info.name));
+        }
+
+        let mut written = Vec::new();
+        for (info_hash, dir, name) in &added {
+            let torrent = &session.torrents[info_hash];
+            assert_eq!(torrent.download_dir(), dir);
+            torrent
+                .piece_manager()
+                .add_piece(&0, bytes::Bytes::from_static(b"abcd"))
+                .await;
+            written.push(tokio::fs::read(dir.join(name)).await);
+        }
+        tokio::fs::remove_dir_all(&base).await.unwrap();
+
+        for file in written {
+            assert_eq!(file.unwrap(), b"abcd");
+        }
+    }
+
+    #[tokio::test]
+    async fn remove_torrent() {
+        let mut session = Session::default();
+        let info_hash = session.add_torrent(TEST_TORRENT, None).await.unwrap();
+        assert_eq!(session.torrents.len(), 1);
+
+        session.remove_torrent(&info_hash).await.unwrap();
+
+        assert!(!session.torrents.contains_key(&info_hash));
+        assert!(matches!(
+            session.remove_torrent(&info_hash).await,
+            Err(SessionErr::TorrentNotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn taken_port_skipped_and_announced() {
+        let taken = TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0)).await.unwrap();
+        let taken_port = taken.local_addr().unwrap().port();
+
+        let tracker = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tracker_port = tracker.local_addr().unwrap().port();
+        let announce = tokio::spawn(async move {
+            let (mut stream, _) = tracker.accept().await.unwrap();
+            let mut request = vec![0u8; 4096];
+            let read = stream.read(&mut request).await.unwrap();
+            let body = b"d8:intervali900e5:peers0:e";
+            let header = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            stream.write_all(header.as_bytes()).await.unwrap();
+            stream.write_all(body).await.unwrap();
+            String::from_utf8_lossy(&request[..read]).into_owned()
+        });
+
+        let config = SessionConfig::builder()
+            .port(taken_port)
+            .download_dir(std::env::temp_dir())
+            .build()
+            .unwrap();
+        let mut session = Session::new(config);
+        let meta_info = test_meta_info()
+            .announce(format!("http://127.0.0.1:{tracker_port}/announce"))
+            .hash([6u8; 20])
+            .name("listen-test")
+            .build();
+        let torrent = Torrent::new(
+            meta_info,
+            session.peer_id,
+            session.tracker.clone(),
+            &session.rate_limits,
+            &session.config,
+            session.bans.clone(),
+        )
+        .await;
+        let info_hash = session.insert_torrent(torrent).unwrap();
+
+        let port = session.listen().await.unwrap();
+        session.start_torrent(&info_hash).await.unwrap();
+        let request = announce.await.unwrap();
+        drop(taken);
+
+        assert!(port > taken_port && port < taken_port + LISTEN_PORT_ATTEMPTS);
+        assert_eq!(session.port(), port);
+        assert!(request.contains(&format!("port={port}&")), "{request}");
+    }
+
+    #[tokio::test]
+    async fn incoming_peer_handed_to_its_torrent() {
+        let download_dir = temp_path("incoming");
+        let config = SessionConfig::builder()
+            .port(0)
+            .download_dir(download_dir.clone())
+            .state_dir(download_dir.clone())
+            .build()
+            .unwrap();
+        let mut session = Session::new(config);
+        let meta_info = test_meta_info()
+            .announce("http://127.0.0.1:1/announce")
+            .name("incoming.bin")
+            .build();
+        let info_hash = session
+            .add_torrent_bytes(&meta_info.to_bytes(), None)
+            .await
+            .unwrap();
+        let port = session.listen().await.unwrap();
+        session.start_torrent(&info_hash).await.unwrap();
+
+        let mut unknown = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        unknown
+            .write_all(&Handshake::new([9u8; 20], [3u8; 20]).to_bytes())
+            .await
+            .unwrap();
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        stream
+            .write_all(&Handshake::new(info_hash, [3u8; 20]).to_bytes())
+            .await
+            .unwrap();
+        let reply = Handshake::from_stream(&mut stream).await.unwrap();
+        let closed = unknown.read(&mut [0u8; 1]).await;
+        session.shutdown().await;
+        // Only created if anything was written
+        let _ = tokio::fs::remove_dir_all(&download_dir).await;
+
+        assert_eq!(reply.info_hash, info_hash);
+        assert_eq!(&reply.peer_id, session.peer_id().as_bytes());
+        assert!(matches!(closed, Ok(0) | Err(_)));
+    }
+
+    #[tokio::test]
+    async fn started_session_accepts_peers_for_its_torrents() {
+        let download_dir = temp_path("session-start");
+        let config = SessionConfig::builder()
+            .download_dir(download_dir.clone())
+            .state_dir(download_dir.clone())
+            .build()
+            .unwrap();
+        let mut session = Session::new(config);
+        // Without a tracker or peers the wait in `start` ends right away
+        let meta_info = test_meta_info()
+            .no_announce()
+            .name("session-start.bin")
+            .build();
+        let torrent = Torrent::new(
+            meta_info,
+            session.peer_id,
+            session.tracker.clone(),
+            &session.rate_limits,
+            &session.config,
+            session.bans.clone(),
+        )
+        .await;
+        let info_hash = session.insert_torrent(torrent).unwrap();
+
+        tokio::time::timeout(Duration::from_secs(5), session.start())
+            .await
+            .unwrap();
+        let incoming = session
+            .incoming_torrents
+            .lock()
+            .unwrap()
+            .contains_key(&info_hash);
+        let local = session
+            .local_torrents
+            .lock()
+            .unwrap()
+            .contains_key(&info_hash);
+        session.shutdown().await;
+        let _ = tokio::fs::remove_dir_all(&download_dir).await;
+
+        assert!(incoming);
+        assert!(local);
+    }
+}
@@ -0,0 +1,147 @@
+//! Fixtures shared by the unit tests
+
+use std::{future::Future, net::SocketAddr, path::PathBuf};
+
+use sha1::{Digest, Sha1};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    handshake::{self, Handshake},
+    meta_info::{MetaInfo, TorrentInfo},
+};
+
+/// Path in the temp dir unique to this test process
+pub(crate) fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("rtorrent-{}-{name}", std::process::id()))
+}
+
+/// Starts a single file `MetaInfo` of one 4 byte piece holding `abcd`
+pub(crate) fn test_meta_info() -> TestMetaInfo {
+    TestMetaInfo {
+        announce: Some("test".to_string()),
+        httpseeds: None,
+        hash: [1u8; 20],
+        name: "test".to_string(),
+        piece_length: 4,
+        length: 4,
+        data: Some(b"abcd".to_vec()),
+    }
+}
+
+pub(crate) struct TestMetaInfo {
+    announce: Option<String>,
+    httpseeds: Option<Vec<String>>,
+    hash: [u8; 20],
+    name: String,
+    piece_length: usize,
+    length: usize,
+    data: Option<Vec<u8>>,
+}
+
+impl TestMetaInfo {
+    pub(crate) fn announce(mut self, announce: impl Into<String>) -> Self {
+        self.announce = Some(announce.into());
+        self
+    }
+
+    /// Leaves out `announce`, so the torrent has no tracker
+    pub(crate) fn no_announce(mut self) -> Self {
+        self.announce = None;
+        self
+    }
+
+    pub(crate) fn httpseeds(mut self, httpseeds: &[&str]) -> Self {
+        self.httpseeds = Some(httpseeds.iter().map(|seed| seed.to_string()).collect());
+        self
+    }
+
+    pub(crate) fn hash(mut self, hash: [u8; 20]) -> Self {
+        self.hash = hash;
+        self
+    }
+
+    pub(crate) fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    pub(crate) fn piece_length(mut self, piece_length: usize) -> Self {
+        self.piece_length = piece_length;
+        self
+    }
+
+    /// Content of the torrent, which the piece hashes are taken from
+    pub(crate) fn data(mut self, data: &[u8]) -> Self {
+        self.length = data.len();
+        self.data = Some(data.to_vec());
+        self
+    }
+
+    /// Length of a torrent whose content doesn't matter, every piece hash
+    /// is zeroed
+    pub(crate) fn length(mut self, length: usize) -> Self {
+        self.length = length;
+        self.data = None;
+        self
+    }
+
+    pub(crate) fn build(self) -> MetaInfo {
+        let pieces = match &self.data {
+            Some(data) => data
+                .chunks(self.piece_length)
+                .flat_map(Sha1::digest)
+                .collect(),
+            None => vec![0; self.length.div_ceil(self.piece_length) * 20],
+        };
+        MetaInfo {
+            announce: self.announce,
+            nodes: None,
+            url_list: None,
+            httpseeds: self.httpseeds,
+            announce_list: None,
+            hash: self.hash,
+            hash_v2: None,
+            creation_date: None,
+            comment: None,
+            created_by: None,
+            encoding: None,
+            info: TorrentInfo {
+                name: self.name,
+                piece_length: self.piece_length as i64,
+                pieces,
+                length: Some(self.length as i64),
+                files: None,
+                private: None,
+                meta_version: None,
+                file_tree: None,
+                source: None,
+            },
+        }
+    }
+}
+
+/// Listens for one peer connection and answers its handshake with a plain
+/// handshake for the `[1u8; 20]` info hash. Returns the address to connect
+/// to and the remote end of the connection once the handshake is done.
+pub(crate) async fn fake_remote_peer() -> (SocketAddr, impl Future<Output = TcpStream>) {
+    fake_remote_peer_replying(Handshake::new([1u8; 20], [3u8; 20])).await
+}
+
+/// Like `fake_remote_peer`, answering with `reply`
+pub(crate) async fn fake_remote_peer_replying(
+    reply: Handshake,
+) -> (SocketAddr, impl Future<Output = TcpStream>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let remote = async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; handshake::TOTAL_SIZE];
+        stream.read_exact(&mut buf).await.unwrap();
+        stream.write_all(&reply.to_bytes()).await.unwrap();
+        stream
+    };
+    (addr, remote)
+}
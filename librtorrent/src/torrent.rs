@@ -1,15 +1,82 @@
-use std::{fs, io, path::PathBuf, sync::Arc};
+use std::{
+    fmt::Display,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use log::{error, info};
+use tokio::sync::broadcast;
 
 use crate::{
+    ban_list::BanList,
     bencode::{self, BencodeParseErr, BencodeType},
+    config::SessionConfig,
+    event::TorrentEvent,
     meta_info::{FromBencodeTypeErr, FromBencodemap, MetaInfo},
-    peer_manager::PeerManager,
+    peer_id::PeerId,
+    peer_manager::{DiscoveredPeers, IncomingPeers, PeerManager, PeerSource},
+    piece_manager::{PieceManager, PiecePriority, PieceStrategy},
+    rate_limiter::RateLimits,
+    tracker::TrackerClient,
 };
 
 #[derive(Debug)]
 pub struct Torrent {
     meta_info: Arc<MetaInfo>,
     peer_manager: PeerManager,
+    rate_limits: RateLimits,
+    /// Directory the torrent's files are written to
+    download_dir: PathBuf,
+    /// Stopped by `pause` until `resume` is called
+    paused: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TorrentState {
+    Stopped,
+    /// Stopped by the user, keeping its progress until resumed
+    Paused,
+    /// Started, but the tracker has not returned any peers yet
+    Searching,
+    Downloading,
+    Seeding,
+}
+
+impl Display for TorrentState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TorrentState::Stopped => write!(f, "stopped"),
+            TorrentState::Paused => write!(f, "paused"),
+            TorrentState::Searching => write!(f, "searching"),
+            TorrentState::Downloading => write!(f, "downloading"),
+            TorrentState::Seeding => write!(f, "seeding"),
+        }
+    }
+}
+
+/// Snapshot of the progress of a torrent
+#[derive(Debug, Clone)]
+pub struct TorrentStatus {
+    pub info_hash: [u8; 20],
+    pub name: String,
+    pub state: TorrentState,
+    pub pieces_completed: usize,
+    pub pieces_total: usize,
+    pub bytes_downloaded: u64,
+    pub bytes_total: u64,
+    pub connected_peers: usize,
+    /// Bytes per second downloaded over the last few seconds
+    pub download_rate: u64,
+    /// Bytes per second uploaded over the last few seconds
+    pub upload_rate: u64,
+    /// When the tracker last answered an announce
+    pub last_announce: Option<SystemTime>,
+    /// When the tracker may be asked for peers again
+    pub next_announce: Option<SystemTime>,
+    /// Number of peers the tracker returned in its last answer
+    pub tracker_peer_count: usize,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -22,41 +89,625 @@ pub enum TorrentErr {
     FromBencodeTypeErr(#[from] FromBencodeTypeErr),
     #[error("Invalid torrent file")]
     InvalidFile(PathBuf),
+    #[error("Invalid torrent data")]
+    InvalidData,
+    #[error("Magnet links are not supported")]
+    MagnetUnsupported,
+    #[error("Torrent already added")]
+    AlreadyAdded([u8; 20]),
 }
 
 impl Torrent {
-    pub async fn new(meta_info: MetaInfo) -> Self {
+    /// Creates a torrent whose rate limits are nested under `global_limits`
+    pub async fn new(
+        meta_info: MetaInfo,
+        peer_id: PeerId,
+        tracker: TrackerClient,
+        global_limits: &RateLimits,
+        config: &SessionConfig,
+        bans: Arc<BanList>,
+    ) -> Self {
         let arc = Arc::new(meta_info);
+        let rate_limits = RateLimits::child_of(global_limits);
         Torrent {
             meta_info: arc.clone(),
-            peer_manager: PeerManager::new(arc.clone()).await,
+            peer_manager: PeerManager::new(
+                arc.clone(),
+                peer_id,
+                tracker,
+                rate_limits.clone(),
+                config,
+                bans,
+            )
+            .await,
+            rate_limits,
+            download_dir: config.download_dir.clone(),
+            paused: false,
         }
     }
 
     pub async fn start(&mut self) {
-        let result = self.peer_manager.start().await;
-        println!("Torrent started {result:#?}");
+        self.peer_manager.start();
+        info!("Torrent {} started", self.meta_info.info.name);
     }
 
-    pub async fn from_file(path: &PathBuf) -> Result<Self, TorrentErr> {
-        let contents = fs::read(path)?;
+    /// Waits until every peer connection of this torrent has finished
+    pub async fn wait(&mut self) {
+        self.peer_manager.wait().await;
+    }
 
-        let bencode_vec = bencode::decode_to_vec(&contents)?;
+    /// Stops all peer connections, notifies the tracker and writes pieces
+    /// still held in memory to disk
+    pub async fn stop(&mut self) {
+        self.peer_manager.stop().await;
 
-        if let Some(first) = bencode_vec.first() {
-            match first {
-                BencodeType::Dictionary(map) => {
-                    let data = MetaInfo::from_bencodemap(map)?;
-                    return Ok(Torrent::new(data).await);
-                }
-                _ => Err(TorrentErr::InvalidFile(path.clone())),
-            }
+        if let Err(err) = self.peer_manager.piece_manager().flush().await {
+            error!(
+                "Failed to save torrent {} to disk: {err}",
+                self.meta_info.info.name
+            );
+        }
+    }
+
+    /// Disconnects from all peers and tells the tracker we stopped, keeping
+    /// the downloaded pieces on disk until `resume` is called
+    pub async fn pause(&mut self) {
+        if self.paused {
+            return;
+        }
+
+        self.stop().await;
+        self.paused = true;
+        info!("Torrent {} paused", self.meta_info.info.name);
+    }
+
+    /// Announces the torrent again and reconnects to peers, continuing with
+    /// the pieces downloaded before `pause`
+    pub async fn resume(&mut self) {
+        if !self.paused {
+            return;
+        }
+
+        self.paused = false;
+        self.start().await;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Sets the port announced to the tracker
+    pub fn set_port(&mut self, port: u16) {
+        self.peer_manager.set_port(port);
+    }
+
+    /// Sets the download limit of this torrent in bytes per second, 0 is unlimited
+    pub fn set_download_limit(&self, bytes_per_second: u64) {
+        self.rate_limits.download.set_rate(bytes_per_second);
+    }
+
+    /// Sets the upload limit of this torrent in bytes per second, 0 is unlimited
+    pub fn set_upload_limit(&self, bytes_per_second: u64) {
+        self.rate_limits.upload.set_rate(bytes_per_second);
+    }
+
+    /// Sets the order pieces of this torrent are downloaded in
+    pub fn set_piece_strategy(&self, strategy: PieceStrategy) {
+        self.peer_manager.piece_manager().set_strategy(strategy);
+    }
+
+    /// Sets the piece sequential downloading continues from, e.g. after
+    /// seeking in a media player
+    pub fn set_playback_position(&self, piece: usize) {
+        self.peer_manager
+            .piece_manager()
+            .set_playback_position(piece);
+    }
+
+    /// Sets the priority of file `index` of the torrent's file list. Returns
+    /// false if there is no such file.
+    pub fn set_file_priority(&self, index: usize, priority: PiecePriority) -> bool {
+        self.peer_manager
+            .piece_manager()
+            .set_file_priority(index, priority)
+    }
+
+    pub fn status(&self) -> TorrentStatus {
+        let piece_manager = self.peer_manager.piece_manager();
+        let pieces_completed = piece_manager.completed_pieces();
+        let pieces_total = piece_manager.get_piece_count();
+
+        let state = if self.paused {
+            TorrentState::Paused
+        } else if !self.peer_manager.is_running() {
+            TorrentState::Stopped
+        } else if pieces_completed == pieces_total {
+            TorrentState::Seeding
+        } else if self.peer_manager.is_searching() {
+            TorrentState::Searching
         } else {
-            Err(TorrentErr::InvalidFile(path.clone()))
+            TorrentState::Downloading
+        };
+
+        TorrentStatus {
+            info_hash: self.meta_info.hash,
+            name: self.meta_info.info.name.clone(),
+            state,
+            pieces_completed,
+            pieces_total,
+            bytes_downloaded: piece_manager.downloaded_bytes(),
+            bytes_total: piece_manager.get_total_length(),
+            connected_peers: self.peer_manager.peer_count(),
+            download_rate: self.peer_manager.download_rate(),
+            upload_rate: self.peer_manager.upload_rate(),
+            last_announce: self.peer_manager.last_announce(),
+            next_announce: self.peer_manager.next_announce(),
+            tracker_peer_count: self.peer_manager.tracker_peer_count(),
         }
     }
 
-    pub fn from_magnet(_magnet: &str) -> Result<Self, TorrentErr> {
-        todo!("Add support for magnet strings")
+    /// Returns a receiver for the events of this torrent from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<TorrentEvent> {
+        self.peer_manager.piece_manager().subscribe_events()
+    }
+
+    pub fn download_dir(&self) -> &Path {
+        &self.download_dir
+    }
+
+    pub fn piece_manager(&self) -> &Arc<PieceManager> {
+        self.peer_manager.piece_manager()
+    }
+
+    /// Whether peers may be looked for through `source`
+    pub fn wants_peers_from(&self, source: PeerSource) -> bool {
+        self.peer_manager.wants_peers_from(source)
+    }
+
+    /// Sender for peers of this torrent found outside its trackers
+    pub fn discovered_peers(&self) -> DiscoveredPeers {
+        self.peer_manager.discovered_peers()
+    }
+
+    /// Sender for connections peers opened to us for this torrent
+    pub fn incoming_peers(&self) -> IncomingPeers {
+        self.peer_manager.incoming_peers()
+    }
+
+    pub fn info_hash(&self) -> &[u8; 20] {
+        &self.meta_info.hash
+    }
+
+    pub async fn from_file(
+        path: &PathBuf,
+        peer_id: PeerId,
+        tracker: TrackerClient,
+        global_limits: &RateLimits,
+        config: &SessionConfig,
+        bans: Arc<BanList>,
+    ) -> Result<Self, TorrentErr> {
+        let data = read_meta_info(path)?;
+        Ok(Torrent::new(data, peer_id, tracker, global_limits, config, bans).await)
+    }
+
+    /// Creates a torrent from the contents of a `.torrent` file
+    pub async fn from_bytes(
+        bytes: &[u8],
+        peer_id: PeerId,
+        tracker: TrackerClient,
+        global_limits: &RateLimits,
+        config: &SessionConfig,
+        bans: Arc<BanList>,
+    ) -> Result<Self, TorrentErr> {
+        let data = decode_meta_info(bytes)?;
+        Ok(Torrent::new(data, peer_id, tracker, global_limits, config, bans).await)
+    }
+
+    pub fn from_magnet(
+        _magnet: &str,
+        _peer_id: PeerId,
+        _tracker: TrackerClient,
+        _global_limits: &RateLimits,
+        _config: &SessionConfig,
+        _bans: Arc<BanList>,
+    ) -> Result<Self, TorrentErr> {
+        Err(TorrentErr::MagnetUnsupported)
+    }
+}
+
+/// Reads and decodes the meta info of a `.torrent` file
+pub fn read_meta_info(path: &PathBuf) -> Result<MetaInfo, TorrentErr> {
+    let contents = fs::read(path)?;
+
+    decode_meta_info(&contents).map_err(|err| match err {
+        TorrentErr::InvalidData => TorrentErr::InvalidFile(path.clone()),
+        err => err,
+    })
+}
+
+/// Decodes the meta info in the contents of a `.torrent` file
+pub fn decode_meta_info(bytes: &[u8]) -> Result<MetaInfo, TorrentErr> {
+    let bencode_vec = bencode::decode_to_vec(bytes)?;
+
+    match bencode_vec.first() {
+        Some(BencodeType::Dictionary(map)) => Ok(MetaInfo::from_bencodemap(map)?),
+        _ => Err(TorrentErr::InvalidData),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::{Buf, Bytes};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use crate::{
+        handshake::Handshake,
+        message::{Message, MessageType},
+        piece_manager::PieceOutcome,
+        test_util::{fake_remote_peer_replying, temp_path, test_meta_info},
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn status_reflects_completed_pieces() {
+        let data: [&[u8]; 3] = [b"abcd", b"efgh", b"ij"];
+        let meta_info = test_meta_info().data(&data.concat()).build();
+        let torrent = Torrent::new(
+            meta_info,
+            PeerId::generate(),
+            TrackerClient::default(),
+            &RateLimits::default(),
+            &SessionConfig::default(),
+            Arc::new(BanList::new()),
+        )
+        .await;
+        let piece_manager = torrent.peer_manager.piece_manager();
+
+        assert_eq!(
+            piece_manager
+                .add_piece(&0, Bytes::from_static(data[0]))
+                .await,
+            PieceOutcome::Added
+        );
+        assert_eq!(
+            piece_manager
+                .add_piece(&2, Bytes::from_static(data[2]))
+                .await,
+            PieceOutcome::Added
+        );
+
+        let status = torrent.status();
+        assert_eq!(status.pieces_completed, 2);
+        assert_eq!(status.pieces_total, 3);
+        assert_eq!(status.bytes_downloaded, 6);
+        assert_eq!(status.bytes_total, 10);
+        assert_eq!(status.connected_peers, 0);
+        assert_eq!(status.state, TorrentState::Stopped);
+    }
+
+    #[tokio::test]
+    async fn trackerless_torrent_downloading_once_started() {
+        let meta_info = test_meta_info()
+            .no_announce()
+            .httpseeds(&["http://seed.example/seed.php"])
+            .build();
+        let download_dir = temp_path("trackerless");
+        let config = SessionConfig::builder()
+            .download_dir(&download_dir)
+            .state_dir(&download_dir)
+            .build()
+            .unwrap();
+        let mut torrent = Torrent::new(
+            meta_info,
+            PeerId::generate(),
+            TrackerClient::default(),
+            &RateLimits::default(),
+            &config,
+            Arc::new(BanList::new()),
+        )
+        .await;
+
+        torrent.start().await;
+        // Finding there is no tracker to search happens in the background
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while torrent.peer_manager.is_searching() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+        let started = torrent.status();
+        torrent.stop().await;
+        let stopped = torrent.status();
+        let _ = fs::remove_dir_all(&download_dir);
+
+        assert_eq!(started.state, TorrentState::Downloading);
+        assert_eq!(stopped.state, TorrentState::Stopped);
+    }
+
+    #[tokio::test]
+    async fn progress_kept_while_paused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            // Answers every announce without peers
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf).await.unwrap();
+                let body = b"d8:intervali900e5:peers0:e";
+                let header = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                stream.write_all(header.as_bytes()).await.unwrap();
+                stream.write_all(body).await.unwrap();
+            }
+        });
+
+        let data: [&[u8]; 3] = [b"abcd", b"efgh", b"ij"];
+        let meta_info = test_meta_info()
+            .announce(format!("http://127.0.0.1:{port}/announce"))
+            .hash([4u8; 20])
+            .name("paused.bin")
+            .data(&data.concat())
+            .build();
+        let download_dir = temp_path("paused");
+        let config = SessionConfig::builder()
+            .download_dir(&download_dir)
+            .state_dir(&download_dir)
+            .build()
+            .unwrap();
+        let mut torrent = Torrent::new(
+            meta_info.clone(),
+            PeerId::generate(),
+            TrackerClient::default(),
+            &RateLimits::default(),
+            &config,
+            Arc::new(BanList::new()),
+        )
+        .await;
+        torrent.start().await;
+        torrent
+            .piece_manager()
+            .add_piece(&1, Bytes::from_static(data[1]))
+            .await;
+
+        torrent.pause().await;
+        let paused = torrent.status();
+        // The piece was written to disk, so a restart would find it too
+        let reloaded = PieceManager::with_state_dir(&meta_info, &download_dir, &download_dir)
+            .await
+            .completed_pieces();
+        torrent.resume().await;
+        let resumed = torrent.status();
+        torrent.stop().await;
+        fs::remove_dir_all(&download_dir).unwrap();
+
+        assert_eq!(paused.state, TorrentState::Paused);
+        assert_eq!(paused.pieces_completed, 1);
+        assert_eq!(reloaded, 1);
+        assert_eq!(resumed.state, TorrentState::Searching);
+        assert_eq!(resumed.pieces_completed, 1);
+        assert_eq!(resumed.bytes_downloaded, 4);
+    }
+
+    #[tokio::test]
+    async fn piece_in_flight_requeued_on_pause() {
+        let hash = [7u8; 20];
+        let (peer_addr, remote_peer) =
+            fake_remote_peer_replying(Handshake::new(hash, [3u8; 20])).await;
+        let (requested, request) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let mut stream = remote_peer.await;
+            let mut requested = Some(requested);
+            // Unchokes us and then never answers the request
+            while let Ok(message) = Message::from_stream(&mut stream).await {
+                let reply = match message.id {
+                    Some(id) if id == MessageType::Bitfield as u8 => Message::new(
+                        2,
+                        Some(MessageType::Bitfield as u8),
+                        Some(Bytes::from_static(&[0b1110_0000])),
+                    ),
+                    Some(id) if id == MessageType::Interested as u8 => {
+                        Message::new(1, Some(MessageType::Unchoke as u8), None)
+                    }
+                    Some(id) if id == MessageType::Request as u8 => {
+                        if let Some(requested) = requested.take() {
+                            let _ = requested.send(message.payload.unwrap().get_u32());
+                        }
+                        continue;
+                    }
+                    _ => continue,
+                };
+                stream.write_all(&reply.to_bytes()).await.unwrap();
+            }
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let peers = match peer_addr {
+                std::net::SocketAddr::V4(addr) => {
+                    [&addr.ip().octets()[..], &addr.port().to_be_bytes()].concat()
+                }
+                std::net::SocketAddr::V6(_) => unreachable!("listening on 127.0.0.1"),
+            };
+            let body = [
+                format!("d8:intervali900e5:peers{}:", peers.len()).as_bytes(),
+                &peers,
+                b"e",
+            ]
+            .concat();
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf).await.unwrap();
+                let header = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                stream.write_all(header.as_bytes()).await.unwrap();
+                stream.write_all(&body).await.unwrap();
+            }
+        });
+
+        let meta_info = test_meta_info()
+            .announce(format!("http://127.0.0.1:{port}/announce"))
+            .hash(hash)
+            .name("in_flight.bin")
+            .data(b"abcdefghij")
+            .build();
+        let download_dir = temp_path("in-flight");
+        let config = SessionConfig::builder()
+            .download_dir(&download_dir)
+            .state_dir(&download_dir)
+            .build()
+            .unwrap();
+        let mut torrent = Torrent::new(
+            meta_info,
+            PeerId::generate(),
+            TrackerClient::default(),
+            &RateLimits::default(),
+            &config,
+            Arc::new(BanList::new()),
+        )
+        .await;
+        torrent.start().await;
+        let index = tokio::time::timeout(Duration::from_secs(10), request)
+            .await
+            .unwrap()
+            .unwrap() as usize;
+        let piece_manager = torrent.piece_manager().clone();
+        let in_flight = (
+            piece_manager.pieces_in_progress(),
+            piece_manager.piece_availability(index),
+        );
+
+        torrent.pause().await;
+        let paused = (
+            piece_manager.pieces_in_progress(),
+            piece_manager.piece_availability(index),
+        );
+        torrent.resume().await;
+        torrent.stop().await;
+        let _ = fs::remove_dir_all(&download_dir);
+
+        assert_eq!(in_flight, (1, 1));
+        assert_eq!(paused, (0, 0));
+        assert_eq!(
+            piece_manager.get_next_piece(&Bytes::from_static(&[0b1110_0000])),
+            Some(index)
+        );
+    }
+
+    #[tokio::test]
+    async fn completed_piece_delivered_to_subscribers() {
+        let data: [&[u8]; 2] = [b"abcd", b"ef"];
+        let meta_info = test_meta_info()
+            .hash([5u8; 20])
+            .name("events")
+            .data(&data.concat())
+            .build();
+        let torrent = Torrent::new(
+            meta_info,
+            PeerId::generate(),
+            TrackerClient::default(),
+            &RateLimits::default(),
+            &SessionConfig::default(),
+            Arc::new(BanList::new()),
+        )
+        .await;
+        let mut events = torrent.subscribe();
+
+        torrent
+            .piece_manager()
+            .add_piece(&1, Bytes::from_static(data[1]))
+            .await;
+
+        assert_eq!(
+            events.recv().await.unwrap(),
+            TorrentEvent::PieceCompleted(1)
+        );
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn from_bytes_matches_from_file() {
+        let path = PathBuf::from("../test/torrent_files/debian-13.1.0-amd64-netinst.iso.torrent");
+        let bytes = fs::read(&path).unwrap();
+        let load = |torrent: Result<Torrent, TorrentErr>| torrent.unwrap().meta_info;
+
+        let from_file = load(
+            Torrent::from_file(
+                &path,
+                PeerId::generate(),
+                TrackerClient::default(),
+                &RateLimits::default(),
+                &SessionConfig::default(),
+                Arc::new(BanList::new()),
+            )
+            .await,
+        );
+        let from_bytes = load(
+            Torrent::from_bytes(
+                &bytes,
+                PeerId::generate(),
+                TrackerClient::default(),
+                &RateLimits::default(),
+                &SessionConfig::default(),
+                Arc::new(BanList::new()),
+            )
+            .await,
+        );
+
+        assert_eq!(from_bytes, from_file);
+        assert!(matches!(
+            decode_meta_info(b"li1ee"),
+            Err(TorrentErr::InvalidData)
+        ));
+    }
+
+    #[tokio::test]
+    async fn completed_torrent_written_to_download_dir() {
+        let data: [&[u8]; 3] = [b"abcd", b"efgh", b"ij"];
+        let meta_info = test_meta_info()
+            .hash([4u8; 20])
+            .name("download.bin")
+            .data(&data.concat())
+            .build();
+        let download_dir = temp_path("downloads");
+        let config = SessionConfig::builder()
+            .download_dir(&download_dir)
+            .state_dir(&download_dir)
+            .build()
+            .unwrap();
+        let torrent = Torrent::new(
+            meta_info,
+            PeerId::generate(),
+            TrackerClient::default(),
+            &RateLimits::default(),
+            &config,
+            Arc::new(BanList::new()),
+        )
+        .await;
+
+        let piece_manager = torrent.peer_manager.piece_manager();
+        for (index, piece) in data.iter().enumerate() {
+            assert_eq!(
+                piece_manager
+                    .add_piece(&index, Bytes::from_static(piece))
+                    .await,
+                PieceOutcome::Added
+            );
+        }
+
+        let written = fs::read(download_dir.join("download.bin"));
+        fs::remove_dir_all(&download_dir).unwrap();
+        assert_eq!(written.unwrap(), b"abcdefghij");
     }
 }
@@ -1,49 +1,107 @@
 use crate::{
     bencode::{BencodeMap, BencodeMapDecoder, BencodeParseErr},
     meta_info::{FromBencodeTypeErr, FromBencodemap},
+    mse::EncryptionMode,
     peer::Peer,
+    peer_id::PeerId,
 };
-use reqwest::{Client, Url};
+use bytes::Bytes;
+use reqwest::{redirect::Policy, Client, StatusCode, Url};
 use serde::Serialize;
-use std::str::FromStr;
+use std::{collections::HashSet, str::FromStr, time::Duration};
 use thiserror::Error;
-use url::form_urlencoded::byte_serialize;
 use url::ParseError;
 
-use crate::meta_info::{MetaInfo, TorrentType};
+use crate::meta_info::MetaInfo;
 
 // GetResponse keys
 const INTERVAL_KEY: &str = "interval";
+const MIN_INTERVAL_KEY: &str = "min interval";
 const PEERS_KEY: &str = "peers";
+const PEERS6_KEY: &str = "peers6";
 const FAILURE_REASON_KEY: &str = "failure reason";
+const WARNING_MESSAGE_KEY: &str = "warning message";
+
+// ScrapeData keys
+const FILES_KEY: &str = "files";
+const COMPLETE_KEY: &str = "complete";
+const INCOMPLETE_KEY: &str = "incomplete";
+const DOWNLOADED_KEY: &str = "downloaded";
+
+pub const DEFAULT_TRACKER_TIMEOUT: Duration = Duration::from_secs(30);
+/// Number of redirects followed for a single tracker request before giving up
+pub const MAX_TRACKER_REDIRECTS: usize = 5;
+/// Number of peers asked for in each announce when none is configured
+pub const DEFAULT_NUMWANT: u32 = 50;
+pub const USER_AGENT: &str = concat!("rTorrent/", env!("CARGO_PKG_VERSION"));
 
 // ERRORS
 
 #[derive(Serialize)]
 struct GetRequest {
-    peer_id: String,
     ip: Option<String>,
     port: u16,
     uploaded: i64,
     downloaded: i64,
-    left: i64,
+    left: u64,
     event: Option<TrackerEvent>,
+    /// 1 asks for peers as a compact string instead of a list of dictionaries
+    compact: u8,
+    /// 1 tells the tracker it may leave out peer ids from dictionary peers
+    no_peer_id: u8,
+    /// Number of peers the tracker should return
+    numwant: u32,
+    /// 1 tells the tracker we can connect to peers using encryption
+    supportcrypto: Option<u8>,
+    /// 1 tells the tracker we only connect to peers using encryption
+    requirecrypto: Option<u8>,
 }
 
 #[derive(Debug)]
 pub struct GetResponse {
     pub interval: Option<i64>,
+    /// Seconds the client must wait at least before announcing again
+    pub min_interval: Option<i64>,
     pub peers: Option<Vec<Peer>>,
     pub failure_reason: Option<String>,
+    /// Non-fatal message from the tracker, the response is still used
+    pub warning_message: Option<String>,
 }
 
-#[derive(Serialize)]
-enum TrackerEvent {
+/// Swarm statistics for a single torrent returned by a scrape (BEP-48)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScrapeData {
+    /// Number of seeders
+    pub complete: i64,
+    /// Number of leechers
+    pub incomplete: i64,
+    /// Number of times the torrent has been fully downloaded
+    pub downloaded: i64,
+}
+
+/// HTTP(S) client used for announces, cheap to clone and shared between torrents
+#[derive(Debug, Clone)]
+pub struct TrackerClient {
+    client: Client,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrackerEvent {
     Started,
     Completed,
     Stopped,
 }
 
+/// What an announce tells the tracker about our side of the torrent
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Announce {
+    pub event: Option<TrackerEvent>,
+    /// Bytes still to download, 0 once complete so the tracker counts us
+    /// as a seeder
+    pub left: u64,
+}
+
 // TODO implement with thiserror::Error
 #[derive(Debug, Error)]
 pub enum TrackerErr {
@@ -59,6 +117,35 @@ pub enum TrackerErr {
     ReqwestError(reqwest::Error),
     #[error("Serde error {0}")]
     SerdeErr(serde_qs::Error),
+    #[error("Tracker does not support scraping")]
+    ScrapeNotSupported,
+    #[error("Tracker failure: {0}")]
+    Failure(String),
+    #[error("Tracker returned HTTP {0}")]
+    HttpStatus(u16),
+}
+
+impl TrackerErr {
+    /// Whether retrying the same request later may succeed, e.g. network
+    /// errors or tracker failures, as opposed to an invalid announce URL or
+    /// a tracker that doesn't answer in bencode
+    pub fn is_transient(&self) -> bool {
+        match self {
+            TrackerErr::ReqwestError(err) => !err.is_builder(),
+            TrackerErr::Failure(_) => true,
+            // Server errors and rate limiting may clear up, a missing
+            // announce path will not
+            TrackerErr::HttpStatus(status) => {
+                *status >= 500 || *status == StatusCode::TOO_MANY_REQUESTS.as_u16()
+            }
+            TrackerErr::BencodeParseErr(_)
+            | TrackerErr::FromBencodeTypeErr(_)
+            | TrackerErr::InvalidMetaInfo
+            | TrackerErr::UrlParseError(_)
+            | TrackerErr::SerdeErr(_)
+            | TrackerErr::ScrapeNotSupported => false,
+        }
+    }
 }
 
 impl FromBencodemap for GetResponse {
@@ -70,91 +157,667 @@ impl FromBencodemap for GetResponse {
         }
 
         let interval: Option<i64> = bencode_map.get_decode(INTERVAL_KEY);
+        let min_interval: Option<i64> = bencode_map.get_decode(MIN_INTERVAL_KEY);
         let failure_reason: Option<String> = bencode_map.get_decode(FAILURE_REASON_KEY);
+        let warning_message: Option<String> = bencode_map.get_decode(WARNING_MESSAGE_KEY);
 
-        let peers: Option<Vec<BencodeMap>> = bencode_map.get_decode(PEERS_KEY);
+        // Peers are either a list of dictionaries or a compact string
+        let mut peers_final: Option<Vec<Peer>> =
+            match bencode_map.get_decode::<Vec<BencodeMap>>(PEERS_KEY) {
+                Some(x) => Some(Peer::from_bencodemap_list(&x)),
+                None => match bencode_map.get_decode::<Vec<u8>>(PEERS_KEY) {
+                    Some(x) => Some(Peer::from_compact_v4(&x)?),
+                    None => None,
+                },
+            };
 
-        let peers_final: Option<Vec<Peer>>;
+        if let Some(x) = bencode_map.get_decode::<Vec<u8>>(PEERS6_KEY) {
+            peers_final
+                .get_or_insert_with(Vec::new)
+                .extend(Peer::from_compact_v6(&x)?);
+        }
 
-        match peers {
-            Some(x) => peers_final = Some(Peer::from_bencodemap_list(&x)?),
-            None => peers_final = None,
+        // A peer may be listed more than once, connecting to it once is enough
+        if let Some(peers) = &mut peers_final {
+            let mut seen = HashSet::new();
+            peers.retain(|peer| seen.insert(peer.addr));
         }
 
         Ok(GetResponse {
-            interval: interval,
+            interval,
+            min_interval,
             peers: peers_final,
-            failure_reason: failure_reason,
+            failure_reason,
+            warning_message,
         })
     }
 
     fn is_valid_bencodemap(bencode_map: &BencodeMap) -> bool {
         (bencode_map.contains_key(INTERVAL_KEY.as_bytes())
-            && bencode_map.contains_key(PEERS_KEY.as_bytes()))
+            && (bencode_map.contains_key(PEERS_KEY.as_bytes())
+                || bencode_map.contains_key(PEERS6_KEY.as_bytes())))
             || bencode_map.contains_key(FAILURE_REASON_KEY.as_bytes())
     }
 }
 
-impl GetRequest {
-    pub fn from_metainfo(meta_info: &MetaInfo) -> Result<Self, TrackerErr> {
-        let left;
-
-        match meta_info.info.is_single_or_multi_file() {
-            TorrentType::SingleFile => {
-                left = meta_info.info.length.ok_or(TrackerErr::InvalidMetaInfo)?
-            }
-            TorrentType::MultiFile => todo!("Add support for multi-file torrents"),
+impl FromBencodemap for ScrapeData {
+    fn from_bencodemap(bencode_map: &BencodeMap) -> Result<Self, FromBencodeTypeErr> {
+        if !Self::is_valid_bencodemap(bencode_map) {
+            return Err(FromBencodeTypeErr::MissingValue(String::from(
+                "Missing values for ScrapeData",
+            )));
         }
 
-        Ok(GetRequest {
-            peer_id: "12345678901234567890".to_string(),
+        Ok(ScrapeData {
+            complete: bencode_map.get_decode(COMPLETE_KEY).unwrap_or_default(),
+            incomplete: bencode_map.get_decode(INCOMPLETE_KEY).unwrap_or_default(),
+            downloaded: bencode_map.get_decode(DOWNLOADED_KEY).unwrap_or_default(),
+        })
+    }
+
+    fn is_valid_bencodemap(bencode_map: &BencodeMap) -> bool {
+        bencode_map.contains_key(COMPLETE_KEY.as_bytes())
+            && bencode_map.contains_key(INCOMPLETE_KEY.as_bytes())
+    }
+}
+
+impl GetRequest {
+    pub fn new(port: u16, numwant: u32, encryption: EncryptionMode, announce: Announce) -> Self {
+        GetRequest {
             ip: None,
-            port: 6881,
+            port,
             uploaded: 0,
             downloaded: 0,
-            left: left,
-            event: None,
-        })
+            left: announce.left,
+            event: announce.event,
+            compact: 1,
+            no_peer_id: 1,
+            numwant,
+            supportcrypto: (encryption != EncryptionMode::Disabled).then_some(1),
+            requirecrypto: (encryption == EncryptionMode::Required).then_some(1),
+        }
     }
 }
 
-pub async fn send_get_request(meta_info: &MetaInfo) -> Result<GetResponse, TrackerErr> {
-    let url = construct_get_url(&meta_info)?;
-    let client = Client::new();
-    let res = client
-        .get(url)
-        .send()
-        .await
-        .map_err(|req_error| TrackerErr::ReqwestError(req_error))?
-        .bytes()
-        .await
-        .map_err(|req_error| TrackerErr::ReqwestError(req_error))?;
+impl TrackerClient {
+    /// Creates a client whose requests fail with `TrackerErr::ReqwestError`
+    /// if the tracker does not respond within `timeout`
+    pub fn new(timeout: Duration) -> Result<Self, TrackerErr> {
+        let client = Client::builder()
+            .timeout(timeout)
+            .user_agent(USER_AGENT)
+            .redirect(Policy::limited(MAX_TRACKER_REDIRECTS))
+            .build()
+            .map_err(TrackerErr::ReqwestError)?;
+
+        Ok(TrackerClient { client })
+    }
 
-    let map = BencodeMap::try_decode(&res.into())
-        .map_err(|bencode_err| TrackerErr::BencodeParseErr(bencode_err))?;
+    /// Sends `announce` to the tracker, asking for up to `numwant` peers
+    /// and advertising whether we connect to them with `encryption`
+    pub async fn send_get_request(
+        &self,
+        meta_info: &MetaInfo,
+        peer_id: &PeerId,
+        port: u16,
+        numwant: u32,
+        encryption: EncryptionMode,
+        announce: Announce,
+    ) -> Result<GetResponse, TrackerErr> {
+        let url = construct_get_url(meta_info, peer_id, port, numwant, encryption, announce)?;
+        self.send_announce(url).await
+    }
 
-    let deserial = GetResponse::from_bencodemap(&map)
-        .map_err(|from_error| TrackerErr::FromBencodeTypeErr(from_error))?;
+    /// Sends an announce built with `announce_url`, which lets the caller
+    /// pick which of the torrent's trackers it goes to
+    pub async fn send_announce(&self, url: Url) -> Result<GetResponse, TrackerErr> {
+        let res = self.get(url).await?;
 
-    Ok(deserial)
-}
+        let map = BencodeMap::try_decode(&res).map_err(TrackerErr::BencodeParseErr)?;
 
-fn construct_get_url(meta_info: &MetaInfo) -> Result<Url, TrackerErr> {
-    let payload = GetRequest::from_metainfo(&meta_info)?;
-    let params =
-        serde_qs::to_string(&payload).map_err(|serde_error| TrackerErr::SerdeErr(serde_error))?;
-    let announce;
+        let deserial =
+            GetResponse::from_bencodemap(&map).map_err(TrackerErr::FromBencodeTypeErr)?;
 
-    match meta_info.announce.clone() {
-        Some(url) => announce = url,
-        _ => todo!("Support for torrents without announce field"),
+        Ok(deserial)
     }
 
+    /// Asks the tracker for the swarm statistics of `meta_info` without announcing
+    pub async fn send_scrape_request(
+        &self,
+        meta_info: &MetaInfo,
+    ) -> Result<ScrapeData, TrackerErr> {
+        let announce = meta_info
+            .trackers()
+            .into_iter()
+            .next()
+            .ok_or(TrackerErr::InvalidMetaInfo)?;
+        let url = Url::from_str(&format!(
+            "{}?info_hash={}",
+            scrape_url(announce)?,
+            percent_encode(&meta_info.hash)
+        ))?;
+
+        let res = self.get(url).await?;
+
+        parse_scrape_response(&res, &meta_info.hash)
+    }
+
+    /// Fetches the body of `url`, failing with `TrackerErr::HttpStatus`
+    /// instead of handing an error page to the bencode decoder
+    async fn get(&self, url: Url) -> Result<Bytes, TrackerErr> {
+        let response = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .map_err(TrackerErr::ReqwestError)?;
+
+        let status = response.status();
+        if !status.is_success() {
+            return Err(TrackerErr::HttpStatus(status.as_u16()));
+        }
+
+        response.bytes().await.map_err(TrackerErr::ReqwestError)
+    }
+}
+
+impl Default for TrackerClient {
+    fn default() -> Self {
+        TrackerClient::new(DEFAULT_TRACKER_TIMEOUT).expect("Failed to build tracker client")
+    }
+}
+
+/// Builds the announce URL for the torrent's main tracker, `announce` or,
+/// when it is missing, the first URL of `announce-list`. Torrents that only
+/// list DHT `nodes` fail with `InvalidMetaInfo` since there is no DHT
+/// client to announce to them.
+fn construct_get_url(
+    meta_info: &MetaInfo,
+    peer_id: &PeerId,
+    port: u16,
+    numwant: u32,
+    encryption: EncryptionMode,
+    announce: Announce,
+) -> Result<Url, TrackerErr> {
+    let tracker = meta_info
+        .trackers()
+        .into_iter()
+        .next()
+        .ok_or(TrackerErr::InvalidMetaInfo)?;
+
+    announce_url(
+        tracker, meta_info, peer_id, port, numwant, encryption, announce,
+    )
+}
+
+/// Builds the URL sending `announce` to the tracker at `tracker`
+pub fn announce_url(
+    tracker: &str,
+    meta_info: &MetaInfo,
+    peer_id: &PeerId,
+    port: u16,
+    numwant: u32,
+    encryption: EncryptionMode,
+    announce: Announce,
+) -> Result<Url, TrackerErr> {
+    let payload = GetRequest::new(port, numwant, encryption, announce);
+    let params = serde_qs::to_string(&payload).map_err(TrackerErr::SerdeErr)?;
+
     Url::from_str(&format!(
-        "{}?{}&info_hash={}",
-        announce,
+        "{}?{}&info_hash={}&peer_id={}",
+        tracker,
         params,
-        byte_serialize(&meta_info.hash).collect::<String>(),
+        percent_encode(&meta_info.hash),
+        percent_encode(peer_id.as_bytes()),
     ))
-    .map_err(|parse_error| TrackerErr::UrlParseError(parse_error))
+    .map_err(TrackerErr::UrlParseError)
+}
+
+/// Derives the scrape URL by replacing `announce` at the start of the last
+/// path segment with `scrape`, as described in BEP-48
+fn scrape_url(announce: &str) -> Result<String, TrackerErr> {
+    let segment_start = announce.rfind('/').ok_or(TrackerErr::ScrapeNotSupported)? + 1;
+    let segment = &announce[segment_start..];
+
+    match segment.strip_prefix("announce") {
+        Some(rest) if !segment.contains('?') => {
+            Ok(format!("{}scrape{}", &announce[..segment_start], rest))
+        }
+        _ => Err(TrackerErr::ScrapeNotSupported),
+    }
+}
+
+fn parse_scrape_response(bytes: &[u8], info_hash: &[u8; 20]) -> Result<ScrapeData, TrackerErr> {
+    let map = BencodeMap::try_decode(bytes)?;
+
+    if let Some(reason) = map.get_decode::<String>(FAILURE_REASON_KEY) {
+        return Err(TrackerErr::Failure(reason));
+    }
+
+    let files: BencodeMap = map
+        .get_decode(FILES_KEY)
+        .ok_or(FromBencodeTypeErr::MissingValue(String::from(
+            "Missing files in scrape response",
+        )))?;
+    let stats = files
+        .get(info_hash.as_slice())
+        .ok_or(FromBencodeTypeErr::MissingValue(String::from(
+            "Torrent missing from scrape response",
+        )))?;
+
+    Ok(ScrapeData::from_bencodemap(
+        &BencodeMap::try_from(stats).map_err(FromBencodeTypeErr::BencodeGetErr)?,
+    )?)
+}
+
+/// Percent-encodes every byte outside the URL unreserved set (`A-Z a-z 0-9 - . _ ~`),
+/// as trackers expect for raw binary values like the info hash.
+pub(crate) fn percent_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len() * 3);
+    for &byte in bytes {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use crate::bencode::{BencodeMapEncoder, BencodeType};
+
+    use crate::{config::DEFAULT_PORT, test_util::test_meta_info};
+
+    use super::*;
+
+    #[test]
+    fn info_hash_is_strictly_percent_encoded() {
+        let mut hash = [b'a'; 20];
+        hash[0] = 0x20;
+        hash[1] = 0x2b;
+        hash[2] = 0xff;
+        hash[3] = b'~';
+        let meta_info = test_meta_info()
+            .announce("http://tracker.example.com/announce")
+            .hash(hash)
+            .build();
+
+        let url = construct_get_url(
+            &meta_info,
+            &PeerId::generate(),
+            DEFAULT_PORT,
+            DEFAULT_NUMWANT,
+            EncryptionMode::Disabled,
+            Announce::default(),
+        )
+        .unwrap();
+        let expected = format!("info_hash=%20%2B%FF~{}", "a".repeat(16));
+
+        assert!(url.as_str().contains(&expected), "{url}");
+        assert!(!url.as_str().contains("info_hash=+"), "{url}");
+    }
+
+    #[test]
+    fn announce_list_used_without_announce() {
+        let mut meta_info = test_meta_info().no_announce().build();
+        meta_info.announce_list = Some(vec![
+            "http://first.example.com/announce".to_string(),
+            "http://second.example.com/announce".to_string(),
+        ]);
+        let url = |meta_info: &MetaInfo| {
+            construct_get_url(
+                meta_info,
+                &PeerId::generate(),
+                DEFAULT_PORT,
+                DEFAULT_NUMWANT,
+                EncryptionMode::Disabled,
+                Announce::default(),
+            )
+        };
+
+        let announce = url(&meta_info).unwrap();
+        meta_info.announce_list = None;
+
+        assert_eq!(announce.host_str(), Some("first.example.com"));
+        assert!(matches!(url(&meta_info), Err(TrackerErr::InvalidMetaInfo)));
+    }
+
+    #[test]
+    fn compact_peers_requested() {
+        let meta_info = test_meta_info()
+            .announce("http://tracker.example.com/announce")
+            .build();
+
+        let url = construct_get_url(
+            &meta_info,
+            &PeerId::generate(),
+            DEFAULT_PORT,
+            DEFAULT_NUMWANT,
+            EncryptionMode::Disabled,
+            Announce::default(),
+        )
+        .unwrap();
+        let query: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+
+        assert!(query.contains(&("compact".into(), "1".into())), "{url}");
+        assert!(query.contains(&("no_peer_id".into(), "1".into())), "{url}");
+    }
+
+    #[test]
+    fn custom_numwant_requested() {
+        let meta_info = test_meta_info()
+            .announce("http://tracker.example.com/announce")
+            .build();
+
+        let url = construct_get_url(
+            &meta_info,
+            &PeerId::generate(),
+            DEFAULT_PORT,
+            200,
+            EncryptionMode::Disabled,
+            Announce::default(),
+        )
+        .unwrap();
+        let query: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+
+        assert!(query.contains(&("numwant".into(), "200".into())), "{url}");
+    }
+
+    #[test]
+    fn encryption_preference_advertised() {
+        let meta_info = test_meta_info()
+            .announce("http://tracker.example.com/announce")
+            .build();
+        let crypto_params = |encryption| {
+            let url = construct_get_url(
+                &meta_info,
+                &PeerId::generate(),
+                DEFAULT_PORT,
+                DEFAULT_NUMWANT,
+                encryption,
+                Announce::default(),
+            )
+            .unwrap();
+            let mut params: Vec<(String, String)> = url
+                .query_pairs()
+                .into_owned()
+                .filter(|(key, _)| key.ends_with("crypto"))
+                .collect();
+            params.sort();
+            params
+        };
+        let param = |key: &str| (key.to_string(), "1".to_string());
+
+        assert_eq!(crypto_params(EncryptionMode::Disabled), vec![]);
+        assert_eq!(
+            crypto_params(EncryptionMode::Preferred),
+            vec![param("supportcrypto")]
+        );
+        assert_eq!(
+            crypto_params(EncryptionMode::Required),
+            vec![param("requirecrypto"), param("supportcrypto")]
+        );
+    }
+
+    #[tokio::test]
+    async fn unresponsive_tracker_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = Vec::new();
+            // Read the request but never respond
+            let _ = stream.read_to_end(&mut buf).await;
+        });
+
+        let meta_info = test_meta_info()
+            .announce(format!("http://127.0.0.1:{port}/announce"))
+            .build();
+        let client = TrackerClient::new(Duration::from_millis(100)).unwrap();
+
+        let result = client
+            .send_get_request(
+                &meta_info,
+                &PeerId::generate(),
+                DEFAULT_PORT,
+                DEFAULT_NUMWANT,
+                EncryptionMode::Disabled,
+                Announce::default(),
+            )
+            .await;
+
+        match result {
+            Err(TrackerErr::ReqwestError(err)) => assert!(err.is_timeout(), "{err}"),
+            other => panic!("Expected a timeout, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn http_error_status_reported() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf).await.unwrap();
+            let body = "<html>Service Unavailable</html>";
+            let header = format!(
+                "HTTP/1.1 503 Service Unavailable\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            stream.write_all(header.as_bytes()).await.unwrap();
+            stream.write_all(body.as_bytes()).await.unwrap();
+        });
+
+        let meta_info = test_meta_info()
+            .announce(format!("http://127.0.0.1:{port}/announce"))
+            .build();
+        let result = TrackerClient::default()
+            .send_get_request(
+                &meta_info,
+                &PeerId::generate(),
+                DEFAULT_PORT,
+                DEFAULT_NUMWANT,
+                EncryptionMode::Disabled,
+                Announce::default(),
+            )
+            .await;
+
+        match result {
+            Err(err @ TrackerErr::HttpStatus(503)) => {
+                assert!(err.is_transient());
+                assert_eq!(err.to_string(), "Tracker returned HTTP 503");
+            }
+            other => panic!("Expected HTTP 503, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn malformed_response_not_retried() {
+        let parse_err = TrackerErr::BencodeParseErr(BencodeParseErr::EmptyBencode);
+        let missing = TrackerErr::FromBencodeTypeErr(FromBencodeTypeErr::MissingValue(
+            "Missing values for GetResponse".to_string(),
+        ));
+
+        assert!(!parse_err.is_transient());
+        assert!(!missing.is_transient());
+        assert!(TrackerErr::Failure("overloaded".to_string()).is_transient());
+    }
+
+    #[test]
+    fn scrape_url_from_announce() {
+        assert_eq!(
+            scrape_url("http://example.com/announce").unwrap(),
+            "http://example.com/scrape"
+        );
+        assert_eq!(
+            scrape_url("http://example.com/x/announce.php").unwrap(),
+            "http://example.com/x/scrape.php"
+        );
+        assert!(matches!(
+            scrape_url("http://example.com/a"),
+            Err(TrackerErr::ScrapeNotSupported)
+        ));
+        assert!(matches!(
+            scrape_url("http://example.com/announce?x=y"),
+            Err(TrackerErr::ScrapeNotSupported)
+        ));
+    }
+
+    #[test]
+    fn min_interval_and_warning_decode() {
+        let body = b"d8:intervali1800e12:min intervali900e5:peers0:15:warning message9:slow downe";
+
+        let response =
+            GetResponse::from_bencodemap(&BencodeMap::try_decode(body).unwrap()).unwrap();
+
+        assert_eq!(response.interval, Some(1800));
+        assert_eq!(response.min_interval, Some(900));
+        assert_eq!(response.warning_message.as_deref(), Some("slow down"));
+        assert!(response.failure_reason.is_none());
+    }
+
+    #[test]
+    fn compact_peers_and_peers6_decode() {
+        let mut body = BencodeMap::new();
+        body.insert(INTERVAL_KEY.into(), BencodeType::Integer(1800));
+        body.insert(
+            PEERS_KEY.into(),
+            BencodeType::String(vec![10, 0, 0, 1, 0x1a, 0xe1]),
+        );
+        let mut peers6 = vec![0x20, 0x01, 0x0d, 0xb8];
+        peers6.extend([0u8; 11]);
+        peers6.extend([1, 0x1a, 0xe2]);
+        body.insert(PEERS6_KEY.into(), BencodeType::String(peers6));
+
+        let response = GetResponse::from_bencodemap(&body).unwrap();
+        let addrs: Vec<String> = response
+            .peers
+            .unwrap()
+            .iter()
+            .map(|peer| peer.addr.to_string())
+            .collect();
+
+        assert_eq!(addrs, vec!["10.0.0.1:6881", "[2001:db8::1]:6882"]);
+    }
+
+    #[test]
+    fn duplicate_compact_peers_merged() {
+        let ipv6 = |last: u8| {
+            let mut peer = vec![0x20, 0x01, 0x0d, 0xb8];
+            peer.extend([0u8; 11]);
+            peer.extend([last, 0x1a, 0xe2]);
+            peer
+        };
+        let mut body = BencodeMap::new();
+        body.insert(INTERVAL_KEY.into(), BencodeType::Integer(1800));
+        body.insert(
+            PEERS_KEY.into(),
+            BencodeType::String(
+                [
+                    [10, 0, 0, 1, 0x1a, 0xe1],
+                    [10, 0, 0, 2, 0x1a, 0xe1],
+                    [10, 0, 0, 1, 0x1a, 0xe1],
+                ]
+                .concat(),
+            ),
+        );
+        body.insert(
+            PEERS6_KEY.into(),
+            BencodeType::String([ipv6(1), ipv6(2), ipv6(1)].concat()),
+        );
+        let addrs = |body: &BencodeMap| -> Vec<String> {
+            GetResponse::from_bencodemap(body)
+                .unwrap()
+                .peers
+                .unwrap()
+                .iter()
+                .map(|peer| peer.addr.to_string())
+                .collect()
+        };
+
+        assert_eq!(
+            addrs(&body),
+            vec![
+                "10.0.0.1:6881",
+                "10.0.0.2:6881",
+                "[2001:db8::1]:6882",
+                "[2001:db8::2]:6882"
+            ]
+        );
+        body.remove(PEERS_KEY.as_bytes());
+        assert_eq!(
+            addrs(&body),
+            vec!["[2001:db8::1]:6882", "[2001:db8::2]:6882"]
+        );
+    }
+
+    #[test]
+    fn truncated_peers6_rejected() {
+        let mut body = BencodeMap::new();
+        body.insert(INTERVAL_KEY.into(), BencodeType::Integer(1800));
+        body.insert(PEERS6_KEY.into(), BencodeType::String(vec![0u8; 17]));
+
+        assert!(GetResponse::from_bencodemap(&body).is_err());
+    }
+
+    #[tokio::test]
+    async fn scrape_decodes_counts() {
+        let hash = [7u8; 20];
+        let mut stats = BencodeMap::new();
+        stats.insert(COMPLETE_KEY.into(), BencodeType::Integer(12));
+        stats.insert(INCOMPLETE_KEY.into(), BencodeType::Integer(3));
+        stats.insert(DOWNLOADED_KEY.into(), BencodeType::Integer(40));
+        let mut files = BencodeMap::new();
+        files.insert(hash.to_vec(), BencodeType::Dictionary(stats));
+        let mut body = BencodeMap::new();
+        body.insert(FILES_KEY.into(), BencodeType::Dictionary(files));
+        let body = body.get_encode();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let n = stream.read(&mut buf).await.unwrap();
+            let request = String::from_utf8_lossy(&buf[..n]).to_string();
+            let header = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            stream.write_all(header.as_bytes()).await.unwrap();
+            stream.write_all(&body).await.unwrap();
+            request
+        });
+
+        let meta_info = test_meta_info()
+            .announce(format!("http://127.0.0.1:{port}/announce"))
+            .hash(hash)
+            .build();
+        let scrape = TrackerClient::default()
+            .send_scrape_request(&meta_info)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            scrape,
+            ScrapeData {
+                complete: 12,
+                incomplete: 3,
+                downloaded: 40,
+            }
+        );
+        let request = server.await.unwrap();
+        assert!(
+            request.starts_with("GET /scrape?info_hash=%07%07"),
+            "{request}"
+        );
+    }
 }
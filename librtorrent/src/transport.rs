@@ -0,0 +1,32 @@
+use std::{fmt::Debug, io, net::SocketAddr};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+
+use crate::utp::UtpStream;
+
+/// Byte stream a peer connection runs over, so `Peer` isn't tied to TCP
+pub trait PeerTransport: AsyncRead + AsyncWrite + Unpin + Send + Sync + Debug {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync + Debug> PeerTransport for T {}
+
+/// Protocol outgoing peer connections are made over
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TransportKind {
+    #[default]
+    Tcp,
+    /// uTP (BEP-29), which backs off before other traffic on the link does
+    Utp,
+}
+
+impl TransportKind {
+    /// Opens a connection to `addr` over this protocol
+    pub async fn connect(self, addr: SocketAddr) -> io::Result<Box<dyn PeerTransport>> {
+        Ok(match self {
+            TransportKind::Tcp => Box::new(TcpStream::connect(addr).await?),
+            TransportKind::Utp => Box::new(UtpStream::connect(addr).await?),
+        })
+    }
+}
@@ -0,0 +1,1009 @@
+use std::{
+    collections::VecDeque,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use log::{debug, trace};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf},
+    net::UdpSocket,
+    time::Instant,
+};
+
+/// Version of uTP (BEP-29) in the header of every packet
+const VERSION: u8 = 1;
+pub const HEADER_SIZE: usize = 20;
+/// Largest datagram sent, small enough not to be fragmented on most paths
+const MAX_PACKET_SIZE: usize = 1400;
+/// Largest payload of a single data packet
+pub const MAX_PAYLOAD: usize = MAX_PACKET_SIZE - HEADER_SIZE;
+/// Large enough for any datagram the remote end may send
+const RECV_BUFFER_SIZE: usize = 1 << 16;
+/// Bytes advertised as our receive window, which is also the size of the
+/// buffers between a stream and its connection
+const RECV_WINDOW: usize = 1 << 20;
+
+/// LEDBAT keeps the queuing delay it adds to the path below this
+pub const TARGET_DELAY: Duration = Duration::from_millis(100);
+/// Congestion window of a new connection
+const INITIAL_WINDOW: usize = 2 * MAX_PAYLOAD;
+/// The congestion window never shrinks below a single packet
+const MIN_WINDOW: usize = MAX_PAYLOAD;
+const MAX_WINDOW: usize = RECV_WINDOW;
+
+/// Time before the oldest unacknowledged packet is resent, until the round
+/// trip time was measured
+const INITIAL_TIMEOUT: Duration = Duration::from_secs(1);
+const MIN_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_TIMEOUT: Duration = Duration::from_secs(30);
+/// The connection is given up after the same packet timed out this often
+const MAX_RETRANSMISSIONS: u32 = 5;
+/// Acks repeating the same sequence number that signal a lost packet
+const DUPLICATE_ACKS: u32 = 3;
+/// Time the remote end has to send its FIN after acknowledging ours
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketType {
+    Data = 0,
+    Fin = 1,
+    /// Ack without payload
+    State = 2,
+    Reset = 3,
+    Syn = 4,
+}
+
+impl TryFrom<u8> for PacketType {
+    type Error = UtpErr;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(PacketType::Data),
+            1 => Ok(PacketType::Fin),
+            2 => Ok(PacketType::State),
+            3 => Ok(PacketType::Reset),
+            4 => Ok(PacketType::Syn),
+            _ => Err(UtpErr::InvalidType(value)),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum UtpErr {
+    #[error("Packet of {0} bytes is shorter than a header")]
+    TooShort(usize),
+    #[error("Unsupported uTP version {0}")]
+    UnsupportedVersion(u8),
+    #[error("Invalid packet type {0}")]
+    InvalidType(u8),
+    #[error("Extension header runs past the end of the packet")]
+    InvalidExtension,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub packet_type: PacketType,
+    pub connection_id: u16,
+    /// Microseconds on the sender's clock when the packet was sent
+    pub timestamp: u32,
+    /// Delay the last packet the sender received saw, in microseconds
+    pub timestamp_diff: u32,
+    /// Bytes the sender is still able to receive
+    pub wnd_size: u32,
+    pub seq_nr: u16,
+    /// Sequence number of the last packet the sender received in order
+    pub ack_nr: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub header: Header,
+    pub payload: Bytes,
+}
+
+impl Packet {
+    /// Encodes the packet without extension headers
+    pub fn to_bytes(&self) -> Bytes {
+        let header = &self.header;
+        let mut buf = BytesMut::with_capacity(HEADER_SIZE + self.payload.len());
+
+        buf.put_u8((header.packet_type as u8) << 4 | VERSION);
+        buf.put_u8(0); // no extensions
+        buf.put_u16(header.connection_id);
+        buf.put_u32(header.timestamp);
+        buf.put_u32(header.timestamp_diff);
+        buf.put_u32(header.wnd_size);
+        buf.put_u16(header.seq_nr);
+        buf.put_u16(header.ack_nr);
+        buf.extend_from_slice(&self.payload);
+
+        buf.freeze()
+    }
+
+    /// Decodes a datagram. Extension headers, e.g. selective acks, are
+    /// skipped since none are supported.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Packet, UtpErr> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(UtpErr::TooShort(bytes.len()));
+        }
+
+        let mut buf = bytes;
+        let type_version = buf.get_u8();
+        if type_version & 0x0f != VERSION {
+            return Err(UtpErr::UnsupportedVersion(type_version & 0x0f));
+        }
+        let packet_type = PacketType::try_from(type_version >> 4)?;
+        let mut extension = buf.get_u8();
+
+        let header = Header {
+            packet_type,
+            connection_id: buf.get_u16(),
+            timestamp: buf.get_u32(),
+            timestamp_diff: buf.get_u32(),
+            wnd_size: buf.get_u32(),
+            seq_nr: buf.get_u16(),
+            ack_nr: buf.get_u16(),
+        };
+
+        while extension != 0 {
+            if buf.len() < 2 {
+                return Err(UtpErr::InvalidExtension);
+            }
+            extension = buf.get_u8();
+            let length = buf.get_u8() as usize;
+            if buf.len() < length {
+                return Err(UtpErr::InvalidExtension);
+            }
+            buf.advance(length);
+        }
+
+        Ok(Packet {
+            header,
+            payload: Bytes::copy_from_slice(buf),
+        })
+    }
+}
+
+/// LEDBAT congestion control (RFC 6817): the window grows while packets see
+/// less queuing delay than `TARGET_DELAY` and shrinks when they see more, so
+/// uTP backs off before other traffic on the link suffers
+#[derive(Debug, Clone)]
+pub struct Ledbat {
+    window: usize,
+    /// Lowest one-way delay seen, taken as the delay without any queuing
+    base_delay: Option<u32>,
+}
+
+impl Default for Ledbat {
+    fn default() -> Self {
+        Ledbat {
+            window: INITIAL_WINDOW,
+            base_delay: None,
+        }
+    }
+}
+
+impl Ledbat {
+    /// Bytes that may be in flight at once
+    pub fn window(&self) -> usize {
+        self.window
+    }
+
+    /// Adjusts the window for `bytes_acked` newly acknowledged bytes, whose
+    /// one-way delay the remote end measured as `delay` microseconds
+    pub fn on_ack(&mut self, bytes_acked: usize, delay: u32) {
+        let base_delay = self.base_delay.map_or(delay, |base| base.min(delay));
+        self.base_delay = Some(base_delay);
+
+        let target = TARGET_DELAY.as_micros() as f64;
+        let queuing_delay = (delay - base_delay) as f64;
+        let off_target = (target - queuing_delay) / target;
+        let change = off_target * bytes_acked as f64 * MAX_PAYLOAD as f64 / self.window as f64;
+
+        self.window =
+            (self.window as f64 + change).clamp(MIN_WINDOW as f64, MAX_WINDOW as f64) as usize;
+    }
+
+    /// Halves the window after a packet was lost
+    pub fn on_loss(&mut self) {
+        self.window = (self.window / 2).max(MIN_WINDOW);
+    }
+
+    /// Falls back to a single packet after nothing was acknowledged in time
+    pub fn on_timeout(&mut self) {
+        self.window = MIN_WINDOW;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Waiting for the remote end to acknowledge our SYN
+    SynSent,
+    Connected,
+    /// Waiting for the remote end to acknowledge our FIN
+    FinSent,
+    /// Our FIN was acknowledged, though the remote end may still send data
+    Closed,
+    /// The remote end reset the connection or stopped answering
+    Reset,
+}
+
+/// What became of a packet handed to `Connection::handle`
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Received {
+    /// Payload to deliver to the application, in order
+    pub data: Option<Bytes>,
+    /// Acks and retransmissions to send back
+    pub replies: Vec<Packet>,
+}
+
+#[derive(Debug)]
+struct InFlight {
+    packet: Packet,
+    /// Microseconds on our clock when the packet was last sent
+    sent_at: u64,
+    /// Retransmitted packets don't give reliable round trip times
+    resent: bool,
+}
+
+/// State of a uTP connection, without any IO: incoming packets are handed
+/// to `handle` and the packets it and the other methods return are for the
+/// caller to send. Times are microseconds on the caller's clock.
+#[derive(Debug)]
+pub struct Connection {
+    state: ConnectionState,
+    /// Id of the packets we receive
+    recv_id: u16,
+    /// Id of the packets we send
+    send_id: u16,
+    /// Sequence number of the next packet we send
+    seq_nr: u16,
+    /// Sequence number of the last packet received in order
+    ack_nr: u16,
+    /// Delay of the last packet received, echoed in our headers
+    reply_micro: u32,
+    their_window: u32,
+    congestion: Ledbat,
+    /// Sent packets that weren't acknowledged yet, oldest first
+    in_flight: VecDeque<InFlight>,
+    /// Payload bytes in `in_flight`
+    bytes_in_flight: usize,
+    duplicate_acks: u32,
+    /// Smoothed round trip time
+    rtt: Option<u64>,
+    timeout: u64,
+    /// When the oldest packet in flight is resent
+    resend_at: Option<u64>,
+    retransmissions: u32,
+    /// Whether the remote end sent its FIN
+    eof: bool,
+}
+
+impl Connection {
+    fn new(recv_id: u16, send_id: u16, seq_nr: u16, state: ConnectionState) -> Self {
+        Connection {
+            state,
+            recv_id,
+            send_id,
+            seq_nr,
+            ack_nr: 0,
+            reply_micro: 0,
+            their_window: RECV_WINDOW as u32,
+            congestion: Ledbat::default(),
+            in_flight: VecDeque::new(),
+            bytes_in_flight: 0,
+            duplicate_acks: 0,
+            rtt: None,
+            timeout: INITIAL_TIMEOUT.as_micros() as u64,
+            resend_at: None,
+            retransmissions: 0,
+            eof: false,
+        }
+    }
+
+    /// Starts a connection receiving packets with `recv_id`, returning the
+    /// SYN to send
+    pub fn connect(recv_id: u16, now: u64) -> (Connection, Packet) {
+        let mut connection = Connection::new(
+            recv_id,
+            recv_id.wrapping_add(1),
+            1,
+            ConnectionState::SynSent,
+        );
+        // The SYN is the only packet sent with our receive id
+        let mut syn = connection.packet(PacketType::Syn, Bytes::new(), now);
+        syn.header.connection_id = recv_id;
+        connection.track(syn.clone(), now);
+
+        (connection, syn)
+    }
+
+    /// Accepts the connection `syn` asks for, starting our sequence numbers
+    /// at `seq_nr`. Returns the ack to send.
+    pub fn accept(syn: &Packet, seq_nr: u16, now: u64) -> (Connection, Packet) {
+        let mut connection = Connection::new(
+            syn.header.connection_id.wrapping_add(1),
+            syn.header.connection_id,
+            seq_nr,
+            ConnectionState::Connected,
+        );
+        connection.ack_nr = syn.header.seq_nr;
+        connection.reply_micro = (now as u32).wrapping_sub(syn.header.timestamp);
+        let ack = connection.ack(now);
+
+        (connection, ack)
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    /// Whether the remote end finished sending
+    pub fn is_eof(&self) -> bool {
+        self.eof
+    }
+
+    pub fn window(&self) -> usize {
+        self.congestion.window()
+    }
+
+    /// When `on_timeout` has to be called unless an ack arrives first
+    pub fn resend_at(&self) -> Option<u64> {
+        self.resend_at
+    }
+
+    /// Whether `len` more bytes fit in the congestion and receive windows
+    pub fn can_send(&self, len: usize) -> bool {
+        let window = self.congestion.window().min(self.their_window as usize);
+        self.state == ConnectionState::Connected
+            && (self.bytes_in_flight == 0 || self.bytes_in_flight + len <= window)
+    }
+
+    /// Returns the data packet carrying `payload`
+    pub fn send(&mut self, payload: Bytes, now: u64) -> Packet {
+        let packet = self.packet(PacketType::Data, payload, now);
+        self.track(packet.clone(), now);
+        packet
+    }
+
+    /// Returns the FIN telling the remote end we are done sending
+    pub fn close(&mut self, now: u64) -> Packet {
+        let fin = self.packet(PacketType::Fin, Bytes::new(), now);
+        self.track(fin.clone(), now);
+        self.state = ConnectionState::FinSent;
+        fin
+    }
+
+    /// Acknowledges everything received in order so far
+    pub fn ack(&self, now: u64) -> Packet {
+        Packet {
+            header: self.header(PacketType::State, now),
+            payload: Bytes::new(),
+        }
+    }
+
+    /// Processes a packet from the remote end. Packets of other connections
+    /// are ignored, and data that arrives out of order is dropped for the
+    /// remote end to resend.
+    pub fn handle(&mut self, packet: &Packet, now: u64) -> Received {
+        let header = &packet.header;
+        let mut received = Received::default();
+        if header.connection_id != self.recv_id {
+            return received;
+        }
+
+        self.reply_micro = (now as u32).wrapping_sub(header.timestamp);
+        self.their_window = header.wnd_size;
+
+        match header.packet_type {
+            PacketType::Reset => {
+                self.reset();
+                return received;
+            }
+            PacketType::Syn => {
+                // Our ack got lost
+                received.replies.push(self.ack(now));
+                return received;
+            }
+            PacketType::State if self.state == ConnectionState::SynSent => {
+                self.state = ConnectionState::Connected;
+                // Acks don't take up a sequence number, so the first data
+                // packet will carry this one
+                self.ack_nr = header.seq_nr.wrapping_sub(1);
+            }
+            _ if self.state == ConnectionState::SynSent => return received,
+            _ => {}
+        }
+
+        if let Some(resend) = self.process_ack(header, now) {
+            received.replies.push(resend);
+        }
+        if self.state == ConnectionState::FinSent && self.in_flight.is_empty() {
+            self.state = ConnectionState::Closed;
+        }
+
+        if matches!(header.packet_type, PacketType::Data | PacketType::Fin) {
+            if !self.eof && header.seq_nr == self.ack_nr.wrapping_add(1) {
+                self.ack_nr = header.seq_nr;
+                if header.packet_type == PacketType::Fin {
+                    self.eof = true;
+                } else if !packet.payload.is_empty() {
+                    received.data = Some(packet.payload.clone());
+                }
+            }
+            received.replies.push(self.ack(now));
+        }
+
+        received
+    }
+
+    /// Resends the oldest packet in flight after it wasn't acknowledged in
+    /// time, or resets the connection once it was resent too often
+    pub fn on_timeout(&mut self, now: u64) -> Option<Packet> {
+        if self.in_flight.is_empty() {
+            self.resend_at = None;
+            return None;
+        }
+
+        self.retransmissions += 1;
+        if self.retransmissions > MAX_RETRANSMISSIONS {
+            self.reset();
+            return None;
+        }
+
+        self.congestion.on_timeout();
+        self.timeout = (self.timeout * 2).min(MAX_TIMEOUT.as_micros() as u64);
+        self.resend_at = Some(now + self.timeout);
+        self.resend_oldest(now)
+    }
+
+    fn header(&self, packet_type: PacketType, now: u64) -> Header {
+        Header {
+            packet_type,
+            connection_id: self.send_id,
+            timestamp: now as u32,
+            timestamp_diff: self.reply_micro,
+            wnd_size: RECV_WINDOW as u32,
+            seq_nr: self.seq_nr,
+            ack_nr: self.ack_nr,
+        }
+    }
+
+    /// Builds a packet taking up the next sequence number
+    fn packet(&mut self, packet_type: PacketType, payload: Bytes, now: u64) -> Packet {
+        let packet = Packet {
+            header: self.header(packet_type, now),
+            payload,
+        };
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        packet
+    }
+
+    /// Keeps `packet` to be resent until it is acknowledged
+    fn track(&mut self, packet: Packet, now: u64) {
+        self.bytes_in_flight += packet.payload.len();
+        self.in_flight.push_back(InFlight {
+            packet,
+            sent_at: now,
+            resent: false,
+        });
+        self.resend_at.get_or_insert(now + self.timeout);
+    }
+
+    /// Drops the packets `header` acknowledges. Returns the oldest packet
+    /// in flight to resend if the remote end keeps acking the one before it.
+    fn process_ack(&mut self, header: &Header, now: u64) -> Option<Packet> {
+        let mut acked = 0;
+        let mut progress = false;
+        while let Some(oldest) = self.in_flight.front() {
+            if !seq_at_most(oldest.packet.header.seq_nr, header.ack_nr) {
+                break;
+            }
+
+            let oldest = self.in_flight.pop_front().unwrap();
+            if !oldest.resent {
+                self.update_rtt(now.saturating_sub(oldest.sent_at));
+            }
+            acked += oldest.packet.payload.len();
+            progress = true;
+        }
+
+        if progress {
+            self.bytes_in_flight -= acked;
+            self.duplicate_acks = 0;
+            self.retransmissions = 0;
+            self.resend_at = (!self.in_flight.is_empty()).then_some(now + self.timeout);
+            if acked > 0 {
+                self.congestion.on_ack(acked, header.timestamp_diff);
+            }
+            return None;
+        }
+
+        if header.packet_type != PacketType::State || self.in_flight.is_empty() {
+            return None;
+        }
+        self.duplicate_acks += 1;
+        if self.duplicate_acks != DUPLICATE_ACKS {
+            return None;
+        }
+
+        trace!(
+            "Packet {} was lost, resending it",
+            header.ack_nr.wrapping_add(1)
+        );
+        self.congestion.on_loss();
+        self.resend_oldest(now)
+    }
+
+    fn resend_oldest(&mut self, now: u64) -> Option<Packet> {
+        let oldest = self.in_flight.front_mut()?;
+        oldest.sent_at = now;
+        oldest.resent = true;
+        oldest.packet.header.timestamp = now as u32;
+        oldest.packet.header.timestamp_diff = self.reply_micro;
+        oldest.packet.header.ack_nr = self.ack_nr;
+
+        Some(oldest.packet.clone())
+    }
+
+    fn update_rtt(&mut self, sample: u64) {
+        let rtt = match self.rtt {
+            Some(rtt) => (rtt * 7 + sample) / 8,
+            None => sample,
+        };
+        self.rtt = Some(rtt);
+        self.timeout = (rtt * 2).clamp(
+            MIN_TIMEOUT.as_micros() as u64,
+            MAX_TIMEOUT.as_micros() as u64,
+        );
+    }
+
+    fn reset(&mut self) {
+        self.state = ConnectionState::Reset;
+        self.in_flight.clear();
+        self.bytes_in_flight = 0;
+        self.resend_at = None;
+    }
+}
+
+/// Whether sequence number `a` comes before or is `b`, allowing for them
+/// wrapping around
+fn seq_at_most(a: u16, b: u16) -> bool {
+    b.wrapping_sub(a) < 0x8000
+}
+
+/// Microseconds since a connection started, which its timestamps count
+#[derive(Debug, Clone, Copy)]
+struct Clock(Instant);
+
+impl Clock {
+    fn now(&self) -> u64 {
+        self.0.elapsed().as_micros() as u64
+    }
+
+    fn at(&self, micros: u64) -> Instant {
+        self.0 + Duration::from_micros(micros)
+    }
+}
+
+/// Outgoing uTP connection, read and written like a `TcpStream`. The
+/// protocol runs in a task exchanging datagrams with the remote end, which
+/// ends when the stream is dropped or the connection fails.
+#[derive(Debug)]
+pub struct UtpStream {
+    inner: DuplexStream,
+}
+
+impl UtpStream {
+    /// Connects to the uTP endpoint at `addr` from a new UDP socket
+    pub async fn connect(addr: SocketAddr) -> io::Result<UtpStream> {
+        let local = match addr {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(addr).await?;
+
+        let clock = Clock(Instant::now());
+        let (mut connection, syn) = Connection::connect(rand::random(), clock.now());
+        socket.send(&syn.to_bytes()).await?;
+
+        let mut buf = vec![0u8; RECV_BUFFER_SIZE];
+        while connection.state() == ConnectionState::SynSent {
+            let resend_at = clock.at(connection.resend_at().unwrap_or_default());
+            tokio::select! {
+                received = socket.recv(&mut buf) => {
+                    if let Ok(packet) = Packet::from_bytes(&buf[..received?]) {
+                        connection.handle(&packet, clock.now());
+                    }
+                }
+                _ = tokio::time::sleep_until(resend_at) => {
+                    if let Some(syn) = connection.on_timeout(clock.now()) {
+                        socket.send(&syn.to_bytes()).await?;
+                    }
+                }
+            }
+        }
+
+        if connection.state() != ConnectionState::Connected {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                "uTP connection was not accepted",
+            ));
+        }
+
+        let (stream, app) = tokio::io::duplex(RECV_WINDOW);
+        tokio::spawn(async move {
+            if let Err(err) = run(&socket, &mut connection, app, clock).await {
+                debug!("uTP connection to {addr} failed: {err}");
+            }
+        });
+
+        Ok(UtpStream { inner: stream })
+    }
+}
+
+/// Moves data between the application's end of the stream and the socket
+/// until both sides finished sending, or `CLOSE_TIMEOUT` after our FIN was
+/// acknowledged if the remote end doesn't finish
+async fn run(
+    socket: &UdpSocket,
+    connection: &mut Connection,
+    mut app: DuplexStream,
+    clock: Clock,
+) -> io::Result<()> {
+    let mut buf = vec![0u8; RECV_BUFFER_SIZE];
+    let mut outgoing = vec![0u8; MAX_PAYLOAD];
+    let mut app_done = false;
+    let mut close_deadline = None;
+
+    loop {
+        match connection.state() {
+            ConnectionState::Reset => return Err(io::ErrorKind::ConnectionReset.into()),
+            ConnectionState::Closed if connection.is_eof() => return Ok(()),
+            ConnectionState::Closed => {
+                close_deadline.get_or_insert_with(|| Instant::now() + CLOSE_TIMEOUT);
+            }
+            _ => {}
+        }
+
+        let resend_at = connection.resend_at();
+        tokio::select! {
+            received = socket.recv(&mut buf) => {
+                let packet = match Packet::from_bytes(&buf[..received?]) {
+                    Ok(packet) => packet,
+                    Err(err) => {
+                        trace!("Ignoring invalid uTP packet: {err}");
+                        continue;
+                    }
+                };
+
+                let was_eof = connection.is_eof();
+                let received = connection.handle(&packet, clock.now());
+                for reply in received.replies {
+                    socket.send(&reply.to_bytes()).await?;
+                }
+                if let Some(data) = received.data {
+                    app.write_all(&data).await?;
+                }
+                if connection.is_eof() && !was_eof {
+                    app.shutdown().await?;
+                }
+            }
+            read = app.read(&mut outgoing), if !app_done && connection.can_send(MAX_PAYLOAD) => {
+                let packet = match read? {
+                    0 => {
+                        app_done = true;
+                        connection.close(clock.now())
+                    }
+                    len => connection.send(Bytes::copy_from_slice(&outgoing[..len]), clock.now()),
+                };
+                socket.send(&packet.to_bytes()).await?;
+            }
+            _ = tokio::time::sleep_until(clock.at(resend_at.unwrap_or_default())),
+                if resend_at.is_some() =>
+            {
+                if let Some(packet) = connection.on_timeout(clock.now()) {
+                    socket.send(&packet.to_bytes()).await?;
+                }
+            }
+            _ = tokio::time::sleep_until(close_deadline.unwrap_or_else(Instant::now)),
+                if close_deadline.is_some() =>
+            {
+                debug!("uTP remote end did not finish after our FIN, closing");
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data_packet(seq_nr: u16, payload: &'static [u8]) -> Packet {
+        Packet {
+            header: Header {
+                packet_type: PacketType::Data,
+                connection_id: 7,
+                timestamp: 1_000,
+                timestamp_diff: 20,
+                wnd_size: 4_096,
+                seq_nr,
+                ack_nr: 3,
+            },
+            payload: Bytes::from_static(payload),
+        }
+    }
+
+    #[test]
+    fn packet_round_trip() {
+        let packet = data_packet(0xabcd, b"hello");
+
+        let bytes = packet.to_bytes();
+
+        assert_eq!(bytes.len(), HEADER_SIZE + 5);
+        assert_eq!(bytes[..4], [0x01, 0, 0, 7]);
+        assert_eq!(Packet::from_bytes(&bytes).unwrap(), packet);
+    }
+
+    #[test]
+    fn invalid_packets_rejected() {
+        let bytes = data_packet(1, b"").to_bytes();
+
+        let mut version = bytes.to_vec();
+        version[0] = 0x02;
+        let mut packet_type = bytes.to_vec();
+        packet_type[0] = 0x51;
+        let mut extension = bytes.to_vec();
+        extension[1] = 1;
+        extension.extend([0, 4, 1, 2]);
+
+        assert!(matches!(
+            Packet::from_bytes(&bytes[..HEADER_SIZE - 1]),
+            Err(UtpErr::TooShort(19))
+        ));
+        assert!(matches!(
+            Packet::from_bytes(&version),
+            Err(UtpErr::UnsupportedVersion(2))
+        ));
+        assert!(matches!(
+            Packet::from_bytes(&packet_type),
+            Err(UtpErr::InvalidType(5))
+        ));
+        assert!(matches!(
+            Packet::from_bytes(&extension),
+            Err(UtpErr::InvalidExtension)
+        ));
+    }
+
+    #[test]
+    fn extensions_skipped() {
+        let mut bytes = data_packet(1, b"").to_bytes().to_vec();
+        bytes[1] = 1;
+        bytes.extend([0, 4, 0xff, 0xff, 0xff, 0xff]);
+        bytes.extend(b"data");
+
+        let packet = Packet::from_bytes(&bytes).unwrap();
+
+        assert_eq!(packet.payload, Bytes::from_static(b"data"));
+    }
+
+    #[test]
+    fn connection_lifecycle() {
+        let (mut client, syn) = Connection::connect(100, 0);
+        assert_eq!(client.state(), ConnectionState::SynSent);
+        assert_eq!(syn.header.packet_type, PacketType::Syn);
+        assert_eq!(syn.header.connection_id, 100);
+        assert!(!client.can_send(MAX_PAYLOAD));
+
+        let (mut server, ack) = Connection::accept(&syn, 500, 10);
+        assert_eq!(ack.header.connection_id, 100);
+        assert_eq!(ack.header.ack_nr, syn.header.seq_nr);
+        assert_eq!(client.handle(&ack, 20), Received::default());
+        assert_eq!(client.state(), ConnectionState::Connected);
+        assert_eq!(client.resend_at(), None);
+
+        let data = client.send(Bytes::from_static(b"ping"), 30);
+        assert_eq!(data.header.connection_id, 101);
+        let received = server.handle(&data, 40);
+        assert_eq!(received.data, Some(Bytes::from_static(b"ping")));
+        assert_eq!(received.replies[0].header.ack_nr, data.header.seq_nr);
+        client.handle(&received.replies[0], 50);
+        assert!(client.in_flight.is_empty());
+
+        let reply = server.send(Bytes::from_static(b"pong"), 60);
+        assert_eq!(reply.header.seq_nr, 500);
+        let received = client.handle(&reply, 70);
+        assert_eq!(received.data, Some(Bytes::from_static(b"pong")));
+
+        let fin = client.close(80);
+        assert_eq!(client.state(), ConnectionState::FinSent);
+        let received = server.handle(&fin, 90);
+        assert!(server.is_eof());
+        client.handle(&received.replies[0], 100);
+        assert_eq!(client.state(), ConnectionState::Closed);
+    }
+
+    #[test]
+    fn out_of_order_data_dropped() {
+        let (mut client, syn) = Connection::connect(100, 0);
+        let (mut server, ack) = Connection::accept(&syn, 500, 0);
+        client.handle(&ack, 0);
+
+        let first = client.send(Bytes::from_static(b"first"), 0);
+        let second = client.send(Bytes::from_static(b"second"), 0);
+
+        let early = server.handle(&second, 0);
+        assert_eq!(early.data, None);
+        assert_eq!(early.replies[0].header.ack_nr, syn.header.seq_nr);
+        assert_eq!(
+            server.handle(&first, 0).data,
+            Some(Bytes::from_static(b"first"))
+        );
+        assert_eq!(
+            server.handle(&second, 0).data,
+            Some(Bytes::from_static(b"second"))
+        );
+        // Repeats are acked again but not delivered twice
+        assert_eq!(server.handle(&second, 0).data, None);
+    }
+
+    #[test]
+    fn lost_packets_resent() {
+        let (mut client, syn) = Connection::connect(100, 0);
+        let (server, ack) = Connection::accept(&syn, 500, 0);
+        client.handle(&ack, 0);
+
+        let lost = client.send(Bytes::from_static(b"lost"), 0);
+        client.send(Bytes::from_static(b"next"), 0);
+        let window = client.window();
+
+        let duplicate = server.ack(0);
+        assert!(client.handle(&duplicate, 0).replies.is_empty());
+        assert!(client.handle(&duplicate, 0).replies.is_empty());
+        let resent = client.handle(&duplicate, 0).replies;
+        assert_eq!(resent[0].header.seq_nr, lost.header.seq_nr);
+        assert_eq!(client.window(), (window / 2).max(MIN_WINDOW));
+
+        let mut now = client.resend_at().unwrap();
+        for _ in 0..MAX_RETRANSMISSIONS {
+            let resent = client.on_timeout(now).unwrap();
+            assert_eq!(resent.payload, Bytes::from_static(b"lost"));
+            now = client.resend_at().unwrap();
+        }
+        assert_eq!(client.on_timeout(now), None);
+        assert_eq!(client.state(), ConnectionState::Reset);
+    }
+
+    #[test]
+    fn reset_ends_connection() {
+        let (mut client, syn) = Connection::connect(100, 0);
+        let mut reset = Connection::accept(&syn, 500, 0).1;
+        reset.header.packet_type = PacketType::Reset;
+
+        client.handle(&reset, 0);
+
+        assert_eq!(client.state(), ConnectionState::Reset);
+        assert_eq!(client.resend_at(), None);
+    }
+
+    #[test]
+    fn ledbat_window_follows_queuing_delay() {
+        let mut ledbat = Ledbat::default();
+        ledbat.on_ack(MAX_PAYLOAD, 50_000);
+        let base = ledbat.window();
+
+        for _ in 0..10 {
+            ledbat.on_ack(MAX_PAYLOAD, 60_000);
+        }
+        let grown = ledbat.window();
+        for _ in 0..100 {
+            ledbat.on_ack(MAX_PAYLOAD, 450_000);
+        }
+
+        assert!(grown > base);
+        assert_eq!(ledbat.window(), MIN_WINDOW);
+        ledbat.on_loss();
+        assert_eq!(ledbat.window(), MIN_WINDOW);
+    }
+
+    #[tokio::test]
+    async fn stream_exchanges_data() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; RECV_BUFFER_SIZE];
+            let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+            let syn = Packet::from_bytes(&buf[..len]).unwrap();
+            let (mut connection, ack) = Connection::accept(&syn, 1_000, 0);
+            socket.send_to(&ack.to_bytes(), from).await.unwrap();
+
+            // Answer every chunk of data with the same bytes reversed
+            loop {
+                let len = socket.recv(&mut buf).await.unwrap();
+                let packet = Packet::from_bytes(&buf[..len]).unwrap();
+                let received = connection.handle(&packet, 0);
+                for reply in received.replies {
+                    socket.send_to(&reply.to_bytes(), from).await.unwrap();
+                }
+                if let Some(data) = received.data {
+                    let reversed: Vec<u8> = data.iter().rev().copied().collect();
+                    let reply = connection.send(Bytes::from(reversed), 0);
+                    socket.send_to(&reply.to_bytes(), from).await.unwrap();
+                }
+            }
+        });
+
+        let mut stream = UtpStream::connect(addr).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply).await.unwrap();
+
+        assert_eq!(&reply, b"gnip");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn close_gives_up_on_remote_fin() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; RECV_BUFFER_SIZE];
+            let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+            let syn = Packet::from_bytes(&buf[..len]).unwrap();
+            let (mut connection, ack) = Connection::accept(&syn, 1_000, 0);
+            socket.send_to(&ack.to_bytes(), from).await.unwrap();
+
+            // Acknowledges everything, our FIN included, but never sends its own
+            loop {
+                let len = socket.recv(&mut buf).await.unwrap();
+                let packet = Packet::from_bytes(&buf[..len]).unwrap();
+                for reply in connection.handle(&packet, 0).replies {
+                    socket.send_to(&reply.to_bytes(), from).await.unwrap();
+                }
+            }
+        });
+
+        let mut stream = UtpStream::connect(addr).await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut buf = [0u8; 1];
+        let read = tokio::time::timeout(CLOSE_TIMEOUT * 2, stream.read(&mut buf)).await;
+
+        // The connection task ended, closing our end of the stream
+        assert_eq!(read.unwrap().unwrap(), 0);
+    }
+}
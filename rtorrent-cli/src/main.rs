@@ -1,8 +1,21 @@
+use std::{error::Error, path::PathBuf};
+
 use clap::{Parser, Subcommand};
+use librtorrent::{
+    ipc::{self, IpcErr, IpcRequest, IpcResponse},
+    meta_info::{format_size, TorrentType},
+    piece_manager::PieceManager,
+    torrent::{self, TorrentStatus},
+};
+
+type CliResult = Result<(), Box<dyn Error>>;
 
 #[derive(Debug, Parser)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+    /// Address of the rtorrentd control socket
+    #[arg(long, default_value = ipc::DEFAULT_CONTROL_ADDR)]
+    addr: String,
     #[command(subcommand)]
     command: Command,
 }
@@ -10,16 +23,189 @@ struct Args {
 #[derive(Debug, Subcommand)]
 #[clap(rename_all = "snake_case")]
 enum Command {
-    Add { value: String },
-    Remove { value: String },
-    Info { value: String },
-    List { value: String },
+    Add {
+        value: String,
+    },
+    Remove {
+        value: String,
+    },
+    Info {
+        value: String,
+        /// Print the meta info as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    List {
+        value: String,
+    },
+    /// Show the status of one or all torrents in the daemon
+    Status {
+        value: Option<String>,
+    },
+    /// Re-hash an existing download against its torrent file
+    Verify {
+        torrent: String,
+        /// The downloaded file, or directory for multi-file torrents
+        path: String,
+    },
 }
 
-fn main() {
+#[tokio::main]
+async fn main() {
     let args = Args::parse();
 
-    match args.command {
-        x => todo!("{x:#?}"),
+    let result = match args.command {
+        Command::Add { value } => add(&args.addr, &value).await,
+        Command::Remove { value } => remove(&args.addr, &value).await,
+        Command::Info { value, json } => info(&value, json),
+        Command::List { value } => list(&value),
+        Command::Status { value } => status(&args.addr, value.as_deref()).await,
+        Command::Verify { torrent, path } => verify(&torrent, &path).await,
+    };
+
+    if let Err(err) = result {
+        eprintln!("Error: {err}");
+        std::process::exit(1);
+    }
+}
+
+/// Asks the daemon to add and start the torrent at `path`
+async fn add(addr: &str, path: &str) -> CliResult {
+    // The daemon may run in another directory, so always send an absolute path
+    let path = std::fs::canonicalize(path)?;
+    let request = IpcRequest::Add {
+        path: path.to_string_lossy().to_string(),
+    };
+
+    match ipc::send_request(addr, &request).await? {
+        IpcResponse::Added { info_hash } => println!("Added {}", to_hex(&info_hash)),
+        response => return unexpected_response(response),
+    }
+
+    Ok(())
+}
+
+/// Asks the daemon to stop and remove the torrent with the hex info hash `value`
+async fn remove(addr: &str, value: &str) -> CliResult {
+    let request = IpcRequest::Remove {
+        info_hash: parse_info_hash(value)?,
+    };
+
+    match ipc::send_request(addr, &request).await? {
+        IpcResponse::Removed => println!("Removed {value}"),
+        response => return unexpected_response(response),
+    }
+
+    Ok(())
+}
+
+/// Prints the status of the torrent with the hex info hash `value`, or all torrents
+async fn status(addr: &str, value: Option<&str>) -> CliResult {
+    let request = match value {
+        Some(value) => IpcRequest::Info {
+            info_hash: parse_info_hash(value)?,
+        },
+        None => IpcRequest::List,
+    };
+
+    match ipc::send_request(addr, &request).await? {
+        IpcResponse::Info(status) => print_status(&status),
+        IpcResponse::List(statuses) => statuses.iter().for_each(print_status),
+        response => return unexpected_response(response),
+    }
+
+    Ok(())
+}
+
+fn print_status(status: &TorrentStatus) {
+    println!(
+        "{}  {}  {}  {}/{} pieces  {} peers  {}/s down  {}/s up",
+        to_hex(&status.info_hash),
+        status.name,
+        status.state,
+        status.pieces_completed,
+        status.pieces_total,
+        status.connected_peers,
+        format_size(status.download_rate as i64),
+        format_size(status.upload_rate as i64)
+    );
+}
+
+fn unexpected_response(response: IpcResponse) -> CliResult {
+    match response {
+        IpcResponse::Error(message) => Err(IpcErr::DaemonErr(message).into()),
+        response => Err(format!("Unexpected response from daemon: {response:?}").into()),
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn parse_info_hash(value: &str) -> Result<[u8; 20], Box<dyn Error>> {
+    if value.len() != 40 || !value.is_ascii() {
+        return Err(format!("Invalid info hash {value}").into());
+    }
+
+    let mut info_hash = [0u8; 20];
+    for (index, byte) in info_hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&value[index * 2..index * 2 + 2], 16)?;
+    }
+
+    Ok(info_hash)
+}
+
+/// Prints the meta info of the torrent file at `path`, as JSON if `json` is set
+fn info(path: &str, json: bool) -> CliResult {
+    let meta_info = torrent::read_meta_info(&PathBuf::from(path))?;
+
+    if json {
+        println!("{}", meta_info.to_json());
+        return Ok(());
+    }
+
+    println!("{meta_info}");
+    for tracker in meta_info.trackers() {
+        println!("  {tracker}");
+    }
+
+    Ok(())
+}
+
+/// Prints how many pieces of the download at `path` match the torrent file `torrent`
+async fn verify(torrent: &str, path: &str) -> CliResult {
+    let meta_info = torrent::read_meta_info(&PathBuf::from(torrent))?;
+    let report = PieceManager::verify_download(&meta_info, &PathBuf::from(path)).await?;
+
+    println!(
+        "{}/{} pieces valid, {} invalid ({:.1}% complete)",
+        report.valid,
+        report.valid + report.invalid,
+        report.invalid,
+        report.percent_complete()
+    );
+
+    Ok(())
+}
+
+/// Prints every file contained in the torrent file at `path` with its size
+fn list(path: &str) -> CliResult {
+    let meta_info = torrent::read_meta_info(&PathBuf::from(path))?;
+    let info = &meta_info.info;
+
+    match info.is_single_or_multi_file() {
+        TorrentType::SingleFile => {
+            println!("{}\t{} bytes", info.name, info.length.unwrap_or(0));
+        }
+        TorrentType::MultiFile => {
+            for file in info.visible_files() {
+                let file_path: PathBuf = file.path.iter().collect();
+                println!("{}\t{} bytes", file_path.display(), file.length);
+            }
+        }
     }
+
+    println!("Total: {} bytes", info.total_length());
+
+    Ok(())
 }
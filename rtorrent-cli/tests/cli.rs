@@ -0,0 +1,89 @@
+use std::{path::PathBuf, process::Command};
+
+use librtorrent::meta_info::MetaInfo;
+
+const DEBIAN_TORRENT: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/../test/torrent_files/debian-13.1.0-amd64-netinst.iso.torrent"
+);
+const MULTI_FILE_TORRENT: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/../test/torrent_files/multi_file.torrent"
+);
+
+fn run(args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_rtorrent-cli"))
+        .args(args)
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "{output:?}");
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn info_prints_metadata() {
+    let stdout = run(&["info", DEBIAN_TORRENT]);
+
+    assert!(stdout.contains("2ced861966e919e5ca9e35d27dc23e0b02fb7ff8"));
+    assert!(stdout.contains("debian-13.1.0-amd64-netinst.iso"));
+    assert!(stdout.contains("http://bttracker.debian.org:6969/announce"));
+}
+
+#[test]
+fn list_prints_all_files() {
+    let stdout = run(&["list", MULTI_FILE_TORRENT]);
+
+    assert!(stdout.contains("README.txt\t12 bytes"));
+    assert!(stdout.contains("docs/guide.md\t800 bytes"));
+    assert!(stdout.contains("data/nested/blob.bin\t5120 bytes"));
+    assert!(stdout.contains("Total: 5932 bytes"));
+}
+
+#[test]
+fn list_prints_single_file() {
+    let stdout = run(&["list", DEBIAN_TORRENT]);
+
+    assert!(stdout.contains("debian-13.1.0-amd64-netinst.iso\t821035008 bytes"));
+}
+
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("rtorrent-cli-{}-{name}", std::process::id()))
+}
+
+#[test]
+fn verify_counts_valid_pieces() {
+    let dir = temp_path("verify");
+    std::fs::create_dir_all(&dir).unwrap();
+    let data_path = dir.join("data.bin");
+    let mut data: Vec<u8> = (0..64).collect();
+    std::fs::write(&data_path, &data).unwrap();
+    let torrent_path = dir.join("data.torrent");
+    let meta_info = MetaInfo::create(
+        &data_path,
+        16,
+        &["http://tracker.example.com/announce".to_string()],
+    )
+    .unwrap();
+    std::fs::write(&torrent_path, meta_info.to_bytes()).unwrap();
+    let args = [
+        "verify",
+        torrent_path.to_str().unwrap(),
+        data_path.to_str().unwrap(),
+    ];
+
+    let complete = run(&args);
+    data[40] ^= 0xff;
+    std::fs::write(&data_path, &data).unwrap();
+    let corrupted = run(&args);
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert!(
+        complete.contains("4/4 pieces valid, 0 invalid (100.0% complete)"),
+        "{complete}"
+    );
+    assert!(
+        corrupted.contains("3/4 pieces valid, 1 invalid (75.0% complete)"),
+        "{corrupted}"
+    );
+}
@@ -1,10 +1,73 @@
-use librtorrent::session::Session;
+use std::sync::Arc;
+
+use librtorrent::{config::SessionConfig, ipc, session::Session};
+use log::{error, info};
+use tokio::{net::TcpListener, sync::Mutex};
 
 #[tokio::main]
 async fn main() {
-    let mut session = Session::new();
-    session
-        .add_torrent("test/torrent_files/debian-13.1.0-amd64-netinst.iso.torrent")
-        .await;
-    session.start().await;
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let addr = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| ipc::DEFAULT_CONTROL_ADDR.to_string());
+
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("Failed to bind control socket {addr}: {err}");
+            return;
+        }
+    };
+    info!("Listening for commands on {addr}");
+
+    let mut session = Session::new(SessionConfig::default());
+    if let Err(err) = session.listen().await {
+        error!("Failed to listen for peers: {err}");
+    }
+    if let Err(err) = session.start_local_discovery().await {
+        error!("Failed to start local peer discovery: {err}");
+    }
+
+    let session = Arc::new(Mutex::new(session));
+    tokio::select! {
+        result = ipc::serve(listener, session.clone()) => {
+            if let Err(err) = result {
+                error!("Control socket failed: {err}");
+            }
+        }
+        _ = shutdown_signal() => info!("Shutting down"),
+    }
+
+    session.lock().await.shutdown().await;
+}
+
+/// Resolves on SIGINT, or SIGTERM on unix
+async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            error!("Failed to listen for SIGINT: {err}");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(err) => {
+                error!("Failed to listen for SIGTERM: {err}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
+    }
 }
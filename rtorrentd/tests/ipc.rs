@@ -0,0 +1,63 @@
+use std::{
+    net::TcpListener,
+    process::{Child, Command},
+    time::Duration,
+};
+
+use librtorrent::ipc::{self, IpcRequest, IpcResponse};
+
+const TEST_TORRENT: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/../test/torrent_files/debian-13.1.0-amd64-netinst.iso.torrent"
+);
+
+/// Kills the daemon when the test ends, even if it panics
+struct Daemon(Child);
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn free_addr() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+async fn send_when_ready(addr: &str, request: &IpcRequest) -> IpcResponse {
+    for _ in 0..50 {
+        if let Ok(response) = ipc::send_request(addr, request).await {
+            return response;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    panic!("Daemon never accepted the request");
+}
+
+#[tokio::test]
+async fn add_over_control_socket() {
+    let addr = free_addr();
+    let _daemon = Daemon(
+        Command::new(env!("CARGO_BIN_EXE_rtorrentd"))
+            .arg(&addr)
+            .current_dir(std::env::temp_dir())
+            .spawn()
+            .unwrap(),
+    );
+
+    let request = IpcRequest::Add {
+        path: TEST_TORRENT.to_string(),
+    };
+    let info_hash = match send_when_ready(&addr, &request).await {
+        IpcResponse::Added { info_hash } => info_hash,
+        response => panic!("Unexpected response {response:?}"),
+    };
+
+    let expected = librtorrent::torrent::read_meta_info(&TEST_TORRENT.into())
+        .unwrap()
+        .hash;
+    assert_eq!(info_hash, expected);
+}